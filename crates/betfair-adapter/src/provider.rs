@@ -31,4 +31,21 @@ impl BetfairRpcClient<Authenticated> {
     pub const fn session_token(&self) -> &SessionToken {
         &self.state.session_token
     }
+
+    /// Returns a client with the same configuration but without the session, e.g. to log in
+    /// again once the session has expired.
+    #[must_use]
+    pub fn unauthenticated(&self) -> BetfairRpcClient<Unauthenticated> {
+        BetfairRpcClient {
+            bot_login_client: self.bot_login_client.clone(),
+            rest_base: self.rest_base.clone(),
+            keep_alive: self.keep_alive.clone(),
+            bot_login: self.bot_login.clone(),
+            logout: self.logout.clone(),
+            login: self.login.clone(),
+            stream: self.stream.clone(),
+            secret_provider: self.secret_provider.clone(),
+            state: Unauthenticated,
+        }
+    }
 }
@@ -23,6 +23,8 @@ impl BetfairRpcClient<Unauthenticated> {
     }
 
     /// Authenticates the user and returns an `AuthenticatedBetfairRpcProvider`.
+    ///
+    /// Failed login attempts are retried using the default exponential backoff.
     pub async fn authenticate(
         self,
     ) -> Result<
@@ -32,7 +34,25 @@ impl BetfairRpcClient<Unauthenticated> {
         ),
         ApiError,
     > {
-        let mut backoff = ExponentialBuilder::new().build();
+        self.authenticate_with_backoff(ExponentialBuilder::new())
+            .await
+    }
+
+    /// Authenticates the user and returns an `AuthenticatedBetfairRpcProvider`.
+    ///
+    /// Failed login attempts are retried with delays produced by `backoff`; once the backoff is
+    /// exhausted the authentication fails.
+    pub async fn authenticate_with_backoff(
+        self,
+        backoff: impl BackoffBuilder,
+    ) -> Result<
+        (
+            Arc<BetfairRpcClient<Authenticated>>,
+            JoinHandle<Result<(), ApiError>>,
+        ),
+        ApiError,
+    > {
+        let mut backoff = backoff.build();
         let mut first_call = true;
         let (session_token, authenticated_client) = loop {
             if !first_call {
@@ -43,9 +63,13 @@ impl BetfairRpcClient<Unauthenticated> {
                 };
                 sleep(delay).await;
             }
-            first_call = true;
-            let Ok(res) = self.bot_log_in().await else {
-                continue;
+            first_call = false;
+            let res = match self.bot_log_in().await {
+                Ok(res) => res,
+                Err(err) => {
+                    tracing::warn!(?err, "login failed");
+                    continue;
+                }
             };
 
             break res;
@@ -9,7 +9,9 @@ pub use wiremock;
 use wiremock::matchers::{PathExactMatcher, method, path};
 use wiremock::{Mock, MockBuilder, MockServer, ResponseTemplate};
 
+mod stream_server;
 mod urlencoded_matcher;
+pub use stream_server::{StreamServer, StreamServerSettings};
 use urlencoded_matcher::FormEncodedBodyMatcher;
 
 pub const USERNAME: &str = "usrn";
@@ -0,0 +1,484 @@
+//! A local, scriptable stand-in for the Betfair Exchange Stream API (ESA).
+//!
+//! The server speaks the real wire protocol: CRLF separated JSON messages, a `connection`
+//! message as soon as a client connects, `authentication` checked against [`SESSION_TOKEN`] and
+//! [`APP_KEY`], and a `status` answer to every heartbeat and subscription. Market and order
+//! changes are never generated on their own, tests push them explicitly (or replay a fixture
+//! file) so that every message the client sees is known up front.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use betfair_adapter::Stream;
+use betfair_adapter::jurisdiction::CustomUrl;
+use betfair_stream_types::request::RequestMessage;
+use betfair_stream_types::response::ResponseMessage;
+use betfair_stream_types::response::connection_message::ConnectionMessage;
+use betfair_stream_types::response::status_message::{
+    ErrorCode, StatusError, StatusMessage, StatusSuccess,
+};
+use futures::StreamExt as _;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt as _};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio_util::codec::{FramedRead, LinesCodec};
+
+use crate::{APP_KEY, SESSION_TOKEN};
+
+/// Settings of a [`StreamServer`].
+#[derive(Debug, Clone)]
+pub struct StreamServerSettings {
+    /// The session token a client must authenticate with.
+    pub session_token: String,
+    /// The application key a client must authenticate with.
+    pub app_key: String,
+    /// Wrap every connection in TLS using a freshly generated self-signed certificate for
+    /// `localhost` and `127.0.0.1`.
+    pub tls: bool,
+    /// The `connectionsAvailable` value reported after a successful authentication.
+    pub connections_available: i32,
+}
+
+impl Default for StreamServerSettings {
+    fn default() -> Self {
+        Self {
+            session_token: SESSION_TOKEN.to_owned(),
+            app_key: APP_KEY.to_owned(),
+            tls: false,
+            connections_available: 10,
+        }
+    }
+}
+
+/// A mock ESA server listening on a random local port.
+///
+/// Every connection accepted by the server is driven by its own task. The control methods
+/// (`push`, `drop_connections`, `fail_next_*`, ...) apply to all connections that are currently
+/// open, so tests usually only keep a single client connected at a time.
+pub struct StreamServer {
+    local_addr: SocketAddr,
+    certificate: Option<CertificateDer<'static>>,
+    shared: Arc<Shared>,
+    requests: tokio::sync::Mutex<mpsc::UnboundedReceiver<RequestMessage>>,
+    authentications: watch::Receiver<usize>,
+    accept_task: JoinHandle<()>,
+}
+
+struct Shared {
+    settings: StreamServerSettings,
+    script: Mutex<Script>,
+    connections: Mutex<Vec<mpsc::UnboundedSender<Command>>>,
+    connection_count: AtomicU64,
+    requests: mpsc::UnboundedSender<RequestMessage>,
+    authentications: watch::Sender<usize>,
+}
+
+/// Behaviour scripted by the test for upcoming requests.
+#[derive(Debug, Default)]
+struct Script {
+    authentication_errors: VecDeque<ErrorCode>,
+    subscription_errors: VecDeque<ErrorCode>,
+    heartbeat_delay: Option<Duration>,
+}
+
+#[derive(Debug)]
+enum Command {
+    Send(String),
+    Close,
+}
+
+impl StreamServer {
+    /// Start a plaintext TCP server with the default credentials.
+    pub async fn new() -> Self {
+        Self::new_with_settings(StreamServerSettings::default()).await
+    }
+
+    /// Start a TLS server with the default credentials.
+    pub async fn new_with_tls() -> Self {
+        let settings = StreamServerSettings {
+            tls: true,
+            ..Default::default()
+        };
+        Self::new_with_settings(settings).await
+    }
+
+    /// Start a server with custom settings.
+    ///
+    /// # Panics
+    /// if the listener cannot be bound or the TLS certificate cannot be generated
+    pub async fn new_with_settings(settings: StreamServerSettings) -> Self {
+        let listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))
+            .await
+            .expect("bind stream mock listener");
+        let local_addr = listener.local_addr().expect("stream mock local address");
+
+        let (certificate, acceptor) = if settings.tls {
+            let (certificate, acceptor) = tls_acceptor();
+            (Some(certificate), Some(acceptor))
+        } else {
+            (None, None)
+        };
+
+        let (requests_tx, requests_rx) = mpsc::unbounded_channel();
+        let (authentications_tx, authentications_rx) = watch::channel(0);
+        let shared = Arc::new(Shared {
+            settings,
+            script: Mutex::new(Script::default()),
+            connections: Mutex::new(Vec::new()),
+            connection_count: AtomicU64::new(0),
+            requests: requests_tx,
+            authentications: authentications_tx,
+        });
+
+        let accept_task = tokio::spawn(accept_loop(listener, acceptor, Arc::clone(&shared)));
+
+        Self {
+            local_addr,
+            certificate,
+            shared,
+            requests: tokio::sync::Mutex::new(requests_rx),
+            authentications: authentications_rx,
+            accept_task,
+        }
+    }
+
+    /// The address the server is listening on.
+    #[must_use]
+    pub const fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// A stream url pointing at this server.
+    ///
+    /// # Panics
+    /// never, the url is always valid
+    #[must_use]
+    pub fn url(&self) -> CustomUrl<Stream> {
+        let scheme = if self.certificate.is_some() {
+            "tls"
+        } else {
+            "tcp"
+        };
+        let url = format!("{scheme}://{}", self.local_addr);
+        CustomUrl::new(url.parse().expect("valid stream mock url"))
+    }
+
+    /// The self-signed certificate presented to clients, `None` for a plaintext server.
+    ///
+    /// Add it to the client's root store to make it trust the server.
+    #[must_use]
+    pub const fn certificate(&self) -> Option<&CertificateDer<'static>> {
+        self.certificate.as_ref()
+    }
+
+    /// Send a message to every open connection.
+    ///
+    /// # Panics
+    /// if the message cannot be serialized
+    pub fn push(&self, message: &ResponseMessage) {
+        let line = serde_json::to_string(message).expect("serialize stream message");
+        self.push_raw(line);
+    }
+
+    /// Send a single raw line to every open connection. The line separator is added by the
+    /// server.
+    pub fn push_raw(&self, line: impl Into<String>) {
+        let line = line.into();
+        self.shared.broadcast(|| Command::Send(line.clone()));
+    }
+
+    /// Send every non-empty line of a fixture file (one stream message per line) to every open
+    /// connection.
+    ///
+    /// # Errors
+    /// if the file cannot be read
+    pub async fn push_fixture(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let contents = tokio::fs::read_to_string(path).await?;
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .for_each(|line| self.push_raw(line));
+        Ok(())
+    }
+
+    /// Close every open connection without any further message, as if the network dropped.
+    pub fn drop_connections(&self) {
+        self.shared.broadcast(|| Command::Close);
+    }
+
+    /// Reject the next authentication request with `error_code`. The connection is closed
+    /// afterwards, like Betfair does.
+    pub fn fail_next_authentication(&self, error_code: ErrorCode) {
+        self.shared
+            .script()
+            .authentication_errors
+            .push_back(error_code);
+    }
+
+    /// Reject the next market or order subscription with `error_code`.
+    pub fn fail_next_subscription(&self, error_code: ErrorCode) {
+        self.shared
+            .script()
+            .subscription_errors
+            .push_back(error_code);
+    }
+
+    /// Wait this long before answering each heartbeat request, `None` answers immediately.
+    pub fn set_heartbeat_delay(&self, delay: Option<Duration>) {
+        self.shared.script().heartbeat_delay = delay;
+    }
+
+    /// The next request received from any client, in the order they arrived.
+    pub async fn next_request(&self) -> Option<RequestMessage> {
+        self.requests.lock().await.recv().await
+    }
+
+    /// Wait until at least `count` successful authentications happened since the server started.
+    ///
+    /// # Panics
+    /// if the server has shut down
+    pub async fn wait_for_authentications(&self, count: usize) {
+        self.authentications
+            .clone()
+            .wait_for(|authentications| *authentications >= count)
+            .await
+            .expect("stream mock is running");
+    }
+
+    /// The number of connections that are currently open.
+    #[must_use]
+    pub fn open_connections(&self) -> usize {
+        let mut connections = self.shared.connections();
+        connections.retain(|connection| !connection.is_closed());
+        connections.len()
+    }
+}
+
+impl Drop for StreamServer {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+        self.drop_connections();
+    }
+}
+
+impl Shared {
+    fn script(&self) -> std::sync::MutexGuard<'_, Script> {
+        self.script.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn connections(&self) -> std::sync::MutexGuard<'_, Vec<mpsc::UnboundedSender<Command>>> {
+        self.connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn broadcast(&self, command: impl Fn() -> Command) {
+        self.connections()
+            .retain(|connection| connection.send(command()).is_ok());
+    }
+
+    fn status_success(&self, id: Option<i32>, connection_id: &str) -> String {
+        to_line(&ResponseMessage::Status(StatusMessage::Success(
+            StatusSuccess {
+                id,
+                connections_available: Some(self.settings.connections_available),
+                connection_closed: Some(false),
+                connection_id: Some(connection_id.to_owned()),
+            },
+        )))
+    }
+}
+
+fn status_failure(
+    id: Option<i32>,
+    connection_id: &str,
+    error_code: ErrorCode,
+    connection_closed: bool,
+) -> String {
+    to_line(&ResponseMessage::Status(StatusMessage::Failure(
+        StatusError {
+            id,
+            error_message: Some(format!("{error_code:?}")),
+            error_code,
+            connection_id: Some(connection_id.to_owned()),
+            connection_closed: Some(connection_closed),
+        },
+    )))
+}
+
+fn to_line(message: &ResponseMessage) -> String {
+    serde_json::to_string(message).expect("serialize stream message")
+}
+
+fn tls_acceptor() -> (CertificateDer<'static>, tokio_rustls::TlsAcceptor) {
+    let certified =
+        rcgen::generate_simple_self_signed(vec!["localhost".to_owned(), "127.0.0.1".to_owned()])
+            .expect("generate self-signed certificate");
+    let certificate = certified.cert.der().clone();
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![certificate.clone()], key)
+        .expect("valid self-signed certificate");
+    (
+        certificate,
+        tokio_rustls::TlsAcceptor::from(Arc::new(config)),
+    )
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    acceptor: Option<tokio_rustls::TlsAcceptor>,
+    shared: Arc<Shared>,
+) {
+    loop {
+        let socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(err) => {
+                tracing::warn!(?err, "stream mock failed to accept a connection");
+                continue;
+            }
+        };
+        let shared = Arc::clone(&shared);
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_socket(socket, acceptor, shared).await {
+                tracing::debug!(?err, "stream mock connection ended");
+            }
+        });
+    }
+}
+
+async fn handle_socket(
+    socket: TcpStream,
+    acceptor: Option<tokio_rustls::TlsAcceptor>,
+    shared: Arc<Shared>,
+) -> std::io::Result<()> {
+    match acceptor {
+        Some(acceptor) => serve(acceptor.accept(socket).await?, shared).await,
+        None => serve(socket, shared).await,
+    }
+}
+
+/// Drive a single client connection until either side closes it.
+async fn serve<S>(stream: S, shared: Arc<Shared>) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let number = shared
+        .connection_count
+        .fetch_add(1, Ordering::Relaxed)
+        .saturating_add(1);
+    let connection_id = format!("mock-{number}");
+    let (commands_tx, mut commands) = mpsc::unbounded_channel();
+    shared.connections().push(commands_tx);
+
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = FramedRead::new(reader, LinesCodec::new());
+    let mut authenticated = false;
+
+    let connection = ResponseMessage::Connection(ConnectionMessage {
+        id: None,
+        connection_id: Some(connection_id.clone()),
+    });
+    write_line(&mut writer, &to_line(&connection)).await?;
+
+    loop {
+        let line = tokio::select! {
+            line = reader.next() => line,
+            command = commands.recv() => {
+                match command {
+                    Some(Command::Send(line)) => write_line(&mut writer, &line).await?,
+                    Some(Command::Close) | None => break,
+                }
+                continue;
+            }
+        };
+        let Some(line) = line else {
+            break;
+        };
+        let line = line.map_err(std::io::Error::other)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let request = match serde_json::from_str::<RequestMessage>(&line) {
+            Ok(request) => request,
+            Err(err) => {
+                tracing::warn!(?err, line, "stream mock received an invalid request");
+                let reply = status_failure(None, &connection_id, ErrorCode::InvalidInput, true);
+                write_line(&mut writer, &reply).await?;
+                break;
+            }
+        };
+        let _ = shared.requests.send(request.clone());
+
+        let (reply, close) = match request {
+            RequestMessage::Authentication(msg) => {
+                let scripted = shared.script().authentication_errors.pop_front();
+                let error_code = scripted.or_else(|| {
+                    if msg.app_key != shared.settings.app_key {
+                        Some(ErrorCode::InvalidAppKey)
+                    } else if msg.session != shared.settings.session_token {
+                        Some(ErrorCode::InvalidSessionInformation)
+                    } else {
+                        None
+                    }
+                });
+                match error_code {
+                    Some(error_code) => (
+                        status_failure(msg.id, &connection_id, error_code, true),
+                        true,
+                    ),
+                    None => {
+                        authenticated = true;
+                        shared
+                            .authentications
+                            .send_modify(|authentications| *authentications += 1);
+                        (shared.status_success(msg.id, &connection_id), false)
+                    }
+                }
+            }
+            request if !authenticated => (
+                status_failure(request.id(), &connection_id, ErrorCode::NoSession, true),
+                true,
+            ),
+            RequestMessage::Heartbeat(msg) => {
+                let delay = shared.script().heartbeat_delay;
+                if let Some(delay) = delay {
+                    tokio::time::sleep(delay).await;
+                }
+                (shared.status_success(msg.id, &connection_id), false)
+            }
+            RequestMessage::MarketSubscription(_) | RequestMessage::OrderSubscription(_) => {
+                let id = request.id();
+                let scripted = shared.script().subscription_errors.pop_front();
+                match scripted {
+                    Some(error_code) => {
+                        (status_failure(id, &connection_id, error_code, false), false)
+                    }
+                    None => (shared.status_success(id, &connection_id), false),
+                }
+            }
+        };
+        write_line(&mut writer, &reply).await?;
+        if close {
+            break;
+        }
+    }
+
+    writer.shutdown().await
+}
+
+async fn write_line<W: AsyncWrite + Unpin>(writer: &mut W, line: &str) -> std::io::Result<()> {
+    writer.write_all(line.as_bytes()).await?;
+    writer.write_all(b"\r\n").await?;
+    writer.flush().await
+}
@@ -2,3 +2,4 @@ mod cancel_bets;
 mod keep_alive;
 mod list_market_book;
 mod list_market_catalogue;
+mod stream_server;
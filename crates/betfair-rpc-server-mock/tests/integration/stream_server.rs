@@ -0,0 +1,254 @@
+use std::sync::Arc;
+
+use betfair_adapter::RetrieveUrl as _;
+use betfair_rpc_server_mock::{APP_KEY, SESSION_TOKEN, StreamServer};
+use betfair_stream_types::request::RequestMessage;
+use betfair_stream_types::request::authentication_message::AuthenticationMessage;
+use betfair_stream_types::request::heartbeat_message::HeartbeatMessage;
+use betfair_stream_types::request::market_subscription_message::MarketSubscriptionMessage;
+use betfair_stream_types::response::ResponseMessage;
+use betfair_stream_types::response::status_message::{ErrorCode, StatusMessage};
+use futures::{SinkExt as _, StreamExt as _};
+use pretty_assertions::assert_eq;
+use rstest::rstest;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_util::codec::{Framed, LinesCodec};
+
+/// Minimal line based client, deliberately independent of `betfair-stream-api`.
+struct Client<S> {
+    framed: Framed<S, LinesCodec>,
+}
+
+impl Client<TcpStream> {
+    async fn connect(server: &StreamServer) -> Self {
+        let socket = TcpStream::connect(server.local_addr()).await.unwrap();
+        Self::new(socket)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Client<S> {
+    fn new(stream: S) -> Self {
+        Self {
+            framed: Framed::new(stream, LinesCodec::new()),
+        }
+    }
+
+    async fn send(&mut self, request: RequestMessage) {
+        let line = serde_json::to_string(&request).unwrap();
+        self.framed.send(line).await.unwrap();
+    }
+
+    async fn recv(&mut self) -> Option<ResponseMessage> {
+        let line = self.framed.next().await?.unwrap();
+        Some(serde_json::from_str(&line).unwrap())
+    }
+
+    async fn authenticate(&mut self, session: &str, app_key: &str) -> StatusMessage {
+        self.send(RequestMessage::Authentication(AuthenticationMessage {
+            id: Some(1),
+            session: session.to_owned(),
+            app_key: app_key.to_owned(),
+        }))
+        .await;
+        self.status().await
+    }
+
+    async fn status(&mut self) -> StatusMessage {
+        match self.recv().await {
+            Some(ResponseMessage::Status(status)) => status,
+            other => panic!("expected a status message, got {other:?}"),
+        }
+    }
+}
+
+fn market_subscription(id: i32) -> RequestMessage {
+    RequestMessage::MarketSubscription(MarketSubscriptionMessage {
+        id: Some(id),
+        ..Default::default()
+    })
+}
+
+#[rstest]
+#[test_log::test(tokio::test)]
+async fn sends_connection_and_accepts_valid_credentials() {
+    let server = StreamServer::new().await;
+    let mut client = Client::connect(&server).await;
+
+    let Some(ResponseMessage::Connection(connection)) = client.recv().await else {
+        panic!("expected a connection message");
+    };
+    assert_eq!(connection.connection_id.as_deref(), Some("mock-1"));
+
+    let StatusMessage::Success(status) = client.authenticate(SESSION_TOKEN, APP_KEY).await else {
+        panic!("expected authentication to succeed");
+    };
+    assert_eq!(status.id, Some(1));
+    assert_eq!(status.connection_id.as_deref(), Some("mock-1"));
+    server.wait_for_authentications(1).await;
+
+    let Some(RequestMessage::Authentication(auth)) = server.next_request().await else {
+        panic!("expected the authentication request to be recorded");
+    };
+    assert_eq!(auth.session, SESSION_TOKEN);
+}
+
+#[rstest]
+#[test_log::test(tokio::test)]
+async fn rejects_invalid_session_and_closes() {
+    let server = StreamServer::new().await;
+    let mut client = Client::connect(&server).await;
+    client.recv().await.unwrap();
+
+    let StatusMessage::Failure(status) = client.authenticate("invalid", APP_KEY).await else {
+        panic!("expected authentication to fail");
+    };
+    assert_eq!(status.error_code, ErrorCode::InvalidSessionInformation);
+    assert_eq!(status.connection_closed, Some(true));
+    assert!(client.recv().await.is_none());
+}
+
+#[rstest]
+#[test_log::test(tokio::test)]
+async fn scripted_authentication_failure() {
+    let server = StreamServer::new().await;
+    server.fail_next_authentication(ErrorCode::MaxConnectionLimitExceeded);
+
+    let mut client = Client::connect(&server).await;
+    client.recv().await.unwrap();
+    let StatusMessage::Failure(status) = client.authenticate(SESSION_TOKEN, APP_KEY).await else {
+        panic!("expected authentication to fail");
+    };
+    assert_eq!(status.error_code, ErrorCode::MaxConnectionLimitExceeded);
+
+    // only the next attempt is affected
+    let mut client = Client::connect(&server).await;
+    client.recv().await.unwrap();
+    let status = client.authenticate(SESSION_TOKEN, APP_KEY).await;
+    assert!(matches!(status, StatusMessage::Success(_)));
+}
+
+#[rstest]
+#[test_log::test(tokio::test)]
+async fn requires_authentication_before_subscribing() {
+    let server = StreamServer::new().await;
+    let mut client = Client::connect(&server).await;
+    client.recv().await.unwrap();
+
+    client.send(market_subscription(2)).await;
+    let StatusMessage::Failure(status) = client.status().await else {
+        panic!("expected subscription to fail");
+    };
+    assert_eq!(status.error_code, ErrorCode::NoSession);
+    assert!(client.recv().await.is_none());
+}
+
+#[rstest]
+#[test_log::test(tokio::test)]
+async fn answers_subscriptions_and_pushes_messages() {
+    let server = StreamServer::new().await;
+    let mut client = Client::connect(&server).await;
+    client.recv().await.unwrap();
+    client.authenticate(SESSION_TOKEN, APP_KEY).await;
+
+    server.fail_next_subscription(ErrorCode::SubscriptionLimitExceeded);
+    client.send(market_subscription(2)).await;
+    let StatusMessage::Failure(status) = client.status().await else {
+        panic!("expected subscription to fail");
+    };
+    assert_eq!(status.id, Some(2));
+    assert_eq!(status.error_code, ErrorCode::SubscriptionLimitExceeded);
+
+    client.send(market_subscription(3)).await;
+    let StatusMessage::Success(status) = client.status().await else {
+        panic!("expected subscription to succeed");
+    };
+    assert_eq!(status.id, Some(3));
+
+    server.push_raw(r#"{"op":"mcm","id":3,"clk":"AAAAAAAA","pt":1,"ct":"HEARTBEAT"}"#);
+    let Some(ResponseMessage::MarketChange(change)) = client.recv().await else {
+        panic!("expected a market change message");
+    };
+    assert_eq!(change.id, Some(3));
+}
+
+#[rstest]
+#[test_log::test(tokio::test)]
+async fn pushes_fixture_files_line_by_line() {
+    let server = StreamServer::new().await;
+    let mut client = Client::connect(&server).await;
+    client.recv().await.unwrap();
+    client.authenticate(SESSION_TOKEN, APP_KEY).await;
+
+    let fixture = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../betfair-stream-api/fixtures/29788105"
+    );
+    server.push_fixture(fixture).await.unwrap();
+
+    for _ in 0..10 {
+        let message = client.recv().await.unwrap();
+        assert!(matches!(message, ResponseMessage::MarketChange(_)));
+    }
+}
+
+#[rstest]
+#[test_log::test(tokio::test)]
+async fn delays_heartbeats() {
+    let server = StreamServer::new().await;
+    let mut client = Client::connect(&server).await;
+    client.recv().await.unwrap();
+    client.authenticate(SESSION_TOKEN, APP_KEY).await;
+
+    let delay = core::time::Duration::from_millis(200);
+    server.set_heartbeat_delay(Some(delay));
+    let started = tokio::time::Instant::now();
+    client
+        .send(RequestMessage::Heartbeat(HeartbeatMessage { id: Some(4) }))
+        .await;
+    let StatusMessage::Success(status) = client.status().await else {
+        panic!("expected heartbeat to succeed");
+    };
+    assert_eq!(status.id, Some(4));
+    assert!(started.elapsed() >= delay);
+}
+
+#[rstest]
+#[test_log::test(tokio::test)]
+async fn drops_connections() {
+    let server = StreamServer::new().await;
+    let mut client = Client::connect(&server).await;
+    client.recv().await.unwrap();
+    client.authenticate(SESSION_TOKEN, APP_KEY).await;
+    assert_eq!(server.open_connections(), 1);
+
+    server.drop_connections();
+
+    assert!(client.recv().await.is_none());
+    assert_eq!(server.open_connections(), 0);
+}
+
+#[rstest]
+#[test_log::test(tokio::test)]
+async fn serves_tls_with_self_signed_certificate() {
+    let server = StreamServer::new_with_tls().await;
+    assert_eq!(server.url().url().url().scheme(), "tls");
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(server.certificate().unwrap().clone()).unwrap();
+    let config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+    let socket = TcpStream::connect(server.local_addr()).await.unwrap();
+    let domain = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+    let stream = connector.connect(domain, socket).await.unwrap();
+
+    let mut client = Client::new(stream);
+    assert!(matches!(
+        client.recv().await,
+        Some(ResponseMessage::Connection(_))
+    ));
+    let status = client.authenticate(SESSION_TOKEN, APP_KEY).await;
+    assert!(matches!(status, StatusMessage::Success(_)));
+}
@@ -64,6 +64,67 @@ fn process_message_image(c: &mut Criterion) {
     });
 }
 
+/// An image of a market with `runners` runners, each with `levels` prices to back, lay and
+/// traded.
+fn large_market_image(runners: u32, levels: u32) -> ResponseMessage {
+    let ladder = |offset: u32| {
+        (0..levels)
+            .map(|level| {
+                format!(
+                    "[{:.2},{}]",
+                    1.01 + f64::from(offset + level) / 100.0,
+                    10 + level
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    let runner_changes = (1..=runners)
+        .map(|id| {
+            format!(
+                r#"{{"id":{id},"atb":[{}],"atl":[{}],"trd":[{}]}}"#,
+                ladder(0),
+                ladder(levels),
+                ladder(0)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    let json = format!(
+        r#"{{"op":"mcm","id":1,"clk":"AAAAAAAA","pt":1471370160471,"ct":"SUB_IMAGE","mc":[{{"id":"1.1","img":true,"rc":[{runner_changes}]}}]}}"#
+    );
+    serde_json::from_str(&json).unwrap()
+}
+
+/// Update one price of one runner of a large market and emit the market, the common case for
+/// big subscriptions where every message copies the emitted market
+fn process_message_large_market_delta(c: &mut Criterion) {
+    let mut cache = Cache::new();
+    cache.process_message(large_market_image(100, 50));
+
+    // alternate between two sizes so that the ladders keep their shape
+    let deltas = [10, 20].map(|size| {
+        let json = format!(
+            r#"{{"op":"mcm","id":2,"clk":"AAAAAAAB","pt":1471370160472,"mc":[{{"id":"1.1","rc":[{{"id":50,"atb":[[1.01,{size}]]}}]}}]}}"#
+        );
+        serde_json::from_str::<ResponseMessage>(&json).unwrap()
+    });
+
+    let mut next = 0;
+    c.bench_function("process_message_large_market_delta", |b| {
+        b.iter_batched(
+            || {
+                next = (next + 1) % deltas.len();
+                deltas[next].clone()
+            },
+            |msg| {
+                black_box(cache.process_message(msg));
+            },
+            criterion::BatchSize::SmallInput,
+        );
+    });
+}
+
 /// Isolate the cost of cloning a populated MarketBookCache (measures P0 bottleneck)
 fn cache_clone_isolated(c: &mut Criterion) {
     let image_json = fixture("streaming_mcm_SUB_IMAGE.json");
@@ -82,7 +143,7 @@ fn cache_clone_isolated(c: &mut Criterion) {
 
     c.bench_function("cache_clone_isolated", |b| {
         b.iter(|| {
-            let cloned: Vec<MarketBookCache> = black_box(&owned).iter().cloned().collect();
+            let cloned: Vec<MarketBookCache> = black_box(&owned).to_vec();
             black_box(cloned);
         });
     });
@@ -92,6 +153,7 @@ criterion_group!(
     benches,
     process_message_delta,
     process_message_image,
+    process_message_large_market_delta,
     cache_clone_isolated,
 );
 criterion_main!(benches);
@@ -0,0 +1,249 @@
+//! Matches `status` responses to the requests that caused them.
+//!
+//! Every request sent through [`BetfairStreamClient::send_with_ack`](crate::BetfairStreamClient::send_with_ack)
+//! gets a unique id. Betfair echoes that id in the `status` message answering the request, which
+//! the stream task hands to [`PendingAcks::resolve`] before the message reaches the processor.
+
+use core::fmt;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicI32, Ordering};
+use core::task::{Context, Poll};
+use core::time::Duration;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use betfair_stream_types::request::RequestMessage;
+use betfair_stream_types::response::status_message::StatusMessage;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::oneshot;
+use tokio::time::Sleep;
+
+/// How long a [`RequestAck`] waits for the status message unless configured otherwise.
+pub const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Requests that are waiting for their status message.
+#[derive(Debug)]
+pub(crate) struct PendingAcks {
+    /// The next id to hand out. Starts at 1: the stream task authenticates with id `-1` and
+    /// heartbeats are sent with ids below that.
+    next_id: AtomicI32,
+    waiters: Mutex<HashMap<i32, oneshot::Sender<StatusMessage>>>,
+}
+
+impl Default for PendingAcks {
+    fn default() -> Self {
+        Self {
+            next_id: AtomicI32::new(1),
+            waiters: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl PendingAcks {
+    /// Assign a fresh id to `request`, send it to the stream task and return a future resolving
+    /// to Betfair's answer.
+    pub(crate) async fn send(
+        self: &Arc<Self>,
+        sender: &Sender<RequestMessage>,
+        mut request: RequestMessage,
+    ) -> Result<RequestAck, SendError<RequestMessage>> {
+        let id = self.allocate_id();
+        request.set_id(id);
+
+        let (tx, rx) = oneshot::channel();
+        self.waiters().insert(id, tx);
+        if let Err(err) = sender.send(request).await {
+            self.waiters().remove(&id);
+            return Err(err);
+        }
+
+        Ok(RequestAck {
+            id,
+            rx,
+            timeout: Box::pin(tokio::time::sleep(DEFAULT_ACK_TIMEOUT)),
+            pending: Arc::clone(self),
+        })
+    }
+
+    /// Complete the request answered by `status`. Returns `true` if somebody was waiting for it.
+    pub(crate) fn resolve(&self, status: &StatusMessage) -> bool {
+        let Some(id) = status.id() else {
+            return false;
+        };
+        let Some(waiter) = self.waiters().remove(&id) else {
+            return false;
+        };
+        waiter.send(status.clone()).is_ok()
+    }
+
+    /// Fail every outstanding request with [`AckError::Disconnected`].
+    pub(crate) fn cancel_all(&self) {
+        self.waiters().clear();
+    }
+
+    fn allocate_id(&self) -> i32 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        if id > 0 {
+            return id;
+        }
+        // wrapped around after 2^31 requests, start again from 1
+        self.next_id.store(2, Ordering::Relaxed);
+        1
+    }
+
+    fn waiters(&self) -> std::sync::MutexGuard<'_, HashMap<i32, oneshot::Sender<StatusMessage>>> {
+        self.waiters.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Future resolving to the status message Betfair sent in response to a request.
+///
+/// Dropping it is fine: the request has already been handed to the stream task and will be sent
+/// regardless, only the answer is discarded.
+#[derive(Debug)]
+pub struct RequestAck {
+    id: i32,
+    rx: oneshot::Receiver<StatusMessage>,
+    timeout: Pin<Box<Sleep>>,
+    pending: Arc<PendingAcks>,
+}
+
+impl RequestAck {
+    /// The id that was assigned to the request.
+    #[must_use]
+    pub const fn id(&self) -> i32 {
+        self.id
+    }
+
+    /// Wait at most `timeout` (from now) for the status message instead of
+    /// [`DEFAULT_ACK_TIMEOUT`].
+    #[must_use]
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout
+            .as_mut()
+            .reset(tokio::time::Instant::now() + timeout);
+        self
+    }
+}
+
+impl Future for RequestAck {
+    type Output = Result<StatusMessage, AckError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Poll::Ready(status) = Pin::new(&mut self.rx).poll(cx) {
+            return Poll::Ready(status.map_err(|_| AckError::Disconnected));
+        }
+        if self.timeout.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(Err(AckError::Timeout));
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for RequestAck {
+    fn drop(&mut self) {
+        self.pending.waiters().remove(&self.id);
+    }
+}
+
+/// Why no status message was received for a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckError {
+    /// Betfair did not answer in time, e.g. because the connection dropped after the request was
+    /// sent.
+    Timeout,
+    /// The stream task stopped before the answer arrived.
+    Disconnected,
+}
+
+impl fmt::Display for AckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Timeout => write!(f, "timed out waiting for the stream to answer the request"),
+            Self::Disconnected => write!(f, "stream task stopped before answering the request"),
+        }
+    }
+}
+
+impl core::error::Error for AckError {}
+
+#[cfg(test)]
+mod tests {
+    use betfair_stream_types::request::heartbeat_message::HeartbeatMessage;
+    use betfair_stream_types::response::status_message::{ErrorCode, StatusError};
+    use pretty_assertions::assert_eq;
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    fn failure(id: i32) -> StatusMessage {
+        StatusMessage::Failure(StatusError {
+            id: Some(id),
+            error_message: None,
+            error_code: ErrorCode::SubscriptionLimitExceeded,
+            connection_id: None,
+            connection_closed: Some(false),
+        })
+    }
+
+    fn heartbeat() -> RequestMessage {
+        RequestMessage::Heartbeat(HeartbeatMessage { id: None })
+    }
+
+    #[tokio::test]
+    async fn resolves_the_request_with_the_matching_id() {
+        let pending = Arc::new(PendingAcks::default());
+        let (tx, mut rx) = mpsc::channel(2);
+
+        let first = pending.send(&tx, heartbeat()).await.unwrap();
+        let second = pending.send(&tx, heartbeat()).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap().id(), Some(first.id()));
+        assert_eq!(rx.recv().await.unwrap().id(), Some(second.id()));
+        assert_ne!(first.id(), second.id());
+
+        let second_id = second.id();
+        assert!(pending.resolve(&failure(second_id)));
+        assert_eq!(second.await, Ok(failure(second_id)));
+        // already answered
+        assert!(!pending.resolve(&failure(second_id)));
+
+        drop(first);
+        assert!(pending.waiters().is_empty());
+    }
+
+    #[tokio::test]
+    async fn times_out_and_forgets_the_request() {
+        let pending = Arc::new(PendingAcks::default());
+        let (tx, _rx) = mpsc::channel(1);
+
+        let ack = pending
+            .send(&tx, heartbeat())
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_millis(10));
+
+        assert_eq!(ack.await, Err(AckError::Timeout));
+        assert!(pending.waiters().is_empty());
+    }
+
+    #[tokio::test]
+    async fn send_failure_does_not_leave_a_waiter() {
+        let pending = Arc::new(PendingAcks::default());
+        let (tx, rx) = mpsc::channel(1);
+        drop(rx);
+
+        assert!(pending.send(&tx, heartbeat()).await.is_err());
+        assert!(pending.waiters().is_empty());
+    }
+
+    #[test]
+    fn ids_wrap_around_to_one() {
+        let pending = PendingAcks::default();
+        pending.next_id.store(i32::MAX, Ordering::Relaxed);
+
+        assert_eq!(pending.allocate_id(), i32::MAX);
+        assert_eq!(pending.allocate_id(), 1);
+        assert_eq!(pending.allocate_id(), 2);
+    }
+}
@@ -0,0 +1,228 @@
+//! Market-level analytics computed from the ladders of a cached market.
+//!
+//! Removed runners are skipped throughout, and markets with several winners (e.g. place markets)
+//! are normalised by their `number_of_winners`, so a fair book is 100% for every market.
+
+use std::collections::HashMap;
+
+use betfair_adapter::betfair_types::numeric::F64Ord;
+use betfair_adapter::betfair_types::types::sports_aping::{SelectionId, Side};
+use betfair_stream_types::response::market_change_message::StreamRunnerDefinitionStatus;
+
+use super::primitives::MarketBookCache;
+use super::primitives::runner_book_cache::RunnerBookCache;
+
+impl MarketBookCache {
+    /// The book percentage of a side: the sum of the implied probabilities of the runners, divided
+    /// by the number of winners.
+    ///
+    /// # Parameters
+    ///
+    /// * `side` - `Back` for the back overround from the best back prices, `Lay` for the lay
+    ///   overround from the best lay prices.
+    /// * `adjustment_factor` - Use the adjustment factor of each runner, Betfair's estimate of its
+    ///   chance, instead of its best price.
+    ///
+    /// # Returns
+    ///
+    /// The percentage, `100.0` for a fair book, above it for a back overround and below it for a
+    /// lay one; `None` if the market has no runners or a runner has no price (or adjustment
+    /// factor) to use.
+    #[must_use]
+    pub fn book_percentage(&self, side: Side, adjustment_factor: bool) -> Option<f64> {
+        let mut total = 0.0;
+        let mut runners = 0;
+        for (_, runner) in self.active_runners() {
+            total += implied_probability(runner, side, adjustment_factor)?;
+            runners += 1;
+        }
+        (runners > 0).then(|| total / self.number_of_winners() * 100.0)
+    }
+
+    /// The implied probability of each runner with the overround removed.
+    ///
+    /// The probabilities are scaled so that they sum to the number of winners, i.e. to `1.0` for a
+    /// win market and to `3.0` for a place market paying three places.
+    ///
+    /// # Parameters
+    ///
+    /// * `side` - The side whose best prices imply the probabilities.
+    /// * `adjustment_factor` - Use the adjustment factor of each runner instead of its best price.
+    ///
+    /// # Returns
+    ///
+    /// The probabilities by the key of [`runners`](Self::runners); runners without a price (or
+    /// adjustment factor) are left out and do not count towards the sum.
+    #[must_use]
+    pub fn implied_probabilities(
+        &self,
+        side: Side,
+        adjustment_factor: bool,
+    ) -> HashMap<(SelectionId, Option<F64Ord>), f64> {
+        let mut probabilities = self
+            .active_runners()
+            .filter_map(|(key, runner)| {
+                implied_probability(runner, side, adjustment_factor)
+                    .map(|probability| (*key, probability))
+            })
+            .collect::<HashMap<_, _>>();
+        let total = probabilities.values().sum::<f64>();
+        if total > 0.0 {
+            let scale = self.number_of_winners() / total;
+            for probability in probabilities.values_mut() {
+                *probability *= scale;
+            }
+        }
+        probabilities
+    }
+
+    /// The weight of money of each runner: the share of the size available to back among the size
+    /// available on both sides.
+    ///
+    /// # Parameters
+    ///
+    /// * `depth` - The number of levels of each side to include.
+    ///
+    /// # Returns
+    ///
+    /// A value between `0.0` and `1.0` by the key of [`runners`](Self::runners), above `0.5` if
+    /// more money waits to be matched by backers than by layers; runners without any size in the
+    /// levels are left out.
+    #[must_use]
+    pub fn weight_of_money(&self, depth: usize) -> HashMap<(SelectionId, Option<F64Ord>), f64> {
+        let size = |runner: &RunnerBookCache, side| {
+            runner
+                .levels(side, depth)
+                .iter()
+                .map(|level| level.size.as_f64())
+                .sum::<f64>()
+        };
+        self.active_runners()
+            .filter_map(|(key, runner)| {
+                let back = size(runner, Side::Back);
+                let total = back + size(runner, Side::Lay);
+                (total > 0.0).then(|| (*key, back / total))
+            })
+            .collect()
+    }
+
+    /// The runners that have not been removed from the market.
+    fn active_runners(
+        &self,
+    ) -> impl Iterator<Item = (&(SelectionId, Option<F64Ord>), &RunnerBookCache)> {
+        self.runners()
+            .iter()
+            .map(|(key, runner)| (key, runner.as_ref()))
+            .filter(|(_, runner)| {
+                !matches!(
+                    runner.definition().and_then(|definition| definition.status),
+                    Some(
+                        StreamRunnerDefinitionStatus::Removed
+                            | StreamRunnerDefinitionStatus::RemovedVacant
+                    )
+                )
+            })
+    }
+
+    /// The number of winners of the market, `1` if the definition is unknown.
+    fn number_of_winners(&self) -> f64 {
+        self.market_definition()
+            .map_or(1, |definition| definition.number_of_winners.max(1))
+            .into()
+    }
+}
+
+/// The probability of a runner implied by its best price or its adjustment factor.
+fn implied_probability(
+    runner: &RunnerBookCache,
+    side: Side,
+    adjustment_factor: bool,
+) -> Option<f64> {
+    if adjustment_factor {
+        let factor = runner.definition()?.adjustment_factor?.0;
+        return (factor > 0.0).then(|| factor / 100.0);
+    }
+    runner.best(side).map(|level| 1.0 / level.price.as_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use betfair_adapter::betfair_types::types::sports_aping::MarketId;
+    use betfair_stream_types::response::market_change_message::MarketChange;
+    use chrono::Utc;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn market(winners: i32, runners: &str) -> MarketBookCache {
+        let change: MarketChange = serde_json::from_str(&format!(
+            r#"{{"id":"1.1","img":true,"marketDefinition":{{"bspMarket":false,"turnInPlayEnabled":true,"persistenceEnabled":true,"marketBaseRate":5,"eventId":"1","eventTypeId":"7","numberOfWinners":{winners},"bettingType":"ODDS","marketType":"PLACE","marketTime":"2016-11-09T18:15:00.000Z","suspendTime":"2016-11-09T18:15:00.000Z","bspReconciled":false,"complete":true,"inPlay":false,"crossMatching":true,"runnersVoidable":false,"numberOfActiveRunners":3,"betDelay":0,"status":"OPEN","runners":[{{"status":"ACTIVE","sortPriority":1,"id":1,"adjustmentFactor":50}},{{"status":"ACTIVE","sortPriority":2,"id":2,"adjustmentFactor":30}},{{"status":"REMOVED","sortPriority":3,"id":3,"adjustmentFactor":20}}],"regulators":["MR_INT"],"discountAllowed":true,"timezone":"Europe/London","openDate":"2016-11-09T18:15:00.000Z","version":1}},"rc":{runners}}}"#
+        ))
+        .unwrap();
+        let mut market = MarketBookCache::new(MarketId::new("1.1"), Utc::now());
+        market.update_cache(change, Utc::now(), true);
+        market
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn book_percentage_skips_removed_runners() {
+        let market = market(
+            1,
+            r#"[{"id":1,"atb":[[2.0,10]],"atl":[[2.02,5]]},{"id":2,"atb":[[2.0,4]],"atl":[[2.1,6]]},{"id":3,"atb":[[1.5,100]]}]"#,
+        );
+
+        assert_close(market.book_percentage(Side::Back, false).unwrap(), 100.0);
+        assert_close(
+            market.book_percentage(Side::Lay, false).unwrap(),
+            (1.0 / 2.02 + 1.0 / 2.1) * 100.0,
+        );
+        assert_close(market.book_percentage(Side::Back, true).unwrap(), 80.0);
+    }
+
+    #[test]
+    fn book_percentage_needs_a_price_for_every_runner() {
+        let market = market(1, r#"[{"id":1,"atb":[[2.0,10]]},{"id":2}]"#);
+
+        assert_eq!(market.book_percentage(Side::Back, false), None);
+    }
+
+    #[test]
+    fn implied_probabilities_sum_to_the_number_of_winners() {
+        let market = market(
+            2,
+            r#"[{"id":1,"atb":[[1.25,10]]},{"id":2,"atb":[[2.5,4]]},{"id":3,"atb":[[1.5,100]]}]"#,
+        );
+
+        // 0.8 + 0.4 implied for two winners
+        assert_close(market.book_percentage(Side::Back, false).unwrap(), 60.0);
+        let probabilities = market.implied_probabilities(Side::Back, false);
+        assert_eq!(probabilities.len(), 2);
+        assert_close(probabilities[&(SelectionId(1), None)], 0.8 / 1.2 * 2.0);
+        assert_close(probabilities[&(SelectionId(2), None)], 0.4 / 1.2 * 2.0);
+
+        let probabilities = market.implied_probabilities(Side::Back, true);
+        assert_close(probabilities[&(SelectionId(1), None)], 0.5 / 0.8 * 2.0);
+        assert_close(probabilities[&(SelectionId(2), None)], 0.3 / 0.8 * 2.0);
+    }
+
+    #[test]
+    fn weight_of_money_compares_the_sizes_of_both_sides() {
+        let market = market(
+            1,
+            r#"[{"id":1,"atb":[[2.0,30],[1.9,10]],"atl":[[2.02,10],[2.04,50]]},{"id":2},{"id":3,"atb":[[1.5,100]]}]"#,
+        );
+
+        assert_eq!(
+            market.weight_of_money(1),
+            HashMap::from([((SelectionId(1), None), 0.75)])
+        );
+        assert_eq!(
+            market.weight_of_money(2),
+            HashMap::from([((SelectionId(1), None), 0.4)])
+        );
+    }
+}
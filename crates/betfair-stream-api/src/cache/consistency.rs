@@ -0,0 +1,198 @@
+//! Consistency check between a cached market and a REST snapshot of it.
+//!
+//! Comparing a `listMarketBook` response with the cache built from the stream shows whether the
+//! cache drifted, e.g. after a missed or misapplied delta.
+
+use std::collections::BTreeMap;
+
+use betfair_adapter::betfair_types::numeric::F64Ord;
+use betfair_adapter::betfair_types::price::Price;
+use betfair_adapter::betfair_types::size::Size;
+use betfair_adapter::betfair_types::types::sports_aping::{
+    ExchangePrices, MarketBook, PriceSize, Runner, SelectionId,
+};
+use betfair_stream_types::request::market_subscription_message::MarketDataFilter;
+
+use super::primitives::MarketBookCache;
+
+/// The ladder of a runner a price level belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PriceLadder {
+    /// Prices available to back.
+    AvailableToBack,
+    /// Prices available to lay.
+    AvailableToLay,
+    /// Volume traded per price.
+    Traded,
+}
+
+impl PriceLadder {
+    fn levels(self, ex: &ExchangePrices) -> Option<&Vec<PriceSize>> {
+        match self {
+            Self::AvailableToBack => ex.available_to_back.as_ref(),
+            Self::AvailableToLay => ex.available_to_lay.as_ref(),
+            Self::Traded => ex.traded_volume.as_ref(),
+        }
+    }
+}
+
+/// A price level whose size differs between the cache and a REST snapshot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PriceMismatch {
+    /// The runner of the level.
+    pub selection_id: SelectionId,
+    /// The handicap of the runner.
+    pub handicap: F64Ord,
+    /// The ladder of the level.
+    pub ladder: PriceLadder,
+    /// The price of the level.
+    pub price: Price,
+    /// The size in the cache, `None` if the cache has no such level.
+    pub cached: Option<Size>,
+    /// The size in the snapshot, `None` if the snapshot has no such level.
+    pub snapshot: Option<Size>,
+}
+
+impl MarketBookCache {
+    /// Compares the price levels of the cache with a `listMarketBook` snapshot of the market.
+    ///
+    /// The cache is converted with [`to_market_book`](Self::to_market_book) first, so `filter`
+    /// should match the price projection the snapshot was requested with. Ladders only one side
+    /// has are not compared, while a runner only one side has is compared with empty ladders.
+    ///
+    /// # Parameters
+    ///
+    /// * `book` - The market book returned by `listMarketBook`.
+    /// * `filter` - The market data filter of the subscription.
+    ///
+    /// # Returns
+    ///
+    /// Every mismatched level, by runner in the order of the snapshot and then by price; empty
+    /// if the cache is consistent with the snapshot.
+    #[must_use]
+    pub fn compare_with_market_book(
+        &self,
+        book: &MarketBook,
+        filter: &MarketDataFilter,
+    ) -> Vec<PriceMismatch> {
+        let cached = self.to_market_book(filter).runners.unwrap_or_default();
+        let snapshot = book.runners.as_deref().unwrap_or_default();
+        // a runner missing on one side is compared with empty ladders
+        let missing = ExchangePrices {
+            available_to_back: Some(Vec::new()),
+            available_to_lay: Some(Vec::new()),
+            traded_volume: Some(Vec::new()),
+        };
+
+        let mut mismatches = Vec::new();
+        for runner in snapshot {
+            let cached = find(&cached, runner).map_or(Some(&missing), |other| other.ex.as_ref());
+            compare_runner(runner, cached, runner.ex.as_ref(), &mut mismatches);
+        }
+        for runner in &cached {
+            if find(snapshot, runner).is_none() {
+                compare_runner(runner, runner.ex.as_ref(), Some(&missing), &mut mismatches);
+            }
+        }
+        mismatches
+    }
+}
+
+/// The runner of `runners` with the selection and handicap of `runner`.
+fn find<'a>(runners: &'a [Runner], runner: &Runner) -> Option<&'a Runner> {
+    runners.iter().find(|other| {
+        other.selection_id == runner.selection_id && other.handicap == runner.handicap
+    })
+}
+
+/// Appends the mismatched levels of a runner to `mismatches`.
+fn compare_runner(
+    runner: &Runner,
+    cached: Option<&ExchangePrices>,
+    snapshot: Option<&ExchangePrices>,
+    mismatches: &mut Vec<PriceMismatch>,
+) {
+    for ladder in [
+        PriceLadder::AvailableToBack,
+        PriceLadder::AvailableToLay,
+        PriceLadder::Traded,
+    ] {
+        let (Some(cached_levels), Some(snapshot_levels)) = (
+            cached.and_then(|ex| ladder.levels(ex)),
+            snapshot.and_then(|ex| ladder.levels(ex)),
+        ) else {
+            continue;
+        };
+
+        let mut levels = BTreeMap::<Price, (Option<Size>, Option<Size>)>::new();
+        for level in cached_levels {
+            levels.entry(level.price).or_default().0 = Some(level.size);
+        }
+        for level in snapshot_levels {
+            levels.entry(level.price).or_default().1 = Some(level.size);
+        }
+        mismatches.extend(
+            levels
+                .into_iter()
+                .filter(|(_, (cached, snapshot))| cached != snapshot)
+                .map(|(price, (cached, snapshot))| PriceMismatch {
+                    selection_id: runner.selection_id,
+                    handicap: runner.handicap,
+                    ladder,
+                    price,
+                    cached,
+                    snapshot,
+                }),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use betfair_adapter::betfair_types::types::sports_aping::MarketId;
+    use betfair_stream_types::response::market_change_message::MarketChange;
+    use chrono::Utc;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn reports_levels_that_differ_from_the_snapshot() {
+        let change: MarketChange = serde_json::from_str(
+            r#"{"id":"1.1","img":true,"rc":[{"id":1,"atb":[[2.0,10],[1.9,5]],"atl":[[2.1,3]],"trd":[[2.0,7]]}]}"#,
+        )
+        .unwrap();
+        let mut market = MarketBookCache::new(MarketId::new("1.1"), Utc::now());
+        market.update_cache(change, Utc::now(), true);
+        let filter = MarketDataFilter::default();
+        let mut book = market.to_market_book(&filter);
+        let ex = book.runners.as_mut().unwrap()[0].ex.as_mut().unwrap();
+        ex.available_to_back.as_mut().unwrap()[0].size = Size::new(12.0);
+        ex.available_to_lay.as_mut().unwrap().push(PriceSize {
+            price: Price::new(2.2).unwrap(),
+            size: Size::new(4.0),
+        });
+
+        assert_eq!(
+            market.compare_with_market_book(&book, &filter),
+            vec![
+                PriceMismatch {
+                    selection_id: SelectionId(1),
+                    handicap: F64Ord::zero(),
+                    ladder: PriceLadder::AvailableToBack,
+                    price: Price::new(2.0).unwrap(),
+                    cached: Some(Size::new(10.0)),
+                    snapshot: Some(Size::new(12.0)),
+                },
+                PriceMismatch {
+                    selection_id: SelectionId(1),
+                    handicap: F64Ord::zero(),
+                    ladder: PriceLadder::AvailableToLay,
+                    price: Price::new(2.2).unwrap(),
+                    cached: None,
+                    snapshot: Some(Size::new(4.0)),
+                },
+            ]
+        );
+    }
+}
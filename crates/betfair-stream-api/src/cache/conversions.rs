@@ -0,0 +1,904 @@
+//! Conversions between the stream caches and the `sports_aping` types returned by
+//! `listMarketBook`, `listMarketCatalogue` and `listCurrentOrders`, so that the same code can
+//! consume either and a cache can be seeded from a REST snapshot.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use betfair_adapter::betfair_types::customer_strategy_ref::CustomerStrategyRef;
+use betfair_adapter::betfair_types::handicap::Handicap;
+use betfair_adapter::betfair_types::numeric::F64Ord;
+use betfair_adapter::betfair_types::price::Price;
+use betfair_adapter::betfair_types::size::Size;
+use betfair_adapter::betfair_types::types::sports_aping::{
+    self, CurrentOrderSummary, ExchangePrices, KeyLineDescription, MarketBook, MarketCatalogue,
+    MarketId, MarketStatus, PriceLadderType, PriceSize, Runner, RunnerStatus, SelectionId,
+    StartingPrices,
+};
+use betfair_stream_types::request::market_subscription_message::{
+    Fields, MarketDataFilter, StreamMarketFilterBettingType,
+};
+use betfair_stream_types::response::market_change_message::{
+    self as stream, KeyLineDefinition, MarketChange, MarketDefinition, PriceLadderDefinition,
+    RunnerChange, RunnerDefinition, StreamMarketDefinitionStatus, StreamRunnerDefinitionStatus,
+};
+use betfair_stream_types::response::order_change_message::{
+    Order, OrderMarketChange, OrderRunnerChange, OrderType, PersistenceType, Side,
+    StreamOrderStatus,
+};
+use betfair_stream_types::response::{Position, UpdateSet2, UpdateSet3};
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use super::primitives::available_cache::Available;
+use super::primitives::orderbook_runner_cache::OrderBookRunner;
+use super::primitives::runner_book_cache::RunnerBookCache;
+use super::primitives::{MarketBookCache, OrderBookCache};
+
+/// The parts of the cache requested by a [`MarketDataFilter`].
+struct Requested<'a>(&'a MarketDataFilter);
+
+impl Requested<'_> {
+    /// Whether `field` was requested. Without any fields everything cached is converted.
+    fn has(&self, field: Fields) -> bool {
+        self.0
+            .fields
+            .as_ref()
+            .is_none_or(|fields| fields.contains(&field))
+    }
+
+    /// How many levels of the depth-based ladders to convert.
+    fn depth(&self) -> usize {
+        self.0
+            .ladder_levels
+            .as_ref()
+            .map_or(usize::MAX, |levels| usize::from(levels.get()))
+    }
+}
+
+impl MarketBookCache {
+    /// Converts the cache into the [`MarketBook`] `listMarketBook` would return.
+    ///
+    /// Only the data requested by `filter` (the market data filter of the subscription the cache
+    /// was built from) is filled in; prices of the depth-based ladders are limited to its
+    /// `ladder_levels`. Runners are ordered by their sort priority.
+    ///
+    /// # Parameters
+    ///
+    /// * `filter` - The market data filter of the subscription.
+    ///
+    /// # Returns
+    ///
+    /// The market book.
+    #[must_use]
+    pub fn to_market_book(&self, filter: &MarketDataFilter) -> MarketBook {
+        let requested = Requested(filter);
+        let definition = self.market_definition();
+
+        let mut runners = self.runners().values().collect::<Vec<_>>();
+        runners.sort_by_key(|runner| {
+            (
+                runner
+                    .definition()
+                    .and_then(|definition| definition.sort_priority),
+                runner.selection_id().0,
+                runner.handicap(),
+            )
+        });
+
+        MarketBook {
+            market_id: self.market_id().clone(),
+            is_market_data_delayed: false,
+            status: definition.map(|definition| market_status(definition.status)),
+            bet_delay: definition.map(|definition| definition.bet_delay),
+            bsp_reconciled: definition.map(|definition| definition.bsp_reconciled),
+            complete: definition.map(|definition| definition.complete),
+            inplay: definition.map(|definition| definition.in_play),
+            number_of_winners: definition.map(|definition| definition.number_of_winners),
+            number_of_runners: definition
+                .map(|definition| i32::try_from(definition.runners.len()).unwrap_or(i32::MAX)),
+            number_of_active_runners: definition
+                .map(|definition| definition.number_of_active_runners),
+            last_match_time: None,
+            total_matched: requested
+                .has(Fields::ExTradedVol)
+                .then(|| F64Ord::new(self.total_matched().as_f64())),
+            total_available: None,
+            cross_matching: definition.map(|definition| definition.cross_matching),
+            runners_voidable: definition.map(|definition| definition.runners_voidable),
+            version: definition.map(|definition| definition.version),
+            runners: Some(
+                runners
+                    .into_iter()
+                    .map(|runner| runner.to_runner(filter))
+                    .collect(),
+            ),
+            key_line_description: definition
+                .and_then(|definition| definition.key_line_definition.as_deref())
+                .and_then(key_line_description),
+        }
+    }
+}
+
+impl MarketBookCache {
+    /// Builds a cache from a `listMarketBook` snapshot and the catalogue of the market, so that
+    /// it can be pre-populated over REST and then advanced by stream deltas.
+    ///
+    /// The market definition is assembled from both; anything neither of them carries, e.g. the
+    /// event of a catalogue requested without the `EVENT` projection, is left at its default.
+    /// The prices of the book are read the way `filter` would have requested them: with
+    /// `EX_ALL_OFFERS` the available prices are the full ladder, otherwise the best (or the
+    /// virtual best) offers. Runners only carry a handicap in Asian handicap markets, as on the
+    /// stream.
+    ///
+    /// # Parameters
+    ///
+    /// * `book` - The market book returned by `listMarketBook`.
+    /// * `catalogue` - The catalogue of the same market returned by `listMarketCatalogue`.
+    /// * `filter` - The market data filter of the subscription that will advance the cache.
+    /// * `publish_time` - When the snapshot was taken.
+    ///
+    /// # Returns
+    ///
+    /// The cache, holding a full image of the market.
+    #[must_use]
+    pub fn from_market_book(
+        book: &MarketBook,
+        catalogue: &MarketCatalogue,
+        filter: &MarketDataFilter,
+        publish_time: DateTime<Utc>,
+    ) -> Self {
+        let definition = market_definition(book, catalogue);
+        let asian_handicap = asian_handicap(definition.betting_type);
+        let change = MarketChange {
+            runner_change: book.runners.as_ref().map(|runners| {
+                runners
+                    .iter()
+                    .map(|runner| runner_change(runner, asian_handicap, filter))
+                    .collect()
+            }),
+            full_image: Some(true),
+            total_value: book
+                .total_matched
+                .map(|matched| Size::new(matched.as_f64())),
+            conflated: None,
+            market_definition: Some(Box::new(definition)),
+            market_id: Some(book.market_id.clone()),
+        };
+
+        let mut cache = Self::new(book.market_id.clone(), publish_time);
+        cache.update_cache(change, publish_time, true);
+        cache
+    }
+}
+
+impl RunnerBookCache {
+    /// Converts the cache into the [`Runner`] `listMarketBook` would return.
+    ///
+    /// Runners hidden by Betfair are reported as active, as `listMarketBook` has no such status.
+    /// See [`MarketBookCache::to_market_book`] for how `filter` is applied.
+    ///
+    /// # Parameters
+    ///
+    /// * `filter` - The market data filter of the subscription.
+    ///
+    /// # Returns
+    ///
+    /// The runner.
+    #[must_use]
+    pub fn to_runner(&self, filter: &MarketDataFilter) -> Runner {
+        let requested = Requested(filter);
+        let definition = self.definition();
+        let depth = requested.depth();
+
+        let (available_to_back, available_to_lay) = if requested.has(Fields::ExAllOffers) {
+            (
+                Some(full_ladder(self.available_to_back(), true)),
+                Some(full_ladder(self.available_to_lay(), false)),
+            )
+        } else if requested.has(Fields::ExBestOffers) {
+            (
+                Some(depth_ladder(self.best_available_to_back(), depth)),
+                Some(depth_ladder(self.best_available_to_lay(), depth)),
+            )
+        } else if requested.has(Fields::ExBestOffersDisp) {
+            (
+                Some(depth_ladder(self.best_display_available_to_back(), depth)),
+                Some(depth_ladder(self.best_display_available_to_lay(), depth)),
+            )
+        } else {
+            (None, None)
+        };
+        let traded_volume = requested
+            .has(Fields::ExTraded)
+            .then(|| full_ladder(self.traded(), false));
+        let ex =
+            (available_to_back.is_some() || traded_volume.is_some()).then_some(ExchangePrices {
+                available_to_back,
+                available_to_lay,
+                traded_volume,
+            });
+
+        let projected = requested.has(Fields::SpProjected);
+        let traded = requested.has(Fields::SpTraded);
+        let sp = (projected || traded).then(|| StartingPrices {
+            near_price: self.starting_price_near().filter(|_| projected).map(price),
+            far_price: self.starting_price_far().filter(|_| projected).map(price),
+            back_stake_taken: traded.then(|| full_ladder(self.starting_price_back(), false)),
+            lay_liability_taken: traded.then(|| full_ladder(self.starting_price_lay(), false)),
+            actual_sp: definition.and_then(|definition| definition.bsp),
+        });
+
+        Runner {
+            selection_id: *self.selection_id(),
+            handicap: self.handicap().unwrap_or_default(),
+            status: definition
+                .and_then(|definition| definition.status)
+                .map_or(RunnerStatus::Active, runner_status),
+            adjustment_factor: definition.and_then(|definition| definition.adjustment_factor),
+            last_price_traded: self
+                .last_price_traded()
+                .filter(|_| requested.has(Fields::ExLtp))
+                .map(price),
+            total_matched: self
+                .total_matched()
+                .filter(|_| requested.has(Fields::ExTradedVol))
+                .map(|size| F64Ord::new(size.as_f64())),
+            removal_date: definition
+                .and_then(|definition| definition.removal_date.as_deref())
+                .and_then(|date| date.parse::<DateTime<Utc>>().ok()),
+            sp,
+            ex,
+            orders: None,
+            matches: None,
+            matches_by_strategy: None,
+        }
+    }
+}
+
+impl OrderBookCache {
+    /// Converts the orders of the market into the [`CurrentOrderSummary`]s `listCurrentOrders`
+    /// would return, ordered by the date they were placed.
+    ///
+    /// # Returns
+    ///
+    /// The orders of every runner of the market.
+    #[must_use]
+    pub fn current_orders(&self) -> Vec<CurrentOrderSummary> {
+        let mut orders = self
+            .runners()
+            .values()
+            .flat_map(OrderBookRunner::current_orders)
+            .collect::<Vec<_>>();
+        orders.sort_by(|a, b| (a.placed_date, &a.bet_id.0).cmp(&(b.placed_date, &b.bet_id.0)));
+        orders
+    }
+}
+
+impl OrderBookRunner {
+    /// Converts the orders on this runner into the [`CurrentOrderSummary`]s `listCurrentOrders`
+    /// would return.
+    ///
+    /// # Returns
+    ///
+    /// The orders on the runner, in no particular order.
+    pub fn current_orders(&self) -> impl Iterator<Item = CurrentOrderSummary> + '_ {
+        self.unmatched_orders
+            .values()
+            .map(|order| self.current_order(order))
+    }
+
+    fn current_order(&self, order: &Order) -> CurrentOrderSummary {
+        let non_empty = |value: &str| (!value.is_empty()).then(|| Arc::new(value.to_owned()));
+        CurrentOrderSummary {
+            bet_id: order.id.clone(),
+            market_id: self.market_id.clone(),
+            selection_id: self.selection_id,
+            handicap: sports_aping::Handicap(self.handicap.unwrap_or_default()),
+            price_size: PriceSize {
+                price: order.price,
+                size: order.size,
+            },
+            bsp_liability: order
+                .bsp
+                .map_or_else(Size::zero, |bsp| Size::new(bsp.as_f64())),
+            side: name(match order.side {
+                Side::Back => "BACK",
+                Side::Lay => "LAY",
+            }),
+            status: name(match order.status {
+                StreamOrderStatus::Executable => "EXECUTABLE",
+                StreamOrderStatus::ExecutionComplete => "EXECUTION_COMPLETE",
+            }),
+            persistence_type: name(match order.persistence_type {
+                PersistenceType::Lapse => "LAPSE",
+                PersistenceType::Persist => "PERSIST",
+                PersistenceType::MarketOnClose => "MARKET_ON_CLOSE",
+            }),
+            order_type: name(match order.order_type {
+                OrderType::Limit => "LIMIT",
+                OrderType::LimitOnClose => "LIMIT_ON_CLOSE",
+                OrderType::MarketOnClose => "MARKET_ON_CLOSE",
+            }),
+            placed_date: order.place_date,
+            matched_date: order.matched_date,
+            average_price_matched: order.average_price_matched,
+            size_matched: Some(order.size_matched),
+            size_remaining: Some(order.size_remaining),
+            size_lapsed: Some(order.size_lapsed),
+            size_cancelled: Some(order.size_cancelled),
+            size_voided: Some(order.size_voided),
+            regulator_auth_code: non_empty(&order.regulator_auth_code),
+            regulator_code: non_empty(&order.regulator_code),
+            customer_order_ref: (!order.order_reference.as_str().is_empty())
+                .then(|| order.order_reference.clone()),
+            customer_strategy_ref: (order.strategy_reference != CustomerStrategyRef::EMPTY)
+                .then(|| order.strategy_reference.clone()),
+        }
+    }
+}
+
+impl OrderBookCache {
+    /// Builds a cache from the `listCurrentOrders` summaries of a market.
+    ///
+    /// Only the orders themselves are cached; the matched ladders and strategy matches the stream
+    /// maintains are left empty. Runners only carry a handicap if it is not zero, as on the
+    /// stream.
+    ///
+    /// # Parameters
+    ///
+    /// * `market_id` - The market of the orders.
+    /// * `orders` - The orders of the market returned by `listCurrentOrders`; orders of other
+    ///   markets are ignored.
+    /// * `publish_time` - When the orders were requested.
+    ///
+    /// # Returns
+    ///
+    /// The cache, holding a full image of the orders of the market.
+    #[must_use]
+    pub fn from_current_orders(
+        market_id: MarketId,
+        orders: &[CurrentOrderSummary],
+        publish_time: DateTime<Utc>,
+    ) -> Self {
+        let mut runners = Vec::<OrderRunnerChange>::new();
+        for summary in orders.iter().filter(|order| order.market_id == market_id) {
+            let handicap =
+                (summary.handicap.0 != F64Ord::zero()).then_some(Handicap(summary.handicap.0));
+            let order = stream_order(summary);
+            match runners
+                .iter_mut()
+                .find(|runner| runner.id == summary.selection_id && runner.handicap == handicap)
+            {
+                Some(runner) => runner.unmatched_orders.get_or_insert_default().push(order),
+                None => runners.push(OrderRunnerChange {
+                    matched_backs: None,
+                    matched_lays: None,
+                    strategy_matches: None,
+                    unmatched_orders: Some(vec![order]),
+                    id: summary.selection_id,
+                    handicap,
+                    full_image: Some(true),
+                }),
+            }
+        }
+        let change = OrderMarketChange {
+            account_id: None,
+            order_runner_change: Some(runners),
+            closed: None,
+            market_id: market_id.clone(),
+            full_image: Some(true),
+        };
+
+        let mut cache = Self::new(market_id, publish_time);
+        cache.update_cache(change, publish_time);
+        cache
+    }
+}
+
+fn name(name: &str) -> Arc<String> {
+    Arc::new(name.to_owned())
+}
+
+fn price(price: &Price) -> F64Ord {
+    F64Ord::new(price.as_f64())
+}
+
+/// A full ladder, best price first: descending for back prices, ascending otherwise.
+fn full_ladder(ladder: &Available<UpdateSet2>, descending: bool) -> Vec<PriceSize> {
+    let levels = ladder
+        .book
+        .iter()
+        .map(|(&price, &size)| PriceSize { price, size });
+    if descending {
+        levels.rev().collect()
+    } else {
+        levels.collect()
+    }
+}
+
+/// A depth-based ladder cut to `depth` levels, best level first.
+fn depth_ladder(ladder: &Available<UpdateSet3>, depth: usize) -> Vec<PriceSize> {
+    ladder
+        .book
+        .values()
+        .take(depth)
+        .map(|&(price, size)| PriceSize { price, size })
+        .collect()
+}
+
+const fn market_status(status: StreamMarketDefinitionStatus) -> MarketStatus {
+    match status {
+        StreamMarketDefinitionStatus::Inactive => MarketStatus::Inactive,
+        StreamMarketDefinitionStatus::Open => MarketStatus::Open,
+        StreamMarketDefinitionStatus::Suspended => MarketStatus::Suspended,
+        StreamMarketDefinitionStatus::Closed => MarketStatus::Closed,
+    }
+}
+
+const fn runner_status(status: StreamRunnerDefinitionStatus) -> RunnerStatus {
+    match status {
+        StreamRunnerDefinitionStatus::Active | StreamRunnerDefinitionStatus::Hidden => {
+            RunnerStatus::Active
+        }
+        StreamRunnerDefinitionStatus::Winner => RunnerStatus::Winner,
+        StreamRunnerDefinitionStatus::Loser => RunnerStatus::Loser,
+        StreamRunnerDefinitionStatus::Removed => RunnerStatus::Removed,
+        StreamRunnerDefinitionStatus::RemovedVacant => RunnerStatus::RemovedVacant,
+        StreamRunnerDefinitionStatus::Placed => RunnerStatus::Placed,
+    }
+}
+
+fn key_line_description(definition: &KeyLineDefinition) -> Option<KeyLineDescription> {
+    let key_line = definition
+        .key_line
+        .as_ref()?
+        .iter()
+        .filter_map(|selection| {
+            Some(sports_aping::KeyLineSelection {
+                selection_id: SelectionId(selection.id?),
+                handicap: sports_aping::Handicap(selection.handicap.unwrap_or_default()),
+            })
+        })
+        .collect();
+    Some(KeyLineDescription { key_line })
+}
+
+/// The stream market definition described by a market book and its catalogue.
+fn market_definition(book: &MarketBook, catalogue: &MarketCatalogue) -> MarketDefinition {
+    let description = catalogue.description.as_ref();
+    let event = catalogue.event.as_ref();
+    let line = description.and_then(|description| description.line_range_info.as_ref());
+    let betting_type = description.map_or_else(Default::default, |description| {
+        betting_type(&description.betting_type)
+    });
+    let asian_handicap = asian_handicap(betting_type);
+    let sort_priorities = catalogue
+        .runners
+        .iter()
+        .flatten()
+        .map(|runner| ((runner.selection_id, runner.handicap), runner.sort_priority))
+        .collect::<HashMap<_, _>>();
+    let text = |value: Option<&Arc<String>>| value.map(|value| value.as_str().to_owned());
+
+    MarketDefinition {
+        venue: text(event.and_then(|event| event.venue.as_ref())),
+        race_type: text(description.and_then(|description| description.race_type.as_ref())),
+        settled_time: description
+            .and_then(|description| description.settle_time)
+            .map(date),
+        timezone: text(event.and_then(|event| event.timezone.as_ref())).unwrap_or_default(),
+        each_way_divisor: description.and_then(|description| description.each_way_divisor),
+        regulators: text(description.and_then(|description| description.regulator.as_ref()))
+            .into_iter()
+            .collect(),
+        market_type: description
+            .map(|description| description.market_type.0.as_str().to_owned())
+            .unwrap_or_default(),
+        market_base_rate: description
+            .and_then(|description| description.market_base_rate)
+            .unwrap_or_default(),
+        number_of_winners: book.number_of_winners.unwrap_or_default(),
+        country_code: event
+            .and_then(|event| event.country_code.as_ref())
+            .map(|code| code.0.as_str().to_owned()),
+        line_max_unit: line.map(|line| line.max_unit_value),
+        in_play: book.inplay.unwrap_or_default(),
+        bet_delay: book.bet_delay.unwrap_or_default(),
+        bsp_market: description.is_some_and(|description| description.bsp_market),
+        betting_type,
+        number_of_active_runners: book.number_of_active_runners.unwrap_or_default(),
+        line_min_unit: line.map(|line| line.min_unit_value),
+        event_id: event
+            .and_then(|event| event.id.as_ref())
+            .map(|id| id.0.as_str().to_owned())
+            .unwrap_or_default(),
+        cross_matching: book.cross_matching.unwrap_or_default(),
+        runners_voidable: book.runners_voidable.unwrap_or_default(),
+        turn_in_play_enabled: description
+            .is_some_and(|description| description.turn_in_play_enabled),
+        price_ladder_definition: description
+            .and_then(|description| description.price_ladder_description.as_ref())
+            .map(|ladder| {
+                Box::new(PriceLadderDefinition {
+                    r#type: Some(match ladder.r_type {
+                        PriceLadderType::Classic => stream::Type::Classic,
+                        PriceLadderType::Finest => stream::Type::Finest,
+                        PriceLadderType::LineRange => stream::Type::LineRange,
+                    }),
+                })
+            }),
+        key_line_definition: book.key_line_description.as_ref().map(|description| {
+            Box::new(KeyLineDefinition {
+                key_line: Some(
+                    description
+                        .key_line
+                        .iter()
+                        .map(|selection| stream::KeyLineSelection {
+                            id: Some(selection.selection_id.0),
+                            handicap: Some(selection.handicap.0),
+                        })
+                        .collect(),
+                ),
+            })
+        }),
+        suspend_time: description.map(|description| date(description.suspend_time)),
+        discount_allowed: description
+            .and_then(|description| description.discount_allowed)
+            .unwrap_or_default(),
+        persistence_enabled: description
+            .and_then(|description| description.persistence_enabled)
+            .unwrap_or_default(),
+        runners: book
+            .runners
+            .iter()
+            .flatten()
+            .map(|runner| RunnerDefinition {
+                sort_priority: sort_priorities
+                    .get(&(runner.selection_id, runner.handicap))
+                    .copied(),
+                removal_date: runner.removal_date.map(date),
+                id: Some(runner.selection_id),
+                handicap: asian_handicap.then_some(runner.handicap),
+                adjustment_factor: runner.adjustment_factor,
+                bsp: runner.sp.as_ref().and_then(|sp| sp.actual_sp),
+                status: Some(stream_runner_status(runner.status)),
+            })
+            .collect(),
+        version: book.version.unwrap_or_default(),
+        event_type_id: catalogue
+            .event_type
+            .as_ref()
+            .and_then(|event_type| event_type.id.as_ref())
+            .map(|id| id.0.as_str().to_owned())
+            .unwrap_or_default(),
+        complete: book.complete.unwrap_or_default(),
+        open_date: event.and_then(|event| event.open_date).map(date),
+        market_time: description
+            .map(|description| description.market_time)
+            .or(catalogue.market_start_time)
+            .map(date),
+        bsp_reconciled: book.bsp_reconciled.unwrap_or_default(),
+        line_interval: line.map(|line| line.interval),
+        status: book
+            .status
+            .map_or(StreamMarketDefinitionStatus::Inactive, stream_market_status),
+    }
+}
+
+/// The stream image of a runner of a market book, see [`MarketBookCache::from_market_book`].
+fn runner_change(runner: &Runner, asian_handicap: bool, filter: &MarketDataFilter) -> RunnerChange {
+    let requested = Requested(filter);
+    let full = requested.has(Fields::ExAllOffers);
+    let best = !full && requested.has(Fields::ExBestOffers);
+    let display = !full && !best && requested.has(Fields::ExBestOffersDisp);
+    let ex = runner.ex.as_ref();
+    let back = ex.and_then(|ex| ex.available_to_back.as_deref());
+    let lay = ex.and_then(|ex| ex.available_to_lay.as_deref());
+    let sp = runner.sp.as_ref();
+
+    RunnerChange {
+        total_value: runner
+            .total_matched
+            .map(|matched| Size::new(matched.as_f64())),
+        best_available_to_back: back.filter(|_| best).map(positions),
+        starting_price_back: sp.and_then(|sp| sp.back_stake_taken.as_deref()).map(levels),
+        best_display_available_to_lay: lay.filter(|_| display).map(positions),
+        // an empty traded ladder would reset the volume matched on the runner
+        traded: ex
+            .and_then(|ex| ex.traded_volume.as_deref())
+            .filter(|traded| !traded.is_empty())
+            .map(levels),
+        starting_price_far: sp.and_then(|sp| sp.far_price).and_then(stream_price),
+        last_traded_price: runner.last_price_traded.and_then(stream_price),
+        available_to_back: back.filter(|_| full).map(levels),
+        starting_price_lay: sp
+            .and_then(|sp| sp.lay_liability_taken.as_deref())
+            .map(levels),
+        starting_price_near: sp.and_then(|sp| sp.near_price).and_then(stream_price),
+        available_to_lay: lay.filter(|_| full).map(levels),
+        best_available_to_lay: lay.filter(|_| best).map(positions),
+        id: Some(runner.selection_id),
+        handicap: asian_handicap.then_some(runner.handicap),
+        best_display_available_to_back: back.filter(|_| display).map(positions),
+    }
+}
+
+fn betting_type(name: &str) -> StreamMarketFilterBettingType {
+    match name {
+        "LINE" => StreamMarketFilterBettingType::Line,
+        "RANGE" => StreamMarketFilterBettingType::Range,
+        "ASIAN_HANDICAP_DOUBLE_LINE" => StreamMarketFilterBettingType::AsianHandicapDoubleLine,
+        "ASIAN_HANDICAP_SINGLE_LINE" => StreamMarketFilterBettingType::AsianHandicapSingleLine,
+        _ => StreamMarketFilterBettingType::Odds,
+    }
+}
+
+/// The stream order described by a `listCurrentOrders` summary.
+fn stream_order(summary: &CurrentOrderSummary) -> Order {
+    Order {
+        side: match summary.side.as_str() {
+            "LAY" => Side::Lay,
+            _ => Side::Back,
+        },
+        size_voided: summary.size_voided.unwrap_or_else(Size::zero),
+        persistence_type: match summary.persistence_type.as_str() {
+            "PERSIST" => PersistenceType::Persist,
+            "MARKET_ON_CLOSE" => PersistenceType::MarketOnClose,
+            _ => PersistenceType::Lapse,
+        },
+        order_type: match summary.order_type.as_str() {
+            "LIMIT_ON_CLOSE" => OrderType::LimitOnClose,
+            "MARKET_ON_CLOSE" => OrderType::MarketOnClose,
+            _ => OrderType::Limit,
+        },
+        lapse_status_reason_code: None,
+        price: summary.price_size.price,
+        size_cancelled: summary.size_cancelled.unwrap_or_else(Size::zero),
+        regulator_code: summary
+            .regulator_code
+            .as_deref()
+            .cloned()
+            .unwrap_or_default(),
+        size: summary.price_size.size,
+        place_date: summary.placed_date,
+        regulator_auth_code: summary
+            .regulator_auth_code
+            .as_deref()
+            .cloned()
+            .unwrap_or_default(),
+        matched_date: summary.matched_date,
+        cancelled_date: None,
+        lapsed_date: None,
+        size_lapsed: summary.size_lapsed.unwrap_or_else(Size::zero),
+        average_price_matched: summary.average_price_matched,
+        size_matched: summary.size_matched.unwrap_or_else(Size::zero),
+        order_reference: summary.customer_order_ref.clone().unwrap_or_default(),
+        id: summary.bet_id.clone(),
+        bsp: (summary.bsp_liability != Size::zero())
+            .then(|| F64Ord::new(summary.bsp_liability.as_f64())),
+        strategy_reference: summary
+            .customer_strategy_ref
+            .clone()
+            .unwrap_or(CustomerStrategyRef::EMPTY),
+        status: match summary.status.as_str() {
+            "EXECUTABLE" => StreamOrderStatus::Executable,
+            _ => StreamOrderStatus::ExecutionComplete,
+        },
+        size_remaining: summary.size_remaining.unwrap_or_else(Size::zero),
+    }
+}
+
+/// Whether the runners of a market are keyed by their handicap as well.
+const fn asian_handicap(betting_type: StreamMarketFilterBettingType) -> bool {
+    matches!(
+        betting_type,
+        StreamMarketFilterBettingType::AsianHandicapDoubleLine
+            | StreamMarketFilterBettingType::AsianHandicapSingleLine
+    )
+}
+
+/// Dates are sent as strings on the stream.
+fn date(date: DateTime<Utc>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn stream_price(price: F64Ord) -> Option<Price> {
+    Price::new(price.as_f64()).ok()
+}
+
+/// A full ladder as sent on the stream.
+fn levels(ladder: &[PriceSize]) -> Vec<UpdateSet2> {
+    ladder
+        .iter()
+        .map(|level| UpdateSet2(level.price, level.size))
+        .collect()
+}
+
+/// A depth-based ladder as sent on the stream, best level first.
+fn positions(ladder: &[PriceSize]) -> Vec<UpdateSet3> {
+    ladder
+        .iter()
+        .zip(0..=u8::MAX)
+        .map(|(level, position)| UpdateSet3(Position(position), level.price, level.size))
+        .collect()
+}
+
+const fn stream_market_status(status: MarketStatus) -> StreamMarketDefinitionStatus {
+    match status {
+        MarketStatus::Inactive => StreamMarketDefinitionStatus::Inactive,
+        MarketStatus::Open => StreamMarketDefinitionStatus::Open,
+        MarketStatus::Suspended => StreamMarketDefinitionStatus::Suspended,
+        MarketStatus::Closed => StreamMarketDefinitionStatus::Closed,
+    }
+}
+
+const fn stream_runner_status(status: RunnerStatus) -> StreamRunnerDefinitionStatus {
+    match status {
+        RunnerStatus::Active => StreamRunnerDefinitionStatus::Active,
+        RunnerStatus::Winner => StreamRunnerDefinitionStatus::Winner,
+        RunnerStatus::Loser => StreamRunnerDefinitionStatus::Loser,
+        RunnerStatus::Removed => StreamRunnerDefinitionStatus::Removed,
+        RunnerStatus::RemovedVacant => StreamRunnerDefinitionStatus::RemovedVacant,
+        RunnerStatus::Placed => StreamRunnerDefinitionStatus::Placed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use betfair_adapter::betfair_types::types::sports_aping::{BetId, MarketId};
+    use betfair_stream_types::request::market_subscription_message::LadderLevel;
+    use betfair_stream_types::response::market_change_message::MarketChange;
+    use betfair_stream_types::response::order_change_message::OrderMarketChange;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn market() -> MarketBookCache {
+        let change: MarketChange = serde_json::from_str(
+            r#"{"id":"1.1","tv":12.5,"marketDefinition":{"bspMarket":false,"turnInPlayEnabled":true,"persistenceEnabled":true,"marketBaseRate":5,"eventId":"1","eventTypeId":"7","numberOfWinners":1,"bettingType":"ODDS","marketType":"WIN","marketTime":"2016-11-09T18:15:00.000Z","bspReconciled":false,"complete":true,"inPlay":false,"crossMatching":true,"runnersVoidable":false,"numberOfActiveRunners":1,"betDelay":0,"status":"OPEN","runners":[{"status":"REMOVED","sortPriority":2,"id":2,"adjustmentFactor":10.5,"removalDate":"2016-11-09T17:00:00.000Z"},{"status":"ACTIVE","sortPriority":1,"id":1}],"regulators":["MR_INT"],"discountAllowed":true,"timezone":"UTC","openDate":"2016-11-09T18:15:00.000Z","version":3},"rc":[{"id":1,"ltp":2.0,"tv":12.5,"batb":[[0,2.0,10],[1,1.99,20]],"batl":[[0,2.02,5]],"trd":[[2.0,12.5]]}]}"#,
+        )
+        .unwrap();
+        let mut market = MarketBookCache::new(MarketId::new("1.1"), Utc::now());
+        market.update_cache(change, Utc::now(), true);
+        market
+    }
+
+    fn catalogue() -> MarketCatalogue {
+        serde_json::from_str(
+            r#"{"marketId":"1.1","marketName":"1m Hcap","totalMatched":12.5,"description":{"persistenceEnabled":true,"bspMarket":false,"marketTime":"2016-11-09T18:15:00.000Z","suspendTime":"2016-11-09T18:15:00.000Z","bettingType":"ODDS","turnInPlayEnabled":true,"marketType":"WIN","regulator":"MR_INT","marketBaseRate":5,"discountAllowed":true},"runners":[{"selectionId":1,"runnerName":"One","handicap":0,"sortPriority":1},{"selectionId":2,"runnerName":"Two","handicap":0,"sortPriority":2}],"eventType":{"id":"7","name":"Horse Racing"},"event":{"id":"1","name":"Kempton","countryCode":"GB","timezone":"Europe/London","openDate":"2016-11-09T18:15:00.000Z"}}"#,
+        )
+        .unwrap()
+    }
+
+    fn price_size(price: f64, size: f64) -> PriceSize {
+        PriceSize {
+            price: Price::new(price).unwrap(),
+            size: Size::new(size),
+        }
+    }
+
+    #[test]
+    fn market_book_contains_the_requested_fields() {
+        let filter = MarketDataFilter {
+            ladder_levels: Some(LadderLevel::new(1).unwrap()),
+            fields: Some(vec![
+                Fields::ExBestOffers,
+                Fields::ExLtp,
+                Fields::ExMarketDef,
+            ]),
+        };
+
+        let book = market().to_market_book(&filter);
+
+        assert_eq!(book.status, Some(MarketStatus::Open));
+        assert_eq!(book.version, Some(3));
+        assert_eq!(book.number_of_runners, Some(2));
+        assert_eq!(book.total_matched, None);
+        let runners = book.runners.unwrap();
+        assert_eq!(
+            runners
+                .iter()
+                .map(|runner| (runner.selection_id, runner.status))
+                .collect::<Vec<_>>(),
+            vec![
+                (SelectionId(1), RunnerStatus::Active),
+                (SelectionId(2), RunnerStatus::Removed)
+            ]
+        );
+        assert_eq!(runners[0].last_price_traded, Some(F64Ord::new(2.0)));
+        assert_eq!(runners[0].total_matched, None);
+        assert_eq!(
+            runners[0].ex,
+            Some(ExchangePrices {
+                available_to_back: Some(vec![price_size(2.0, 10.0)]),
+                available_to_lay: Some(vec![price_size(2.02, 5.0)]),
+                traded_volume: None,
+            })
+        );
+        assert_eq!(runners[1].adjustment_factor, Some(F64Ord::new(10.5)));
+        assert_eq!(
+            runners[1].removal_date,
+            Some("2016-11-09T17:00:00Z".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn market_book_without_fields_contains_everything_cached() {
+        let book = market().to_market_book(&MarketDataFilter::default());
+
+        assert_eq!(book.total_matched, Some(F64Ord::new(12.5)));
+        let runner = &book.runners.unwrap()[0];
+        assert_eq!(runner.total_matched, Some(F64Ord::new(12.5)));
+        let ex = runner.ex.as_ref().unwrap();
+        // the full ladder wins over the best offers, as with `listMarketBook`
+        assert_eq!(ex.available_to_back, Some(vec![]));
+        assert_eq!(ex.traded_volume, Some(vec![price_size(2.0, 12.5)]));
+    }
+
+    #[test]
+    fn cache_seeded_from_a_market_book_converts_back_into_it() {
+        let filter = MarketDataFilter {
+            ladder_levels: Some(LadderLevel::new(2).unwrap()),
+            fields: Some(vec![
+                Fields::ExBestOffers,
+                Fields::ExTraded,
+                Fields::ExTradedVol,
+                Fields::ExLtp,
+                Fields::ExMarketDef,
+            ]),
+        };
+        let book = market().to_market_book(&filter);
+
+        let seeded = MarketBookCache::from_market_book(&book, &catalogue(), &filter, Utc::now());
+
+        assert_eq!(seeded.to_market_book(&filter), book);
+        let definition = seeded.market_definition().unwrap();
+        assert_eq!(definition.market_type, "WIN");
+        assert_eq!(definition.event_id, "1");
+        assert_eq!(definition.event_type_id, "7");
+        assert_eq!(definition.timezone, "Europe/London");
+        assert_eq!(definition.regulators, vec!["MR_INT".to_owned()]);
+        assert_eq!(
+            definition.market_time.as_deref(),
+            Some("2016-11-09T18:15:00.000Z")
+        );
+        assert!(seeded.compare_with_market_book(&book, &filter).is_empty());
+    }
+
+    #[test]
+    fn current_orders_are_converted() {
+        let change: OrderMarketChange = serde_json::from_str(
+            r#"{"id":"1.1","orc":[{"id":1,"uo":[{"id":"1","p":2.0,"s":10,"side":"L","status":"E","pt":"P","ot":"L","pd":1478717720000,"sm":4,"sr":6,"sl":0,"sc":0,"sv":0,"avp":2.0,"rfo":"order","rc":"REG_GGC","rac":""}]}]}"#,
+        )
+        .unwrap();
+        let mut orders = OrderBookCache::new(MarketId::new("1.1"), Utc::now());
+        orders.update_cache(change, Utc::now());
+
+        let summaries = orders.current_orders();
+
+        assert_eq!(summaries.len(), 1);
+        let summary = &summaries[0];
+        assert_eq!(summary.bet_id, BetId::new("1"));
+        assert_eq!(summary.market_id, MarketId::new("1.1"));
+        assert_eq!(summary.selection_id, SelectionId(1));
+        assert_eq!(summary.price_size, price_size(2.0, 10.0));
+        assert_eq!(summary.side.as_str(), "LAY");
+        assert_eq!(summary.status.as_str(), "EXECUTABLE");
+        assert_eq!(summary.persistence_type.as_str(), "PERSIST");
+        assert_eq!(summary.size_matched, Some(Size::new(4.0)));
+        assert_eq!(summary.size_remaining, Some(Size::new(6.0)));
+        assert_eq!(
+            summary
+                .customer_order_ref
+                .as_ref()
+                .map(|reference| reference.as_str()),
+            Some("order")
+        );
+        assert_eq!(summary.customer_strategy_ref, None);
+        assert_eq!(
+            summary.regulator_code.as_deref().map(String::as_str),
+            Some("REG_GGC")
+        );
+        assert_eq!(summary.regulator_auth_code, None);
+    }
+}
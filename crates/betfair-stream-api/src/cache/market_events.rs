@@ -0,0 +1,227 @@
+//! Typed events derived from market cache updates.
+//!
+//! Enable them with [`Cache::with_market_events`](crate::Cache::with_market_events); they are
+//! delivered as [`CachedMessage::MarketEvents`](crate::CachedMessage::MarketEvents) right after
+//! the market batch they were derived from.
+
+use betfair_adapter::betfair_types::numeric::F64Ord;
+use betfair_adapter::betfair_types::price::Price;
+use betfair_adapter::betfair_types::types::sports_aping::{MarketId, SelectionId};
+use betfair_stream_types::response::market_change_message::{
+    MarketDefinition, StreamMarketDefinitionStatus, StreamRunnerDefinitionStatus,
+};
+use betfair_stream_types::response::order_change_message::Side;
+
+use super::primitives::runner_book_cache::RunnerBookCache;
+
+/// Something that changed in a market, found by comparing the cache before and after applying
+/// a change.
+///
+/// Events are only derived for markets that already had an image: the first image of a market
+/// produces none.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MarketEvent {
+    /// The market status changed to suspended.
+    MarketSuspended {
+        /// The market that was suspended.
+        market_id: MarketId,
+    },
+    /// The market status changed from suspended back to open.
+    MarketReopened {
+        /// The market that was reopened.
+        market_id: MarketId,
+    },
+    /// The market turned in-play.
+    WentInPlay {
+        /// The market that went in-play.
+        market_id: MarketId,
+    },
+    /// The market status changed to closed.
+    MarketClosed {
+        /// The market that was closed.
+        market_id: MarketId,
+    },
+    /// A runner was removed from the market.
+    RunnerRemoved {
+        /// The market of the runner.
+        market_id: MarketId,
+        /// The removed runner.
+        selection_id: SelectionId,
+        /// The handicap of the removed runner.
+        handicap: Option<F64Ord>,
+        /// The adjustment factor applied to the remaining runners, if Betfair provided one.
+        adjustment_factor: Option<F64Ord>,
+    },
+    /// The best price available to back or lay on a runner changed.
+    ///
+    /// Taken from the full ladder if subscribed to, otherwise from the best prices or the
+    /// virtual (display) best prices.
+    BestPriceChanged {
+        /// The market of the runner.
+        market_id: MarketId,
+        /// The runner whose price changed.
+        selection_id: SelectionId,
+        /// The handicap of the runner.
+        handicap: Option<F64Ord>,
+        /// Whether the price available to back or to lay changed.
+        side: Side,
+        /// The previous best price, `None` if there was nothing available.
+        old: Option<Price>,
+        /// The new best price, `None` if nothing is available anymore.
+        new: Option<Price>,
+    },
+    /// The last price traded on a runner changed.
+    LastTradedPriceChanged {
+        /// The market of the runner.
+        market_id: MarketId,
+        /// The runner that traded.
+        selection_id: SelectionId,
+        /// The handicap of the runner.
+        handicap: Option<F64Ord>,
+        /// The previous last traded price, `None` if the runner had not traded.
+        old: Option<Price>,
+        /// The new last traded price.
+        new: Option<Price>,
+    },
+    /// A new version of the market definition was received.
+    MarketDefinitionVersionChanged {
+        /// The market whose definition changed.
+        market_id: MarketId,
+        /// The previous version.
+        old: i64,
+        /// The new version.
+        new: i64,
+    },
+}
+
+impl MarketEvent {
+    /// The market the event belongs to.
+    #[must_use]
+    pub const fn market_id(&self) -> &MarketId {
+        match *self {
+            Self::MarketSuspended { ref market_id }
+            | Self::MarketReopened { ref market_id }
+            | Self::WentInPlay { ref market_id }
+            | Self::MarketClosed { ref market_id }
+            | Self::RunnerRemoved { ref market_id, .. }
+            | Self::BestPriceChanged { ref market_id, .. }
+            | Self::LastTradedPriceChanged { ref market_id, .. }
+            | Self::MarketDefinitionVersionChanged { ref market_id, .. } => market_id,
+        }
+    }
+}
+
+/// The prices of a runner events are derived from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct RunnerQuotes {
+    best_back: Option<Price>,
+    best_lay: Option<Price>,
+    last_traded: Option<Price>,
+}
+
+impl RunnerQuotes {
+    pub(crate) fn of(runner: &RunnerBookCache) -> Self {
+        Self {
+            best_back: runner.best_back().map(|level| level.price),
+            best_lay: runner.best_lay().map(|level| level.price),
+            last_traded: runner.last_price_traded().copied(),
+        }
+    }
+}
+
+/// Events caused by replacing the market definition `old` with `new`.
+pub(crate) fn definition_events(
+    market_id: &MarketId,
+    old: &MarketDefinition,
+    new: &MarketDefinition,
+    events: &mut Vec<MarketEvent>,
+) {
+    use StreamMarketDefinitionStatus::{Closed, Open, Suspended};
+
+    let market_id = || market_id.clone();
+    match (old.status, new.status) {
+        (old, Suspended) if old != Suspended => events.push(MarketEvent::MarketSuspended {
+            market_id: market_id(),
+        }),
+        (Suspended, Open) => events.push(MarketEvent::MarketReopened {
+            market_id: market_id(),
+        }),
+        (old, Closed) if old != Closed => events.push(MarketEvent::MarketClosed {
+            market_id: market_id(),
+        }),
+        _ => {}
+    }
+    if !old.in_play && new.in_play {
+        events.push(MarketEvent::WentInPlay {
+            market_id: market_id(),
+        });
+    }
+    if old.version != new.version {
+        events.push(MarketEvent::MarketDefinitionVersionChanged {
+            market_id: market_id(),
+            old: old.version,
+            new: new.version,
+        });
+    }
+
+    let removed = |status: Option<StreamRunnerDefinitionStatus>| {
+        matches!(
+            status,
+            Some(
+                StreamRunnerDefinitionStatus::Removed | StreamRunnerDefinitionStatus::RemovedVacant
+            )
+        )
+    };
+    for runner in new.runners.iter().filter(|runner| removed(runner.status)) {
+        let Some(selection_id) = runner.id else {
+            continue;
+        };
+        let was_removed = old.runners.iter().any(|previous| {
+            previous.id == runner.id
+                && previous.handicap == runner.handicap
+                && removed(previous.status)
+        });
+        if !was_removed {
+            events.push(MarketEvent::RunnerRemoved {
+                market_id: market_id(),
+                selection_id,
+                handicap: runner.handicap,
+                adjustment_factor: runner.adjustment_factor,
+            });
+        }
+    }
+}
+
+/// Events caused by the prices of a runner changing from `old` to `new`.
+pub(crate) fn runner_events(
+    market_id: &MarketId,
+    (selection_id, handicap): (SelectionId, Option<F64Ord>),
+    old: RunnerQuotes,
+    new: RunnerQuotes,
+    events: &mut Vec<MarketEvent>,
+) {
+    for (side, old, new) in [
+        (Side::Back, old.best_back, new.best_back),
+        (Side::Lay, old.best_lay, new.best_lay),
+    ] {
+        if old != new {
+            events.push(MarketEvent::BestPriceChanged {
+                market_id: market_id.clone(),
+                selection_id,
+                handicap,
+                side,
+                old,
+                new,
+            });
+        }
+    }
+    if old.last_traded != new.last_traded {
+        events.push(MarketEvent::LastTradedPriceChanged {
+            market_id: market_id.clone(),
+            selection_id,
+            handicap,
+            old: old.last_traded,
+            new: new.last_traded,
+        });
+    }
+}
@@ -6,12 +6,22 @@ use betfair_stream_types::request::market_subscription_message::{
     Fields, LadderLevel, MarketDataFilter, MarketFilter, MarketSubscriptionMessage,
 };
 
-use crate::{BetfairStreamClient, MessageProcessor};
+use tokio::sync::mpsc::error::SendError;
+
+use super::subscription_manager::no_markets_filter;
+use crate::ack::PendingAcks;
+use crate::{BetfairStreamClient, MessageProcessor, RequestAck};
 
 /// A wrapper around a `StreamListener` that allows subscribing to markets with a somewhat ergonomic
 /// API.
+///
+/// Every method sending a subscription returns a [`RequestAck`] that resolves to the status
+/// message Betfair answered that specific subscription with. It can be dropped if the answer is
+/// not needed.
+#[derive(Debug)]
 pub struct MarketSubscriber {
     command_sender: tokio::sync::mpsc::Sender<RequestMessage>,
+    pending_acks: Arc<PendingAcks>,
     filter: MarketFilter,
     /// The list of market data fields to subscribe to.
     market_data_fields: Vec<Fields>,
@@ -32,8 +42,8 @@ impl MarketSubscriber {
     /// # Returns
     /// A new instance of `MarketSubscriber`.
     #[must_use]
-    pub fn new<T: MessageProcessor>(
-        stream_api_connection: &BetfairStreamClient<T>,
+    pub fn new<T: MessageProcessor, S>(
+        stream_api_connection: &BetfairStreamClient<T, S>,
         filter: MarketFilter,
         market_data_fields: Vec<Fields>,
         ladder_level: Option<LadderLevel>,
@@ -41,6 +51,7 @@ impl MarketSubscriber {
         let command_sender = stream_api_connection.send_to_stream.clone();
         Self {
             command_sender,
+            pending_acks: Arc::clone(&stream_api_connection.pending_acks),
             filter,
             market_data_fields,
             ladder_level,
@@ -57,7 +68,7 @@ impl MarketSubscriber {
     pub async fn subscribe_to_market(
         &mut self,
         market_id: MarketId,
-    ) -> Result<(), tokio::sync::mpsc::error::SendError<RequestMessage>> {
+    ) -> Result<RequestAck, SendError<RequestMessage>> {
         if let Some(ref mut market_ids) = self.filter.market_ids {
             market_ids.push(market_id);
         } else {
@@ -68,6 +79,9 @@ impl MarketSubscriber {
 
     /// Unsubscribe from a market using its `MarketId`.
     ///
+    /// Once no market remains, all markets are unsubscribed and the acknowledgement of that
+    /// request is returned. Otherwise nothing is sent and `None` is returned.
+    ///
     /// # Parameters
     /// - `market_id`: A reference to the `MarketId` of the market to unsubscribe from.
     ///
@@ -76,7 +90,7 @@ impl MarketSubscriber {
     pub async fn unsubscribe_from_market(
         &mut self,
         market_id: &MarketId,
-    ) -> Result<(), tokio::sync::mpsc::error::SendError<RequestMessage>> {
+    ) -> Result<Option<RequestAck>, SendError<RequestMessage>> {
         if let Some(x) = self.filter.market_ids.as_mut() {
             x.retain(|iter_market_id| iter_market_id != market_id);
         }
@@ -87,23 +101,21 @@ impl MarketSubscriber {
             .as_ref()
             .is_none_or(alloc::vec::Vec::is_empty)
         {
-            self.unsubscribe_from_all_markets().await?;
+            return self.unsubscribe_from_all_markets().await.map(Some);
         }
 
-        Ok(())
+        Ok(None)
     }
 
     /// Unsubscribe from all markets.
     ///
-    /// Internally it uses a trick of subscribing to a non-existent market to simulate
-    /// unsubscribing from all markets.
+    /// Betfair has no unsubscribe operation, so this subscribes to an empty list of market ids.
     ///
     /// # Errors
     /// If sending the request to the underlying stream fails.
     pub async fn unsubscribe_from_all_markets(
         &mut self,
-    ) -> Result<(), tokio::sync::mpsc::error::SendError<RequestMessage>> {
-        let market_that_does_not_exist = MarketId(Arc::new("1.23456789".to_owned()));
+    ) -> Result<RequestAck, SendError<RequestMessage>> {
         self.filter = MarketFilter::default();
 
         let req = RequestMessage::MarketSubscription(MarketSubscriptionMessage {
@@ -112,18 +124,7 @@ impl MarketSubscriber {
             clk: None,
             heartbeat_ms: Some(5000),
             initial_clk: None,
-            market_filter: Some(Box::new(MarketFilter {
-                country_codes: None,
-                betting_types: None,
-                turn_in_play_enabled: None,
-                market_types: None,
-                venues: None,
-                market_ids: Some(vec![market_that_does_not_exist]),
-                event_type_ids: None,
-                event_ids: None,
-                bsp_market: None,
-                race_types: None,
-            })),
+            market_filter: Some(Box::new(no_markets_filter())),
             conflate_ms: None,
             market_data_filter: Some(Box::new(MarketDataFilter {
                 ladder_levels: None,
@@ -131,18 +132,14 @@ impl MarketSubscriber {
             })),
         });
 
-        self.command_sender.send(req).await?;
-
-        Ok(())
+        self.pending_acks.send(&self.command_sender, req).await
     }
 
     /// Resubscribe to the markets.
     ///
     /// # Errors
     /// If sending the request to the underlying stream fails.
-    pub async fn resubscribe(
-        &self,
-    ) -> Result<(), tokio::sync::mpsc::error::SendError<RequestMessage>> {
+    pub async fn resubscribe(&self) -> Result<RequestAck, SendError<RequestMessage>> {
         let req = RequestMessage::MarketSubscription(MarketSubscriptionMessage {
             id: None,
             clk: None,         // empty to reset the clock
@@ -156,9 +153,7 @@ impl MarketSubscriber {
                 fields: Some(self.market_data_fields.clone()),
             })),
         });
-        self.command_sender.send(req).await?;
-
-        Ok(())
+        self.pending_acks.send(&self.command_sender, req).await
     }
 
     /// Get the current filter for the market subscription.
@@ -180,7 +175,7 @@ impl MarketSubscriber {
     pub async fn set_filter(
         &mut self,
         filter: MarketFilter,
-    ) -> Result<(), tokio::sync::mpsc::error::SendError<RequestMessage>> {
+    ) -> Result<RequestAck, SendError<RequestMessage>> {
         self.filter = filter;
         self.resubscribe().await
     }
@@ -204,7 +199,7 @@ impl MarketSubscriber {
     pub async fn set_ladder_level(
         &mut self,
         ladder_level: Option<LadderLevel>,
-    ) -> Result<(), tokio::sync::mpsc::error::SendError<RequestMessage>> {
+    ) -> Result<RequestAck, SendError<RequestMessage>> {
         self.ladder_level = ladder_level;
         self.resubscribe().await
     }
@@ -228,7 +223,7 @@ impl MarketSubscriber {
     pub async fn set_market_data_fields(
         &mut self,
         market_data_fields: Vec<Fields>,
-    ) -> Result<(), tokio::sync::mpsc::error::SendError<RequestMessage>> {
+    ) -> Result<RequestAck, SendError<RequestMessage>> {
         self.market_data_fields = market_data_fields;
         self.resubscribe().await
     }
@@ -1,7 +1,14 @@
 //! Contains all the types that are necessary to properly build a local cache representation of the
 //! market
 
+pub mod analytics;
+pub mod consistency;
+mod conversions;
+pub mod market_events;
 pub mod market_subscriber;
+pub mod order_events;
 pub mod order_subscriber;
 pub mod primitives;
+pub mod subscription_manager;
 pub mod tracker;
+pub mod watch;
@@ -0,0 +1,146 @@
+//! Typed events derived from order cache updates.
+//!
+//! Enable them with [`Cache::with_order_events`](crate::Cache::with_order_events); they are
+//! delivered as [`CachedMessage::OrderEvents`](crate::CachedMessage::OrderEvents) right after
+//! the order batch they were derived from.
+
+use betfair_adapter::betfair_types::customer_order_ref::CustomerOrderRef;
+use betfair_adapter::betfair_types::customer_strategy_ref::CustomerStrategyRef;
+use betfair_adapter::betfair_types::handicap::Handicap;
+use betfair_adapter::betfair_types::price::Price;
+use betfair_adapter::betfair_types::size::Size;
+use betfair_adapter::betfair_types::types::sports_aping::{BetId, MarketId, SelectionId};
+use betfair_stream_types::response::order_change_message::{Order, Side};
+
+/// Something that happened to an order, found by comparing it before and after applying a
+/// change.
+///
+/// Events are only derived for markets whose orders were already known: the first image of a
+/// market produces none.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrderEvent {
+    /// The market of the order.
+    pub market_id: MarketId,
+    /// The runner of the order.
+    pub selection_id: SelectionId,
+    /// The handicap of the runner.
+    pub handicap: Option<Handicap>,
+    /// The order the event belongs to.
+    pub bet_id: BetId,
+    /// Whether the order backs or lays.
+    pub side: Side,
+    /// The price the order was placed at.
+    pub price: Price,
+    /// The order reference set when placing the order.
+    pub customer_order_ref: CustomerOrderRef,
+    /// The strategy reference set when placing the order.
+    pub customer_strategy_ref: CustomerStrategyRef,
+    /// What happened.
+    pub kind: OrderEventKind,
+}
+
+/// What happened to an order, see [`OrderEvent`].
+///
+/// A single change can cause several events, e.g. an order that is matched partially right
+/// when it is placed is reported as accepted and then as partially matched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OrderEventKind {
+    /// A new order appeared.
+    OrderAccepted,
+    /// Part of the order was matched; some of it remains unmatched.
+    PartiallyMatched {
+        /// The amount matched since the last change.
+        delta_size: Size,
+        /// The average price the order is matched at so far.
+        avg_price: Option<Price>,
+    },
+    /// The rest of the order was matched; nothing remains unmatched.
+    FullyMatched {
+        /// The amount matched since the last change.
+        delta_size: Size,
+        /// The average price the order is matched at.
+        avg_price: Option<Price>,
+    },
+    /// Part or all of the order was cancelled.
+    Cancelled {
+        /// The amount cancelled since the last change.
+        size_cancelled: Size,
+    },
+    /// Part or all of the order lapsed.
+    Lapsed {
+        /// The amount lapsed since the last change.
+        size_lapsed: Size,
+    },
+    /// Part or all of the order was voided.
+    Voided {
+        /// The amount voided since the last change.
+        size_voided: Size,
+    },
+}
+
+/// Appends the events caused by `order` replacing `old` to `events`.
+pub(crate) fn order_events(
+    market_id: &MarketId,
+    (selection_id, handicap): (SelectionId, Option<Handicap>),
+    old: Option<&Order>,
+    order: &Order,
+    events: &mut Vec<OrderEvent>,
+) {
+    let previous = |size: fn(&Order) -> Size| old.map_or_else(Size::zero, size);
+    let delta = |size: fn(&Order) -> Size| {
+        // rounded, so that float noise in the difference is not reported as a change
+        Size::new(size(order).as_f64() - previous(size).as_f64())
+    };
+    let matched = delta(|order| order.size_matched);
+    let cancelled = delta(|order| order.size_cancelled);
+    let lapsed = delta(|order| order.size_lapsed);
+    let voided = delta(|order| order.size_voided);
+    let zero = Size::zero();
+
+    let mut kinds = Vec::new();
+    if old.is_none() {
+        kinds.push(OrderEventKind::OrderAccepted);
+    }
+    if matched > zero {
+        let completed =
+            order.size_remaining == zero && cancelled <= zero && lapsed <= zero && voided <= zero;
+        kinds.push(if completed {
+            OrderEventKind::FullyMatched {
+                delta_size: matched,
+                avg_price: order.average_price_matched,
+            }
+        } else {
+            OrderEventKind::PartiallyMatched {
+                delta_size: matched,
+                avg_price: order.average_price_matched,
+            }
+        });
+    }
+    if cancelled > zero {
+        kinds.push(OrderEventKind::Cancelled {
+            size_cancelled: cancelled,
+        });
+    }
+    if lapsed > zero {
+        kinds.push(OrderEventKind::Lapsed {
+            size_lapsed: lapsed,
+        });
+    }
+    if voided > zero {
+        kinds.push(OrderEventKind::Voided {
+            size_voided: voided,
+        });
+    }
+
+    events.extend(kinds.into_iter().map(|kind| OrderEvent {
+        market_id: market_id.clone(),
+        selection_id,
+        handicap,
+        bet_id: order.id.clone(),
+        side: order.side,
+        price: order.price,
+        customer_order_ref: order.order_reference.clone(),
+        customer_strategy_ref: order.strategy_reference.clone(),
+        kind,
+    }));
+}
@@ -3,26 +3,35 @@ use betfair_stream_types::request::RequestMessage;
 use betfair_stream_types::request::order_subscription_message::{
     OrderFilter, OrderSubscriptionMessage,
 };
+use std::sync::Arc;
+
 use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::error::SendError;
 
-use crate::{BetfairStreamClient, MessageProcessor};
+use crate::ack::PendingAcks;
+use crate::{BetfairStreamClient, MessageProcessor, RequestAck};
 
 /// A wrapper around a `StreamListener` that allows subscribing to order updates with a somewhat
 /// ergonomic API.
+///
+/// Every method sending a subscription returns a [`RequestAck`] that resolves to the status
+/// message Betfair answered that specific subscription with.
 pub struct OrderSubscriber {
     command_sender: Sender<RequestMessage>,
+    pending_acks: Arc<PendingAcks>,
     filter: OrderFilter,
 }
 
 impl OrderSubscriber {
     #[must_use]
-    pub fn new<T: MessageProcessor>(
-        stream_api_connection: &BetfairStreamClient<T>,
+    pub fn new<T: MessageProcessor, S>(
+        stream_api_connection: &BetfairStreamClient<T, S>,
         filter: OrderFilter,
     ) -> Self {
         let command_sender = stream_api_connection.send_to_stream.clone();
         Self {
             command_sender,
+            pending_acks: Arc::clone(&stream_api_connection.pending_acks),
             filter,
         }
     }
@@ -34,7 +43,7 @@ impl OrderSubscriber {
     pub async fn subscribe_to_strategy_updates(
         &mut self,
         strategy_ref: CustomerStrategyRef,
-    ) -> Result<(), tokio::sync::mpsc::error::SendError<RequestMessage>> {
+    ) -> Result<RequestAck, SendError<RequestMessage>> {
         if let Some(ref mut strategy_refs) = self.filter.customer_strategy_refs {
             strategy_refs.push(strategy_ref);
         } else {
@@ -46,12 +55,15 @@ impl OrderSubscriber {
 
     /// Unsubscribe from a market.
     ///
+    /// Once no strategy remains, all orders are unsubscribed and the acknowledgement of that
+    /// request is returned. Otherwise nothing is sent and `None` is returned.
+    ///
     /// # Errors
     /// If the message cannot be sent to the stream.
     pub async fn unsubscribe_from_strategy_updates(
         &mut self,
         strategy_ref: &CustomerStrategyRef,
-    ) -> Result<(), tokio::sync::mpsc::error::SendError<RequestMessage>> {
+    ) -> Result<Option<RequestAck>, SendError<RequestMessage>> {
         if let Some(x) = self.filter.customer_strategy_refs.as_mut() {
             x.retain(|iter_strategy_ref| iter_strategy_ref != strategy_ref);
         }
@@ -62,10 +74,10 @@ impl OrderSubscriber {
             .as_ref()
             .is_none_or(alloc::vec::Vec::is_empty)
         {
-            self.unsubscribe_from_all_markets().await?;
+            return self.unsubscribe_from_all_markets().await.map(Some);
         }
 
-        Ok(())
+        Ok(None)
     }
 
     /// Unsubscribe from all markets.
@@ -78,7 +90,7 @@ impl OrderSubscriber {
     /// if the message cannot be sent to the stream.
     pub async fn unsubscribe_from_all_markets(
         &mut self,
-    ) -> Result<(), tokio::sync::mpsc::error::SendError<RequestMessage>> {
+    ) -> Result<RequestAck, SendError<RequestMessage>> {
         let strategy_that_does_not_exist = CustomerStrategyRef::new([
             'd', 'o', 's', 'e', 'n', 't', ' ', 'e', 'x', 'i', 's', 't', ' ', ' ', ' ',
         ]);
@@ -98,9 +110,7 @@ impl OrderSubscriber {
             })),
             conflate_ms: None,
         });
-        self.command_sender.send(req).await?;
-
-        Ok(())
+        self.pending_acks.send(&self.command_sender, req).await
     }
 
     /// Resubscribe to the stream.
@@ -109,9 +119,7 @@ impl OrderSubscriber {
     ///
     /// # Errors
     /// if the stream fails to send the message
-    pub async fn resubscribe(
-        &self,
-    ) -> Result<(), tokio::sync::mpsc::error::SendError<RequestMessage>> {
+    pub async fn resubscribe(&self) -> Result<RequestAck, SendError<RequestMessage>> {
         let req = RequestMessage::OrderSubscription(OrderSubscriptionMessage {
             id: None,
             clk: None,         // empty to reset the clock
@@ -121,9 +129,7 @@ impl OrderSubscriber {
             order_filter: Some(Box::new(self.filter.clone())),
             conflate_ms: None,
         });
-        self.command_sender.send(req).await?;
-
-        Ok(())
+        self.pending_acks.send(&self.command_sender, req).await
     }
 
     #[must_use]
@@ -138,7 +144,7 @@ impl OrderSubscriber {
     pub async fn set_filter(
         &mut self,
         filter: OrderFilter,
-    ) -> Result<(), tokio::sync::mpsc::error::SendError<RequestMessage>> {
+    ) -> Result<RequestAck, SendError<RequestMessage>> {
         self.filter = filter;
         self.resubscribe().await
     }
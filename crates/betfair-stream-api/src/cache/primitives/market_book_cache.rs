@@ -1,6 +1,7 @@
 //! Market book cache
 
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use betfair_adapter::betfair_types::numeric::F64Ord;
 use betfair_adapter::betfair_types::size::Size;
@@ -11,18 +12,24 @@ use betfair_stream_types::response::market_change_message::{
 use chrono::{DateTime, Utc};
 
 use super::runner_book_cache::RunnerBookCache;
+use crate::cache::market_events::{MarketEvent, RunnerQuotes, definition_events, runner_events};
 
 /// A cache for market book data, including market and runner information.
+///
+/// The market definition, the runners and every single runner are shared behind an [`Arc`] and
+/// copied on write, so cloning a cache is cheap and an update only copies the runners it changes.
 #[derive(Debug, PartialEq, Eq, Clone, serde::Serialize, serde::Deserialize)]
 pub struct MarketBookCache {
     market_id: MarketId,
     publish_time: DateTime<Utc>,
     active: bool,
     total_matched: Size,
-    market_definition: Option<Box<MarketDefinition>>,
-    runners: HashMap<(SelectionId, Option<F64Ord>), RunnerBookCache>,
+    market_definition: Option<Arc<MarketDefinition>>,
+    runners: Arc<Runners>,
 }
 
+type Runners = HashMap<(SelectionId, Option<F64Ord>), Arc<RunnerBookCache>>;
+
 /// Represents the market book cache.
 impl MarketBookCache {
     /// Creates a new instance of `MarketBookCache`.
@@ -34,7 +41,7 @@ impl MarketBookCache {
             publish_time,
             market_definition: None,
             total_matched: Size::zero(),
-            runners: HashMap::new(),
+            runners: Arc::default(),
         }
     }
 
@@ -52,11 +59,59 @@ impl MarketBookCache {
         market_change: MarketChange,
         publish_time: DateTime<Utc>,
         active: bool,
+    ) {
+        self.apply(market_change, publish_time, active, None);
+    }
+
+    /// Updates the cache with the latest market changes, appending what changed to `events`.
+    pub fn update_cache_with_events(
+        &mut self,
+        market_change: MarketChange,
+        publish_time: DateTime<Utc>,
+        active: bool,
+        events: &mut Vec<MarketEvent>,
+    ) {
+        self.apply(market_change, publish_time, active, Some(events));
+    }
+
+    /// Appends the events between `previous` and this cache to `events`, for a market whose
+    /// cache was rebuilt from a new image.
+    pub(crate) fn image_events(&self, previous: &Self, events: &mut Vec<MarketEvent>) {
+        if let (Some(old), Some(new)) = (&previous.market_definition, &self.market_definition) {
+            definition_events(&self.market_id, old, new, events);
+        }
+        for (key, runner) in self.runners.iter() {
+            let old = previous
+                .runners
+                .get(key)
+                .map(|runner| RunnerQuotes::of(runner))
+                .unwrap_or_default();
+            runner_events(&self.market_id, *key, old, RunnerQuotes::of(runner), events);
+        }
+        for (key, runner) in previous.runners.iter() {
+            if !self.runners.contains_key(key) {
+                let old = RunnerQuotes::of(runner);
+                runner_events(&self.market_id, *key, old, RunnerQuotes::default(), events);
+            }
+        }
+    }
+
+    fn apply(
+        &mut self,
+        market_change: MarketChange,
+        publish_time: DateTime<Utc>,
+        active: bool,
+        mut events: Option<&mut Vec<MarketEvent>>,
     ) {
         self.active = active;
         self.publish_time = publish_time;
 
         if let Some(market_definition) = market_change.market_definition {
+            if let Some(ref mut events) = events
+                && let Some(ref old) = self.market_definition
+            {
+                definition_events(&self.market_id, old, &market_definition, events);
+            }
             self.update_market_definition(market_definition);
         }
 
@@ -70,13 +125,21 @@ impl MarketBookCache {
                 let Some(selection_id) = runner_change.id else {
                     continue;
                 };
-                let runner = self
-                    .runners
-                    .get_mut(&(selection_id, runner_change.handicap));
+                let key = (selection_id, runner_change.handicap);
+                let runner = Arc::make_mut(&mut self.runners)
+                    .get_mut(&key)
+                    .map(Arc::make_mut);
                 let Some(runner) = runner else {
                     self.add_runner_from_change(runner_change);
+                    if let Some(ref mut events) = events
+                        && let Some(runner) = self.runners.get(&key)
+                    {
+                        let new = RunnerQuotes::of(runner);
+                        runner_events(&self.market_id, key, RunnerQuotes::default(), new, events);
+                    }
                     continue;
                 };
+                let old = events.is_some().then(|| RunnerQuotes::of(runner));
 
                 if let Some(ltp) = runner_change.last_traded_price {
                     runner.set_last_price_traded(ltp);
@@ -118,6 +181,12 @@ impl MarketBookCache {
                 if let Some(spl) = runner_change.starting_price_lay {
                     runner.update_starting_price_lay(spl);
                 }
+                if let Some(ref mut events) = events
+                    && let Some(old) = old
+                {
+                    let new = RunnerQuotes::of(runner);
+                    runner_events(&self.market_id, key, old, new, events);
+                }
             }
         }
 
@@ -139,7 +208,9 @@ impl MarketBookCache {
             };
             let hc = runner_definition.handicap;
             let key = (selection_id, hc);
-            let runner = self.runners.get_mut(&key);
+            let runner = Arc::make_mut(&mut self.runners)
+                .get_mut(&key)
+                .map(Arc::make_mut);
             if let Some(runner) = runner {
                 runner.set_definition(runner_definition.clone());
             } else {
@@ -147,7 +218,7 @@ impl MarketBookCache {
             }
         }
 
-        self.market_definition = Some(market_definition);
+        self.market_definition = Some(Arc::from(market_definition));
     }
 
     /// Adds a runner from a change.
@@ -159,7 +230,7 @@ impl MarketBookCache {
         let Ok(runner) = RunnerBookCache::new_from_runner_change(runner_change) else {
             return;
         };
-        self.runners.insert(key, runner);
+        Arc::make_mut(&mut self.runners).insert(key, Arc::new(runner));
     }
 
     /// Adds a runner from a definition.
@@ -171,7 +242,7 @@ impl MarketBookCache {
         let Ok(runner) = RunnerBookCache::new_from_runner_definition(runner_definition) else {
             return;
         };
-        self.runners.insert(key, runner);
+        Arc::make_mut(&mut self.runners).insert(key, Arc::new(runner));
     }
 
     /// Returns the publish time of the market.
@@ -182,14 +253,13 @@ impl MarketBookCache {
 
     /// Returns a reference to the runners in the market.
     #[must_use]
-    pub const fn runners(&self) -> &HashMap<(SelectionId, Option<F64Ord>), RunnerBookCache> {
+    pub fn runners(&self) -> &Runners {
         &self.runners
     }
 
     /// Returns the market definition if it exists.
-    #[expect(clippy::borrowed_box)]
     #[must_use]
-    pub const fn market_definition(&self) -> Option<&Box<MarketDefinition>> {
+    pub const fn market_definition(&self) -> Option<&Arc<MarketDefinition>> {
         self.market_definition.as_ref()
     }
 
@@ -216,6 +286,7 @@ mod tests {
     use betfair_stream_types::response::market_change_message::{
         MarketChangeMessage, StreamRunnerDefinitionStatus,
     };
+    use betfair_stream_types::response::order_change_message::Side;
 
     fn init() -> (MarketId, DateTime<Utc>, MarketBookCache) {
         let market_id = MarketId::new("1.23456789");
@@ -337,7 +408,7 @@ mod tests {
 
         assert_eq!(
             init.market_definition,
-            Some(Box::new(mock_market_definition))
+            Some(Arc::new(mock_market_definition))
         );
     }
 
@@ -522,4 +593,195 @@ mod tests {
             assert_eq!(init.total_matched, Size::new(num!(2.0)));
         }
     }
+
+    fn definition(
+        status: StreamMarketDefinitionStatus,
+        in_play: bool,
+        version: i64,
+        runner_status: StreamRunnerDefinitionStatus,
+    ) -> MarketDefinition {
+        MarketDefinition {
+            status,
+            in_play,
+            version,
+            runners: vec![RunnerDefinition {
+                id: Some(SelectionId(1)),
+                status: Some(runner_status),
+                adjustment_factor: Some(F64Ord(num!(12.5))),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_definition_events() {
+        let (market_id, _, mut init) = init();
+        init.update_market_definition(Box::new(definition(
+            StreamMarketDefinitionStatus::Open,
+            false,
+            1,
+            StreamRunnerDefinitionStatus::Active,
+        )));
+        let mut events = Vec::new();
+
+        let suspended = MarketChange {
+            market_id: Some(market_id.clone()),
+            market_definition: Some(Box::new(definition(
+                StreamMarketDefinitionStatus::Suspended,
+                false,
+                2,
+                StreamRunnerDefinitionStatus::Removed,
+            ))),
+            ..Default::default()
+        };
+        init.update_cache_with_events(suspended, Utc::now(), true, &mut events);
+        let reopened = MarketChange {
+            market_id: Some(market_id.clone()),
+            market_definition: Some(Box::new(definition(
+                StreamMarketDefinitionStatus::Open,
+                true,
+                2,
+                StreamRunnerDefinitionStatus::Removed,
+            ))),
+            ..Default::default()
+        };
+        init.update_cache_with_events(reopened, Utc::now(), true, &mut events);
+
+        assert_eq!(
+            events,
+            vec![
+                MarketEvent::MarketSuspended {
+                    market_id: market_id.clone(),
+                },
+                MarketEvent::MarketDefinitionVersionChanged {
+                    market_id: market_id.clone(),
+                    old: 1,
+                    new: 2,
+                },
+                MarketEvent::RunnerRemoved {
+                    market_id: market_id.clone(),
+                    selection_id: SelectionId(1),
+                    handicap: None,
+                    adjustment_factor: Some(F64Ord(num!(12.5))),
+                },
+                MarketEvent::MarketReopened {
+                    market_id: market_id.clone(),
+                },
+                MarketEvent::WentInPlay { market_id },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_runner_price_events() {
+        let (market_id, _, mut init) = init();
+        let price = |price| Price::new(price).unwrap();
+        let change = |runner_change| MarketChange {
+            market_id: Some(market_id.clone()),
+            runner_change: Some(vec![runner_change]),
+            ..Default::default()
+        };
+        init.update_cache(
+            change(RunnerChange {
+                id: Some(SelectionId(1)),
+                available_to_back: Some(vec![UpdateSet2(price(2.0), Size::new(num!(10)))]),
+                ..Default::default()
+            }),
+            Utc::now(),
+            true,
+        );
+        let mut events = Vec::new();
+
+        init.update_cache_with_events(
+            change(RunnerChange {
+                id: Some(SelectionId(1)),
+                last_traded_price: Some(price(2.0)),
+                available_to_back: Some(vec![UpdateSet2(price(1.99), Size::new(num!(5)))]),
+                available_to_lay: Some(vec![UpdateSet2(price(2.02), Size::new(num!(5)))]),
+                ..Default::default()
+            }),
+            Utc::now(),
+            true,
+            &mut events,
+        );
+        // a change below the best price raises no event
+        init.update_cache_with_events(
+            change(RunnerChange {
+                id: Some(SelectionId(1)),
+                available_to_back: Some(vec![UpdateSet2(price(1.99), Size::zero())]),
+                ..Default::default()
+            }),
+            Utc::now(),
+            true,
+            &mut events,
+        );
+
+        assert_eq!(
+            events,
+            vec![
+                MarketEvent::BestPriceChanged {
+                    market_id: market_id.clone(),
+                    selection_id: SelectionId(1),
+                    handicap: None,
+                    side: Side::Lay,
+                    old: None,
+                    new: Some(price(2.02)),
+                },
+                MarketEvent::LastTradedPriceChanged {
+                    market_id,
+                    selection_id: SelectionId(1),
+                    handicap: None,
+                    old: None,
+                    new: Some(price(2.0)),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_clone_shares_unchanged_runners() {
+        let (market_id, _, mut init) = init();
+        let runner_change = |id, price| RunnerChange {
+            available_to_back: Some(vec![UpdateSet2(
+                Price::new(price).unwrap(),
+                Size::new(num!(10)),
+            )]),
+            id: Some(SelectionId(id)),
+            ..Default::default()
+        };
+        init.update_cache(
+            MarketChange {
+                market_id: Some(market_id.clone()),
+                runner_change: Some(vec![runner_change(1, 2.0), runner_change(2, 3.0)]),
+                ..Default::default()
+            },
+            Utc::now(),
+            true,
+        );
+        let previous = init.clone();
+        assert!(Arc::ptr_eq(&init.runners, &previous.runners));
+
+        init.update_cache(
+            MarketChange {
+                market_id: Some(market_id),
+                runner_change: Some(vec![runner_change(1, 2.02)]),
+                ..Default::default()
+            },
+            Utc::now(),
+            true,
+        );
+
+        let key = |id| (SelectionId(id), None);
+        assert!(Arc::ptr_eq(
+            &init.runners[&key(2)],
+            &previous.runners[&key(2)]
+        ));
+        assert!(!Arc::ptr_eq(
+            &init.runners[&key(1)],
+            &previous.runners[&key(1)]
+        ));
+        assert_eq!(previous.runners[&key(1)].available_to_back().book.len(), 1);
+        assert_eq!(init.runners[&key(1)].available_to_back().book.len(), 2);
+    }
 }
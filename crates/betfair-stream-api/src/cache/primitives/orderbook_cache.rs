@@ -9,6 +9,7 @@ use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
 use super::orderbook_runner_cache::OrderBookRunner;
+use crate::cache::order_events::{OrderEvent, order_events};
 
 /// Represents a cache for order book data.
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
@@ -44,6 +45,38 @@ impl OrderBookCache {
 
     /// Updates the cache with changes from the order market.
     pub fn update_cache(&mut self, change: OrderMarketChange, publish_time: DateTime<Utc>) {
+        self.apply(change, publish_time, None);
+    }
+
+    /// Updates the cache with changes from the order market, appending what happened to the
+    /// orders to `events`.
+    pub fn update_cache_with_events(
+        &mut self,
+        change: OrderMarketChange,
+        publish_time: DateTime<Utc>,
+        events: &mut Vec<OrderEvent>,
+    ) {
+        self.apply(change, publish_time, Some(events));
+    }
+
+    /// Appends the events between `previous` and this cache to `events`, for a market whose
+    /// cache was rebuilt from a new image.
+    pub(crate) fn image_events(&self, previous: &Self, events: &mut Vec<OrderEvent>) {
+        for (key, runner) in &self.runners {
+            let old = previous.runners.get(key);
+            for order in runner.unmatched_orders.values() {
+                let old = old.and_then(|old| old.unmatched_orders.get(&order.id));
+                order_events(&self.market_id, *key, old, order, events);
+            }
+        }
+    }
+
+    fn apply(
+        &mut self,
+        change: OrderMarketChange,
+        publish_time: DateTime<Utc>,
+        mut events: Option<&mut Vec<OrderEvent>>,
+    ) {
         self.publish_time = publish_time;
         self.closed = change.closed.unwrap_or(self.closed);
 
@@ -63,6 +96,13 @@ impl OrderBookCache {
                     runner.update_matched_backs(mb);
                 }
                 if let Some(ref uo) = runner_change.unmatched_orders {
+                    if let Some(ref mut events) = events {
+                        let key = (runner_change.id, runner_change.handicap);
+                        for order in uo {
+                            let old = runner.unmatched_orders.get(&order.id);
+                            order_events(&self.market_id, key, old, order, events);
+                        }
+                    }
                     runner.update_unmatched(uo);
                 }
                 if let Some(ref sm) = runner_change.strategy_matches {
@@ -104,3 +144,73 @@ impl OrderBookCache {
         self.last_change.as_ref()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use betfair_adapter::betfair_types::price::Price;
+    use betfair_adapter::betfair_types::size::Size;
+    use betfair_adapter::betfair_types::types::sports_aping::BetId;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::cache::order_events::OrderEventKind;
+
+    fn change(matched: f64, remaining: f64, cancelled: f64) -> OrderMarketChange {
+        let status = if remaining > 0.0 { "E" } else { "EC" };
+        serde_json::from_str(&format!(
+            r#"{{"id":"1.1","orc":[{{"id":1,"uo":[{{"id":"1","p":2.0,"s":10,"side":"B","status":"{status}","pt":"L","ot":"L","pd":1478717720000,"sm":{matched},"sr":{remaining},"sl":0,"sc":{cancelled},"sv":0,"avp":2.0,"rfo":"order","rfs":"strategy"}}]}}]}}"#
+        ))
+        .unwrap()
+    }
+
+    fn kinds(events: Vec<OrderEvent>) -> Vec<OrderEventKind> {
+        events.into_iter().map(|event| event.kind).collect()
+    }
+
+    #[test]
+    fn test_order_events() {
+        let mut cache = OrderBookCache::new(MarketId::new("1.1"), Utc::now());
+        let mut events = Vec::new();
+
+        cache.update_cache_with_events(change(0.0, 10.0, 0.0), Utc::now(), &mut events);
+        let accepted = events.first().cloned().unwrap();
+        assert_eq!(accepted.bet_id, BetId::new("1"));
+        assert_eq!(accepted.selection_id, SelectionId(1));
+        assert_eq!(accepted.customer_order_ref.as_str(), "order");
+        assert_eq!(kinds(events), vec![OrderEventKind::OrderAccepted]);
+
+        let mut events = Vec::new();
+        cache.update_cache_with_events(change(4.0, 6.0, 0.0), Utc::now(), &mut events);
+        cache.update_cache_with_events(change(4.0, 6.0, 0.0), Utc::now(), &mut events);
+        cache.update_cache_with_events(change(4.0, 0.0, 6.0), Utc::now(), &mut events);
+        assert_eq!(
+            kinds(events),
+            vec![
+                OrderEventKind::PartiallyMatched {
+                    delta_size: Size::new(4.0),
+                    avg_price: Some(Price::new(2.0).unwrap()),
+                },
+                OrderEventKind::Cancelled {
+                    size_cancelled: Size::new(6.0),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_order_fully_matched() {
+        let mut cache = OrderBookCache::new(MarketId::new("1.1"), Utc::now());
+        cache.update_cache(change(4.0, 6.0, 0.0), Utc::now());
+        let mut events = Vec::new();
+
+        cache.update_cache_with_events(change(10.0, 0.0, 0.0), Utc::now(), &mut events);
+
+        assert_eq!(
+            kinds(events),
+            vec![OrderEventKind::FullyMatched {
+                delta_size: Size::new(6.0),
+                avg_price: Some(Price::new(2.0).unwrap()),
+            }]
+        );
+    }
+}
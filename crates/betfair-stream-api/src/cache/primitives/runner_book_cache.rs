@@ -3,7 +3,7 @@
 use betfair_adapter::betfair_types::numeric::F64Ord;
 use betfair_adapter::betfair_types::price::Price;
 use betfair_adapter::betfair_types::size::Size;
-use betfair_adapter::betfair_types::types::sports_aping::SelectionId;
+use betfair_adapter::betfair_types::types::sports_aping::{PriceSize, SelectionId, Side};
 use betfair_stream_types::response::market_change_message::{RunnerChange, RunnerDefinition};
 use betfair_stream_types::response::{UpdateSet2, UpdateSet3};
 use eyre::bail;
@@ -253,11 +253,261 @@ impl RunnerBookCache {
     pub const fn definition(&self) -> Option<&RunnerDefinition> {
         self.definition.as_ref()
     }
+
+    /// The best level of a side.
+    ///
+    /// Like every ladder query, this reads the first of the full (`atb`/`atl`), best
+    /// (`batb`/`batl`) and virtual (`bdatb`/`bdatl`) ladders of the side that has any levels, so
+    /// it works with whichever ladder the market data filter subscribes to.
+    ///
+    /// # Parameters
+    ///
+    /// * `side` - `Back` for the prices available to back, `Lay` for the prices available to lay.
+    ///
+    /// # Returns
+    ///
+    /// The highest price available to back or the lowest price available to lay, with its size.
+    #[must_use]
+    pub fn best(&self, side: Side) -> Option<PriceSize> {
+        self.ladder(side).next()
+    }
+
+    /// The best level available to back, see [`best`](Self::best).
+    #[must_use]
+    pub fn best_back(&self) -> Option<PriceSize> {
+        self.best(Side::Back)
+    }
+
+    /// The best level available to lay, see [`best`](Self::best).
+    #[must_use]
+    pub fn best_lay(&self) -> Option<PriceSize> {
+        self.best(Side::Lay)
+    }
+
+    /// The top levels of a side.
+    ///
+    /// # Parameters
+    ///
+    /// * `side` - The side of the ladder.
+    /// * `depth` - The maximum number of levels.
+    ///
+    /// # Returns
+    ///
+    /// Up to `depth` levels, best price first: descending prices to back, ascending prices to lay.
+    #[must_use]
+    pub fn levels(&self, side: Side, depth: usize) -> Vec<PriceSize> {
+        self.ladder(side).take(depth).collect()
+    }
+
+    /// The size available on a side at a price or better.
+    ///
+    /// # Parameters
+    ///
+    /// * `side` - The side of the ladder.
+    /// * `price` - The worst price to include: the lowest one to back, the highest one to lay.
+    ///
+    /// # Returns
+    ///
+    /// The cumulative size of the levels from the best price up to and including `price`.
+    #[must_use]
+    pub fn size_to_price(&self, side: Side, price: Price) -> Size {
+        self.ladder(side)
+            .take_while(|level| match side {
+                Side::Back => level.price >= price,
+                Side::Lay => level.price <= price,
+            })
+            .fold(Size::zero(), |acc, level| acc.saturating_add(&level.size))
+    }
+
+    /// The average price a stake would be matched at by taking the levels of a side.
+    ///
+    /// # Parameters
+    ///
+    /// * `side` - The side of the ladder, `Back` to fill a back bet from the prices available to
+    ///   back.
+    /// * `stake` - The stake to fill.
+    ///
+    /// # Returns
+    ///
+    /// The volume-weighted average price over the levels needed to fill `stake`, best price
+    /// first; `None` if the ladder cannot fill the whole stake or the stake is not positive.
+    #[must_use]
+    pub fn average_fill_price(&self, side: Side, stake: Size) -> Option<f64> {
+        let stake = stake.as_f64();
+        if stake <= 0.0 {
+            return None;
+        }
+        let mut remaining = stake;
+        let mut cost = 0.0;
+        for level in self.ladder(side) {
+            let matched = remaining.min(level.size.as_f64());
+            cost += matched * level.price.as_f64();
+            remaining -= matched;
+            if remaining <= 0.0 {
+                return Some(cost / stake);
+            }
+        }
+        None
+    }
+
+    /// The number of ticks between the best back and the best lay price.
+    ///
+    /// # Returns
+    ///
+    /// `1` for a market with no gap between the sides, `None` if either side is empty. The spread
+    /// may be zero or negative for crossed virtual ladders.
+    #[must_use]
+    pub fn spread_ticks(&self) -> Option<i64> {
+        let back = self.best_back()?.price.tick_index();
+        let lay = self.best_lay()?.price.tick_index();
+        Some(i64::from(lay) - i64::from(back))
+    }
+
+    /// The midpoint of the best back and the best lay price.
+    ///
+    /// # Returns
+    ///
+    /// `None` if either side is empty.
+    #[must_use]
+    pub fn mid_price(&self) -> Option<f64> {
+        let back = self.best_back()?.price.as_f64();
+        let lay = self.best_lay()?.price.as_f64();
+        Some(f64::midpoint(back, lay))
+    }
+
+    /// The best back and the best lay price weighted by the size on the opposite side.
+    ///
+    /// The price leans towards the side with less size, which is the one more likely to be taken
+    /// next.
+    ///
+    /// # Returns
+    ///
+    /// `None` if either side is empty.
+    #[must_use]
+    pub fn micro_price(&self) -> Option<f64> {
+        let back = self.best_back()?;
+        let lay = self.best_lay()?;
+        let (back_size, lay_size) = (back.size.as_f64(), lay.size.as_f64());
+        Some(
+            back.price
+                .as_f64()
+                .mul_add(lay_size, lay.price.as_f64() * back_size)
+                / (back_size + lay_size),
+        )
+    }
+
+    /// The levels of a side, best price first, from the first of the full, best and virtual
+    /// ladders that has any.
+    fn ladder(&self, side: Side) -> Box<dyn Iterator<Item = PriceSize> + '_> {
+        let (full, best, display) = match side {
+            Side::Back => (
+                &self.available_to_back,
+                &self.best_available_to_back,
+                &self.best_display_available_to_back,
+            ),
+            Side::Lay => (
+                &self.available_to_lay,
+                &self.best_available_to_lay,
+                &self.best_display_available_to_lay,
+            ),
+        };
+        let level = |(&price, &size): (&Price, &Size)| PriceSize { price, size };
+        if !full.book.is_empty() {
+            // the full ladder is keyed by price, the best levels are the highest prices to back
+            return match side {
+                Side::Back => Box::new(full.book.iter().rev().map(level)),
+                Side::Lay => Box::new(full.book.iter().map(level)),
+            };
+        }
+        let positions = if best.book.is_empty() { display } else { best };
+        Box::new(
+            positions
+                .book
+                .values()
+                .map(|&(price, size)| PriceSize { price, size }),
+        )
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn runner(change: &str) -> RunnerBookCache {
+        RunnerBookCache::new_from_runner_change(serde_json::from_str(change).unwrap()).unwrap()
+    }
+
+    fn level(price: f64, size: f64) -> PriceSize {
+        PriceSize {
+            price: Price::new(price).unwrap(),
+            size: Size::new(size),
+        }
+    }
 
     #[test]
     const fn test_update_traded() {}
+
+    #[test]
+    fn queries_the_full_ladder_best_price_first() {
+        let runner =
+            runner(r#"{"id":1,"atb":[[1.9,5],[2.0,10],[1.8,20]],"atl":[[2.1,3],[2.2,7],[2.3,1]]}"#);
+
+        assert_eq!(runner.best_back(), Some(level(2.0, 10.0)));
+        assert_eq!(runner.best_lay(), Some(level(2.1, 3.0)));
+        assert_eq!(
+            runner.levels(Side::Back, 2),
+            vec![level(2.0, 10.0), level(1.9, 5.0)]
+        );
+        assert_eq!(
+            runner.levels(Side::Lay, 5),
+            vec![level(2.1, 3.0), level(2.2, 7.0), level(2.3, 1.0)]
+        );
+        assert_eq!(
+            runner.size_to_price(Side::Back, Price::new(1.9).unwrap()),
+            Size::new(15.0)
+        );
+        assert_eq!(
+            runner.size_to_price(Side::Lay, Price::new(2.2).unwrap()),
+            Size::new(10.0)
+        );
+        assert_eq!(runner.spread_ticks(), Some(5));
+        assert_eq!(runner.mid_price(), Some(2.05));
+        // (2.0 * 3 + 2.1 * 10) / 13
+        assert!((runner.micro_price().unwrap() - 27.0 / 13.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn averages_the_price_to_fill_a_stake() {
+        let runner = runner(r#"{"id":1,"atl":[[2.1,3],[2.2,7]]}"#);
+
+        assert_eq!(
+            runner.average_fill_price(Side::Lay, Size::new(3.0)),
+            Some(2.1)
+        );
+        // (2.1 * 3 + 2.2 * 2) / 5
+        let average = runner
+            .average_fill_price(Side::Lay, Size::new(5.0))
+            .unwrap();
+        assert!((average - 2.14).abs() < 1e-9);
+        assert_eq!(runner.average_fill_price(Side::Lay, Size::new(11.0)), None);
+        assert_eq!(runner.average_fill_price(Side::Back, Size::new(1.0)), None);
+    }
+
+    #[test]
+    fn falls_back_to_the_best_and_virtual_ladders() {
+        let runner =
+            runner(r#"{"id":1,"batb":[[1,1.9,5],[0,2.0,10]],"bdatl":[[0,2.1,3],[1,2.2,7]]}"#);
+
+        assert_eq!(
+            runner.levels(Side::Back, 3),
+            vec![level(2.0, 10.0), level(1.9, 5.0)]
+        );
+        assert_eq!(
+            runner.levels(Side::Lay, 3),
+            vec![level(2.1, 3.0), level(2.2, 7.0)]
+        );
+        assert_eq!(runner.spread_ticks(), Some(5));
+    }
 }
@@ -0,0 +1,607 @@
+//! Shares the single market subscription of a connection between many owners.
+//!
+//! Betfair replaces the whole market subscription with every `MarketSubscriptionMessage`, so
+//! independent components each sending their own subscription overwrite one another. The
+//! [`SubscriptionManager`] tracks what every [`SubscriptionOwner`] is interested in and always
+//! sends the union of all interests as one subscription.
+
+use core::fmt;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use betfair_adapter::betfair_types::types::sports_aping::MarketId;
+use betfair_stream_types::request::RequestMessage;
+use betfair_stream_types::request::market_subscription_message::{
+    Fields, LadderLevel, MarketDataFilter, MarketFilter, MarketSubscriptionMessage,
+};
+use tokio::sync::Mutex;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::error::SendError;
+
+use crate::ack::PendingAcks;
+use crate::{BetfairStreamClient, MessageProcessor, RequestAck};
+
+/// Merges the market interests of many [`SubscriptionOwner`]s into a single subscription.
+///
+/// Cheap to clone; all clones share the same state. Use a single manager per connection and
+/// do not mix it with a [`MarketSubscriber`](super::market_subscriber::MarketSubscriber) on
+/// the same connection, as both send market subscriptions.
+///
+/// Market ids are reference counted: a market stays subscribed until every owner that
+/// subscribed to it has unsubscribed or was dropped. Owners may also register a whole
+/// [`MarketFilter`]. Betfair can only be sent one filter, and a filter matches every combination
+/// of the values of its dimensions, so two filters can only be merged exactly if they differ in
+/// at most one dimension (e.g. only in their market ids, or only in their event types). A change
+/// that would need filters differing in more dimensions (e.g. market ids for one owner and event
+/// types for another, or different event types and countries) is rejected with
+/// [`SubscriptionError::IncompatibleFilters`], as no single filter covers both without
+/// subscribing to markets no owner asked for. Use a separate connection for such owners.
+#[derive(Debug, Clone)]
+pub struct SubscriptionManager {
+    command_sender: Sender<RequestMessage>,
+    pending_acks: Arc<PendingAcks>,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    next_owner_id: u64,
+    owners: HashMap<u64, Interest>,
+    /// The subscription last sent to Betfair.
+    sent: Option<MarketSubscriptionMessage>,
+}
+
+/// What a single owner wants to receive.
+#[derive(Debug, Clone, Default)]
+struct Interest {
+    market_ids: HashMap<MarketId, usize>,
+    filter: Option<MarketFilter>,
+    market_data_fields: Vec<Fields>,
+    ladder_level: Option<LadderLevel>,
+}
+
+impl Interest {
+    fn filter(&self) -> Option<MarketFilter> {
+        if let Some(ref filter) = self.filter {
+            return Some(filter.clone());
+        }
+        if self.market_ids.is_empty() {
+            return None;
+        }
+        let mut market_ids = self.market_ids.keys().cloned().collect::<Vec<_>>();
+        market_ids.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        Some(MarketFilter {
+            market_ids: Some(market_ids),
+            ..MarketFilter::default()
+        })
+    }
+}
+
+impl State {
+    /// The subscription covering the interests of every owner.
+    fn merged(&self) -> Result<MarketSubscriptionMessage, SubscriptionError> {
+        // merge in registration order so that the result does not depend on hashing
+        let mut owners = self.owners.iter().collect::<Vec<_>>();
+        owners.sort_unstable_by_key(|&(&id, _)| id);
+        let owners = owners
+            .into_iter()
+            .map(|(_, interest)| interest)
+            .collect::<Vec<_>>();
+
+        let mut filters = owners.iter().filter_map(|interest| interest.filter());
+        let market_filter = match filters.next() {
+            Some(first) => filters.try_fold(first, widen)?,
+            None => no_markets_filter(),
+        };
+
+        let mut fields = owners
+            .iter()
+            .flat_map(|interest| interest.market_data_fields.iter().copied())
+            .collect::<Vec<_>>();
+        fields.sort_unstable();
+        fields.dedup();
+        let ladder_levels = owners
+            .iter()
+            .filter_map(|interest| interest.ladder_level.clone())
+            .reduce(|deepest, level| if level > deepest { level } else { deepest });
+
+        Ok(MarketSubscriptionMessage {
+            id: None,
+            clk: None,
+            initial_clk: None,
+            segmentation_enabled: Some(true),
+            heartbeat_ms: Some(5000),
+            market_filter: Some(Box::new(market_filter)),
+            conflate_ms: None,
+            market_data_filter: Some(Box::new(MarketDataFilter {
+                ladder_levels,
+                fields: Some(fields),
+            })),
+        })
+    }
+}
+
+/// A filter that matches no market, used when nobody is interested in any market.
+///
+/// Betfair has no unsubscribe operation, so this subscribes to an empty list of market ids.
+pub(crate) fn no_markets_filter() -> MarketFilter {
+    MarketFilter {
+        market_ids: Some(Vec::new()),
+        ..MarketFilter::default()
+    }
+}
+
+/// The filter matching exactly the markets matched by `lhs` or `rhs`.
+///
+/// # Errors
+/// If the filters differ in more than one dimension, as joining the values of every dimension
+/// would then also match combinations that neither filter matches.
+fn widen(lhs: MarketFilter, rhs: MarketFilter) -> Result<MarketFilter, SubscriptionError> {
+    let differences = [
+        !same_values(lhs.country_codes.as_ref(), rhs.country_codes.as_ref()),
+        !same_values(lhs.betting_types.as_ref(), rhs.betting_types.as_ref()),
+        lhs.turn_in_play_enabled != rhs.turn_in_play_enabled,
+        !same_values(lhs.market_types.as_ref(), rhs.market_types.as_ref()),
+        !same_values(lhs.venues.as_ref(), rhs.venues.as_ref()),
+        !same_values(lhs.market_ids.as_ref(), rhs.market_ids.as_ref()),
+        !same_values(lhs.event_type_ids.as_ref(), rhs.event_type_ids.as_ref()),
+        !same_values(lhs.event_ids.as_ref(), rhs.event_ids.as_ref()),
+        lhs.bsp_market != rhs.bsp_market,
+        !same_values(lhs.race_types.as_ref(), rhs.race_types.as_ref()),
+    ];
+    if differences.iter().filter(|&&differs| differs).count() > 1 {
+        return Err(SubscriptionError::IncompatibleFilters);
+    }
+
+    Ok(MarketFilter {
+        country_codes: union(lhs.country_codes, rhs.country_codes),
+        betting_types: union(lhs.betting_types, rhs.betting_types),
+        turn_in_play_enabled: same(lhs.turn_in_play_enabled, rhs.turn_in_play_enabled),
+        market_types: union(lhs.market_types, rhs.market_types),
+        venues: union(lhs.venues, rhs.venues),
+        market_ids: union(lhs.market_ids, rhs.market_ids).map(|mut market_ids| {
+            market_ids.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+            market_ids
+        }),
+        event_type_ids: union(lhs.event_type_ids, rhs.event_type_ids),
+        event_ids: union(lhs.event_ids, rhs.event_ids),
+        bsp_market: same(lhs.bsp_market, rhs.bsp_market),
+        race_types: union(lhs.race_types, rhs.race_types),
+    })
+}
+
+/// Whether both dimensions match the same values, ignoring their order.
+fn same_values<T: PartialEq>(lhs: Option<&Vec<T>>, rhs: Option<&Vec<T>>) -> bool {
+    match (lhs, rhs) {
+        (None, None) => true,
+        (Some(lhs), Some(rhs)) => {
+            lhs.iter().all(|item| rhs.contains(item)) && rhs.iter().all(|item| lhs.contains(item))
+        }
+        _ => false,
+    }
+}
+
+/// `None` (no restriction) wins over any list.
+fn union<T: PartialEq>(lhs: Option<Vec<T>>, rhs: Option<Vec<T>>) -> Option<Vec<T>> {
+    let (mut lhs, rhs) = (lhs?, rhs?);
+    for item in rhs {
+        if !lhs.contains(&item) {
+            lhs.push(item);
+        }
+    }
+    Some(lhs)
+}
+
+fn same(lhs: Option<bool>, rhs: Option<bool>) -> Option<bool> {
+    if lhs == rhs { lhs } else { None }
+}
+
+/// Why the interest of a [`SubscriptionOwner`] could not be changed.
+#[derive(Debug)]
+pub enum SubscriptionError {
+    /// The filter of this owner differs from the filters of the other owners on the same
+    /// connection in more than one dimension. The interest of the owner was left unchanged.
+    IncompatibleFilters,
+    /// The merged subscription could not be sent to the stream.
+    Send(SendError<RequestMessage>),
+}
+
+impl fmt::Display for SubscriptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::IncompatibleFilters => {
+                write!(f, "market filters differ in more than one dimension")
+            }
+            Self::Send(ref err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl core::error::Error for SubscriptionError {}
+
+impl From<SendError<RequestMessage>> for SubscriptionError {
+    fn from(err: SendError<RequestMessage>) -> Self {
+        Self::Send(err)
+    }
+}
+
+impl SubscriptionManager {
+    /// Creates a new `SubscriptionManager` for the given stream.
+    ///
+    /// # Parameters
+    /// - `stream_api_connection`: A reference to the `StreamApi` connection.
+    ///
+    /// # Returns
+    /// A new instance of `SubscriptionManager` without any owners.
+    #[must_use]
+    pub fn new<T: MessageProcessor, S>(stream_api_connection: &BetfairStreamClient<T, S>) -> Self {
+        Self {
+            command_sender: stream_api_connection.send_to_stream.clone(),
+            pending_acks: Arc::clone(&stream_api_connection.pending_acks),
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    /// Registers a new owner without any interest.
+    ///
+    /// # Returns
+    /// A handle through which the owner subscribes to markets. Dropping it releases all of its
+    /// interest.
+    pub async fn owner(&self) -> SubscriptionOwner {
+        let mut state = self.state.lock().await;
+        let id = state.next_owner_id;
+        state.next_owner_id += 1;
+        state.owners.insert(id, Interest::default());
+        SubscriptionOwner {
+            id,
+            manager: self.clone(),
+            released: false,
+        }
+    }
+
+    /// The subscription covering the interests of every owner as it was last sent to Betfair,
+    /// or `None` if nothing was sent yet.
+    pub async fn merged_subscription(&self) -> Option<MarketSubscriptionMessage> {
+        self.state.lock().await.sent.clone()
+    }
+
+    /// Apply `change` to the interest of `owner` and send the merged subscription if it changed.
+    ///
+    /// The change is undone if it cannot be merged with the interests of the other owners. The
+    /// lock is held while sending so that concurrent changes reach Betfair in the order in which
+    /// they were merged.
+    async fn update(
+        &self,
+        owner: u64,
+        change: impl FnOnce(&mut Interest),
+    ) -> Result<Option<RequestAck>, SubscriptionError> {
+        let mut state = self.state.lock().await;
+        let Some(interest) = state.owners.get_mut(&owner) else {
+            return Ok(None);
+        };
+        let previous = interest.clone();
+        change(interest);
+        let merged = match state.merged() {
+            Ok(merged) => merged,
+            Err(err) => {
+                state.owners.insert(owner, previous);
+                return Err(err);
+            }
+        };
+        self.send_if_changed(&mut state, merged).await
+    }
+
+    async fn release(&self, owner: u64) -> Result<Option<RequestAck>, SubscriptionError> {
+        let mut state = self.state.lock().await;
+        state.owners.remove(&owner);
+        // the remaining filters were compatible before and still are
+        let merged = state.merged()?;
+        self.send_if_changed(&mut state, merged).await
+    }
+
+    async fn send_if_changed(
+        &self,
+        state: &mut State,
+        merged: MarketSubscriptionMessage,
+    ) -> Result<Option<RequestAck>, SubscriptionError> {
+        if state.sent.as_ref() == Some(&merged) {
+            return Ok(None);
+        }
+        // nothing has been subscribed yet, so there is nothing to unsubscribe from
+        if state.sent.is_none() && state.owners.values().all(|i| i.filter().is_none()) {
+            return Ok(None);
+        }
+        state.sent = Some(merged.clone());
+        let req = RequestMessage::MarketSubscription(merged);
+        Ok(Some(
+            self.pending_acks.send(&self.command_sender, req).await?,
+        ))
+    }
+}
+
+/// One component's share of a [`SubscriptionManager`].
+///
+/// Every method updates this owner's interest and, if the merged subscription changed as a
+/// result, sends it and returns a [`RequestAck`] for it. `None` means the markets were already
+/// covered by the current subscription and nothing was sent.
+#[derive(Debug)]
+pub struct SubscriptionOwner {
+    id: u64,
+    manager: SubscriptionManager,
+    released: bool,
+}
+
+impl SubscriptionOwner {
+    /// Subscribe to a market using its `MarketId`.
+    ///
+    /// Subscribing to the same market several times requires as many calls to
+    /// [`Self::unsubscribe_from_market`] to release it.
+    ///
+    /// # Parameters
+    /// - `market_id`: The `MarketId` of the market to subscribe to.
+    ///
+    /// # Errors
+    /// If the message cannot be sent to the stream, or another owner has set a filter that does
+    /// not only restrict market ids.
+    pub async fn subscribe_to_market(
+        &self,
+        market_id: MarketId,
+    ) -> Result<Option<RequestAck>, SubscriptionError> {
+        self.manager
+            .update(self.id, |interest| {
+                *interest.market_ids.entry(market_id).or_default() += 1;
+            })
+            .await
+    }
+
+    /// Release one subscription of this owner to a market.
+    ///
+    /// The market is only removed from the merged subscription once no owner is subscribed to
+    /// it anymore.
+    ///
+    /// # Parameters
+    /// - `market_id`: A reference to the `MarketId` of the market to unsubscribe from.
+    ///
+    /// # Errors
+    /// If the message cannot be sent to the stream.
+    pub async fn unsubscribe_from_market(
+        &self,
+        market_id: &MarketId,
+    ) -> Result<Option<RequestAck>, SubscriptionError> {
+        self.manager
+            .update(self.id, |interest| {
+                if let Some(count) = interest.market_ids.get_mut(market_id) {
+                    *count = count.saturating_sub(1);
+                    if *count == 0 {
+                        interest.market_ids.remove(market_id);
+                    }
+                }
+            })
+            .await
+    }
+
+    /// Set a filter for this owner, replacing its market ids in the merged subscription.
+    ///
+    /// # Parameters
+    /// - `filter`: The `MarketFilter` to apply, or `None` to go back to the subscribed market
+    ///   ids.
+    ///
+    /// # Errors
+    /// If the request to change the subscription fails, or the filter differs from the filters
+    /// of the other owners in more than one dimension.
+    pub async fn set_filter(
+        &self,
+        filter: Option<MarketFilter>,
+    ) -> Result<Option<RequestAck>, SubscriptionError> {
+        self.manager
+            .update(self.id, |interest| interest.filter = filter)
+            .await
+    }
+
+    /// Set the market data fields this owner needs.
+    ///
+    /// # Parameters
+    /// - `market_data_fields`: A vector of `Fields` to set.
+    ///
+    /// # Errors
+    /// If the request to change the subscription fails.
+    pub async fn set_market_data_fields(
+        &self,
+        market_data_fields: Vec<Fields>,
+    ) -> Result<Option<RequestAck>, SubscriptionError> {
+        self.manager
+            .update(self.id, |interest| {
+                interest.market_data_fields = market_data_fields;
+            })
+            .await
+    }
+
+    /// Set the ladder level this owner needs; the deepest level of all owners is subscribed.
+    ///
+    /// # Parameters
+    /// - `ladder_level`: An optional `LadderLevel` to set.
+    ///
+    /// # Errors
+    /// If the request to change the subscription fails.
+    pub async fn set_ladder_level(
+        &self,
+        ladder_level: Option<LadderLevel>,
+    ) -> Result<Option<RequestAck>, SubscriptionError> {
+        self.manager
+            .update(self.id, |interest| interest.ladder_level = ladder_level)
+            .await
+    }
+
+    /// Release all interest of this owner.
+    ///
+    /// Dropping the owner does the same in a background task; call this to wait for the
+    /// updated subscription to be sent.
+    ///
+    /// # Errors
+    /// If the request to change the subscription fails.
+    pub async fn release(mut self) -> Result<Option<RequestAck>, SubscriptionError> {
+        self.released = true;
+        self.manager.release(self.id).await
+    }
+}
+
+impl Drop for SubscriptionOwner {
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            tracing::warn!(
+                owner = self.id,
+                "subscription owner dropped outside of a runtime"
+            );
+            return;
+        };
+        let manager = self.manager.clone();
+        let id = self.id;
+        runtime.spawn(async move {
+            if let Err(err) = manager.release(id).await {
+                tracing::warn!(?err, "could not release subscription owner");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use betfair_adapter::betfair_types::types::sports_aping::{CountryCode, EventTypeId};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn market_ids(message: &MarketSubscriptionMessage) -> Option<Vec<String>> {
+        message
+            .market_filter
+            .as_ref()
+            .and_then(|filter| filter.market_ids.as_ref())
+            .map(|ids| ids.iter().map(|id| id.0.to_string()).collect())
+    }
+
+    fn owner(state: &mut State, market_ids: &[&str]) -> u64 {
+        let id = state.next_owner_id;
+        state.next_owner_id += 1;
+        let interest = Interest {
+            market_ids: market_ids
+                .iter()
+                .map(|&market_id| (MarketId::new(market_id), 1))
+                .collect(),
+            ..Interest::default()
+        };
+        state.owners.insert(id, interest);
+        id
+    }
+
+    #[test]
+    fn merges_market_ids_of_all_owners() {
+        let mut state = State::default();
+        owner(&mut state, &["1.2", "1.1"]);
+        let second = owner(&mut state, &["1.1", "1.3"]);
+
+        assert_eq!(
+            market_ids(&state.merged().unwrap()),
+            Some(vec!["1.1".to_owned(), "1.2".to_owned(), "1.3".to_owned()])
+        );
+
+        state.owners.remove(&second);
+        assert_eq!(
+            market_ids(&state.merged().unwrap()),
+            Some(vec!["1.1".to_owned(), "1.2".to_owned()])
+        );
+    }
+
+    #[test]
+    fn no_interest_subscribes_to_no_markets() {
+        let state = State::default();
+
+        assert_eq!(market_ids(&state.merged().unwrap()), Some(Vec::new()));
+    }
+
+    fn event_type(id: &str) -> EventTypeId {
+        EventTypeId(Arc::new(id.to_owned()))
+    }
+
+    fn country(code: &str) -> CountryCode {
+        CountryCode(Arc::new(code.to_owned()))
+    }
+
+    #[test]
+    fn filters_restricting_the_same_dimensions_are_joined() {
+        let mut state = State::default();
+        owner(&mut state, &[]);
+        state.owners.get_mut(&0).unwrap().filter = Some(MarketFilter {
+            event_type_ids: Some(vec![event_type("7")]),
+            bsp_market: Some(true),
+            ..MarketFilter::default()
+        });
+        owner(&mut state, &[]);
+        state.owners.get_mut(&1).unwrap().filter = Some(MarketFilter {
+            event_type_ids: Some(vec![event_type("4339")]),
+            bsp_market: Some(true),
+            ..MarketFilter::default()
+        });
+
+        let merged = state.merged().unwrap().market_filter.unwrap();
+        assert_eq!(
+            merged.event_type_ids,
+            Some(vec![event_type("7"), event_type("4339")])
+        );
+        assert_eq!(merged.bsp_market, Some(true));
+        assert_eq!(merged.market_ids, None);
+    }
+
+    #[test]
+    fn filters_differing_in_several_dimensions_are_rejected() {
+        let mut state = State::default();
+        owner(&mut state, &[]);
+        state.owners.get_mut(&0).unwrap().filter = Some(MarketFilter {
+            event_type_ids: Some(vec![event_type("7")]),
+            country_codes: Some(vec![country("GB")]),
+            ..MarketFilter::default()
+        });
+        owner(&mut state, &[]);
+        state.owners.get_mut(&1).unwrap().filter = Some(MarketFilter {
+            event_type_ids: Some(vec![event_type("4339")]),
+            country_codes: Some(vec![country("IE")]),
+            ..MarketFilter::default()
+        });
+
+        // joining both dimensions would also match 7/IE and 4339/GB
+        assert!(matches!(
+            state.merged(),
+            Err(SubscriptionError::IncompatibleFilters)
+        ));
+
+        state.owners.get_mut(&1).unwrap().filter = Some(MarketFilter {
+            event_type_ids: Some(vec![event_type("4339")]),
+            country_codes: Some(vec![country("GB")]),
+            ..MarketFilter::default()
+        });
+        let merged = state.merged().unwrap().market_filter.unwrap();
+        assert_eq!(
+            merged.event_type_ids,
+            Some(vec![event_type("7"), event_type("4339")])
+        );
+        assert_eq!(merged.country_codes, Some(vec![country("GB")]));
+    }
+
+    #[test]
+    fn filters_restricting_different_dimensions_are_rejected() {
+        let mut state = State::default();
+        owner(&mut state, &["1.1"]);
+        owner(&mut state, &[]);
+        state.owners.get_mut(&1).unwrap().filter = Some(MarketFilter {
+            event_type_ids: Some(vec![event_type("7")]),
+            ..MarketFilter::default()
+        });
+
+        assert!(matches!(
+            state.merged(),
+            Err(SubscriptionError::IncompatibleFilters)
+        ));
+    }
+}
@@ -3,13 +3,16 @@ use std::collections::HashMap;
 use betfair_adapter::betfair_types::types::sports_aping::MarketId;
 use betfair_stream_types::response::market_change_message::MarketChangeMessage;
 
-use super::HasFullImage;
+use super::validator::Validator;
+use super::{HasFullImage, PendingSegments};
+use crate::cache::market_events::MarketEvent;
 use crate::cache::primitives::MarketBookCache;
 
 #[derive(Debug, Clone)]
 pub struct MarketStreamTracker {
     market_state: HashMap<MarketId, MarketBookCache>,
     updates_processed: u64,
+    pending: PendingSegments,
 }
 
 impl MarketStreamTracker {
@@ -17,12 +20,23 @@ impl MarketStreamTracker {
         Self {
             market_state: HashMap::new(),
             updates_processed: 0,
+            pending: PendingSegments::default(),
         }
     }
 
+    /// Apply `msg` to the caches.
+    ///
+    /// The updated caches are only returned when `emit` is set, otherwise they are remembered
+    /// and returned together with the updates of a later call, so that a segmented message is
+    /// delivered as a single batch. Every market the change is applied to is checked by
+    /// `validator`, and what changed in markets that already had an image is appended to
+    /// `events`.
     pub(crate) fn process(
         &mut self,
         msg: MarketChangeMessage,
+        emit: bool,
+        mut validator: Option<&mut Validator>,
+        mut events: Option<&mut Vec<MarketEvent>>,
     ) -> (Option<Vec<&MarketBookCache>>, HasFullImage) {
         let mut img = HasFullImage(false);
         let Some(publish_time) = msg.publish_time else {
@@ -30,43 +44,62 @@ impl MarketStreamTracker {
             return (None, img);
         };
 
+        let has_data = msg.0.data.is_some();
         if let Some(data) = msg.0.data {
-            let mut updated_caches: Vec<&MarketBookCache> = Vec::with_capacity(data.len());
-            let mut market_ids = Vec::with_capacity(data.len());
             for market_change in data {
                 let Some(market_id) = market_change.market_id.clone() else {
                     continue;
                 };
 
+                let mut known = true;
                 let market = self
                     .market_state
                     .entry(market_id.clone())
                     .or_insert_with(|| {
                         img = HasFullImage(true);
+                        known = false;
                         MarketBookCache::new(market_id.clone(), publish_time)
                     });
 
                 let full_image = market_change.full_image.unwrap_or(false);
                 if full_image {
                     img = HasFullImage(true);
-                    *market = MarketBookCache::new(market_id.clone(), publish_time);
+                    let previous = core::mem::replace(
+                        market,
+                        MarketBookCache::new(market_id.clone(), publish_time),
+                    );
+                    market.update_cache(market_change, publish_time, true);
+                    if known && let Some(ref mut events) = events {
+                        market.image_events(&previous, events);
+                    }
+                } else if known && let Some(ref mut events) = events {
+                    market.update_cache_with_events(market_change, publish_time, true, events);
+                } else {
+                    market.update_cache(market_change, publish_time, true);
                 }
-                market.update_cache(market_change, publish_time, true);
-                market_ids.push(market_id);
+                if let Some(ref mut validator) = validator {
+                    validator.check_market(market);
+                }
+                self.pending.push(market_id);
             }
+        }
 
-            for market_id in market_ids {
-                let market = self.market_state.get(&market_id);
-                let Some(market) = market else {
-                    continue;
-                };
+        if !emit || (!has_data && !self.pending.has_updates()) {
+            return (None, img);
+        }
 
-                updated_caches.push(market);
-                self.updates_processed = self.updates_processed.saturating_add(1);
-            }
-            return (Some(updated_caches), img);
-        };
-        (None, img)
+        let market_ids = self.pending.take();
+        let mut updated_caches = Vec::with_capacity(market_ids.len());
+        for market_id in market_ids {
+            let market = self.market_state.get(&market_id);
+            let Some(market) = market else {
+                continue;
+            };
+
+            updated_caches.push(market);
+            self.updates_processed = self.updates_processed.saturating_add(1);
+        }
+        (Some(updated_caches), img)
     }
 
     pub(crate) fn clear_stale_cache(&mut self, publish_time: chrono::DateTime<chrono::Utc>) {
@@ -77,6 +110,15 @@ impl MarketStreamTracker {
         });
     }
 
+    /// Seed the cache of a market, replacing whatever was cached for it.
+    pub(crate) fn insert(&mut self, market: MarketBookCache) {
+        self.market_state.insert(market.market_id().clone(), market);
+    }
+
+    pub(crate) fn market(&self, market_id: &MarketId) -> Option<&MarketBookCache> {
+        self.market_state.get(market_id)
+    }
+
     pub fn states(&self) -> Vec<&MarketBookCache> {
         self.market_state.values().collect()
     }
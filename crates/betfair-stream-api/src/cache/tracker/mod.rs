@@ -1,15 +1,22 @@
 mod market_stream_tracker;
 mod order_stream_tracker;
+pub mod validator;
 
+use std::collections::HashSet;
+
+use betfair_adapter::betfair_types::types::sports_aping::MarketId;
 use betfair_stream_types::response::market_change_message::MarketChangeMessage;
 use betfair_stream_types::response::order_change_message::OrderChangeMessage;
 use betfair_stream_types::response::{
-    ChangeType, Clock, DataChange, DatasetChangeMessage, InitialClock,
+    ChangeType, Clock, DataChange, DatasetChangeMessage, InitialClock, SegmentType,
 };
 use serde::de::DeserializeOwned;
 
 use self::market_stream_tracker::MarketStreamTracker;
 use self::order_stream_tracker::OrderStreamTracker;
+use self::validator::Validator;
+use super::market_events::MarketEvent;
+use super::order_events::OrderEvent;
 use super::primitives::{MarketBookCache, OrderBookCache};
 
 /// Separate stream struct to hold market/order caches
@@ -17,6 +24,8 @@ use super::primitives::{MarketBookCache, OrderBookCache};
 pub struct StreamState {
     pub stream_id: Option<u64>,
     pub update_clk: Option<Clock>,
+    /// Log a warning for changes received later than this after they were published. See
+    /// [`AlarmThresholds`](crate::AlarmThresholds) for alarms delivered to the output.
     pub max_latency_ms: Option<u64>,
     pub unique_id: Option<i32>,
     pub initial_clock: Option<InitialClock>,
@@ -24,6 +33,52 @@ pub struct StreamState {
     pub time_updated: chrono::DateTime<chrono::Utc>,
     pub market_stream_tracker: MarketStreamTracker,
     pub order_stream_tracker: OrderStreamTracker,
+    pub segmentation: SegmentationMode,
+    /// Checks applied changes for data-quality issues, if enabled
+    pub validator: Option<Validator>,
+    /// Events derived from applied market changes not yet taken, `None` unless enabled
+    pub market_events: Option<Vec<MarketEvent>>,
+    /// Events derived from applied order changes not yet taken, `None` unless enabled
+    pub order_events: Option<Vec<OrderEvent>>,
+}
+
+/// How messages split into segments (`segmentationEnabled` on the subscription) are delivered.
+///
+/// Betfair splits large messages, typically the initial image of a big subscription, into a
+/// `SEG_START`, any number of `SEG` and a `SEG_END` message.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SegmentationMode {
+    /// Apply every segment to the cache but only return the updated caches once `SEG_END` has
+    /// been received, as one consistent batch.
+    #[default]
+    Reassemble,
+    /// Return the caches updated by every segment as soon as it is applied. Lower latency, but
+    /// consumers may see a partially built image.
+    PerSegment,
+}
+
+/// Markets updated by the segments of a message received so far, in order of first update.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PendingSegments {
+    market_ids: Vec<MarketId>,
+    seen: HashSet<MarketId>,
+}
+
+impl PendingSegments {
+    pub(crate) fn push(&mut self, market_id: MarketId) {
+        if self.seen.insert(market_id.clone()) {
+            self.market_ids.push(market_id);
+        }
+    }
+
+    pub(crate) fn has_updates(&self) -> bool {
+        !self.market_ids.is_empty()
+    }
+
+    pub(crate) fn take(&mut self) -> Vec<MarketId> {
+        self.seen.clear();
+        core::mem::take(&mut self.market_ids)
+    }
 }
 
 pub enum Updates<'a> {
@@ -52,14 +107,92 @@ impl StreamState {
             time_updated: chrono::Utc::now(),
             market_stream_tracker: MarketStreamTracker::new(),
             order_stream_tracker: OrderStreamTracker::new(),
+            segmentation: SegmentationMode::default(),
+            validator: None,
+            market_events: None,
+            order_events: None,
+        }
+    }
+
+    /// Sets how segmented messages are delivered.
+    #[must_use]
+    pub const fn with_segmentation(mut self, segmentation: SegmentationMode) -> Self {
+        self.segmentation = segmentation;
+        self
+    }
+
+    /// Checks every applied change with `validator`.
+    #[must_use]
+    pub fn with_validator(mut self, validator: Validator) -> Self {
+        self.validator = Some(validator);
+        self
+    }
+
+    /// Seeds the cache of a market, e.g. one built with [`MarketBookCache::from_market_book`].
+    ///
+    /// The stream advances it like any other cached market; as the market is already known,
+    /// events are derived from its first stream image as well.
+    #[must_use]
+    pub fn with_market(mut self, market: MarketBookCache) -> Self {
+        self.market_stream_tracker.insert(market);
+        self
+    }
+
+    /// Derives [`MarketEvent`]s from every applied market change.
+    #[must_use]
+    pub fn with_market_events(mut self) -> Self {
+        self.market_events = Some(Vec::new());
+        self
+    }
+
+    /// The market events derived since the last call.
+    pub(crate) fn take_market_events(&mut self) -> Vec<MarketEvent> {
+        self.market_events
+            .as_mut()
+            .map(core::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Derives [`OrderEvent`]s from every applied order change.
+    #[must_use]
+    pub fn with_order_events(mut self) -> Self {
+        self.order_events = Some(Vec::new());
+        self
+    }
+
+    /// The order events derived since the last call.
+    pub(crate) fn take_order_events(&mut self) -> Vec<OrderEvent> {
+        self.order_events
+            .as_mut()
+            .map(core::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Whether the validator asked for fresh images since the last call.
+    pub(crate) fn take_resubscribe(&mut self) -> bool {
+        self.validator
+            .as_mut()
+            .is_some_and(Validator::take_resubscribe)
+    }
+
+    /// Tell the validator that the images it asked for will not arrive.
+    pub(crate) fn cancel_resubscribe(&mut self) {
+        if let Some(ref mut validator) = self.validator {
+            validator.cancel_resubscribe();
         }
     }
 
     pub fn order_change_update(&mut self, msg: OrderChangeMessage) -> Option<Vec<&OrderBookCache>> {
+        if let Some(ref mut validator) = self.validator {
+            validator.check_message(&msg);
+        }
         match msg.change_type {
             Some(ChangeType::SubImage) => {
                 self.update_clk(&msg);
-                self.order_stream_tracker.process(msg).0
+                let emit = self.emit_segment(&msg);
+                self.order_stream_tracker
+                    .process(msg, emit, self.order_events.as_mut())
+                    .0
             }
             Some(ChangeType::Heartbeat) => {
                 self.update_clk(&msg);
@@ -68,7 +201,10 @@ impl StreamState {
             }
             None | Some(ChangeType::ResubDelta) => {
                 self.on_update(&msg);
-                self.order_stream_tracker.process(msg).0
+                let emit = self.emit_segment(&msg);
+                self.order_stream_tracker
+                    .process(msg, emit, self.order_events.as_mut())
+                    .0
             }
         }
     }
@@ -77,10 +213,22 @@ impl StreamState {
         &mut self,
         msg: MarketChangeMessage,
     ) -> Option<Vec<&MarketBookCache>> {
+        if let Some(ref mut validator) = self.validator {
+            validator.check_message(&msg);
+            validator.check_market_delta(&msg, &self.market_stream_tracker);
+        }
         match msg.change_type {
             Some(ChangeType::SubImage) => {
                 self.update_clk(&msg);
-                self.market_stream_tracker.process(msg).0
+                let emit = self.emit_segment(&msg);
+                self.market_stream_tracker
+                    .process(
+                        msg,
+                        emit,
+                        self.validator.as_mut(),
+                        self.market_events.as_mut(),
+                    )
+                    .0
             }
             Some(ChangeType::Heartbeat) => {
                 self.update_clk(&msg);
@@ -89,7 +237,15 @@ impl StreamState {
             }
             None | Some(ChangeType::ResubDelta) => {
                 self.on_update(&msg);
-                self.market_stream_tracker.process(msg).0
+                let emit = self.emit_segment(&msg);
+                self.market_stream_tracker
+                    .process(
+                        msg,
+                        emit,
+                        self.validator.as_mut(),
+                        self.market_events.as_mut(),
+                    )
+                    .0
             }
         }
     }
@@ -111,6 +267,19 @@ impl StreamState {
         }
     }
 
+    /// Whether the caches updated by `msg` (and by the preceding segments) should be returned.
+    fn emit_segment<T: DeserializeOwned + DataChange<T>>(
+        &self,
+        msg: &DatasetChangeMessage<T>,
+    ) -> bool {
+        match self.segmentation {
+            SegmentationMode::PerSegment => true,
+            SegmentationMode::Reassemble => {
+                matches!(msg.segment_type, None | Some(SegmentType::SegEnd))
+            }
+        }
+    }
+
     fn on_heartbeat<T: DeserializeOwned + DataChange<T>>(&mut self, msg: &DatasetChangeMessage<T>) {
         self.update_clk(msg);
     }
@@ -133,3 +302,92 @@ impl StreamState {
         self.time_updated = chrono::Utc::now();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::cache::order_events::OrderEventKind;
+
+    fn market_change(segment_type: Option<&str>, market_id: &str) -> MarketChangeMessage {
+        let segment_type =
+            segment_type.map_or_else(String::new, |seg| format!(r#""segmentType":"{seg}","#));
+        let data = format!(
+            r#"{{"op":"mcm","id":1,"clk":"AAAAAAAA","pt":1478717720756,{segment_type}"ct":"SUB_IMAGE","mc":[{{"id":"{market_id}","tv":1.0}}]}}"#
+        );
+        serde_json::from_str(&data).unwrap()
+    }
+
+    fn order_change(full_image: bool) -> OrderChangeMessage {
+        let data = format!(
+            r#"{{"op":"ocm","id":1,"clk":"AAAAAAAA","pt":1478717720756,"oc":[{{"id":"1.1","fullImage":{full_image},"orc":[{{"id":1,"uo":[{{"id":"1","p":2.0,"s":10,"side":"B","status":"E","pt":"L","ot":"L","pd":1478717720000,"sm":0,"sr":10,"sl":0,"sc":0,"sv":0}}]}}]}}]}}"#
+        );
+        serde_json::from_str(&data).unwrap()
+    }
+
+    fn market_ids(updates: Option<Vec<&MarketBookCache>>) -> Option<Vec<String>> {
+        updates.map(|caches| {
+            caches
+                .into_iter()
+                .map(|cache| cache.market_id().0.to_string())
+                .collect()
+        })
+    }
+
+    #[test]
+    fn reassembles_segments_into_a_single_batch() {
+        let mut state = StreamState::new();
+
+        let updates = state.market_change_update(market_change(Some("SEG_START"), "1.1"));
+        assert_eq!(market_ids(updates), None);
+        let updates = state.market_change_update(market_change(Some("SEG"), "1.2"));
+        assert_eq!(market_ids(updates), None);
+        let updates = state.market_change_update(market_change(Some("SEG_END"), "1.1"));
+        assert_eq!(
+            market_ids(updates),
+            Some(vec!["1.1".to_owned(), "1.2".to_owned()])
+        );
+    }
+
+    #[test]
+    fn per_segment_mode_emits_every_segment() {
+        let mut state = StreamState::new().with_segmentation(SegmentationMode::PerSegment);
+
+        let updates = state.market_change_update(market_change(Some("SEG_START"), "1.1"));
+        assert_eq!(market_ids(updates), Some(vec!["1.1".to_owned()]));
+        let updates = state.market_change_update(market_change(Some("SEG_END"), "1.2"));
+        assert_eq!(market_ids(updates), Some(vec!["1.2".to_owned()]));
+    }
+
+    #[test]
+    fn unsegmented_messages_are_emitted_immediately() {
+        let mut state = StreamState::new();
+
+        let updates = state.market_change_update(market_change(None, "1.1"));
+        assert_eq!(market_ids(updates), Some(vec!["1.1".to_owned()]));
+    }
+
+    #[test]
+    fn first_order_on_a_new_market_is_accepted() {
+        let mut state = StreamState::new().with_order_events();
+
+        state.order_change_update(order_change(false));
+
+        let kinds = state
+            .take_order_events()
+            .into_iter()
+            .map(|event| event.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, vec![OrderEventKind::OrderAccepted]);
+    }
+
+    #[test]
+    fn first_image_of_a_market_has_no_order_events() {
+        let mut state = StreamState::new().with_order_events();
+
+        state.order_change_update(order_change(true));
+
+        assert_eq!(state.take_order_events(), Vec::new());
+    }
+}
@@ -3,13 +3,15 @@ use std::collections::HashMap;
 use betfair_adapter::betfair_types::types::sports_aping::MarketId;
 use betfair_stream_types::response::order_change_message::OrderChangeMessage;
 
-use super::HasFullImage;
+use super::{HasFullImage, PendingSegments};
+use crate::cache::order_events::OrderEvent;
 use crate::cache::primitives::OrderBookCache;
 
 #[derive(Debug, Clone)]
 pub struct OrderStreamTracker {
     market_state: HashMap<MarketId, OrderBookCache>,
     updates_processed: u64,
+    pending: PendingSegments,
 }
 
 impl OrderStreamTracker {
@@ -17,12 +19,21 @@ impl OrderStreamTracker {
         Self {
             market_state: HashMap::new(),
             updates_processed: 0,
+            pending: PendingSegments::default(),
         }
     }
 
+    /// Apply `msg` to the caches.
+    ///
+    /// The updated caches are only returned when `emit` is set, otherwise they are remembered
+    /// and returned together with the updates of a later call, so that a segmented message is
+    /// delivered as a single batch. What happened to the orders is appended to `events`, except
+    /// for the first full image of a market, which only describes orders placed before.
     pub(crate) fn process(
         &mut self,
         msg: OrderChangeMessage,
+        emit: bool,
+        mut events: Option<&mut Vec<OrderEvent>>,
     ) -> (Option<Vec<&OrderBookCache>>, HasFullImage) {
         let mut img = HasFullImage(false);
         let Some(publish_time) = msg.publish_time else {
@@ -30,40 +41,57 @@ impl OrderStreamTracker {
             return (None, img);
         };
 
+        let has_data = msg.0.data.is_some();
         if let Some(data) = msg.0.data {
-            let mut updated_caches = Vec::with_capacity(data.len());
-            let mut market_ids = Vec::with_capacity(data.len());
             for market_change in data {
                 let market_id = market_change.market_id.clone();
+                let mut known = true;
                 let market = self
                     .market_state
                     .entry(market_id.clone())
                     .or_insert_with(|| {
                         img = HasFullImage(true);
+                        known = false;
                         OrderBookCache::new(market_id.clone(), publish_time)
                     });
 
                 let full_image = market_change.full_image.unwrap_or(false);
                 if full_image {
                     img = HasFullImage(true);
-                    *market = OrderBookCache::new(market_id.clone(), publish_time);
+                    let previous = core::mem::replace(
+                        market,
+                        OrderBookCache::new(market_id.clone(), publish_time),
+                    );
+                    market.update_cache(market_change, publish_time);
+                    if known && let Some(ref mut events) = events {
+                        market.image_events(&previous, events);
+                    }
+                } else if let Some(ref mut events) = events {
+                    // a delta for a new market is the first order placed on it
+                    market.update_cache_with_events(market_change, publish_time, events);
+                } else {
+                    market.update_cache(market_change, publish_time);
                 }
-                market.update_cache(market_change, publish_time);
-                market_ids.push(market_id);
+                self.pending.push(market_id);
             }
+        }
 
-            for market_id in market_ids {
-                let market = self.market_state.get(&market_id);
-                let Some(market) = market else {
-                    continue;
-                };
+        if !emit || (!has_data && !self.pending.has_updates()) {
+            return (None, img);
+        }
 
-                updated_caches.push(market);
-                self.updates_processed = self.updates_processed.saturating_add(1);
-            }
-            return (Some(updated_caches), img);
+        let market_ids = self.pending.take();
+        let mut updated_caches = Vec::with_capacity(market_ids.len());
+        for market_id in market_ids {
+            let market = self.market_state.get(&market_id);
+            let Some(market) = market else {
+                continue;
+            };
+
+            updated_caches.push(market);
+            self.updates_processed = self.updates_processed.saturating_add(1);
         }
-        (None, img)
+        (Some(updated_caches), img)
     }
 
     pub(crate) fn clear_stale_cache(&mut self, publish_time: chrono::DateTime<chrono::Utc>) {
@@ -0,0 +1,428 @@
+//! Data-quality checks on the changes applied to the caches.
+//!
+//! A single dropped or reordered message silently corrupts a ladder: the deltas that follow are
+//! applied on top of the wrong state and nothing looks out of place. The [`Validator`] checks
+//! every change against the current caches and reports what does not add up, so that consumers
+//! can stop trusting a market and, optionally, fresh images are requested.
+
+use std::collections::HashMap;
+
+use betfair_adapter::betfair_types::numeric::F64Ord;
+use betfair_adapter::betfair_types::price::Price;
+use betfair_adapter::betfair_types::types::sports_aping::{MarketId, SelectionId};
+use betfair_stream_types::response::market_change_message::MarketChangeMessage;
+use betfair_stream_types::response::{ChangeType, DataChange, DatasetChangeMessage};
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use tokio::sync::broadcast;
+
+use super::market_stream_tracker::MarketStreamTracker;
+use crate::cache::primitives::MarketBookCache;
+use crate::cache::primitives::runner_book_cache::RunnerBookCache;
+
+/// Capacity of the issue channel; slow receivers skip the oldest issues.
+const ISSUE_CHANNEL_CAPACITY: usize = 256;
+
+/// An anomaly found in the stream data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DataQualityIssue {
+    /// A change was published before the previously received one of the same stream, i.e.
+    /// messages arrived out of order.
+    PublishTimeRegression {
+        /// The publish time of the previous change.
+        previous: DateTime<Utc>,
+        /// The publish time of the change that went backwards.
+        received: DateTime<Utc>,
+    },
+    /// A change carrying data had no `clk`, so the subscription cannot be resumed from it.
+    MissingClock {
+        /// The publish time of the change.
+        publish_time: Option<DateTime<Utc>>,
+    },
+    /// A delta arrived for a market that never received a full image.
+    DeltaWithoutImage {
+        /// The market the delta was for.
+        market_id: MarketId,
+    },
+    /// A delta changed a runner that is neither in the image nor in the market definition.
+    RunnerWithoutImage {
+        /// The market of the runner.
+        market_id: MarketId,
+        /// The selection id of the runner.
+        selection_id: SelectionId,
+        /// The handicap of the runner.
+        handicap: Option<F64Ord>,
+    },
+    /// After applying a change the best back price of a runner is at or above its best lay
+    /// price.
+    CrossedBook {
+        /// The market of the runner.
+        market_id: MarketId,
+        /// The selection id of the runner.
+        selection_id: SelectionId,
+        /// The handicap of the runner.
+        handicap: Option<F64Ord>,
+        /// The best price available to back.
+        best_back: Price,
+        /// The best price available to lay.
+        best_lay: Price,
+    },
+}
+
+impl DataQualityIssue {
+    /// The market the issue was found in, if it concerns a single market.
+    #[must_use]
+    pub const fn market_id(&self) -> Option<&MarketId> {
+        match *self {
+            Self::PublishTimeRegression { .. } | Self::MissingClock { .. } => None,
+            Self::DeltaWithoutImage { ref market_id }
+            | Self::RunnerWithoutImage { ref market_id, .. }
+            | Self::CrossedBook { ref market_id, .. } => Some(market_id),
+        }
+    }
+}
+
+/// Checks the changes applied by [`StreamState`](super::StreamState) and reports
+/// [`DataQualityIssue`]s.
+///
+/// Attach it with [`Cache::with_validator`](crate::Cache::with_validator); clones report to the
+/// same receivers.
+#[derive(Debug, Clone)]
+pub struct Validator {
+    issues: broadcast::Sender<DataQualityIssue>,
+    /// Request fresh images when a market turns out to be inconsistent.
+    resubscribe: bool,
+    /// The publish time of the last change of every stream, keyed by [`DataChange::key`]; the
+    /// market and order streams are clocked separately.
+    last_publish_time: HashMap<&'static str, DateTime<Utc>>,
+    /// A resubscribe was requested but not yet picked up by the stream task.
+    resubscribe_pending: bool,
+    /// A resubscribe was sent and its image has not arrived yet.
+    awaiting_image: bool,
+}
+
+impl Validator {
+    /// Creates a new `Validator` that only reports issues.
+    #[must_use]
+    pub fn new() -> Self {
+        let (issues, _) = broadcast::channel(ISSUE_CHANNEL_CAPACITY);
+        Self {
+            issues,
+            resubscribe: false,
+            last_publish_time: HashMap::new(),
+            resubscribe_pending: false,
+            awaiting_image: false,
+        }
+    }
+
+    /// Request fresh images by resubscribing when a market turns out to be inconsistent.
+    ///
+    /// Betfair cannot resend the image of a single market, so the whole market subscription of
+    /// the connection is sent again without clocks and every market is replaced by a new image.
+    /// No further resubscribe is requested until that image arrived, the connection was replaced
+    /// or there turned out to be no market subscription to send.
+    ///
+    /// # Parameters
+    ///
+    /// * `resubscribe` - Whether to resubscribe on market issues.
+    ///
+    /// # Returns
+    ///
+    /// The updated `Validator`.
+    #[must_use]
+    pub const fn with_resubscribe(mut self, resubscribe: bool) -> Self {
+        self.resubscribe = resubscribe;
+        self
+    }
+
+    /// Receive the issues found from now on.
+    #[must_use]
+    pub fn subscribe(&self) -> broadcast::Receiver<DataQualityIssue> {
+        self.issues.subscribe()
+    }
+
+    /// Whether fresh images were requested since the last call.
+    pub(crate) const fn take_resubscribe(&mut self) -> bool {
+        let pending = self.resubscribe_pending;
+        self.resubscribe_pending = false;
+        pending
+    }
+
+    /// Stop waiting for the images of a resubscribe that will not be answered, because it could
+    /// not be sent or the connection was replaced.
+    pub(crate) const fn cancel_resubscribe(&mut self) {
+        self.resubscribe_pending = false;
+        self.awaiting_image = false;
+    }
+
+    /// Check the ordering and clock of any change message before it is applied.
+    pub(crate) fn check_message<T: DeserializeOwned + DataChange<T>>(
+        &mut self,
+        msg: &DatasetChangeMessage<T>,
+    ) {
+        if let Some(publish_time) = msg.publish_time
+            && let Some(previous) = self.last_publish_time.insert(T::key(), publish_time)
+            && publish_time < previous
+        {
+            self.report(DataQualityIssue::PublishTimeRegression {
+                previous,
+                received: publish_time,
+            });
+        }
+
+        if msg.clock.is_none() && msg.data.as_ref().is_some_and(|data| !data.is_empty()) {
+            self.report(DataQualityIssue::MissingClock {
+                publish_time: msg.publish_time,
+            });
+        }
+    }
+
+    /// Check a market change against the caches before it is applied.
+    pub(crate) fn check_market_delta(
+        &mut self,
+        msg: &MarketChangeMessage,
+        tracker: &MarketStreamTracker,
+    ) {
+        if msg.change_type == Some(ChangeType::SubImage) {
+            self.awaiting_image = false;
+            return;
+        }
+
+        for market_change in msg.0.data.iter().flatten() {
+            let Some(ref market_id) = market_change.market_id else {
+                continue;
+            };
+            if market_change.full_image.unwrap_or(false) {
+                continue;
+            }
+            let Some(market) = tracker.market(market_id) else {
+                self.report_market(DataQualityIssue::DeltaWithoutImage {
+                    market_id: market_id.clone(),
+                });
+                continue;
+            };
+            // runners added by a new market definition in the same change are expected
+            let defined = |selection_id: SelectionId, handicap: Option<F64Ord>| {
+                market_change
+                    .market_definition
+                    .as_ref()
+                    .is_some_and(|definition| {
+                        definition.runners.iter().any(|runner| {
+                            runner.id == Some(selection_id) && runner.handicap == handicap
+                        })
+                    })
+            };
+            for runner_change in market_change.runner_change.iter().flatten() {
+                let Some(selection_id) = runner_change.id else {
+                    continue;
+                };
+                let handicap = runner_change.handicap;
+                if !market.runners().contains_key(&(selection_id, handicap))
+                    && !defined(selection_id, handicap)
+                {
+                    self.report_market(DataQualityIssue::RunnerWithoutImage {
+                        market_id: market_id.clone(),
+                        selection_id,
+                        handicap,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Check a market after a change was applied to it.
+    pub(crate) fn check_market(&mut self, market: &MarketBookCache) {
+        for (&(selection_id, handicap), runner) in market.runners() {
+            if let Some((best_back, best_lay)) = crossed(runner) {
+                self.report_market(DataQualityIssue::CrossedBook {
+                    market_id: market.market_id().clone(),
+                    selection_id,
+                    handicap,
+                    best_back,
+                    best_lay,
+                });
+            }
+        }
+    }
+
+    fn report_market(&mut self, issue: DataQualityIssue) {
+        self.report(issue);
+        if self.resubscribe && !self.awaiting_image {
+            self.resubscribe_pending = true;
+            self.awaiting_image = true;
+        }
+    }
+
+    fn report(&self, issue: DataQualityIssue) {
+        tracing::warn!(?issue, "stream data quality issue");
+        let _ = self.issues.send(issue);
+    }
+}
+
+impl Default for Validator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The best back and lay price of `runner` if they are crossed.
+fn crossed(runner: &RunnerBookCache) -> Option<(Price, Price)> {
+    let best_back = runner.best_back()?.price;
+    let best_lay = runner.best_lay()?.price;
+    (best_back >= best_lay).then_some((best_back, best_lay))
+}
+
+#[cfg(test)]
+mod tests {
+    use betfair_adapter::betfair_types::num;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::cache::tracker::StreamState;
+
+    fn message(ct: &str, pt: u64, mc: &str) -> MarketChangeMessage {
+        let ct = if ct.is_empty() {
+            String::new()
+        } else {
+            format!(r#""ct":"{ct}","#)
+        };
+        let data = format!(r#"{{"op":"mcm","id":1,"clk":"AAAAAAAA","pt":{pt},{ct}"mc":[{mc}]}}"#);
+        serde_json::from_str(&data).unwrap()
+    }
+
+    fn issues(receiver: &mut broadcast::Receiver<DataQualityIssue>) -> Vec<DataQualityIssue> {
+        core::iter::from_fn(|| receiver.try_recv().ok()).collect()
+    }
+
+    fn market_id(id: &str) -> MarketId {
+        MarketId(id.to_owned().into())
+    }
+
+    #[test]
+    fn reports_deltas_and_runners_without_image() {
+        let validator = Validator::new();
+        let mut receiver = validator.subscribe();
+        let mut state = StreamState::new().with_validator(validator);
+
+        state.market_change_update(message(
+            "SUB_IMAGE",
+            1,
+            r#"{"id":"1.1","img":true,"rc":[{"id":1,"atb":[[2.0,10]]}]}"#,
+        ));
+        assert_eq!(issues(&mut receiver), vec![]);
+
+        state.market_change_update(message(
+            "",
+            2,
+            r#"{"id":"1.1","rc":[{"id":2,"atb":[[2.0,10]]}]},{"id":"1.2","rc":[{"id":1,"atb":[[2.0,10]]}]}"#,
+        ));
+        assert_eq!(
+            issues(&mut receiver),
+            vec![
+                DataQualityIssue::RunnerWithoutImage {
+                    market_id: market_id("1.1"),
+                    selection_id: SelectionId(2),
+                    handicap: None,
+                },
+                DataQualityIssue::DeltaWithoutImage {
+                    market_id: market_id("1.2"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_crossed_books_and_publish_time_regressions() {
+        let validator = Validator::new();
+        let mut receiver = validator.subscribe();
+        let mut state = StreamState::new().with_validator(validator);
+
+        state.market_change_update(message(
+            "SUB_IMAGE",
+            5,
+            r#"{"id":"1.1","img":true,"rc":[{"id":1,"atb":[[2.0,10]],"atl":[[2.02,10]]}]}"#,
+        ));
+        state.market_change_update(message(
+            "",
+            4,
+            r#"{"id":"1.1","rc":[{"id":1,"atb":[[2.04,5]]}]}"#,
+        ));
+
+        let received = issues(&mut receiver);
+        assert_eq!(received.len(), 2);
+        assert!(matches!(
+            received[0],
+            DataQualityIssue::PublishTimeRegression { .. }
+        ));
+        assert_eq!(
+            received[1],
+            DataQualityIssue::CrossedBook {
+                market_id: market_id("1.1"),
+                selection_id: SelectionId(1),
+                handicap: None,
+                best_back: Price::new(num!(2.04)).unwrap(),
+                best_lay: Price::new(num!(2.02)).unwrap(),
+            }
+        );
+    }
+
+    #[test]
+    fn publish_times_are_tracked_per_stream() {
+        let validator = Validator::new();
+        let mut receiver = validator.subscribe();
+        let mut state = StreamState::new().with_validator(validator);
+
+        state.market_change_update(message("SUB_IMAGE", 5, r#"{"id":"1.1","img":true}"#));
+        state.order_change_update(
+            serde_json::from_str(r#"{"op":"ocm","id":2,"clk":"AAAAAAAA","pt":3,"oc":[]}"#).unwrap(),
+        );
+        state.market_change_update(message("", 6, r#"{"id":"1.1","tv":1.0}"#));
+
+        assert_eq!(issues(&mut receiver), vec![]);
+    }
+
+    #[test]
+    fn requests_a_single_resubscribe_until_the_image_arrives() {
+        let mut state = StreamState::new().with_validator(Validator::new().with_resubscribe(true));
+
+        state.market_change_update(message(
+            "",
+            1,
+            r#"{"id":"1.1","rc":[{"id":1,"atb":[[2.0,10]]}]}"#,
+        ));
+        assert!(state.take_resubscribe());
+        state.market_change_update(message(
+            "",
+            2,
+            r#"{"id":"1.2","rc":[{"id":1,"atb":[[2.0,10]]}]}"#,
+        ));
+        assert!(!state.take_resubscribe());
+
+        state.market_change_update(message("SUB_IMAGE", 3, r#"{"id":"1.1","img":true}"#));
+        state.market_change_update(message(
+            "",
+            4,
+            r#"{"id":"1.3","rc":[{"id":1,"atb":[[2.0,10]]}]}"#,
+        ));
+        assert!(state.take_resubscribe());
+    }
+
+    #[test]
+    fn cancelled_resubscribe_can_be_requested_again() {
+        let mut state = StreamState::new().with_validator(Validator::new().with_resubscribe(true));
+
+        state.market_change_update(message(
+            "",
+            1,
+            r#"{"id":"1.1","rc":[{"id":1,"atb":[[2.0,10]]}]}"#,
+        ));
+        assert!(state.take_resubscribe());
+        state.cancel_resubscribe();
+        state.market_change_update(message(
+            "",
+            2,
+            r#"{"id":"1.2","rc":[{"id":1,"atb":[[2.0,10]]}]}"#,
+        ));
+        assert!(state.take_resubscribe());
+    }
+}
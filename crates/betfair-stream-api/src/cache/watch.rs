@@ -0,0 +1,180 @@
+//! Per-market watch channels, letting any number of tasks follow individual markets.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use betfair_adapter::betfair_types::types::sports_aping::MarketId;
+use betfair_stream_types::response::market_change_message::StreamMarketDefinitionStatus;
+use tokio::sync::watch;
+
+use super::primitives::{MarketBookCache, OrderBookCache};
+
+/// Registry of [`watch`] channels holding the latest state of every market.
+///
+/// Obtained from [`Cache::watch_registry`](crate::Cache::watch_registry) and cheap to clone,
+/// so it can be handed to every task that needs it. The [`Cache`](crate::Cache) publishes each
+/// updated market book and order book to the registry, whether or not anybody is watching yet,
+/// so a new watcher always starts from the latest known state.
+///
+/// When a market closes its final state is published and its channel is removed: receivers see
+/// the closed book, after which [`watch::Receiver::changed`] returns an error. Channels of
+/// markets that were never published, e.g. watched after they closed, are removed once nobody
+/// watches them anymore.
+#[derive(Debug, Clone, Default)]
+pub struct WatchRegistry {
+    channels: Arc<Mutex<Channels>>,
+}
+
+#[derive(Debug, Default)]
+struct Channels {
+    markets: HashMap<MarketId, watch::Sender<Option<MarketBookCache>>>,
+    orders: HashMap<MarketId, watch::Sender<Option<OrderBookCache>>>,
+}
+
+impl WatchRegistry {
+    /// Watch the market book of `market_id`.
+    ///
+    /// The value is `None` until the first update of the market has been received.
+    #[must_use]
+    pub fn watch_market(&self, market_id: MarketId) -> watch::Receiver<Option<MarketBookCache>> {
+        let mut channels = self.channels();
+        prune(&mut channels.markets);
+        channels
+            .markets
+            .entry(market_id)
+            .or_insert_with(|| watch::Sender::new(None))
+            .subscribe()
+    }
+
+    /// Watch the order book (the orders placed by this account) of `market_id`.
+    ///
+    /// The value is `None` until the first order update of the market has been received.
+    #[must_use]
+    pub fn watch_orders(&self, market_id: MarketId) -> watch::Receiver<Option<OrderBookCache>> {
+        let mut channels = self.channels();
+        prune(&mut channels.orders);
+        channels
+            .orders
+            .entry(market_id)
+            .or_insert_with(|| watch::Sender::new(None))
+            .subscribe()
+    }
+
+    /// Number of markets that currently have a market book channel.
+    #[must_use]
+    pub fn market_count(&self) -> usize {
+        self.channels().markets.len()
+    }
+
+    /// Number of markets that currently have an order book channel.
+    #[must_use]
+    pub fn order_count(&self) -> usize {
+        self.channels().orders.len()
+    }
+
+    pub(crate) fn publish_market(&self, market: &MarketBookCache) {
+        let closed = market
+            .market_definition()
+            .is_some_and(|definition| definition.status == StreamMarketDefinitionStatus::Closed);
+        let mut channels = self.channels();
+        if closed {
+            if let Some(sender) = channels.markets.remove(market.market_id()) {
+                sender.send_replace(Some(market.clone()));
+            }
+            return;
+        }
+        channels
+            .markets
+            .entry(market.market_id().clone())
+            .or_insert_with(|| watch::Sender::new(None))
+            .send_replace(Some(market.clone()));
+    }
+
+    pub(crate) fn publish_orders(&self, orders: &OrderBookCache) {
+        let mut channels = self.channels();
+        if orders.is_closed() {
+            if let Some(sender) = channels.orders.remove(orders.market_id()) {
+                sender.send_replace(Some(orders.clone()));
+            }
+            return;
+        }
+        channels
+            .orders
+            .entry(orders.market_id().clone())
+            .or_insert_with(|| watch::Sender::new(None))
+            .send_replace(Some(orders.clone()));
+    }
+
+    fn channels(&self) -> MutexGuard<'_, Channels> {
+        self.channels.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Remove the channels nobody watches that never received a value.
+fn prune<T>(channels: &mut HashMap<MarketId, watch::Sender<Option<T>>>) {
+    channels.retain(|_, sender| sender.receiver_count() > 0 || sender.borrow().is_some());
+}
+
+#[cfg(test)]
+mod tests {
+    use betfair_stream_types::response::market_change_message::MarketChangeMessage;
+    use chrono::Utc;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn market(status: &str) -> MarketBookCache {
+        let data = format!(
+            r#"{{"op":"mcm","id":1,"clk":"AAAAAAAA","pt":1478717720756,"mc":[{{"id":"1.1","marketDefinition":{{"bspMarket":false,"turnInPlayEnabled":true,"persistenceEnabled":true,"marketBaseRate":5,"eventId":"28009395","eventTypeId":"2","numberOfWinners":1,"bettingType":"ODDS","marketType":"MATCH_ODDS","marketTime":"2016-11-09T18:15:00.000Z","bspReconciled":false,"complete":true,"inPlay":false,"crossMatching":true,"runnersVoidable":false,"numberOfActiveRunners":0,"betDelay":0,"status":"{status}","runners":[],"regulators":["MR_INT"],"discountAllowed":true,"timezone":"UTC","version":1}}}}]}}"#
+        );
+        let message: MarketChangeMessage = serde_json::from_str(&data).unwrap();
+        let mut market = MarketBookCache::new(MarketId::new("1.1"), Utc::now());
+        for change in message.0.data.unwrap() {
+            market.update_cache(change, Utc::now(), true);
+        }
+        market
+    }
+
+    #[test]
+    fn late_watchers_start_from_the_latest_state() {
+        let registry = WatchRegistry::default();
+        let open = market("OPEN");
+
+        registry.publish_market(&open);
+        let watcher = registry.watch_market(MarketId::new("1.1"));
+
+        assert_eq!(*watcher.borrow(), Some(open));
+    }
+
+    #[tokio::test]
+    async fn closing_a_market_delivers_the_final_state_and_removes_the_channel() {
+        let registry = WatchRegistry::default();
+        let mut watcher = registry.watch_market(MarketId::new("1.1"));
+        assert_eq!(*watcher.borrow(), None);
+
+        registry.publish_market(&market("OPEN"));
+        watcher.changed().await.unwrap();
+
+        let closed = market("CLOSED");
+        registry.publish_market(&closed);
+        watcher.changed().await.unwrap();
+        assert_eq!(*watcher.borrow_and_update(), Some(closed));
+        assert!(watcher.changed().await.is_err());
+        assert_eq!(registry.market_count(), 0);
+    }
+
+    #[test]
+    fn unwatched_channels_of_unpublished_markets_are_removed() {
+        let registry = WatchRegistry::default();
+        registry.publish_market(&market("OPEN"));
+
+        // e.g. markets that closed before they were watched
+        for id in ["1.2", "1.3"] {
+            drop(registry.watch_market(MarketId::new(id)));
+        }
+        let _watcher = registry.watch_market(MarketId::new("1.4"));
+
+        // the published market is kept for late watchers
+        assert_eq!(registry.market_count(), 2);
+    }
+}
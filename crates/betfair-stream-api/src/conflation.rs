@@ -0,0 +1,412 @@
+//! Delivery of cache updates without back-pressure on the stream task.
+//!
+//! With the regular channel output a consumer that falls behind stalls the task reading from the
+//! socket, Betfair's send buffer fills up and the connection is eventually dropped. The mailbox
+//! never blocks the stream task: it keeps only the latest [`MarketBookCache`] and
+//! [`OrderBookCache`] per market that the consumer has not picked up yet, and counts how many
+//! intermediate updates were replaced ("conflated") on the way.
+//!
+//! Market and order events cannot be conflated, so consecutive batches of them are merged and
+//! at most [`MAX_PENDING_EVENTS`] are kept; newer events are dropped and counted until the
+//! consumer catches up.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use betfair_adapter::betfair_types::types::sports_aping::MarketId;
+use tokio::sync::Notify;
+
+use crate::cache::primitives::{MarketBookCache, OrderBookCache};
+use crate::{CachedMessage, OutputClosed, OutputSink};
+
+/// Market and order events the mailbox holds before it drops new ones.
+pub const MAX_PENDING_EVENTS: usize = 65_536;
+
+/// Create a connected mailbox sender and receiver.
+pub(crate) fn mailbox() -> (MailboxSender, ConflatedReceiver) {
+    let shared = Arc::new(Shared::default());
+    (
+        MailboxSender {
+            shared: Arc::clone(&shared),
+        },
+        ConflatedReceiver { shared },
+    )
+}
+
+/// Number of updates that were replaced by a newer one before the consumer received them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConflationStats {
+    /// Market book updates that were superseded while waiting in the mailbox.
+    pub market_updates_conflated: u64,
+    /// Order book updates that were superseded while waiting in the mailbox.
+    pub order_updates_conflated: u64,
+    /// Market and order events that were dropped because [`MAX_PENDING_EVENTS`] were waiting.
+    pub events_dropped: u64,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    mailbox: Mutex<Mailbox>,
+    notify: Notify,
+    sender_closed: AtomicBool,
+    receiver_closed: AtomicBool,
+    market_updates_conflated: AtomicU64,
+    order_updates_conflated: AtomicU64,
+    events_dropped: AtomicU64,
+}
+
+impl Shared {
+    fn mailbox(&self) -> MutexGuard<'_, Mailbox> {
+        self.mailbox.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Pending output, in the order it has to be delivered.
+#[derive(Debug, Default)]
+struct Mailbox {
+    queue: VecDeque<Slot>,
+    markets: HashMap<MarketId, MarketBookCache>,
+    orders: HashMap<MarketId, OrderBookCache>,
+    /// Market and order events waiting in the queue.
+    events: usize,
+}
+
+#[derive(Debug)]
+enum Slot {
+    Market(MarketId),
+    Order(MarketId),
+    /// Connection, status, alarm and event messages are never conflated.
+    Message(CachedMessage),
+}
+
+impl Mailbox {
+    /// Store `message`, returning how many updates it replaced and how many events it dropped.
+    fn put(&mut self, message: CachedMessage) -> ConflationStats {
+        let mut conflated = ConflationStats::default();
+        match message {
+            CachedMessage::MarketChange(markets) => {
+                for market in markets {
+                    let market_id = market.market_id().clone();
+                    if self.markets.insert(market_id.clone(), market).is_some() {
+                        conflated.market_updates_conflated += 1;
+                    } else {
+                        self.queue.push_back(Slot::Market(market_id));
+                    }
+                }
+            }
+            CachedMessage::OrderChange(orders) => {
+                for order in orders {
+                    let market_id = order.market_id().clone();
+                    if self.orders.insert(market_id.clone(), order).is_some() {
+                        conflated.order_updates_conflated += 1;
+                    } else {
+                        self.queue.push_back(Slot::Order(market_id));
+                    }
+                }
+            }
+            CachedMessage::MarketEvents(mut events) => {
+                conflated.events_dropped = self.reserve_events(&mut events);
+                if let Some(slot) = self.queue.back_mut()
+                    && let Slot::Message(CachedMessage::MarketEvents(ref mut queued)) = *slot
+                {
+                    queued.append(&mut events);
+                } else if !events.is_empty() {
+                    self.queue
+                        .push_back(Slot::Message(CachedMessage::MarketEvents(events)));
+                }
+            }
+            CachedMessage::OrderEvents(mut events) => {
+                conflated.events_dropped = self.reserve_events(&mut events);
+                if let Some(slot) = self.queue.back_mut()
+                    && let Slot::Message(CachedMessage::OrderEvents(ref mut queued)) = *slot
+                {
+                    queued.append(&mut events);
+                } else if !events.is_empty() {
+                    self.queue
+                        .push_back(Slot::Message(CachedMessage::OrderEvents(events)));
+                }
+            }
+            message @ (CachedMessage::Connection(_)
+            | CachedMessage::Status(_)
+            | CachedMessage::Alarm(_)) => {
+                self.queue.push_back(Slot::Message(message));
+            }
+        }
+        conflated
+    }
+
+    /// Make room for `events`, dropping those beyond [`MAX_PENDING_EVENTS`] and returning how
+    /// many were dropped.
+    fn reserve_events<E>(&mut self, events: &mut Vec<E>) -> u64 {
+        let room = MAX_PENDING_EVENTS.saturating_sub(self.events);
+        let dropped = events.len().saturating_sub(room);
+        events.truncate(room);
+        self.events += events.len();
+        dropped as u64
+    }
+
+    /// Take the next message: either a single connection/status message, or the latest state of
+    /// every market (or order book) queued up to the next message of a different kind.
+    fn take(&mut self) -> Option<CachedMessage> {
+        match self.queue.pop_front()? {
+            Slot::Message(message) => {
+                match message {
+                    CachedMessage::MarketEvents(ref events) => self.events -= events.len(),
+                    CachedMessage::OrderEvents(ref events) => self.events -= events.len(),
+                    _ => {}
+                }
+                Some(message)
+            }
+            Slot::Market(market_id) => {
+                let mut markets = Vec::new();
+                markets.extend(self.markets.remove(&market_id));
+                while let Some(slot) = self.queue.front()
+                    && let Slot::Market(ref market_id) = *slot
+                {
+                    markets.extend(self.markets.remove(market_id));
+                    self.queue.pop_front();
+                }
+                Some(CachedMessage::MarketChange(markets))
+            }
+            Slot::Order(market_id) => {
+                let mut orders = Vec::new();
+                orders.extend(self.orders.remove(&market_id));
+                while let Some(slot) = self.queue.front()
+                    && let Slot::Order(ref market_id) = *slot
+                {
+                    orders.extend(self.orders.remove(market_id));
+                    self.queue.pop_front();
+                }
+                Some(CachedMessage::OrderChange(orders))
+            }
+        }
+    }
+}
+
+/// The stream task's end of the mailbox.
+#[derive(Debug)]
+pub(crate) struct MailboxSender {
+    shared: Arc<Shared>,
+}
+
+impl OutputSink<CachedMessage> for MailboxSender {
+    async fn send(&mut self, message: CachedMessage) -> Result<(), OutputClosed> {
+        if self.shared.receiver_closed.load(Ordering::Acquire) {
+            return Err(OutputClosed);
+        }
+        let conflated = self.shared.mailbox().put(message);
+        if conflated.market_updates_conflated > 0 {
+            self.shared
+                .market_updates_conflated
+                .fetch_add(conflated.market_updates_conflated, Ordering::Relaxed);
+        }
+        if conflated.order_updates_conflated > 0 {
+            self.shared
+                .order_updates_conflated
+                .fetch_add(conflated.order_updates_conflated, Ordering::Relaxed);
+        }
+        if conflated.events_dropped > 0 {
+            tracing::warn!(
+                dropped = conflated.events_dropped,
+                "conflated output is full of events, dropping new ones"
+            );
+            self.shared
+                .events_dropped
+                .fetch_add(conflated.events_dropped, Ordering::Relaxed);
+        }
+        self.shared.notify.notify_one();
+        Ok(())
+    }
+}
+
+impl Drop for MailboxSender {
+    fn drop(&mut self) {
+        self.shared.sender_closed.store(true, Ordering::Release);
+        self.shared.notify.notify_one();
+    }
+}
+
+/// Receives the newest state of every market that changed since the last call.
+///
+/// Returned as the `sink` of [`BetfairStreamBuilder::start_conflated`](crate::BetfairStreamBuilder::start_conflated).
+/// Market and order updates are merged into a single [`CachedMessage::MarketChange`] or
+/// [`CachedMessage::OrderChange`] batch; connection, status and alarm messages are delivered
+/// unchanged and in order. Consecutive market or order events are merged into one batch, and
+/// events beyond [`MAX_PENDING_EVENTS`] are dropped (see [`ConflationStats::events_dropped`]).
+#[derive(Debug)]
+pub struct ConflatedReceiver {
+    shared: Arc<Shared>,
+}
+
+impl ConflatedReceiver {
+    /// Receives the next message, waiting until one is available.
+    ///
+    /// Returns `None` once the stream task has stopped and everything has been received.
+    pub async fn recv(&mut self) -> Option<CachedMessage> {
+        loop {
+            if let Some(message) = self.try_recv() {
+                return Some(message);
+            }
+            if self.shared.sender_closed.load(Ordering::Acquire) {
+                // the task may have delivered a last message right before stopping
+                return self.try_recv();
+            }
+            self.shared.notify.notified().await;
+        }
+    }
+
+    /// Receives the next message if one is available, without waiting.
+    pub fn try_recv(&mut self) -> Option<CachedMessage> {
+        self.shared.mailbox().take()
+    }
+
+    /// How many updates have been conflated and events dropped since the stream was started.
+    #[must_use]
+    pub fn stats(&self) -> ConflationStats {
+        ConflationStats {
+            market_updates_conflated: self.shared.market_updates_conflated.load(Ordering::Relaxed),
+            order_updates_conflated: self.shared.order_updates_conflated.load(Ordering::Relaxed),
+            events_dropped: self.shared.events_dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for ConflatedReceiver {
+    fn drop(&mut self) {
+        self.shared.receiver_closed.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use betfair_stream_types::response::status_message::{StatusMessage, StatusSuccess};
+    use chrono::Utc;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::MarketEvent;
+
+    fn market(market_id: &str) -> MarketBookCache {
+        MarketBookCache::new(MarketId::new(market_id), Utc::now())
+    }
+
+    fn market_ids(message: Option<CachedMessage>) -> Vec<String> {
+        let Some(CachedMessage::MarketChange(markets)) = message else {
+            panic!("expected a market change, got {message:?}");
+        };
+        markets
+            .iter()
+            .map(|market| market.market_id().0.to_string())
+            .collect()
+    }
+
+    fn status() -> CachedMessage {
+        CachedMessage::Status(StatusMessage::Success(StatusSuccess {
+            id: Some(1),
+            connection_closed: Some(false),
+            connection_id: None,
+            connections_available: None,
+        }))
+    }
+
+    #[tokio::test]
+    async fn keeps_only_the_latest_update_per_market() {
+        let (mut tx, mut rx) = mailbox();
+
+        tx.send(CachedMessage::MarketChange(vec![
+            market("1.1"),
+            market("1.2"),
+        ]))
+        .await
+        .unwrap();
+        tx.send(CachedMessage::MarketChange(vec![market("1.1")]))
+            .await
+            .unwrap();
+        tx.send(status()).await.unwrap();
+        tx.send(CachedMessage::MarketChange(vec![market("1.3")]))
+            .await
+            .unwrap();
+
+        assert_eq!(market_ids(rx.recv().await), vec!["1.1", "1.2"]);
+        assert_eq!(rx.recv().await, Some(status()));
+        assert_eq!(market_ids(rx.recv().await), vec!["1.3"]);
+        assert_eq!(rx.try_recv(), None);
+        assert_eq!(
+            rx.stats(),
+            ConflationStats {
+                market_updates_conflated: 1,
+                order_updates_conflated: 0,
+                events_dropped: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn merges_consecutive_events_and_drops_them_beyond_the_limit() {
+        let (mut tx, mut rx) = mailbox();
+        let suspended = |market_id: &str| MarketEvent::MarketSuspended {
+            market_id: MarketId::new(market_id),
+        };
+
+        tx.send(CachedMessage::MarketChange(vec![market("1.1")]))
+            .await
+            .unwrap();
+        tx.send(CachedMessage::MarketEvents(vec![suspended("1.1")]))
+            .await
+            .unwrap();
+        // conflated into the queued market, so the events stay consecutive
+        tx.send(CachedMessage::MarketChange(vec![market("1.1")]))
+            .await
+            .unwrap();
+        tx.send(CachedMessage::MarketEvents(vec![
+            suspended("1.1");
+            MAX_PENDING_EVENTS
+        ]))
+        .await
+        .unwrap();
+
+        assert_eq!(market_ids(rx.recv().await), vec!["1.1"]);
+        let Some(CachedMessage::MarketEvents(events)) = rx.recv().await else {
+            panic!("expected market events");
+        };
+        assert_eq!(events.len(), MAX_PENDING_EVENTS);
+        assert_eq!(rx.try_recv(), None);
+        assert_eq!(
+            rx.stats(),
+            ConflationStats {
+                market_updates_conflated: 1,
+                order_updates_conflated: 0,
+                events_dropped: 1,
+            }
+        );
+
+        // delivered events make room again
+        tx.send(CachedMessage::MarketEvents(vec![suspended("1.2")]))
+            .await
+            .unwrap();
+        assert_eq!(
+            rx.recv().await,
+            Some(CachedMessage::MarketEvents(vec![suspended("1.2")]))
+        );
+    }
+
+    #[tokio::test]
+    async fn drains_remaining_messages_after_the_sender_stopped() {
+        let (mut tx, mut rx) = mailbox();
+
+        tx.send(status()).await.unwrap();
+        drop(tx);
+
+        assert_eq!(rx.recv().await, Some(status()));
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn send_fails_once_the_receiver_is_dropped() {
+        let (mut tx, rx) = mailbox();
+        drop(rx);
+
+        assert!(tx.send(status()).await.is_err());
+    }
+}
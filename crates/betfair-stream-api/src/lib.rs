@@ -5,8 +5,22 @@
 //! Users can customize how incoming messages are handled by implementing the `MessageProcessor` trait
 //! or using the built-in `Cache` processor for maintaining market and order caches.
 extern crate alloc;
+mod ack;
 pub mod cache;
-use backon::{BackoffBuilder as _, ExponentialBuilder};
+mod conflation;
+mod lifecycle;
+mod pool;
+mod reconnect_policy;
+mod recorder;
+mod replay;
+mod rest_fallback;
+mod session;
+mod shutdown;
+mod stats;
+mod subscription_replay;
+mod transport;
+use ack::PendingAcks;
+pub use ack::{AckError, DEFAULT_ACK_TIMEOUT, RequestAck};
 use betfair_adapter::{Authenticated, BetfairRpcClient, Unauthenticated};
 pub use betfair_stream_types as types;
 use betfair_stream_types::{
@@ -18,26 +32,57 @@ use betfair_stream_types::{
     },
 };
 pub use bytes::Bytes;
+pub use cache::consistency::{PriceLadder, PriceMismatch};
+pub use cache::market_events::MarketEvent;
+pub use cache::order_events::{OrderEvent, OrderEventKind};
+pub use cache::tracker::SegmentationMode;
+pub use cache::tracker::validator::{DataQualityIssue, Validator};
 use cache::{
     primitives::{MarketBookCache, OrderBookCache},
     tracker::StreamState,
+    watch::WatchRegistry,
 };
+use conflation::MailboxSender;
+pub use conflation::{ConflatedReceiver, ConflationStats, MAX_PENDING_EVENTS};
 use core::fmt;
+use core::marker::PhantomData;
 use core::{pin::pin, time::Duration};
-use eyre::Context as _;
 use futures::{
     FutureExt, SinkExt as _, StreamExt as _,
     future::{self, BoxFuture, select},
 };
+use lifecycle::Lifecycle;
+pub use lifecycle::LifecycleEvent;
+pub use pool::{
+    BetfairStreamPool, BetfairStreamPoolClient, DEFAULT_CONNECT_TIMEOUT, DEFAULT_MAX_CONNECTIONS,
+    DEFAULT_MAX_MARKETS_PER_CONNECTION, PoolError,
+};
+use reconnect_policy::ReconnectBackoff;
+pub use reconnect_policy::ReconnectPolicy;
+pub use recorder::{Compression, Recorder, RecorderConfig};
+pub use replay::{Pacing, StreamReplayBuilder};
+pub use rest_fallback::{DEFAULT_POLL_INTERVAL, RestFallback};
+use rustls::pki_types::CertificateDer;
+use session::KeepAlive;
+pub use session::SharedSession;
+pub use shutdown::ShutdownHandle;
+use shutdown::{ShutdownRequest, ShutdownSignal};
+pub use stats::{AlarmThresholds, LatencyPercentiles, StatsSnapshot, StreamAlarm, StreamStats};
 use std::sync::Arc;
+use subscription_replay::SubscriptionReplay;
 use tokio::{
     net::TcpStream,
-    sync::mpsc::{self, Receiver, Sender},
+    sync::{
+        broadcast,
+        mpsc::{self, Receiver, Sender},
+    },
     task::JoinHandle,
-    time::sleep,
+    time::{Instant, sleep, sleep_until},
 };
 use tokio_stream::wrappers::{IntervalStream, ReceiverStream};
 use tokio_util::codec::{Decoder, Encoder, Framed};
+use transport::{Connector, MaybeTlsStream};
+pub use transport::{StreamTransport, TlsSettings};
 
 /// A Betfair Stream API client that handles connection, handshake, incoming/outgoing messages,
 /// heartbeat and automatic reconnects.
@@ -54,17 +99,60 @@ pub struct BetfairStreamBuilder<T: MessageProcessor> {
     pub heartbeat_interval: Option<Duration>,
     /// The intermediate processor of messages
     pub processor: T,
+    /// How to back off between failed connection and authentication attempts
+    pub reconnect_policy: ReconnectPolicy,
+    /// How the connection to the stream endpoint is secured
+    pub transport: StreamTransport,
+    /// A shared session used instead of logging in with [`Self::client`]
+    pub session: Option<SharedSession>,
+    /// When to raise latency and staleness alarms
+    pub alarms: AlarmThresholds,
 }
 
 /// Handle to a running Betfair Streaming API client.
 ///
-/// Provides channels to send requests (`send_to_stream`) and receive processed messages (`sink`).
+/// Provides channels to send requests (`send_to_stream`), receive processed messages (`sink`),
+/// observe the state of the connection (`lifecycle`) and its health (`stats`) and stop the
+/// stream task (`shutdown`).
+///
+/// # Type Parameters
+///
+/// - `T`: The `MessageProcessor` of the stream.
+/// - `S`: The receiving end of the processed messages, a [`ConflatedReceiver`] for streams
+///   started with [`BetfairStreamBuilder::start_conflated`].
 #[derive(Debug)]
-pub struct BetfairStreamClient<T: MessageProcessor> {
+pub struct BetfairStreamClient<T: MessageProcessor, S = Receiver<<T as MessageProcessor>::Output>> {
     /// send a message to the Betfair stream
     pub send_to_stream: Sender<RequestMessage>,
     /// Receive a message from the stream
-    pub sink: Receiver<T::Output>,
+    pub sink: S,
+    /// Receive connection lifecycle events (connects, disconnects, reconnect attempts).
+    ///
+    /// Use [`broadcast::Receiver::resubscribe`] to hand out additional receivers.
+    pub lifecycle: broadcast::Receiver<LifecycleEvent>,
+    /// Gracefully stop the stream task.
+    pub shutdown: ShutdownHandle,
+    /// Message rates, latencies and other health statistics of the connection.
+    pub stats: StreamStats,
+    pending_acks: Arc<PendingAcks>,
+    processor: PhantomData<fn() -> T>,
+}
+
+impl<T: MessageProcessor, S> BetfairStreamClient<T, S> {
+    /// Sends a request with a freshly allocated id and returns a future resolving to the status
+    /// message Betfair answers it with.
+    ///
+    /// Any id already set on `request` is overwritten. The status message is still forwarded to
+    /// [`Self::sink`] as usual.
+    ///
+    /// # Errors
+    /// If the stream task has stopped and the request cannot be sent.
+    pub async fn send_with_ack(
+        &self,
+        request: RequestMessage,
+    ) -> Result<RequestAck, mpsc::error::SendError<RequestMessage>> {
+        self.pending_acks.send(&self.send_to_stream, request).await
+    }
 }
 
 /// Default `MessageProcessor` implementation that maintains market and order caches.
@@ -73,6 +161,9 @@ pub struct BetfairStreamClient<T: MessageProcessor> {
 #[derive(Debug, Clone)]
 pub struct Cache {
     state: StreamState,
+    watchers: Option<WatchRegistry>,
+    /// Derived from the last processed message, delivered after its output
+    pending: Option<CachedMessage>,
 }
 
 impl Cache {
@@ -81,8 +172,106 @@ impl Cache {
     pub fn new() -> Self {
         Self {
             state: StreamState::new(),
+            watchers: None,
+            pending: None,
         }
     }
+
+    /// Returns the registry of per-market watch channels, enabling it on first use.
+    ///
+    /// Once enabled, every updated market and order book is also published to its watch
+    /// channel, so individual tasks can follow single markets instead of reading the whole
+    /// output. Call this before the `Cache` is passed to the stream, e.g. via
+    /// `builder.processor.watch_registry()`.
+    ///
+    /// # Returns
+    ///
+    /// A handle to the registry, shared with this `Cache` and all of its clones.
+    pub fn watch_registry(&mut self) -> WatchRegistry {
+        self.watchers
+            .get_or_insert_with(WatchRegistry::default)
+            .clone()
+    }
+
+    /// Sets how messages split into segments are delivered.
+    ///
+    /// # Parameters
+    ///
+    /// * `segmentation` - Whether to wait for `SEG_END` or emit every segment.
+    ///
+    /// # Returns
+    ///
+    /// The updated `Cache`.
+    #[must_use]
+    pub fn with_segmentation(mut self, segmentation: SegmentationMode) -> Self {
+        self.state = self.state.with_segmentation(segmentation);
+        self
+    }
+
+    /// Checks every applied change for data-quality issues.
+    ///
+    /// Subscribe to the issues with [`Validator::subscribe`] before passing it in. If the
+    /// validator was created [`with_resubscribe`](Validator::with_resubscribe), the stream task
+    /// requests fresh images when a market turns out to be inconsistent.
+    ///
+    /// # Parameters
+    ///
+    /// * `validator` - The validator checking the changes.
+    ///
+    /// # Returns
+    ///
+    /// The updated `Cache`.
+    #[must_use]
+    pub fn with_validator(mut self, validator: Validator) -> Self {
+        self.state = self.state.with_validator(validator);
+        self
+    }
+
+    /// Seeds the cache of a market before the stream starts, so it can be pre-populated from a
+    /// REST snapshot with [`MarketBookCache::from_market_book`] and then advanced by the stream.
+    ///
+    /// # Parameters
+    ///
+    /// * `market` - The cache of the market.
+    ///
+    /// # Returns
+    ///
+    /// The updated `Cache`.
+    #[must_use]
+    pub fn with_market(mut self, market: MarketBookCache) -> Self {
+        self.state = self.state.with_market(market);
+        self
+    }
+
+    /// Derives [`MarketEvent`]s such as suspensions or best price changes from every applied
+    /// market change.
+    ///
+    /// The events are delivered as [`CachedMessage::MarketEvents`] right after the
+    /// [`CachedMessage::MarketChange`] batch of the markets they belong to.
+    ///
+    /// # Returns
+    ///
+    /// The updated `Cache`.
+    #[must_use]
+    pub fn with_market_events(mut self) -> Self {
+        self.state = self.state.with_market_events();
+        self
+    }
+
+    /// Derives [`OrderEvent`]s such as fills, cancellations or lapses from every applied order
+    /// change.
+    ///
+    /// The events are delivered as [`CachedMessage::OrderEvents`] right after the
+    /// [`CachedMessage::OrderChange`] batch of the markets they belong to.
+    ///
+    /// # Returns
+    ///
+    /// The updated `Cache`.
+    #[must_use]
+    pub fn with_order_events(mut self) -> Self {
+        self.state = self.state.with_order_events();
+        self
+    }
 }
 
 impl Default for Cache {
@@ -112,6 +301,17 @@ pub enum CachedMessage {
     /// A status message from the stream, used for heartbeats,
     /// subscription confirmations, or error notifications.
     Status(StatusMessage),
+
+    /// The stream is late or silent, see [`BetfairStreamBuilder::with_alarms`].
+    Alarm(StreamAlarm),
+
+    /// What changed in the markets of the preceding [`Self::MarketChange`] batch, see
+    /// [`Cache::with_market_events`].
+    MarketEvents(Vec<MarketEvent>),
+
+    /// What happened to the orders of the preceding [`Self::OrderChange`] batch, see
+    /// [`Cache::with_order_events`].
+    OrderEvents(Vec<OrderEvent>),
 }
 
 impl MessageProcessor for Cache {
@@ -120,21 +320,57 @@ impl MessageProcessor for Cache {
     fn process_message(&mut self, message: ResponseMessage) -> Option<Self::Output> {
         match message {
             ResponseMessage::Connection(connection_message) => {
+                // a new connection does not answer a resubscribe sent on the previous one
+                self.state.cancel_resubscribe();
                 Some(CachedMessage::Connection(connection_message))
             }
-            ResponseMessage::MarketChange(market_change_message) => self
-                .state
-                .market_change_update(market_change_message)
-                .map(|markets| markets.into_iter().cloned().collect::<Vec<_>>())
-                .map(CachedMessage::MarketChange),
-            ResponseMessage::OrderChange(order_change_message) => self
-                .state
-                .order_change_update(order_change_message)
-                .map(|markets| markets.into_iter().cloned().collect::<Vec<_>>())
-                .map(CachedMessage::OrderChange),
+            ResponseMessage::MarketChange(market_change_message) => {
+                let markets = self.state.market_change_update(market_change_message)?;
+                if let Some(ref watchers) = self.watchers {
+                    for market in &markets {
+                        watchers.publish_market(market);
+                    }
+                }
+                let markets = markets.into_iter().cloned().collect::<Vec<_>>();
+                let events = self.state.take_market_events();
+                if !events.is_empty() {
+                    self.pending = Some(CachedMessage::MarketEvents(events));
+                }
+                Some(CachedMessage::MarketChange(markets))
+            }
+            ResponseMessage::OrderChange(order_change_message) => {
+                let markets = self.state.order_change_update(order_change_message)?;
+                if let Some(ref watchers) = self.watchers {
+                    for orders in &markets {
+                        watchers.publish_orders(orders);
+                    }
+                }
+                let markets = markets.into_iter().cloned().collect::<Vec<_>>();
+                let events = self.state.take_order_events();
+                if !events.is_empty() {
+                    self.pending = Some(CachedMessage::OrderEvents(events));
+                }
+                Some(CachedMessage::OrderChange(markets))
+            }
             ResponseMessage::Status(status_message) => Some(CachedMessage::Status(status_message)),
         }
     }
+
+    fn on_alarm(&mut self, alarm: &StreamAlarm) -> Option<Self::Output> {
+        Some(CachedMessage::Alarm(alarm.clone()))
+    }
+
+    fn resubscribe_requested(&mut self) -> bool {
+        self.state.take_resubscribe()
+    }
+
+    fn resubscribe_skipped(&mut self) {
+        self.state.cancel_resubscribe();
+    }
+
+    fn next_output(&mut self) -> Option<Self::Output> {
+        self.pending.take()
+    }
 }
 
 /// `MessageProcessor` that forwards raw `ResponseMessage` objects without transformation.
@@ -147,6 +383,22 @@ impl MessageProcessor for Forwarder {
         Some(message)
     }
 }
+/// Where the stream task delivers processed messages.
+pub(crate) trait OutputSink<O>: Send {
+    /// Deliver `message`, failing if the consumer is gone.
+    fn send(&mut self, message: O) -> impl Future<Output = Result<(), OutputClosed>> + Send;
+}
+
+/// The receiving end of the output has been dropped.
+#[derive(Debug)]
+pub(crate) struct OutputClosed;
+
+impl<O: Send> OutputSink<O> for Sender<O> {
+    async fn send(&mut self, message: O) -> Result<(), OutputClosed> {
+        Self::send(self, message).await.map_err(|_err| OutputClosed)
+    }
+}
+
 /// Trait for processing incoming Betfair streaming `ResponseMessage` objects into user-defined outputs.
 ///
 /// Implementers can filter or transform messages and control which messages are forwarded to the client sink.
@@ -169,10 +421,53 @@ pub trait MessageProcessor: Send + Sync + 'static {
         let _ = message;
     }
 
+    /// Called with every request right before it is written to the stream, including
+    /// authentication, heartbeats and subscriptions resent after a reconnect.
+    ///
+    /// The default implementation is a no-op.
+    fn on_message_sent(&mut self, message: &RequestMessage) {
+        let _ = message;
+    }
+
     /// Process an incoming `ResponseMessage`.
     ///
     /// Returns `Some(Output)` to forward a processed message, or `None` to drop it.
     fn process_message(&mut self, message: ResponseMessage) -> Option<Self::Output>;
+
+    /// Called when the stream task raises an alarm configured with
+    /// [`BetfairStreamBuilder::with_alarms`].
+    ///
+    /// Returns `Some(Output)` to deliver the alarm to the client sink. The default
+    /// implementation drops it; the alarm is logged either way.
+    fn on_alarm(&mut self, alarm: &StreamAlarm) -> Option<Self::Output> {
+        let _ = alarm;
+        None
+    }
+
+    /// Checked after every processed message; return `true` when the processor's state turned
+    /// out to be inconsistent and needs fresh images.
+    ///
+    /// The stream task then sends the active market subscription again without clocks, so that
+    /// Betfair answers with a new image of every subscribed market. The default implementation
+    /// never asks for one.
+    fn resubscribe_requested(&mut self) -> bool {
+        false
+    }
+
+    /// Called when the stream task could not send the resubscribe asked for by
+    /// [`Self::resubscribe_requested`] because there is no market subscription to send again, so
+    /// no images will arrive for it.
+    ///
+    /// The default implementation is a no-op.
+    fn resubscribe_skipped(&mut self) {}
+
+    /// Called after every processed message until it returns `None`; further outputs derived
+    /// from the same message, delivered after the output of [`Self::process_message`].
+    ///
+    /// The default implementation has none.
+    fn next_output(&mut self) -> Option<Self::Output> {
+        None
+    }
 }
 
 impl<T: MessageProcessor> BetfairStreamBuilder<T> {
@@ -192,9 +487,11 @@ impl<T: MessageProcessor> BetfairStreamBuilder<T> {
         BetfairStreamBuilder {
             client,
             heartbeat_interval: None,
-            processor: Cache {
-                state: StreamState::new(),
-            },
+            processor: Cache::new(),
+            reconnect_policy: ReconnectPolicy::default(),
+            transport: StreamTransport::default(),
+            session: None,
+            alarms: AlarmThresholds::default(),
         }
     }
 
@@ -217,6 +514,63 @@ impl<T: MessageProcessor> BetfairStreamBuilder<T> {
             client,
             heartbeat_interval: None,
             processor: Forwarder,
+            reconnect_policy: ReconnectPolicy::default(),
+            transport: StreamTransport::default(),
+            session: None,
+            alarms: AlarmThresholds::default(),
+        }
+    }
+
+    /// Creates a new `BetfairStreamBuilder` that reuses an existing login.
+    ///
+    /// Uses the default `Cache` message processor to maintain market and order caches.
+    /// The stream does not log in itself; if Betfair rejects the session, a new one is obtained
+    /// through the [`SharedSession`] and shared with every other holder of it.
+    ///
+    /// # Parameters
+    ///
+    /// * `session` - An authenticated client, or a [`SharedSession`] that is also used elsewhere.
+    ///
+    /// # Returns
+    ///
+    /// A `BetfairStreamBuilder` configured with cache-based message processing.
+    pub fn new_authenticated(session: impl Into<SharedSession>) -> BetfairStreamBuilder<Cache> {
+        let session = session.into();
+        BetfairStreamBuilder::<Cache>::new(session.client().unauthenticated()).with_session(session)
+    }
+
+    /// Reuses an existing login instead of logging in when the stream starts.
+    ///
+    /// # Parameters
+    ///
+    /// * `session` - An authenticated client, or a [`SharedSession`] that is also used elsewhere.
+    ///
+    /// # Returns
+    ///
+    /// The updated `BetfairStreamBuilder` using the given session.
+    pub fn with_session(mut self, session: impl Into<SharedSession>) -> Self {
+        self.session = Some(session.into());
+        self
+    }
+
+    /// Replaces the message processor, e.g. to wrap the current one in a [`Recorder`].
+    ///
+    /// # Parameters
+    ///
+    /// * `processor` - The processor handling incoming messages.
+    ///
+    /// # Returns
+    ///
+    /// A `BetfairStreamBuilder` with the same settings and the given processor.
+    pub fn with_processor<P: MessageProcessor>(self, processor: P) -> BetfairStreamBuilder<P> {
+        BetfairStreamBuilder {
+            client: self.client,
+            heartbeat_interval: self.heartbeat_interval,
+            processor,
+            reconnect_policy: self.reconnect_policy,
+            transport: self.transport,
+            session: self.session,
+            alarms: self.alarms,
         }
     }
 
@@ -234,6 +588,129 @@ impl<T: MessageProcessor> BetfairStreamBuilder<T> {
         self
     }
 
+    /// Raises a [`StreamAlarm`] when the feed is late or silent for longer than the thresholds.
+    ///
+    /// Alarms are passed to [`MessageProcessor::on_alarm`]; the [`Cache`] processor forwards
+    /// them as [`CachedMessage::Alarm`].
+    ///
+    /// # Parameters
+    ///
+    /// * `alarms` - The latency and silence thresholds.
+    ///
+    /// # Returns
+    ///
+    /// The updated `BetfairStreamBuilder` with the alarm thresholds set.
+    pub fn with_alarms(mut self, alarms: AlarmThresholds) -> Self {
+        self.alarms = alarms;
+        self
+    }
+
+    /// Sets the policy used to back off between failed connection, handshake and
+    /// re-authentication attempts.
+    ///
+    /// By default the stream retries forever with an exponential backoff of up to 60 seconds.
+    ///
+    /// # Parameters
+    ///
+    /// * `policy` - The reconnect policy to use.
+    ///
+    /// # Returns
+    ///
+    /// The updated `BetfairStreamBuilder` with the given reconnect policy.
+    pub fn with_reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = policy;
+        self
+    }
+
+    /// Sets how the connection to the stream endpoint is secured.
+    ///
+    /// By default the connection uses TLS, verified against the native root certificates.
+    ///
+    /// # Parameters
+    ///
+    /// * `transport` - The transport to use.
+    ///
+    /// # Returns
+    ///
+    /// The updated `BetfairStreamBuilder` with the given transport.
+    pub fn with_transport(mut self, transport: StreamTransport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Uses the given rustls client configuration for the TLS connection instead of one built
+    /// from the native root certificates.
+    ///
+    /// # Parameters
+    ///
+    /// * `config` - The rustls client configuration.
+    ///
+    /// # Returns
+    ///
+    /// The updated `BetfairStreamBuilder` using TLS with the given configuration.
+    pub fn with_tls_config(mut self, config: Arc<rustls::ClientConfig>) -> Self {
+        self.tls_settings().client_config = Some(config);
+        self
+    }
+
+    /// Trusts an additional root certificate, e.g. the private CA of a TLS-inspecting proxy.
+    ///
+    /// Ignored when a custom client configuration is set via [`Self::with_tls_config`].
+    ///
+    /// # Parameters
+    ///
+    /// * `certificate` - A DER encoded root certificate.
+    ///
+    /// # Returns
+    ///
+    /// The updated `BetfairStreamBuilder` using TLS with the extra root certificate.
+    pub fn with_root_certificate(mut self, certificate: CertificateDer<'static>) -> Self {
+        self.tls_settings()
+            .extra_root_certificates
+            .push(certificate);
+        self
+    }
+
+    /// Overrides the server name sent via SNI and used to verify the server certificate.
+    ///
+    /// By default the host of the stream url is used.
+    ///
+    /// # Parameters
+    ///
+    /// * `server_name` - A DNS name or IP address.
+    ///
+    /// # Returns
+    ///
+    /// The updated `BetfairStreamBuilder` using TLS with the given server name.
+    pub fn with_server_name(mut self, server_name: impl Into<String>) -> Self {
+        self.tls_settings().server_name = Some(server_name.into());
+        self
+    }
+
+    /// Connects over plain TCP without TLS.
+    ///
+    /// Betfair only accepts TLS connections, this is meant for local stand-in servers and
+    /// tests.
+    ///
+    /// # Returns
+    ///
+    /// The updated `BetfairStreamBuilder` using plain TCP.
+    pub fn with_plaintext(mut self) -> Self {
+        self.transport = StreamTransport::Plaintext;
+        self
+    }
+
+    /// The TLS settings of the transport, switching from plaintext to TLS if needed.
+    fn tls_settings(&mut self) -> &mut TlsSettings {
+        if let StreamTransport::Plaintext = self.transport {
+            self.transport = StreamTransport::Tls(TlsSettings::default());
+        }
+        match self.transport {
+            StreamTransport::Tls(ref mut settings) => settings,
+            StreamTransport::Plaintext => unreachable!("transport was switched to TLS"),
+        }
+    }
+
     /// Starts the Betfair streaming client and returns handles for interaction.
     ///
     /// This will spawn an asynchronous task that manages the connection, handshake,
@@ -254,22 +731,55 @@ impl<T: MessageProcessor> BetfairStreamBuilder<T> {
     /// * `BetfairStreamClient<T>` - A client handle providing:
     ///     - `send_to_stream`: a channel sender for outgoing `RequestMessage`s.
     ///     - `sink`: a channel receiver for processed messages of type `T::Output`.
+    ///     - `lifecycle`: a broadcast receiver for connection [`LifecycleEvent`]s.
+    ///     - `shutdown`: a [`ShutdownHandle`] to gracefully stop the background task.
+    ///     - `stats`: [`StreamStats`] of the connection.
     /// * `H` - A handle to the background task driving the streaming logic, type depends on the spawner.
     pub fn start_with<const C: usize, Sp, H>(self, spawner: Sp) -> (BetfairStreamClient<T>, H)
     where
         Sp: FnOnce(BoxFuture<'static, eyre::Result<()>>) -> H,
+    {
+        self.start_with_output::<C, _, _, _, _>(mpsc::channel(C), spawner)
+    }
+
+    /// Spawns the stream task delivering its output to `from_stream_tx`, received by `sink`.
+    fn start_with_output<const C: usize, O, R, Sp, H>(
+        self,
+        (from_stream_tx, from_stream_rx): (O, R),
+        spawner: Sp,
+    ) -> (BetfairStreamClient<T, R>, H)
+    where
+        O: OutputSink<T::Output> + 'static,
+        Sp: FnOnce(BoxFuture<'static, eyre::Result<()>>) -> H,
     {
         let (to_stream_tx, to_stream_rx) = mpsc::channel(C);
-        let (from_stream_tx, from_stream_rx) = mpsc::channel(C);
+        let (lifecycle, lifecycle_rx) = Lifecycle::new();
+        let (shutdown, shutdown_signal) = ShutdownHandle::new();
+        let stats = StreamStats::new(self.alarms);
+        let pending_acks = Arc::new(PendingAcks::default());
 
         // let task = tokio::task::spawn(self.run(from_stream_tx, to_stream_rx));
-        let fut = self.run(from_stream_tx, to_stream_rx).boxed();
+        let fut = self
+            .run(
+                from_stream_tx,
+                to_stream_rx,
+                lifecycle,
+                Arc::clone(&pending_acks),
+                shutdown_signal,
+                stats.clone(),
+            )
+            .boxed();
         let handle = spawner(fut);
 
         (
             BetfairStreamClient {
                 send_to_stream: to_stream_tx,
                 sink: from_stream_rx,
+                lifecycle: lifecycle_rx,
+                shutdown,
+                stats,
+                pending_acks,
+                processor: PhantomData,
             },
             handle,
         )
@@ -293,24 +803,54 @@ impl<T: MessageProcessor> BetfairStreamBuilder<T> {
 
     async fn run(
         self,
-        from_stream_tx: Sender<T::Output>,
+        from_stream_tx: impl OutputSink<T::Output>,
+        to_stream_rx: Receiver<RequestMessage>,
+        lifecycle: Lifecycle,
+        pending_acks: Arc<PendingAcks>,
+        mut shutdown: ShutdownSignal,
+        stats: StreamStats,
+    ) -> eyre::Result<()> {
+        let result = self
+            .run_with_heartbeat(
+                from_stream_tx,
+                to_stream_rx,
+                &lifecycle,
+                &pending_acks,
+                &mut shutdown,
+                &stats,
+            )
+            .await;
+        pending_acks.cancel_all();
+        if let Err(ref err) = result {
+            lifecycle.emit(LifecycleEvent::Fatal {
+                reason: format!("{err:#}"),
+                error_code: err
+                    .downcast_ref::<ConnectionRejected>()
+                    .map(|rejected| rejected.error_code),
+            });
+        }
+        result
+    }
+
+    async fn run_with_heartbeat(
+        self,
+        from_stream_tx: impl OutputSink<T::Output>,
         to_stream_rx: Receiver<RequestMessage>,
+        lifecycle: &Lifecycle,
+        pending_acks: &PendingAcks,
+        shutdown: &mut ShutdownSignal,
+        stats: &StreamStats,
     ) -> eyre::Result<()> {
         if let Some(hb) = self.heartbeat_interval {
             let heartbeat_stream = {
                 let mut interval = tokio::time::interval(hb);
                 interval.reset();
                 let interval_stream = IntervalStream::new(interval).fuse();
+                let mut id = 0;
                 interval_stream
-                    .map(move |instant| HeartbeatMessage {
-                        id: Some(
-                            instant
-                                .into_std()
-                                .elapsed()
-                                .as_secs()
-                                .try_into()
-                                .unwrap_or_default(),
-                        ),
+                    .map(move |_| {
+                        id = next_heartbeat_id(id);
+                        HeartbeatMessage { id: Some(id) }
                     })
                     .map(RequestMessage::Heartbeat)
                     .boxed()
@@ -320,75 +860,252 @@ impl<T: MessageProcessor> BetfairStreamBuilder<T> {
                 ReceiverStream::new(to_stream_rx).boxed(),
             ]);
 
-            self.run_base(from_stream_tx, input_stream).await
+            self.run_base(
+                from_stream_tx,
+                input_stream,
+                lifecycle,
+                pending_acks,
+                shutdown,
+                stats,
+            )
+            .await
         } else {
-            self.run_base(from_stream_tx, ReceiverStream::new(to_stream_rx))
-                .await
+            self.run_base(
+                from_stream_tx,
+                ReceiverStream::new(to_stream_rx),
+                lifecycle,
+                pending_acks,
+                shutdown,
+                stats,
+            )
+            .await
         }
     }
 
     async fn run_base(
         mut self,
-        mut from_stream_tx: Sender<T::Output>,
+        mut from_stream_tx: impl OutputSink<T::Output>,
         mut to_stream_rx: impl futures::Stream<Item = RequestMessage> + Unpin,
+        lifecycle: &Lifecycle,
+        pending_acks: &PendingAcks,
+        shutdown: &mut ShutdownSignal,
+        stats: &StreamStats,
     ) -> eyre::Result<()> {
-        let (mut client, _) = self.client.clone().authenticate().await?;
-        let mut backoff = ExponentialBuilder::new().build();
+        // dropping the keep-alive of our own login stops it however the task ends
+        let (mut client, mut keep_alive) = if let Some(ref session) = self.session {
+            (session.client(), None)
+        } else {
+            // logging in retries with backoff, so it must not delay a shutdown either
+            let login = pin!(
+                self.client
+                    .clone()
+                    .authenticate_with_backoff(self.reconnect_policy)
+            );
+            match select(pin!(shutdown.requested()), login).await {
+                future::Either::Left((request, _)) => {
+                    // not logged in yet, so there is no session to log out of
+                    tracing::info!(logout = request.logout, "shutting down stream task");
+                    lifecycle.emit(LifecycleEvent::ShutDown);
+                    return Ok(());
+                }
+                future::Either::Right((login, _)) => {
+                    let (client, keep_alive) = login?;
+                    (client, Some(KeepAlive(keep_alive)))
+                }
+            }
+        };
+        let mut backoff = ReconnectBackoff::new(self.reconnect_policy);
         let mut first_call = true;
+        let mut connected_at: Option<Instant> = None;
+        let mut replay = SubscriptionReplay::default();
         'retry: loop {
-            if !first_call {
-                // add exponential recovery
-                let Some(delay) = backoff.next() else {
-                    eyre::bail!("connection retry attempts exceeded")
-                };
-                sleep(delay).await;
+            // a connection that stayed healthy for long enough starts the backoff from scratch
+            if let Some(connected_at) = connected_at.take()
+                && connected_at.elapsed() >= self.reconnect_policy.reset_after
+            {
+                backoff.reset();
             }
-            first_call = true;
 
-            // Connect (with handshake) using retry logic.
-            let mut stream = self
-                .connect_with_retry(&mut from_stream_tx, &mut client)
-                .await?;
+            // Connect (with handshake) using retry logic, unless asked to shut down meanwhile.
+            let connected = {
+                let connect = pin!(async {
+                    if !first_call {
+                        // add exponential recovery
+                        let Some((attempt, delay)) = backoff.next_delay() else {
+                            eyre::bail!("connection retry attempts exceeded")
+                        };
+                        tracing::info!(?delay, attempt, "reconnecting to stream");
+                        lifecycle.emit(LifecycleEvent::Reconnecting { attempt, delay });
+                        sleep(delay).await;
+                    }
+                    self.connect_with_retry(
+                        &mut from_stream_tx,
+                        &mut client,
+                        &mut keep_alive,
+                        &mut backoff,
+                        lifecycle,
+                    )
+                    .await
+                });
+                match select(pin!(shutdown.requested()), connect).await {
+                    future::Either::Left((request, _)) => Err(request),
+                    future::Either::Right((connected, _)) => Ok(connected?),
+                }
+            };
+            first_call = false;
+            let (mut stream, connection_id) = match connected {
+                Ok(connected) => connected,
+                Err(request) => return self.shut_down(&client, request, lifecycle).await,
+            };
+            connected_at = Some(Instant::now());
             tracing::info!("Connected to {}", self.client.stream.url());
+            stats.on_connected(connection_id.clone());
+            lifecycle.emit(LifecycleEvent::Connected { connection_id });
+
+            // Betfair drops all subscriptions together with the connection, resume them
+            let resubscribe = replay.requests();
+            if !resubscribe.is_empty() {
+                for request in resubscribe {
+                    tracing::info!(?request, "resubscribing after reconnect");
+                    self.processor.on_message_sent(&request);
+                    let Ok(()) = stream.send(request).await else {
+                        tracing::warn!("could not resubscribe");
+                        lifecycle.emit(LifecycleEvent::Disconnected {
+                            reason: "could not resubscribe".to_owned(),
+                        });
+                        continue 'retry;
+                    };
+                }
+                lifecycle.emit(LifecycleEvent::Resubscribed);
+            }
 
             loop {
-                let stream_next = pin!(stream.next());
-                let to_stream_rx_next = pin!(to_stream_rx.next());
-                match select(to_stream_rx_next, stream_next).await {
-                    future::Either::Left((request, _)) => {
+                let event = {
+                    let interrupt = async {
+                        let stale = async {
+                            match stats.stale_deadline() {
+                                Some(deadline) => sleep_until(deadline).await,
+                                None => future::pending().await,
+                            }
+                        };
+                        match select(pin!(shutdown.requested()), pin!(stale)).await {
+                            future::Either::Left((request, _)) => TaskEvent::Shutdown(request),
+                            future::Either::Right(((), _)) => TaskEvent::Stale,
+                        }
+                    };
+                    let to_stream_rx_next = pin!(to_stream_rx.next());
+                    let stream_next = pin!(stream.next());
+                    let next = select(to_stream_rx_next, stream_next);
+                    match select(pin!(interrupt), next).await {
+                        future::Either::Left((event, _)) => event,
+                        future::Either::Right((future::Either::Left((request, _)), _)) => {
+                            TaskEvent::Request(request)
+                        }
+                        future::Either::Right((future::Either::Right((message, _)), _)) => {
+                            TaskEvent::Message(message)
+                        }
+                    }
+                };
+
+                match event {
+                    TaskEvent::Shutdown(request) => {
+                        // stop reading, but send what was queued before the shutdown
+                        while let Some(Some(request)) = to_stream_rx.next().now_or_never() {
+                            self.processor.on_message_sent(&request);
+                            if let Err(err) = stream.feed(request).await {
+                                tracing::warn!(?err, "could not send queued request");
+                                break;
+                            }
+                        }
+                        if let Err(err) = stream.close().await {
+                            tracing::warn!(?err, "could not close stream connection");
+                        }
+                        return self.shut_down(&client, request, lifecycle).await;
+                    }
+                    TaskEvent::Stale => {
+                        let Some(alarm) = stats.raise_stale() else {
+                            continue;
+                        };
+                        if self.raise_alarm(&mut from_stream_tx, &alarm).await.is_err() {
+                            tracing::info!(
+                                "output channel receiver dropped, shutting down stream task"
+                            );
+                            return Ok(());
+                        }
+                    }
+                    TaskEvent::Request(request) => {
                         let Some(request) = request else {
                             tracing::info!("request channel closed, shutting down stream task");
                             return Ok(());
                         };
 
                         tracing::debug!(?request, "sending to betfair");
+                        replay.on_request(&request);
+                        stats.on_request(&request);
+                        self.processor.on_message_sent(&request);
                         let Ok(()) = stream.send(request).await else {
                             tracing::warn!("could not send request to stream");
+                            lifecycle.emit(LifecycleEvent::Disconnected {
+                                reason: "could not send request to stream".to_owned(),
+                            });
                             continue 'retry;
                         };
                     }
-                    future::Either::Right((message, _)) => {
+                    TaskEvent::Message(message) => {
                         let Some(message) = message else {
                             tracing::warn!("stream returned None");
+                            lifecycle.emit(LifecycleEvent::Disconnected {
+                                reason: "connection closed by remote".to_owned(),
+                            });
                             continue 'retry;
                         };
 
                         match message {
                             Ok((raw, message)) => {
+                                if let ResponseMessage::Status(ref status) = message {
+                                    pending_acks.resolve(status);
+                                }
+                                replay.on_response(&message);
+                                let alarm = stats.on_message(raw.len(), &message);
                                 self.processor.on_message_received(raw, &message);
                                 let message = self.processor.process_message(message);
                                 tracing::debug!(?message, "received from betfair");
-                                let Some(message) = message else {
-                                    continue;
-                                };
-
-                                if let Err(err) = from_stream_tx.send(message).await {
+                                if self.processor.resubscribe_requested() {
+                                    if let Some(request) = replay.image_request() {
+                                        tracing::warn!(?request, "resubscribing for fresh images");
+                                        replay.on_request(&request);
+                                        self.processor.on_message_sent(&request);
+                                        let Ok(()) = stream.send(request).await else {
+                                            tracing::warn!("could not resubscribe");
+                                            lifecycle.emit(LifecycleEvent::Disconnected {
+                                                reason: "could not resubscribe".to_owned(),
+                                            });
+                                            continue 'retry;
+                                        };
+                                    } else {
+                                        tracing::warn!("no market subscription to resubscribe");
+                                        self.processor.resubscribe_skipped();
+                                    }
+                                }
+                                if let Some(alarm) = alarm
+                                    && self.raise_alarm(&mut from_stream_tx, &alarm).await.is_err()
+                                {
                                     tracing::info!(
-                                        "output channel receiver dropped, shutting down stream task: {:?}",
-                                        err
+                                        "output channel receiver dropped, shutting down stream task"
                                     );
                                     return Ok(());
-                                };
+                                }
+                                let mut message = message;
+                                while let Some(output) =
+                                    message.take().or_else(|| self.processor.next_output())
+                                {
+                                    if from_stream_tx.send(output).await.is_err() {
+                                        tracing::info!(
+                                            "output channel receiver dropped, shutting down stream task"
+                                        );
+                                        return Ok(());
+                                    };
+                                }
                             }
                             Err(err) => tracing::warn!(?err, "reading message error"),
                         }
@@ -398,17 +1115,54 @@ impl<T: MessageProcessor> BetfairStreamBuilder<T> {
         }
     }
 
-    /// Attempt to connect and perform a handshake using exponential backoff.
+    /// Log `alarm` and deliver it to the output if the processor turns it into a message.
+    async fn raise_alarm(
+        &mut self,
+        from_stream_tx: &mut impl OutputSink<T::Output>,
+        alarm: &StreamAlarm,
+    ) -> Result<(), OutputClosed> {
+        tracing::warn!(?alarm, "stream alarm");
+        match self.processor.on_alarm(alarm) {
+            Some(message) => from_stream_tx.send(message).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Finish a requested shutdown once the connection is closed, logging out if asked to.
+    async fn shut_down(
+        &self,
+        client: &BetfairRpcClient<Authenticated>,
+        request: ShutdownRequest,
+        lifecycle: &Lifecycle,
+    ) -> eyre::Result<()> {
+        tracing::info!(logout = request.logout, "shutting down stream task");
+        lifecycle.emit(LifecycleEvent::ShutDown);
+        if request.logout {
+            if let Some(ref session) = self.session {
+                session.logout().await?;
+            } else {
+                client.logout()?.execute().await?.ok()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Attempt to connect and perform a handshake, backing off according to the
+    /// [`ReconnectPolicy`].
     #[tracing::instrument(skip_all, err)]
     async fn connect_with_retry(
         &mut self,
-        from_stream_tx: &mut Sender<T::Output>,
+        from_stream_tx: &mut impl OutputSink<T::Output>,
         client: &mut Arc<BetfairRpcClient<Authenticated>>,
-    ) -> eyre::Result<Framed<tokio_rustls::client::TlsStream<TcpStream>, StreamAPIClientCodec>>
-    {
-        let mut backoff = ExponentialBuilder::new().build();
+        keep_alive: &mut Option<KeepAlive>,
+        backoff: &mut ReconnectBackoff,
+        lifecycle: &Lifecycle,
+    ) -> eyre::Result<(FramedStream, Option<String>)> {
+        let reconnect_policy = self.reconnect_policy;
         let mut delay = async || {
-            if let Some(delay) = backoff.next() {
+            if let Some((attempt, delay)) = backoff.next_delay() {
+                tracing::info!(?delay, attempt, "retrying stream connection");
+                lifecycle.emit(LifecycleEvent::Reconnecting { attempt, delay });
                 sleep(delay).await;
                 Ok(())
             } else {
@@ -416,21 +1170,30 @@ impl<T: MessageProcessor> BetfairStreamBuilder<T> {
             }
         };
 
+        let server_addr = self.client.stream.url().clone();
+        let host = server_addr
+            .host_str()
+            .ok_or_else(|| eyre::eyre!("invalid betfair url"))?;
+        let port = server_addr.port().unwrap_or(443);
+        let connector = Connector::new(&self.transport, host)?;
+
         loop {
-            let server_addr = self.client.stream.url();
-            let host = server_addr
-                .host_str()
-                .ok_or_else(|| eyre::eyre!("invalid betfair url"))?;
-            let port = server_addr.port().unwrap_or(443);
-
-            let domain_str = server_addr
-                .domain()
-                .ok_or_else(|| eyre::eyre!("domain must be known"))?;
-            let domain = rustls::pki_types::ServerName::try_from(domain_str.to_owned())
-                .wrap_err("failed to parse server name")?;
+            lifecycle.emit(LifecycleEvent::Connecting);
+            // another holder of the session may have logged in again in the meantime
+            if let Some(ref session) = self.session {
+                *client = session.client();
+            }
 
             // Resolve socket addresses each iteration in case DNS changes
-            let Some(socket_addr) = tokio::net::lookup_host((host, port)).await?.next() else {
+            let socket_addr = match tokio::net::lookup_host((host, port)).await {
+                Ok(mut addrs) => addrs.next(),
+                Err(err) => {
+                    tracing::error!(?err, "DNS lookup error. Retrying...");
+                    delay().await?;
+                    continue;
+                }
+            };
+            let Some(socket_addr) = socket_addr else {
                 eyre::bail!("no valid socket addresses for {host}:{port}")
             };
 
@@ -440,26 +1203,42 @@ impl<T: MessageProcessor> BetfairStreamBuilder<T> {
                 delay().await?;
                 continue;
             };
-            let tls_stream = tls_connector()?.connect(domain.clone(), stream).await?;
-            let mut tls_stream = Framed::new(tls_stream, StreamAPIClientCodec);
+            let stream = match connector.connect(stream).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    tracing::error!(?err, "TLS handshake error. Retrying...");
+                    delay().await?;
+                    continue;
+                }
+            };
+            let mut stream = Framed::new(stream, StreamAPIClientCodec);
 
-            match self
-                .handshake(from_stream_tx, client, &mut tls_stream)
-                .await
-            {
-                Ok(()) => return Ok(tls_stream),
+            match self.handshake(from_stream_tx, client, &mut stream).await {
+                Ok(connection_id) => return Ok((stream, connection_id)),
                 Err(err) => match err {
                     HandshakeErr::WaitAndRetry => {
                         delay().await?;
                         continue;
                     }
                     HandshakeErr::Reauthenticate => {
-                        let (new_client, _) = self.client.clone().authenticate().await?;
-                        *client = new_client;
+                        *client = if let Some(ref session) = self.session {
+                            session.reauthenticate(client, reconnect_policy).await?
+                        } else {
+                            let (new_client, new_keep_alive) = self
+                                .client
+                                .clone()
+                                .authenticate_with_backoff(reconnect_policy)
+                                .await?;
+                            *keep_alive = Some(KeepAlive(new_keep_alive));
+                            new_client
+                        };
                         delay().await?;
                         continue;
                     }
                     HandshakeErr::Fatal => eyre::bail!("fatal error in stream processing"),
+                    HandshakeErr::Rejected(error_code) => {
+                        return Err(ConnectionRejected { error_code }.into());
+                    }
                 },
             }
         }
@@ -468,10 +1247,10 @@ impl<T: MessageProcessor> BetfairStreamBuilder<T> {
     #[tracing::instrument(err, skip_all)]
     async fn handshake(
         &mut self,
-        from_stream_tx: &mut Sender<T::Output>,
+        from_stream_tx: &mut impl OutputSink<T::Output>,
         client: &BetfairRpcClient<Authenticated>,
-        stream: &mut Framed<tokio_rustls::client::TlsStream<TcpStream>, StreamAPIClientCodec>,
-    ) -> Result<(), HandshakeErr> {
+        stream: &mut FramedStream,
+    ) -> Result<Option<String>, HandshakeErr> {
         // await con message
         let (raw, res) = stream
             .next()
@@ -497,14 +1276,13 @@ impl<T: MessageProcessor> BetfairStreamBuilder<T> {
         from_stream_tx
             .send(message.clone())
             .await
-            .inspect_err(|err| {
-                tracing::warn!("failed to send connection message to channel: {:?}", err)
-            })
+            .inspect_err(|_err| tracing::warn!("failed to send connection message to channel"))
             .map_err(|_| HandshakeErr::Fatal)?;
-        let ResponseMessage::Connection(_) = &res else {
+        let ResponseMessage::Connection(connection) = &res else {
             tracing::warn!("stream responded with invalid connection message");
             return Err(HandshakeErr::Reauthenticate);
         };
+        let connection_id = connection.connection_id.clone();
 
         // send auth msg
         let msg = authentication_message::AuthenticationMessage {
@@ -518,8 +1296,10 @@ impl<T: MessageProcessor> BetfairStreamBuilder<T> {
                 .expose_secret()
                 .clone(),
         };
+        let request = RequestMessage::Authentication(msg);
+        self.processor.on_message_sent(&request);
         stream
-            .send(RequestMessage::Authentication(msg))
+            .send(request)
             .await
             .inspect_err(|err| tracing::warn!(?err, "stream exited"))
             .map_err(|_| HandshakeErr::WaitAndRetry)?;
@@ -549,9 +1329,7 @@ impl<T: MessageProcessor> BetfairStreamBuilder<T> {
         from_stream_tx
             .send(processed_message)
             .await
-            .inspect_err(|err| {
-                tracing::warn!("failed to send status message to channel: {:?}", err)
-            })
+            .inspect_err(|_err| tracing::warn!("failed to send status message to channel"))
             .map_err(|_| HandshakeErr::Fatal)?;
         tracing::info!(?message, "message from stream");
         let ResponseMessage::Status(status_message) = &message else {
@@ -560,24 +1338,24 @@ impl<T: MessageProcessor> BetfairStreamBuilder<T> {
         };
 
         let StatusMessage::Failure(err) = &status_message else {
-            return Ok(());
+            return Ok(connection_id);
         };
 
         tracing::error!(?err, "stream respondend with an error");
         let action = match err.error_code {
-            ErrorCode::NoAppKey => HandshakeErr::Fatal,
-            ErrorCode::InvalidAppKey => HandshakeErr::Fatal,
+            ErrorCode::NoAppKey => HandshakeErr::Rejected(err.error_code),
+            ErrorCode::InvalidAppKey => HandshakeErr::Rejected(err.error_code),
             ErrorCode::NoSession => HandshakeErr::Reauthenticate,
             ErrorCode::InvalidSessionInformation => HandshakeErr::Reauthenticate,
             ErrorCode::NotAuthorized => HandshakeErr::Reauthenticate,
-            ErrorCode::InvalidInput => HandshakeErr::Fatal,
-            ErrorCode::InvalidClock => HandshakeErr::Fatal,
-            ErrorCode::UnexpectedError => HandshakeErr::Fatal,
+            ErrorCode::InvalidInput => HandshakeErr::Rejected(err.error_code),
+            ErrorCode::InvalidClock => HandshakeErr::Rejected(err.error_code),
+            ErrorCode::UnexpectedError => HandshakeErr::Rejected(err.error_code),
             ErrorCode::Timeout => HandshakeErr::WaitAndRetry,
             ErrorCode::SubscriptionLimitExceeded => HandshakeErr::WaitAndRetry,
-            ErrorCode::InvalidRequest => HandshakeErr::Fatal,
+            ErrorCode::InvalidRequest => HandshakeErr::Rejected(err.error_code),
             ErrorCode::ConnectionFailed => HandshakeErr::WaitAndRetry,
-            ErrorCode::MaxConnectionLimitExceeded => HandshakeErr::Fatal,
+            ErrorCode::MaxConnectionLimitExceeded => HandshakeErr::Rejected(err.error_code),
             ErrorCode::TooManyRequests => HandshakeErr::WaitAndRetry,
         };
 
@@ -585,11 +1363,75 @@ impl<T: MessageProcessor> BetfairStreamBuilder<T> {
     }
 }
 
+impl<T: MessageProcessor<Output = CachedMessage>> BetfairStreamBuilder<T> {
+    /// Starts the Betfair streaming client with a conflating output.
+    ///
+    /// Instead of a bounded channel, which makes the stream task wait for a slow consumer, the
+    /// output is a [`ConflatedReceiver`]: the stream task never blocks on it and only the latest
+    /// market and order book of every market is kept until the consumer picks it up. Use this
+    /// when subscribed to many markets and only the current state matters.
+    ///
+    /// # Type Parameters
+    ///
+    /// * `C` - The capacity of the request channel.
+    /// * `Sp` - The type of the spawner function.
+    /// * `H` - The type of the handle returned by the spawner.
+    ///
+    /// # Parameters
+    ///
+    /// * `spawner` - A function that takes a boxed future and returns a handle to the spawned task.
+    ///
+    /// # Returns
+    ///
+    /// * `BetfairStreamClient<T, ConflatedReceiver>` - A client handle whose `sink` is the
+    ///   conflating mailbox.
+    /// * `H` - A handle to the background task driving the streaming logic, type depends on the spawner.
+    pub fn start_conflated_with<const C: usize, Sp, H>(
+        self,
+        spawner: Sp,
+    ) -> (BetfairStreamClient<T, ConflatedReceiver>, H)
+    where
+        Sp: FnOnce(BoxFuture<'static, eyre::Result<()>>) -> H,
+    {
+        self.start_with_output::<C, MailboxSender, _, _, _>(conflation::mailbox(), spawner)
+    }
+
+    /// Starts the Betfair streaming client with a conflating output and the default Tokio task
+    /// spawner.
+    ///
+    /// See [`Self::start_conflated_with`].
+    ///
+    /// # Type Parameters
+    ///
+    /// * `C` - The capacity of the request channel.
+    pub fn start_conflated<const C: usize>(
+        self,
+    ) -> (
+        BetfairStreamClient<T, ConflatedReceiver>,
+        JoinHandle<eyre::Result<()>>,
+    ) {
+        self.start_conflated_with::<C, _, _>(|fut| tokio::spawn(fut))
+    }
+}
+
+/// A framed connection to the Betfair stream.
+type FramedStream = Framed<MaybeTlsStream, StreamAPIClientCodec>;
+
+/// What woke up the stream task while connected.
+enum TaskEvent<M> {
+    Shutdown(ShutdownRequest),
+    Stale,
+    Request(Option<RequestMessage>),
+    Message(Option<M>),
+}
+
 #[derive(Debug)]
 enum HandshakeErr {
     WaitAndRetry,
     Reauthenticate,
     Fatal,
+    /// Betfair refused the connection with an error that retrying does not fix.
+    Rejected(ErrorCode),
 }
 
 impl fmt::Display for HandshakeErr {
@@ -600,22 +1442,20 @@ impl fmt::Display for HandshakeErr {
 
 impl core::error::Error for HandshakeErr {}
 
-#[tracing::instrument(err)]
-fn tls_connector() -> eyre::Result<tokio_rustls::TlsConnector> {
-    use tokio_rustls::TlsConnector;
+/// The error that stops the stream task when Betfair refuses the connection.
+#[derive(Debug)]
+struct ConnectionRejected {
+    error_code: ErrorCode,
+}
 
-    let mut roots = rustls::RootCertStore::empty();
-    let native_certs = rustls_native_certs::load_native_certs();
-    for cert in native_certs.certs {
-        roots.add(cert)?;
+impl fmt::Display for ConnectionRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "stream connection rejected: {:?}", self.error_code)
     }
-
-    let config = rustls::ClientConfig::builder()
-        .with_root_certificates(roots)
-        .with_no_client_auth();
-    Ok(TlsConnector::from(Arc::new(config)))
 }
 
+impl core::error::Error for ConnectionRejected {}
+
 /// Defines the encoding and decoding of Betfair stream api data structures using tokio
 pub struct StreamAPIClientCodec;
 
@@ -667,6 +1507,18 @@ impl Encoder<RequestMessage> for StreamAPIClientCodec {
     }
 }
 
+/// The id of the heartbeat after the one with `id`.
+///
+/// Heartbeats count down from `-2` and wrap around, so their ids never collide with the
+/// authentication request (`-1`) or the positive ids of requests waiting for an ack.
+const fn next_heartbeat_id(id: i32) -> i32 {
+    if id > -2 || id == i32::MIN {
+        -2
+    } else {
+        id - 1
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -690,6 +1542,14 @@ mod tests {
         assert_eq!(socket_addr.port(), 443);
     }
 
+    #[test]
+    fn heartbeat_ids_are_negative_and_wrap_around() {
+        assert_eq!(next_heartbeat_id(0), -2);
+        assert_eq!(next_heartbeat_id(-2), -3);
+        assert_eq!(next_heartbeat_id(i32::MIN + 1), i32::MIN);
+        assert_eq!(next_heartbeat_id(i32::MIN), -2);
+    }
+
     #[test]
     fn can_decode_single_message() {
         let json = r#"{"op":"connection","connectionId":"002-051134157842-432409"}"#;
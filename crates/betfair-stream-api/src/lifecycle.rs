@@ -0,0 +1,70 @@
+//! Connection lifecycle events emitted by the stream task.
+
+use core::time::Duration;
+
+use betfair_stream_types::response::status_message::ErrorCode;
+use tokio::sync::broadcast;
+
+/// Capacity of the lifecycle event channel. Events are rare, so a small buffer is plenty; slow
+/// receivers skip the oldest events instead of stalling the stream task.
+pub(crate) const LIFECYCLE_CHANNEL_CAPACITY: usize = 64;
+
+/// State changes of the underlying stream connection.
+///
+/// Received through [`BetfairStreamClient::lifecycle`](crate::BetfairStreamClient::lifecycle).
+/// Use these to react to a stale feed (e.g. pull quotes on [`LifecycleEvent::Disconnected`])
+/// rather than inferring it from the data messages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LifecycleEvent {
+    /// A TCP connection to the stream endpoint is being opened.
+    Connecting,
+    /// The connection is established and the authentication handshake succeeded.
+    Connected {
+        /// The connection id assigned by Betfair.
+        connection_id: Option<String>,
+    },
+    /// An established connection was lost. No data will arrive until the next
+    /// [`LifecycleEvent::Connected`].
+    Disconnected {
+        /// Why the connection was dropped.
+        reason: String,
+    },
+    /// Waiting before the next connection attempt.
+    Reconnecting {
+        /// The number of consecutive failed attempts, starting from 1.
+        attempt: usize,
+        /// How long the task waits before trying again.
+        delay: Duration,
+    },
+    /// The subscriptions active before the disconnect were sent again on the new connection.
+    Resubscribed,
+    /// The stream task closed the connection because a shutdown was requested through the
+    /// [`ShutdownHandle`](crate::ShutdownHandle).
+    ShutDown,
+    /// The stream task hit an unrecoverable error and has stopped.
+    Fatal {
+        /// Description of the error that stopped the stream task.
+        reason: String,
+        /// The error code Betfair refused the connection with, if that is what stopped it.
+        error_code: Option<ErrorCode>,
+    },
+}
+
+/// Sending half of the lifecycle event channel.
+#[derive(Debug, Clone)]
+pub(crate) struct Lifecycle {
+    tx: broadcast::Sender<LifecycleEvent>,
+}
+
+impl Lifecycle {
+    pub(crate) fn new() -> (Self, broadcast::Receiver<LifecycleEvent>) {
+        let (tx, rx) = broadcast::channel(LIFECYCLE_CHANNEL_CAPACITY);
+        (Self { tx }, rx)
+    }
+
+    /// Publish an event; it is fine if nobody is listening.
+    pub(crate) fn emit(&self, event: LifecycleEvent) {
+        tracing::debug!(?event, "stream lifecycle");
+        let _ = self.tx.send(event);
+    }
+}
@@ -0,0 +1,665 @@
+//! Spreads market subscriptions over several stream connections sharing one session.
+//!
+//! Betfair limits how many markets a single connection may subscribe to. The pool opens
+//! connections as they are needed, places every market on a connection with spare capacity and
+//! merges the output of all connections into one channel. When markets close their slots are
+//! freed and connections that are no longer needed are emptied and closed.
+
+use core::fmt;
+use core::time::Duration;
+use std::collections::HashSet;
+use std::sync::{Arc, PoisonError, Weak};
+
+use betfair_adapter::ApiError;
+use betfair_adapter::betfair_types::types::sports_aping::MarketId;
+use betfair_stream_types::request::market_subscription_message::{
+    Fields, LadderLevel, MarketFilter,
+};
+use betfair_stream_types::response::market_change_message::StreamMarketDefinitionStatus;
+use betfair_stream_types::response::status_message::{ErrorCode, StatusError, StatusMessage};
+use tokio::sync::Mutex;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+
+use crate::cache::market_subscriber::MarketSubscriber;
+use crate::{
+    AckError, BetfairStreamBuilder, BetfairStreamClient, CachedMessage, LifecycleEvent,
+    MessageProcessor, SharedSession, ShutdownHandle, StatsSnapshot, StreamStats,
+};
+
+/// How many markets are placed on one connection unless configured otherwise; Betfair's default
+/// subscription limit.
+pub const DEFAULT_MAX_MARKETS_PER_CONNECTION: usize = 200;
+
+/// How many connections the pool opens at most unless configured otherwise; Betfair's default
+/// connection limit.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 10;
+
+/// How long the pool waits for a new connection to be established unless configured otherwise.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Builder for a pool of stream connections that share one session.
+///
+/// Every connection is started from a clone of [`Self::builder`], so all of them use the same
+/// processor configuration, heartbeat, reconnect policy and transport.
+///
+/// # Type Parameters
+///
+/// - `T`: The `MessageProcessor` of every connection. It has to produce [`CachedMessage`]s so
+///   that the pool can see when markets close.
+#[derive(Debug, Clone)]
+pub struct BetfairStreamPool<T: MessageProcessor> {
+    /// Template for every connection of the pool
+    pub builder: BetfairStreamBuilder<T>,
+    /// How many markets are placed on a single connection
+    pub max_markets_per_connection: usize,
+    /// How many connections are opened at most
+    pub max_connections: usize,
+    /// How long to wait for a new connection to be established
+    pub connect_timeout: Duration,
+    /// The market data fields every connection subscribes to
+    pub market_data_fields: Vec<Fields>,
+    /// The ladder level every connection subscribes to
+    pub ladder_level: Option<LadderLevel>,
+}
+
+impl<T> BetfairStreamPool<T>
+where
+    T: MessageProcessor<Output = CachedMessage> + Clone,
+{
+    /// Creates a new `BetfairStreamPool` from a template builder.
+    ///
+    /// # Parameters
+    ///
+    /// * `builder` - The builder every connection is started from.
+    /// * `market_data_fields` - The market data fields to subscribe to.
+    ///
+    /// # Returns
+    ///
+    /// A `BetfairStreamPool` using Betfair's default market and connection limits.
+    pub const fn new(builder: BetfairStreamBuilder<T>, market_data_fields: Vec<Fields>) -> Self {
+        Self {
+            builder,
+            max_markets_per_connection: DEFAULT_MAX_MARKETS_PER_CONNECTION,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            market_data_fields,
+            ladder_level: None,
+        }
+    }
+
+    /// Sets how many markets are placed on a single connection.
+    ///
+    /// A connection that is rejected with `SUBSCRIPTION_LIMIT_EXCEEDED` before reaching this
+    /// number is not given more markets.
+    ///
+    /// # Parameters
+    ///
+    /// * `max_markets` - The number of markets per connection.
+    ///
+    /// # Returns
+    ///
+    /// The updated `BetfairStreamPool`.
+    #[must_use]
+    pub const fn with_max_markets_per_connection(mut self, max_markets: usize) -> Self {
+        self.max_markets_per_connection = max_markets;
+        self
+    }
+
+    /// Sets how many connections are opened at most.
+    ///
+    /// # Parameters
+    ///
+    /// * `max_connections` - The number of connections.
+    ///
+    /// # Returns
+    ///
+    /// The updated `BetfairStreamPool`.
+    #[must_use]
+    pub const fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Sets how long to wait for a new connection to be established.
+    ///
+    /// Every operation of the pool waits while a connection is opened, so this bounds how long
+    /// an unreachable stream endpoint can block the pool.
+    ///
+    /// # Parameters
+    ///
+    /// * `connect_timeout` - The duration to wait for a connection.
+    ///
+    /// # Returns
+    ///
+    /// The updated `BetfairStreamPool`.
+    #[must_use]
+    pub const fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// Sets the ladder level for depth-based ladders.
+    ///
+    /// # Parameters
+    ///
+    /// * `ladder_level` - An optional `LadderLevel`.
+    ///
+    /// # Returns
+    ///
+    /// The updated `BetfairStreamPool`.
+    #[must_use]
+    pub fn with_ladder_level(mut self, ladder_level: Option<LadderLevel>) -> Self {
+        self.ladder_level = ladder_level;
+        self
+    }
+
+    /// Logs in (unless the template builder already has a session) and starts the pool.
+    ///
+    /// No connection is opened until the first market is subscribed.
+    ///
+    /// # Type Parameters
+    ///
+    /// * `C` - The capacity of the internal message channels, including the merged output.
+    ///
+    /// # Errors
+    /// If logging in fails.
+    pub async fn start<const C: usize>(mut self) -> eyre::Result<BetfairStreamPoolClient<T>> {
+        if self.builder.session.is_none() {
+            let session =
+                SharedSession::login(self.builder.client.clone(), self.builder.reconnect_policy)
+                    .await?;
+            self.builder.session = Some(session);
+        }
+
+        let (output, sink) = mpsc::channel(C);
+        let (closed, closed_rx) = mpsc::unbounded_channel();
+        let summaries = Arc::default();
+        let shards = Arc::new(Mutex::new(Shards {
+            connect: BetfairStreamBuilder::<T>::start::<C>,
+            pool: self,
+            output,
+            closed,
+            connections: Vec::new(),
+            summaries: Arc::clone(&summaries),
+            next_connection_id: 0,
+        }));
+        tokio::spawn(release_closed_markets(Arc::downgrade(&shards), closed_rx));
+
+        Ok(BetfairStreamPoolClient {
+            sink,
+            shards,
+            summaries,
+        })
+    }
+}
+
+/// Handle to a running [`BetfairStreamPool`].
+///
+/// Dropping it closes every connection of the pool.
+#[derive(Debug)]
+pub struct BetfairStreamPoolClient<T: MessageProcessor> {
+    /// Receive the messages of all connections
+    pub sink: Receiver<CachedMessage>,
+    shards: Arc<Mutex<Shards<T>>>,
+    summaries: Summaries,
+}
+
+impl<T> BetfairStreamPoolClient<T>
+where
+    T: MessageProcessor<Output = CachedMessage> + Clone,
+{
+    /// Subscribe to a market on a connection with spare capacity, opening a new connection if
+    /// needed, and wait until Betfair accepted the subscription.
+    ///
+    /// Subscribing to a market that is already subscribed does nothing.
+    ///
+    /// # Parameters
+    /// - `market_id`: The `MarketId` of the market to subscribe to.
+    ///
+    /// # Errors
+    /// If all connections are full, a new connection cannot be established or Betfair rejects
+    /// the subscription.
+    pub async fn subscribe_to_market(&self, market_id: MarketId) -> Result<(), PoolError> {
+        self.shards.lock().await.subscribe(market_id).await
+    }
+
+    /// Unsubscribe from a market and close connections that are no longer needed.
+    ///
+    /// # Parameters
+    /// - `market_id`: A reference to the `MarketId` of the market to unsubscribe from.
+    ///
+    /// # Errors
+    /// If the updated subscription cannot be sent.
+    pub async fn unsubscribe_from_market(&self, market_id: &MarketId) -> Result<(), PoolError> {
+        let mut shards = self.shards.lock().await;
+        shards.release(market_id).await?;
+        shards.rebalance().await;
+        Ok(())
+    }
+
+    /// The number of markets subscribed on every open connection.
+    ///
+    /// Does not wait for a subscription or connection that is in progress; it is reported once
+    /// it has completed.
+    #[must_use]
+    pub fn markets_per_connection(&self) -> Vec<usize> {
+        self.summaries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|summary| summary.markets)
+            .collect()
+    }
+
+    /// The statistics of every open connection.
+    ///
+    /// Like [`Self::markets_per_connection`], this does not wait for pending operations.
+    #[must_use]
+    pub fn stats(&self) -> Vec<StatsSnapshot> {
+        self.summaries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|summary| summary.stats.snapshot())
+            .collect()
+    }
+
+    /// Gracefully close every connection of the pool and wait until they are closed.
+    ///
+    /// See [`ShutdownHandle::shutdown`].
+    pub async fn shutdown(&self) {
+        self.shards.lock().await.shutdown().await;
+    }
+
+    /// Gracefully close every connection of the pool, then log out of the session the
+    /// connections share.
+    ///
+    /// # Errors
+    /// If the logout request fails.
+    pub async fn shutdown_and_logout(&self) -> Result<(), ApiError> {
+        let mut shards = self.shards.lock().await;
+        shards.shutdown().await;
+        match shards.pool.builder.session {
+            Some(ref session) => session.logout().await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Why the pool could not subscribe or unsubscribe a market.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PoolError {
+    /// Every connection is at its limit and no further connection may be opened.
+    Full,
+    /// A new connection stopped before it was established.
+    Connection(String),
+    /// A new connection was not established within [`BetfairStreamPool::connect_timeout`].
+    ConnectTimeout,
+    /// The stream task of the connection has stopped.
+    Disconnected,
+    /// Betfair did not answer the subscription.
+    Ack(AckError),
+    /// Betfair rejected the subscription.
+    Rejected(StatusError),
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Full => write!(f, "all stream connections are full"),
+            Self::Connection(ref reason) => write!(f, "could not open stream connection: {reason}"),
+            Self::ConnectTimeout => write!(f, "timed out opening stream connection"),
+            Self::Disconnected => write!(f, "stream connection has stopped"),
+            Self::Ack(ref err) => write!(f, "{err}"),
+            Self::Rejected(ref err) => write!(f, "subscription rejected: {:?}", err.error_code),
+        }
+    }
+}
+
+impl core::error::Error for PoolError {}
+
+type Connect<T> =
+    fn(BetfairStreamBuilder<T>) -> (BetfairStreamClient<T>, JoinHandle<eyre::Result<()>>);
+
+/// What the pool client reports about its connections, readable while the shards are locked
+/// for a subscription.
+type Summaries = Arc<std::sync::Mutex<Vec<ConnectionSummary>>>;
+
+#[derive(Debug)]
+struct Shards<T: MessageProcessor> {
+    connect: Connect<T>,
+    pool: BetfairStreamPool<T>,
+    output: Sender<CachedMessage>,
+    closed: UnboundedSender<MarketId>,
+    connections: Vec<Connection>,
+    /// Updated from `connections` whenever an operation on them has completed.
+    summaries: Summaries,
+    next_connection_id: usize,
+}
+
+/// The markets and statistics of a connection, as reported by the pool client.
+#[derive(Debug)]
+struct ConnectionSummary {
+    markets: usize,
+    stats: StreamStats,
+}
+
+/// A single connection of the pool and the markets placed on it.
+#[derive(Debug)]
+struct Connection {
+    id: usize,
+    subscriber: MarketSubscriber,
+    markets: HashSet<MarketId>,
+    /// How many markets this connection can take, lowered when Betfair rejects a subscription.
+    capacity: usize,
+    shutdown: ShutdownHandle,
+    stats: StreamStats,
+    task: JoinHandle<eyre::Result<()>>,
+}
+
+impl Connection {
+    /// Remove a market from the subscription of this connection.
+    ///
+    /// Unlike [`MarketSubscriber::unsubscribe_from_market`] this resubscribes the remaining
+    /// markets, so Betfair stops sending the market and it no longer counts towards the limit.
+    async fn unsubscribe(&mut self, market_id: &MarketId) -> Result<(), PoolError> {
+        let sent = match self.subscriber.unsubscribe_from_market(market_id).await {
+            Ok(Some(_ack)) => Ok(()),
+            Ok(None) => self.subscriber.resubscribe().await.map(drop),
+            Err(err) => Err(err),
+        };
+        sent.map_err(|_err| PoolError::Disconnected)
+    }
+
+    /// Gracefully close the connection and wait until its stream task has finished.
+    async fn close(self) {
+        self.shutdown.shutdown().await;
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl<T> Shards<T>
+where
+    T: MessageProcessor<Output = CachedMessage> + Clone,
+{
+    async fn subscribe(&mut self, market_id: MarketId) -> Result<(), PoolError> {
+        if self
+            .connections
+            .iter()
+            .any(|connection| connection.markets.contains(&market_id))
+        {
+            return Ok(());
+        }
+        let placed = self.place(market_id, None).await;
+        self.summarize();
+        placed
+    }
+
+    /// Publish the current markets and statistics of every connection to the pool client.
+    fn summarize(&self) {
+        *self
+            .summaries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = self
+            .connections
+            .iter()
+            .map(|connection| ConnectionSummary {
+                markets: connection.markets.len(),
+                stats: connection.stats.clone(),
+            })
+            .collect();
+    }
+
+    /// Subscribe to a market on a connection with spare capacity other than `skip`.
+    async fn place(&mut self, market_id: MarketId, skip: Option<usize>) -> Result<(), PoolError> {
+        loop {
+            let index = match self.connections.iter().position(|connection| {
+                Some(connection.id) != skip && connection.markets.len() < connection.capacity
+            }) {
+                Some(index) => index,
+                None => self.open_connection().await?,
+            };
+            let connection = &mut self.connections[index];
+            let status = connection
+                .subscriber
+                .subscribe_to_market(market_id.clone())
+                .await
+                .map_err(|_err| PoolError::Disconnected)?
+                .await
+                .map_err(PoolError::Ack)?;
+
+            match status {
+                StatusMessage::Success(_) => {
+                    connection.markets.insert(market_id);
+                    return Ok(());
+                }
+                StatusMessage::Failure(err)
+                    if err.error_code == ErrorCode::SubscriptionLimitExceeded =>
+                {
+                    tracing::warn!(
+                        connection = connection.id,
+                        markets = connection.markets.len(),
+                        "subscription limit exceeded, connection is full"
+                    );
+                    connection.capacity = connection.markets.len();
+                    // restore the subscription that was accepted before
+                    connection.unsubscribe(&market_id).await?;
+                }
+                StatusMessage::Failure(err) => {
+                    drop(connection.unsubscribe(&market_id).await);
+                    return Err(PoolError::Rejected(err));
+                }
+            }
+        }
+    }
+
+    /// Open a new connection and wait until it is established, returning its index.
+    async fn open_connection(&mut self) -> Result<usize, PoolError> {
+        if self.connections.len() >= self.pool.max_connections {
+            return Err(PoolError::Full);
+        }
+
+        let (client, task) = (self.connect)(self.pool.builder.clone());
+        let subscriber = MarketSubscriber::new(
+            &client,
+            MarketFilter::default(),
+            self.pool.market_data_fields.clone(),
+            self.pool.ladder_level.clone(),
+        );
+        let BetfairStreamClient {
+            sink,
+            mut lifecycle,
+            shutdown,
+            stats,
+            ..
+        } = client;
+        tokio::spawn(forward(sink, self.output.clone(), self.closed.clone()));
+
+        let id = self.next_connection_id;
+        self.next_connection_id += 1;
+        let connection = Connection {
+            id,
+            subscriber,
+            markets: HashSet::new(),
+            capacity: self.pool.max_markets_per_connection,
+            shutdown,
+            stats,
+            task,
+        };
+
+        let connected = tokio::time::timeout(self.pool.connect_timeout, async {
+            loop {
+                match lifecycle.recv().await {
+                    Ok(LifecycleEvent::Connected { .. }) => return Ok(()),
+                    Ok(LifecycleEvent::Fatal {
+                        error_code: Some(ErrorCode::MaxConnectionLimitExceeded),
+                        ..
+                    }) => return Err(PoolError::Full),
+                    Ok(LifecycleEvent::Fatal { reason, .. }) => {
+                        return Err(PoolError::Connection(reason));
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => {
+                        return Err(PoolError::Connection("stream task stopped".to_owned()));
+                    }
+                }
+            }
+        })
+        .await;
+        match connected {
+            Ok(Ok(())) => {}
+            Ok(Err(PoolError::Full)) => {
+                tracing::warn!(
+                    connections = self.connections.len(),
+                    "connection limit exceeded, pool is full"
+                );
+                // Betfair allows fewer connections than configured, don't try again
+                self.pool.max_connections = self.connections.len();
+                return Err(PoolError::Full);
+            }
+            Ok(Err(err)) => return Err(err),
+            Err(_elapsed) => {
+                tracing::warn!(connection = id, "timed out opening pool connection");
+                // the stream task may still be retrying, stop it without holding up the pool
+                tokio::spawn(connection.close());
+                return Err(PoolError::ConnectTimeout);
+            }
+        }
+        tracing::info!(connection = id, "opened pool connection");
+
+        self.connections.push(connection);
+        Ok(self.connections.len() - 1)
+    }
+
+    async fn release(&mut self, market_id: &MarketId) -> Result<(), PoolError> {
+        let Some(connection) = self
+            .connections
+            .iter_mut()
+            .find(|connection| connection.markets.contains(market_id))
+        else {
+            return Ok(());
+        };
+        connection.markets.remove(market_id);
+        let unsubscribed = connection.unsubscribe(market_id).await;
+        self.summarize();
+        unsubscribed
+    }
+
+    /// Gracefully close every connection.
+    async fn shutdown(&mut self) {
+        let connections = core::mem::take(&mut self.connections);
+        self.summarize();
+        futures::future::join_all(
+            connections
+                .iter()
+                .map(|connection| connection.shutdown.shutdown()),
+        )
+        .await;
+        tracing::info!(connections = connections.len(), "closed pool connections");
+    }
+
+    /// Close the least used connection while its markets fit into the spare capacity of the
+    /// others.
+    async fn rebalance(&mut self) {
+        while self.connections.len() > 1 {
+            let Some((index, least_used)) = self
+                .connections
+                .iter()
+                .enumerate()
+                .min_by_key(|&(_, connection)| connection.markets.len())
+            else {
+                return;
+            };
+            let spare = self
+                .connections
+                .iter()
+                .enumerate()
+                .filter(|&(other, _)| other != index)
+                .map(|(_, connection)| connection.capacity.saturating_sub(connection.markets.len()))
+                .sum::<usize>();
+            if least_used.markets.len() > spare {
+                return;
+            }
+
+            // keep the connection open until its markets are subscribed elsewhere
+            let id = least_used.id;
+            let markets = least_used.markets.iter().cloned().collect::<Vec<_>>();
+            tracing::info!(
+                connection = id,
+                markets = markets.len(),
+                "moving markets off pool connection"
+            );
+            for market_id in markets {
+                if let Err(err) = self.place(market_id.clone(), Some(id)).await {
+                    tracing::warn!(
+                        ?err,
+                        ?market_id,
+                        "could not move market to another connection"
+                    );
+                    // the connection stays open for the markets that could not be moved
+                    return;
+                }
+                if let Some(connection) = self.connections.iter_mut().find(|c| c.id == id) {
+                    connection.markets.remove(&market_id);
+                }
+                self.summarize();
+            }
+
+            let Some(index) = self.connections.iter().position(|c| c.id == id) else {
+                return;
+            };
+            let connection = self.connections.remove(index);
+            self.summarize();
+            tracing::info!(connection = id, "closing pool connection");
+            connection.close().await;
+        }
+    }
+}
+
+/// Forward the output of one connection into the merged output, reporting closed markets.
+async fn forward(
+    mut sink: Receiver<CachedMessage>,
+    output: Sender<CachedMessage>,
+    closed: UnboundedSender<MarketId>,
+) {
+    while let Some(message) = sink.recv().await {
+        if let CachedMessage::MarketChange(ref markets) = message {
+            for market in markets {
+                let is_closed = market.market_definition().is_some_and(|definition| {
+                    definition.status == StreamMarketDefinitionStatus::Closed
+                });
+                if is_closed {
+                    drop(closed.send(market.market_id().clone()));
+                }
+            }
+        }
+        if output.send(message).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn release_closed_markets<T>(
+    shards: Weak<Mutex<Shards<T>>>,
+    mut closed: UnboundedReceiver<MarketId>,
+) where
+    T: MessageProcessor<Output = CachedMessage> + Clone,
+{
+    while let Some(market_id) = closed.recv().await {
+        let Some(shards) = shards.upgrade() else {
+            return;
+        };
+        let mut shards = shards.lock().await;
+        if let Err(err) = shards.release(&market_id).await {
+            tracing::warn!(?err, ?market_id, "could not unsubscribe from closed market");
+        }
+        shards.rebalance().await;
+    }
+}
@@ -0,0 +1,199 @@
+//! Retry policy used when (re)connecting, handshaking and re-authenticating with the stream.
+
+use core::time::Duration;
+
+use backon::{BackoffBuilder, ExponentialBackoff, ExponentialBuilder};
+
+/// Controls how the stream task waits between failed connection attempts.
+///
+/// Delays grow exponentially from [`Self::initial_delay`] up to [`Self::max_delay`]. The same
+/// policy is applied to TCP/TLS connection errors, handshake failures that ask to wait and retry,
+/// and to logging in again when Betfair rejects the session.
+///
+/// The default policy never gives up, which is what a long running trading process wants: if
+/// Betfair is unreachable for a few minutes the stream task keeps retrying instead of exiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// Delay before the first retry.
+    pub initial_delay: Duration,
+    /// Upper bound for the delay between two retries.
+    pub max_delay: Duration,
+    /// Add a random jitter of up to the current delay to every retry, so that many clients do
+    /// not reconnect in lockstep.
+    pub jitter: bool,
+    /// Maximum number of consecutive failed attempts before the stream task gives up.
+    /// `None` retries forever.
+    pub max_attempts: Option<usize>,
+    /// Once a connection has stayed up for this long, the backoff is reset and the next
+    /// disconnect starts again from [`Self::initial_delay`].
+    pub reset_after: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            jitter: true,
+            max_attempts: None,
+            reset_after: Duration::from_secs(60),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Sets the delay before the first retry.
+    #[must_use]
+    pub const fn with_initial_delay(mut self, initial_delay: Duration) -> Self {
+        self.initial_delay = initial_delay;
+        self
+    }
+
+    /// Sets the upper bound for the delay between two retries.
+    #[must_use]
+    pub const fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Enables or disables jitter.
+    #[must_use]
+    pub const fn with_jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Gives up after `max_attempts` consecutive failed attempts.
+    #[must_use]
+    pub const fn with_max_attempts(mut self, max_attempts: usize) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    /// Retries forever.
+    #[must_use]
+    pub const fn with_infinite_attempts(mut self) -> Self {
+        self.max_attempts = None;
+        self
+    }
+
+    /// Sets how long a connection must stay healthy before the backoff is reset.
+    #[must_use]
+    pub const fn with_reset_after(mut self, reset_after: Duration) -> Self {
+        self.reset_after = reset_after;
+        self
+    }
+}
+
+impl BackoffBuilder for ReconnectPolicy {
+    type Backoff = ExponentialBackoff;
+
+    fn build(self) -> Self::Backoff {
+        let mut builder = ExponentialBuilder::new()
+            .with_min_delay(self.initial_delay)
+            .with_max_delay(self.max_delay);
+        if self.jitter {
+            builder = builder.with_jitter();
+        }
+        builder = match self.max_attempts {
+            Some(max_attempts) => builder.with_max_times(max_attempts),
+            None => builder.without_max_times(),
+        };
+        builder.build()
+    }
+}
+
+/// Backoff state for a streak of consecutive failed attempts, numbering each retry.
+#[derive(Debug)]
+pub(crate) struct ReconnectBackoff {
+    policy: ReconnectPolicy,
+    backoff: ExponentialBackoff,
+    attempt: usize,
+}
+
+impl ReconnectBackoff {
+    pub(crate) fn new(policy: ReconnectPolicy) -> Self {
+        Self {
+            policy,
+            backoff: policy.build(),
+            attempt: 0,
+        }
+    }
+
+    /// Start again from the initial delay.
+    pub(crate) fn reset(&mut self) {
+        *self = Self::new(self.policy);
+    }
+
+    /// The number (starting from 1) and delay of the next retry, or `None` once the policy is
+    /// exhausted.
+    pub(crate) fn next_delay(&mut self) -> Option<(usize, Duration)> {
+        let delay = self.backoff.next()?;
+        self.attempt = self.attempt.saturating_add(1);
+        Some((self.attempt, delay))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn delays_grow_up_to_max_delay() {
+        let policy = ReconnectPolicy::default()
+            .with_jitter(false)
+            .with_initial_delay(Duration::from_secs(1))
+            .with_max_delay(Duration::from_secs(5))
+            .with_max_attempts(5);
+
+        let delays = policy.build().collect::<Vec<_>>();
+
+        assert_eq!(
+            delays,
+            vec![
+                Duration::from_secs(1),
+                Duration::from_secs(2),
+                Duration::from_secs(4),
+                Duration::from_secs(5),
+                Duration::from_secs(5),
+            ]
+        );
+    }
+
+    #[test]
+    fn default_policy_never_gives_up() {
+        let policy = ReconnectPolicy::default();
+        assert_eq!(policy.max_attempts, None);
+        assert_eq!(policy.build().take(1000).count(), 1000);
+    }
+
+    #[test]
+    fn jitter_stays_within_one_delay() {
+        let policy = ReconnectPolicy::default()
+            .with_initial_delay(Duration::from_millis(100))
+            .with_max_attempts(1);
+
+        let delay = policy.build().next().unwrap();
+
+        assert!(delay >= Duration::from_millis(100));
+        assert!(delay <= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn backoff_numbers_attempts_and_resets() {
+        let policy = ReconnectPolicy::default()
+            .with_jitter(false)
+            .with_initial_delay(Duration::from_secs(1))
+            .with_max_attempts(2);
+        let mut backoff = ReconnectBackoff::new(policy);
+
+        assert_eq!(backoff.next_delay(), Some((1, Duration::from_secs(1))));
+        assert_eq!(backoff.next_delay(), Some((2, Duration::from_secs(2))));
+        assert_eq!(backoff.next_delay(), None);
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Some((1, Duration::from_secs(1))));
+    }
+}
@@ -0,0 +1,583 @@
+//! Records the raw stream traffic to NDJSON tape files.
+//!
+//! Every frame is written as a single line containing the original JSON object, extended with
+//! three recorder fields:
+//!
+//! - `"_ts"`: local time in milliseconds since the unix epoch when the frame was received or sent
+//! - `"_dir"`: `"in"` for messages from Betfair, `"out"` for requests sent to Betfair
+//! - `"_conn"`: the connection id announced by Betfair for the connection the frame belongs to
+//!
+//! The extra fields are ignored when deserializing, so inbound lines can be fed to
+//! [`StreamAPIClientCodec`](crate::StreamAPIClientCodec) like the files under `fixtures/`.
+//! Session tokens and application keys are redacted from recorded authentication requests.
+
+use core::time::Duration;
+use std::fs::File;
+use std::io::{self, BufWriter, Write as _};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use betfair_stream_types::request::RequestMessage;
+use betfair_stream_types::response::ResponseMessage;
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+
+use crate::{MessageProcessor, StreamAlarm};
+
+/// Placeholder written instead of secrets in recorded authentication requests.
+const REDACTED: &str = "REDACTED";
+
+/// Compression applied to tape files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Plain `.ndjson` files.
+    #[default]
+    None,
+    /// Gzip compressed `.ndjson.gz` files.
+    Gzip,
+}
+
+impl Compression {
+    const fn extension(self) -> &'static str {
+        match self {
+            Self::None => "ndjson",
+            Self::Gzip => "ndjson.gz",
+        }
+    }
+}
+
+/// Where and how a [`Recorder`] writes its tape files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecorderConfig {
+    /// Directory the tape files are written to. Created if it does not exist.
+    pub directory: PathBuf,
+    /// Prefix of every file name, followed by the creation time and a sequence number.
+    pub file_prefix: String,
+    /// Compression applied to the files.
+    pub compression: Compression,
+    /// Start a new file once this many bytes (before compression) were written to the current
+    /// one. `None` never rotates by size.
+    pub max_file_size: Option<u64>,
+    /// Start a new file once the current one is this old. `None` never rotates by age.
+    pub max_file_age: Option<Duration>,
+    /// How many frames may wait for the writer thread. Frames arriving while the queue is full
+    /// are dropped, see [`Recorder::dropped_frames`].
+    pub queue_capacity: usize,
+}
+
+impl RecorderConfig {
+    /// Uncompressed files in `directory`, rotated every hour or after 256 MiB, queueing up to
+    /// 65536 frames.
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+            file_prefix: "stream".to_owned(),
+            compression: Compression::None,
+            max_file_size: Some(256 * 1024 * 1024),
+            max_file_age: Some(Duration::from_secs(60 * 60)),
+            queue_capacity: 65_536,
+        }
+    }
+
+    /// Sets the prefix of every file name.
+    #[must_use]
+    pub fn with_file_prefix(mut self, file_prefix: impl Into<String>) -> Self {
+        self.file_prefix = file_prefix.into();
+        self
+    }
+
+    /// Sets the compression applied to the files.
+    #[must_use]
+    pub const fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets the size after which a new file is started.
+    #[must_use]
+    pub const fn with_max_file_size(mut self, max_file_size: Option<u64>) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    /// Sets the age after which a new file is started.
+    #[must_use]
+    pub const fn with_max_file_age(mut self, max_file_age: Option<Duration>) -> Self {
+        self.max_file_age = max_file_age;
+        self
+    }
+
+    /// Sets how many frames may wait for the writer thread.
+    #[must_use]
+    pub const fn with_queue_capacity(mut self, queue_capacity: usize) -> Self {
+        self.queue_capacity = queue_capacity;
+        self
+    }
+}
+
+/// [`MessageProcessor`] wrapper that records every raw frame before handing it to the inner
+/// processor.
+///
+/// Files are written on a dedicated thread so the stream task never blocks on disk I/O. If the
+/// writer falls behind and its queue is full, frames are dropped rather than buffered without
+/// limit. Dropping the recorder flushes and closes the current file.
+///
+/// ```no_run
+/// # fn example(client: betfair_adapter::BetfairRpcClient<betfair_adapter::Unauthenticated>) -> std::io::Result<()> {
+/// use betfair_stream_api::{BetfairStreamBuilder, Cache, Recorder, RecorderConfig};
+///
+/// let recorder = Recorder::new(Cache::new(), RecorderConfig::new("./tapes"))?;
+/// let builder = BetfairStreamBuilder::<Cache>::new(client).with_processor(recorder);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Recorder<P> {
+    inner: P,
+    connection_id: Option<String>,
+    lines: Option<mpsc::SyncSender<String>>,
+    writer: Option<JoinHandle<()>>,
+    /// Frames dropped because the writer's queue was full.
+    dropped: u64,
+    /// Whether the previous frame was dropped, to warn once per burst.
+    dropping: bool,
+}
+
+impl<P: MessageProcessor> Recorder<P> {
+    /// Wraps `inner`, recording into the files described by `config`.
+    ///
+    /// # Errors
+    /// if the directory cannot be created or the first file cannot be opened
+    pub fn new(inner: P, config: RecorderConfig) -> io::Result<Self> {
+        std::fs::create_dir_all(&config.directory)?;
+        let (lines, rx) = mpsc::sync_channel(config.queue_capacity);
+        let writer = TapeWriter::new(config)?;
+        let writer = std::thread::Builder::new()
+            .name("betfair-stream-recorder".to_owned())
+            .spawn(move || writer.run(&rx))?;
+        Ok(Self {
+            inner,
+            connection_id: None,
+            lines: Some(lines),
+            writer: Some(writer),
+            dropped: 0,
+            dropping: false,
+        })
+    }
+
+    /// How many frames were not recorded because the writer could not keep up.
+    pub const fn dropped_frames(&self) -> u64 {
+        self.dropped
+    }
+
+    /// The wrapped processor.
+    pub const fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    /// The wrapped processor.
+    pub const fn inner_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    fn record(&mut self, direction: Direction, raw: &[u8]) {
+        let Some(ref lines) = self.lines else {
+            return;
+        };
+        let timestamp = chrono::Utc::now().timestamp_millis();
+        let Some(line) = frame_line(direction, self.connection_id.as_deref(), timestamp, raw)
+        else {
+            tracing::warn!("recorder skipped a frame that is not a JSON object");
+            return;
+        };
+        match lines.try_send(line) {
+            Ok(()) => self.dropping = false,
+            Err(mpsc::TrySendError::Full(_)) => {
+                self.dropped = self.dropped.saturating_add(1);
+                if !self.dropping {
+                    tracing::warn!(
+                        dropped = self.dropped,
+                        "recorder writer is falling behind, dropping frames"
+                    );
+                }
+                self.dropping = true;
+            }
+            Err(mpsc::TrySendError::Disconnected(_)) => {
+                tracing::error!("recorder writer has stopped, no longer recording");
+                self.lines = None;
+            }
+        }
+    }
+}
+
+impl<P: MessageProcessor> MessageProcessor for Recorder<P> {
+    type Output = P::Output;
+
+    fn on_message_received(&mut self, raw: Bytes, message: &ResponseMessage) {
+        if let ResponseMessage::Connection(connection) = message {
+            self.connection_id.clone_from(&connection.connection_id);
+        }
+        self.record(Direction::Inbound, &raw);
+        self.inner.on_message_received(raw, message);
+    }
+
+    fn on_message_sent(&mut self, message: &RequestMessage) {
+        let redacted;
+        let recorded = if let RequestMessage::Authentication(auth) = message {
+            let mut auth = auth.clone();
+            REDACTED.clone_into(&mut auth.session);
+            REDACTED.clone_into(&mut auth.app_key);
+            redacted = RequestMessage::Authentication(auth);
+            &redacted
+        } else {
+            message
+        };
+        match serde_json::to_vec(recorded) {
+            Ok(raw) => self.record(Direction::Outbound, &raw),
+            Err(err) => tracing::warn!(?err, "recorder could not serialize request"),
+        }
+        self.inner.on_message_sent(message);
+    }
+
+    fn process_message(&mut self, message: ResponseMessage) -> Option<Self::Output> {
+        self.inner.process_message(message)
+    }
+
+    fn on_alarm(&mut self, alarm: &StreamAlarm) -> Option<Self::Output> {
+        self.inner.on_alarm(alarm)
+    }
+
+    fn resubscribe_requested(&mut self) -> bool {
+        self.inner.resubscribe_requested()
+    }
+
+    fn next_output(&mut self) -> Option<Self::Output> {
+        self.inner.next_output()
+    }
+}
+
+impl<P> Drop for Recorder<P> {
+    fn drop(&mut self) {
+        // closing the channel lets the writer drain the remaining lines and finish the file
+        self.lines = None;
+        if let Some(writer) = self.writer.take()
+            && writer.join().is_err()
+        {
+            tracing::error!("recorder writer panicked");
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Inbound,
+    Outbound,
+}
+
+impl Direction {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Inbound => "in",
+            Self::Outbound => "out",
+        }
+    }
+}
+
+/// Insert the recorder fields at the start of the JSON object in `raw`.
+fn frame_line(
+    direction: Direction,
+    connection_id: Option<&str>,
+    timestamp_ms: i64,
+    raw: &[u8],
+) -> Option<String> {
+    let raw = core::str::from_utf8(raw).ok()?.trim();
+    let body = raw.strip_prefix('{')?;
+
+    let mut line = String::with_capacity(raw.len().saturating_add(64));
+    line.push_str("{\"_ts\":");
+    line.push_str(&timestamp_ms.to_string());
+    line.push_str(",\"_dir\":\"");
+    line.push_str(direction.as_str());
+    line.push('"');
+    if let Some(connection_id) = connection_id {
+        line.push_str(",\"_conn\":");
+        line.push_str(&serde_json::to_string(connection_id).ok()?);
+    }
+    if !body.trim_start().starts_with('}') {
+        line.push(',');
+    }
+    line.push_str(body);
+    Some(line)
+}
+
+/// Owns the current tape file and rotates it.
+struct TapeWriter {
+    config: RecorderConfig,
+    file: TapeFile,
+    opened_at: Instant,
+    written: u64,
+    sequence: u32,
+}
+
+enum TapeFile {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+impl TapeFile {
+    fn create(path: &Path, compression: Compression) -> io::Result<Self> {
+        let file = BufWriter::new(File::create_new(path)?);
+        Ok(match compression {
+            Compression::None => Self::Plain(file),
+            Compression::Gzip => Self::Gzip(GzEncoder::new(file, flate2::Compression::default())),
+        })
+    }
+
+    fn writer(&mut self) -> &mut dyn io::Write {
+        match *self {
+            Self::Plain(ref mut file) => file,
+            Self::Gzip(ref mut file) => file,
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Self::Plain(mut file) => file.flush(),
+            Self::Gzip(file) => file.finish()?.flush(),
+        }
+    }
+}
+
+impl TapeWriter {
+    fn new(config: RecorderConfig) -> io::Result<Self> {
+        let file = TapeFile::create(&file_path(&config, 0), config.compression)?;
+        Ok(Self {
+            config,
+            file,
+            opened_at: Instant::now(),
+            written: 0,
+            sequence: 0,
+        })
+    }
+
+    fn run(mut self, lines: &mpsc::Receiver<String>) {
+        let result = self.write_all(lines).and_then(|()| self.file.finish());
+        if let Err(err) = result {
+            tracing::error!(?err, "recorder failed to write tape file");
+        }
+    }
+
+    fn write_all(&mut self, lines: &mpsc::Receiver<String>) -> io::Result<()> {
+        while let Ok(line) = lines.recv() {
+            self.write_line(&line)?;
+            // batch whatever is already queued before touching the disk
+            while let Ok(line) = lines.try_recv() {
+                self.write_line(&line)?;
+            }
+            self.file.writer().flush()?;
+        }
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.should_rotate() {
+            self.rotate()?;
+        }
+        let writer = self.file.writer();
+        writer.write_all(line.as_bytes())?;
+        writer.write_all(b"\n")?;
+        self.written = self
+            .written
+            .saturating_add(u64::try_from(line.len()).unwrap_or(u64::MAX))
+            .saturating_add(1);
+        Ok(())
+    }
+
+    fn should_rotate(&self) -> bool {
+        if self.written == 0 {
+            return false;
+        }
+        let too_big = self
+            .config
+            .max_file_size
+            .is_some_and(|max_file_size| self.written >= max_file_size);
+        let too_old = self
+            .config
+            .max_file_age
+            .is_some_and(|max_file_age| self.opened_at.elapsed() >= max_file_age);
+        too_big || too_old
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.sequence = self.sequence.saturating_add(1);
+        let next = TapeFile::create(
+            &file_path(&self.config, self.sequence),
+            self.config.compression,
+        )?;
+        core::mem::replace(&mut self.file, next).finish()?;
+        self.opened_at = Instant::now();
+        self.written = 0;
+        Ok(())
+    }
+}
+
+fn file_path(config: &RecorderConfig, sequence: u32) -> PathBuf {
+    let created = chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ");
+    config.directory.join(format!(
+        "{}-{created}-{sequence:04}.{}",
+        config.file_prefix,
+        config.compression.extension()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read as _;
+
+    use betfair_stream_types::request::authentication_message::AuthenticationMessage;
+    use betfair_stream_types::request::heartbeat_message::HeartbeatMessage;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::Forwarder;
+
+    fn read_lines(directory: &Path) -> Vec<Vec<String>> {
+        let mut paths = std::fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths
+            .into_iter()
+            .map(|path| {
+                let mut contents = String::new();
+                let file = File::open(&path).unwrap();
+                if path.extension().is_some_and(|ext| ext == "gz") {
+                    flate2::read::GzDecoder::new(file)
+                        .read_to_string(&mut contents)
+                        .unwrap();
+                } else {
+                    io::BufReader::new(file)
+                        .read_to_string(&mut contents)
+                        .unwrap();
+                }
+                contents.lines().map(str::to_owned).collect()
+            })
+            .collect()
+    }
+
+    fn receive(recorder: &mut Recorder<Forwarder>, json: &'static str) {
+        let message = serde_json::from_str(json).unwrap();
+        recorder.on_message_received(Bytes::from_static(json.as_bytes()), &message);
+    }
+
+    #[test]
+    fn frame_line_adds_recorder_fields() {
+        let line = frame_line(Direction::Inbound, Some("002-1"), 42, br#"{"op":"status"}"#);
+        assert_eq!(
+            line.as_deref(),
+            Some(r#"{"_ts":42,"_dir":"in","_conn":"002-1","op":"status"}"#)
+        );
+
+        let line = frame_line(Direction::Outbound, None, 42, b"{}");
+        assert_eq!(line.as_deref(), Some(r#"{"_ts":42,"_dir":"out"}"#));
+
+        assert_eq!(frame_line(Direction::Inbound, None, 42, b"[]"), None);
+    }
+
+    #[test]
+    fn recorded_lines_still_parse_as_stream_messages() {
+        let json = r#"{"op":"mcm","id":1,"clk":"AAAAAAAA","pt":1,"ct":"HEARTBEAT"}"#;
+        let line = frame_line(Direction::Inbound, Some("002-1"), 42, json.as_bytes()).unwrap();
+
+        let original = serde_json::from_str::<ResponseMessage>(json).unwrap();
+        let recorded = serde_json::from_str::<ResponseMessage>(&line).unwrap();
+        assert_eq!(recorded, original);
+    }
+
+    #[test]
+    fn records_both_directions_and_redacts_secrets() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut recorder = Recorder::new(Forwarder, RecorderConfig::new(directory.path())).unwrap();
+
+        receive(
+            &mut recorder,
+            r#"{"op":"connection","connectionId":"002-1"}"#,
+        );
+        recorder.on_message_sent(&RequestMessage::Authentication(AuthenticationMessage {
+            id: Some(-1),
+            session: "session-token".to_owned(),
+            app_key: "app-key".to_owned(),
+        }));
+        receive(
+            &mut recorder,
+            r#"{"op":"status","id":-1,"statusCode":"SUCCESS"}"#,
+        );
+        drop(recorder);
+
+        let files = read_lines(directory.path());
+        assert_eq!(files.len(), 1);
+        let lines = files[0]
+            .iter()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["_dir"], "in");
+        assert_eq!(lines[0]["_conn"], "002-1");
+        assert_eq!(lines[1]["_dir"], "out");
+        assert_eq!(lines[1]["_conn"], "002-1");
+        assert_eq!(lines[1]["session"], REDACTED);
+        assert_eq!(lines[1]["appKey"], REDACTED);
+        assert_eq!(lines[2]["op"], "status");
+        assert!(lines.iter().all(|line| line["_ts"].is_i64()));
+    }
+
+    #[test]
+    fn drops_frames_while_the_queue_is_full() {
+        // a writer that never reads
+        let (lines, _rx) = mpsc::sync_channel(1);
+        let mut recorder = Recorder {
+            inner: Forwarder,
+            connection_id: None,
+            lines: Some(lines),
+            writer: None,
+            dropped: 0,
+            dropping: false,
+        };
+
+        for id in 0..3 {
+            recorder.on_message_sent(&RequestMessage::Heartbeat(HeartbeatMessage {
+                id: Some(id),
+            }));
+        }
+
+        assert_eq!(recorder.dropped_frames(), 2);
+    }
+
+    #[test]
+    fn rotates_compressed_files_by_size() {
+        let directory = tempfile::tempdir().unwrap();
+        let config = RecorderConfig::new(directory.path())
+            .with_compression(Compression::Gzip)
+            .with_max_file_size(Some(1));
+        let mut recorder = Recorder::new(Forwarder, config).unwrap();
+
+        for id in 0..3 {
+            recorder.on_message_sent(&RequestMessage::Heartbeat(HeartbeatMessage {
+                id: Some(id),
+            }));
+        }
+        drop(recorder);
+
+        let files = read_lines(directory.path());
+        assert_eq!(files.len(), 3);
+        for (id, lines) in files.iter().enumerate() {
+            assert_eq!(lines.len(), 1);
+            let line = serde_json::from_str::<serde_json::Value>(&lines[0]).unwrap();
+            assert_eq!(line["op"], "heartbeat");
+            assert_eq!(line["id"], id);
+        }
+    }
+}
@@ -0,0 +1,362 @@
+//! Replays Betfair historic data files through the same pipeline as the live stream.
+//!
+//! Historic data files (and the files under `fixtures/`) contain one stream message per line.
+//! Every line is decoded with [`StreamAPIClientCodec`] and handed to a [`MessageProcessor`], so
+//! the output is exactly what [`BetfairStreamClient::sink`](crate::BetfairStreamClient::sink)
+//! would have produced live. Tapes written by the [`Recorder`](crate::Recorder) are supported as
+//! well; the requests it recorded are skipped.
+
+use core::pin::Pin;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use async_compression::tokio::bufread::{BzDecoder, GzipDecoder};
+use betfair_stream_types::response::ResponseMessage;
+use bytes::{Buf as _, Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use eyre::Context as _;
+use futures::{FutureExt as _, StreamExt as _, future::BoxFuture};
+use tokio::io::{AsyncRead, BufReader};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, sleep_until};
+use tokio_util::codec::{Decoder, FramedRead};
+
+use crate::{Cache, Forwarder, MessageProcessor, StreamAPIClientCodec};
+
+/// How fast a replay feeds messages to the processor.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Pacing {
+    /// Process every message as soon as it has been read.
+    #[default]
+    AsFastAsPossible,
+    /// Wait between messages for as long as the difference of their publish times (`pt`), so
+    /// that the replay takes as long as the recorded session did.
+    RealTime,
+}
+
+/// Builder for replaying historic stream data files.
+///
+/// When multiple files are given they are read concurrently and their messages are merged in
+/// publish time order, e.g. to replay all markets of a race meeting as a single stream.
+///
+/// # Type Parameters
+///
+/// - `T`: A type that implements `MessageProcessor`, used to handle the replayed messages.
+#[derive(Debug, Clone)]
+pub struct StreamReplayBuilder<T: MessageProcessor> {
+    /// The files to replay. Files ending with `.gz` or `.bz2` are decompressed.
+    pub files: Vec<PathBuf>,
+    /// The intermediate processor of messages
+    pub processor: T,
+    /// How fast messages are replayed
+    pub pacing: Pacing,
+}
+
+impl<T: MessageProcessor> StreamReplayBuilder<T> {
+    /// Creates a new `StreamReplayBuilder` for the given files.
+    ///
+    /// Uses the default `Cache` message processor to maintain market and order caches and
+    /// replays as fast as possible.
+    ///
+    /// # Parameters
+    ///
+    /// * `files` - The historic data files to replay.
+    ///
+    /// # Returns
+    ///
+    /// A `StreamReplayBuilder` configured with cache-based message processing.
+    pub fn new(files: impl IntoIterator<Item = impl Into<PathBuf>>) -> StreamReplayBuilder<Cache> {
+        StreamReplayBuilder {
+            files: files.into_iter().map(Into::into).collect(),
+            processor: Cache::new(),
+            pacing: Pacing::default(),
+        }
+    }
+
+    /// Creates a new `StreamReplayBuilder` with raw message forwarding.
+    ///
+    /// # Parameters
+    ///
+    /// * `files` - The historic data files to replay.
+    ///
+    /// # Returns
+    ///
+    /// A `StreamReplayBuilder` configured to forward raw messages.
+    pub fn new_without_cache(
+        files: impl IntoIterator<Item = impl Into<PathBuf>>,
+    ) -> StreamReplayBuilder<Forwarder> {
+        StreamReplayBuilder {
+            files: files.into_iter().map(Into::into).collect(),
+            processor: Forwarder,
+            pacing: Pacing::default(),
+        }
+    }
+
+    /// Replaces the message processor.
+    ///
+    /// # Parameters
+    ///
+    /// * `processor` - The processor handling the replayed messages.
+    ///
+    /// # Returns
+    ///
+    /// A `StreamReplayBuilder` with the same files and pacing and the given processor.
+    pub fn with_processor<P: MessageProcessor>(self, processor: P) -> StreamReplayBuilder<P> {
+        StreamReplayBuilder {
+            files: self.files,
+            processor,
+            pacing: self.pacing,
+        }
+    }
+
+    /// Sets how fast messages are replayed.
+    ///
+    /// # Parameters
+    ///
+    /// * `pacing` - The pacing to use.
+    ///
+    /// # Returns
+    ///
+    /// The updated `StreamReplayBuilder`.
+    pub fn with_pacing(mut self, pacing: Pacing) -> Self {
+        self.pacing = pacing;
+        self
+    }
+
+    /// Starts the replay and returns the receiver of processed messages.
+    ///
+    /// The spawned task completes once every file has been replayed or the receiver is dropped.
+    ///
+    /// # Type Parameters
+    ///
+    /// * `C` - The capacity of the output channel.
+    /// * `Sp` - The type of the spawner function.
+    /// * `H` - The type of the handle returned by the spawner.
+    ///
+    /// # Parameters
+    ///
+    /// * `spawner` - A function that takes a boxed future and returns a handle to the spawned task.
+    pub fn start_with<const C: usize, Sp, H>(self, spawner: Sp) -> (Receiver<T::Output>, H)
+    where
+        Sp: FnOnce(BoxFuture<'static, eyre::Result<()>>) -> H,
+    {
+        let (tx, rx) = mpsc::channel(C);
+        let handle = spawner(self.run(tx).boxed());
+        (rx, handle)
+    }
+
+    /// Starts the replay with the default Tokio task spawner.
+    ///
+    /// # Type Parameters
+    ///
+    /// * `C` - The capacity of the output channel.
+    pub fn start<const C: usize>(self) -> (Receiver<T::Output>, JoinHandle<eyre::Result<()>>) {
+        self.start_with::<C, _, _>(|fut| tokio::spawn(fut))
+    }
+
+    async fn run(mut self, tx: Sender<T::Output>) -> eyre::Result<()> {
+        let mut sources = Vec::with_capacity(self.files.len());
+        for path in &self.files {
+            sources.push(Source::open(path).await?);
+        }
+
+        let mut clock: Option<(DateTime<Utc>, Instant)> = None;
+        while let Some((raw, message)) = next_in_publish_order(&mut sources).await? {
+            if self.pacing == Pacing::RealTime
+                && let Some(publish_time) = publish_time(&message)
+            {
+                match clock {
+                    Some((first_publish_time, started)) => {
+                        let offset = (publish_time - first_publish_time)
+                            .to_std()
+                            .unwrap_or_default();
+                        sleep_until(started + offset).await;
+                    }
+                    None => clock = Some((publish_time, Instant::now())),
+                }
+            }
+
+            self.processor.on_message_received(raw, &message);
+            let mut message = self.processor.process_message(message);
+            while let Some(output) = message.take().or_else(|| self.processor.next_output()) {
+                if tx.send(output).await.is_err() {
+                    tracing::info!("output channel receiver dropped, stopping replay");
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+type Frames = FramedRead<Pin<Box<dyn AsyncRead + Send>>, HistoricCodec>;
+
+/// A single file being replayed, with the next message already read.
+struct Source {
+    path: PathBuf,
+    frames: Frames,
+    head: Option<(Bytes, ResponseMessage)>,
+}
+
+impl Source {
+    async fn open(path: &Path) -> eyre::Result<Self> {
+        let file = tokio::fs::File::open(path)
+            .await
+            .wrap_err_with(|| format!("failed to open {}", path.display()))?;
+        let file = BufReader::new(file);
+        let reader: Pin<Box<dyn AsyncRead + Send>> = match path.extension().and_then(OsStr::to_str)
+        {
+            Some("gz") => {
+                let mut decoder = GzipDecoder::new(file);
+                decoder.multiple_members(true);
+                Box::pin(decoder)
+            }
+            Some("bz2") => {
+                let mut decoder = BzDecoder::new(file);
+                decoder.multiple_members(true);
+                Box::pin(decoder)
+            }
+            _ => Box::pin(file),
+        };
+
+        let mut source = Self {
+            path: path.to_owned(),
+            frames: FramedRead::new(reader, HistoricCodec),
+            head: None,
+        };
+        source.advance().await?;
+        Ok(source)
+    }
+
+    async fn advance(&mut self) -> eyre::Result<()> {
+        self.head = self
+            .frames
+            .next()
+            .await
+            .transpose()
+            .wrap_err_with(|| format!("failed to read {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+/// Take the message with the earliest publish time across all sources.
+///
+/// Messages without a publish time (connection and status messages) are taken first, ties are
+/// broken by the order in which the files were given.
+async fn next_in_publish_order(
+    sources: &mut [Source],
+) -> eyre::Result<Option<(Bytes, ResponseMessage)>> {
+    let next = sources
+        .iter_mut()
+        .filter(|source| source.head.is_some())
+        .min_by_key(|source| {
+            source
+                .head
+                .as_ref()
+                .and_then(|(_, message)| publish_time(message))
+        });
+    let Some(source) = next else {
+        return Ok(None);
+    };
+    let head = source.head.take();
+    source.advance().await?;
+    Ok(head)
+}
+
+fn publish_time(message: &ResponseMessage) -> Option<DateTime<Utc>> {
+    match *message {
+        ResponseMessage::MarketChange(ref msg) => msg.publish_time,
+        ResponseMessage::OrderChange(ref msg) => msg.publish_time,
+        ResponseMessage::Connection(_) | ResponseMessage::Status(_) => None,
+    }
+}
+
+/// [`StreamAPIClientCodec`] for files: skips blank lines and recorded requests, and accepts a
+/// last line without a trailing newline.
+struct HistoricCodec;
+
+impl Decoder for HistoricCodec {
+    type Item = (Bytes, ResponseMessage);
+    type Error = eyre::Report;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        while let Some(pos) = src.iter().position(|&byte| byte == b'\n') {
+            if is_skipped(&src[..pos]) {
+                src.advance(pos + 1);
+                continue;
+            }
+            return StreamAPIClientCodec.decode(src);
+        }
+        Ok(None)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if let Some(frame) = self.decode(src)? {
+            return Ok(Some(frame));
+        }
+        if src.iter().all(u8::is_ascii_whitespace) {
+            src.clear();
+            return Ok(None);
+        }
+        src.extend_from_slice(b"\n");
+        self.decode(src)
+    }
+}
+
+/// Lines that do not contain a stream message: blank lines and requests written by the
+/// [`Recorder`](crate::Recorder), which always starts a line with `{"_ts":..,"_dir":".."`.
+fn is_skipped(line: &[u8]) -> bool {
+    const RECORDER_HEADER_LEN: usize = 48;
+
+    if line.iter().all(u8::is_ascii_whitespace) {
+        return true;
+    }
+    let header = &line[..line.len().min(RECORDER_HEADER_LEN)];
+    header.starts_with(b"{\"_ts\":")
+        && header
+            .windows(b"\"_dir\":\"out\"".len())
+            .any(|window| window == b"\"_dir\":\"out\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn decode_all(input: &str) -> Vec<String> {
+        let mut codec = HistoricCodec;
+        let mut buf = BytesMut::from(input);
+        let mut ops = Vec::new();
+        while let Some((raw, _)) = codec.decode_eof(&mut buf).unwrap() {
+            ops.push(String::from_utf8(raw.to_vec()).unwrap());
+        }
+        ops
+    }
+
+    #[test]
+    fn skips_blank_lines_and_recorded_requests() {
+        let input = concat!(
+            "{\"_ts\":1,\"_dir\":\"in\",\"op\":\"connection\",\"connectionId\":\"1\"}\n",
+            "\r\n",
+            "{\"_ts\":2,\"_dir\":\"out\",\"_conn\":\"1\",\"op\":\"heartbeat\",\"id\":1}\n",
+            "{\"op\":\"status\",\"id\":1,\"statusCode\":\"SUCCESS\"}\n",
+        );
+
+        assert_eq!(
+            decode_all(input),
+            vec![
+                r#"{"_ts":1,"_dir":"in","op":"connection","connectionId":"1"}"#,
+                r#"{"op":"status","id":1,"statusCode":"SUCCESS"}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_last_line_without_newline() {
+        let input = "{\"op\":\"connection\",\"connectionId\":\"1\"}\n{\"op\":\"connection\",\"connectionId\":\"2\"}";
+
+        assert_eq!(decode_all(input).len(), 2);
+    }
+}
@@ -0,0 +1,577 @@
+//! Keeps market and order updates flowing over API-NG REST while the stream is down.
+//!
+//! The [`RestFallback`] sits between the stream task and the consumer. While the stream is
+//! connected it only forwards the stream's output and remembers which markets it carries. Once
+//! the connection is lost it polls `listMarketBook` and `listCurrentOrders` for those markets and
+//! emits every market whose response changed since the previous poll as a
+//! [`CachedMessage::MarketChange`] or [`CachedMessage::OrderChange`], until the stream is
+//! connected again and its fresh images take over.
+
+use core::time::Duration;
+use std::collections::{HashMap, HashSet};
+
+use betfair_adapter::betfair_types::types::sports_aping::{
+    CurrentOrderSummary, ExBestOffersOverrides, MarketBook, MarketCatalogue, MarketFilter,
+    MarketId, MarketProjection, MarketStatus, PriceData, PriceProjection, list_current_orders,
+    list_market_book, list_market_catalogue,
+};
+use betfair_stream_types::request::market_subscription_message::{Fields, MarketDataFilter};
+use betfair_stream_types::response::market_change_message::StreamMarketDefinitionStatus;
+use chrono::Utc;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::task::JoinHandle;
+use tokio::time::{Interval, MissedTickBehavior};
+
+use crate::cache::primitives::{MarketBookCache, OrderBookCache};
+use crate::{BetfairStreamClient, CachedMessage, LifecycleEvent, MessageProcessor, SharedSession};
+
+/// How often markets are polled while the stream is down unless configured otherwise.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many markets are requested at once from `listMarketCatalogue` and `listCurrentOrders`.
+const MARKETS_PER_REQUEST: usize = 10;
+
+/// Betfair rejects `listMarketBook` requests whose markets weigh more than this with
+/// `TOO_MUCH_DATA`.
+const MAX_REQUEST_WEIGHT: usize = 200;
+
+/// Polls API-NG REST for the markets of a stream while the stream is disconnected.
+///
+/// Attach it to a running stream with [`Self::attach`]. Markets are polled if they were seen on
+/// the stream and have not closed, or were added with [`Self::with_market_ids`]. The polled
+/// caches are built with [`MarketBookCache::from_market_book`] and
+/// [`OrderBookCache::from_current_orders`], so they replace the stream's caches of a market
+/// rather than being applied to them; no market or order events are derived from them.
+#[derive(Debug, Clone)]
+pub struct RestFallback {
+    /// The session the REST requests are made with
+    pub session: SharedSession,
+    /// How often the markets are polled while the stream is down
+    pub poll_interval: Duration,
+    /// Which prices to request, read like the market data filter of a stream subscription
+    pub market_data_filter: MarketDataFilter,
+    /// Markets polled even if they have not been seen on the stream
+    pub market_ids: Vec<MarketId>,
+}
+
+impl RestFallback {
+    /// Creates a new `RestFallback` polling every [`DEFAULT_POLL_INTERVAL`] for all prices.
+    ///
+    /// # Parameters
+    ///
+    /// * `session` - An authenticated client, or a [`SharedSession`] that is also used elsewhere.
+    ///
+    /// # Returns
+    ///
+    /// A `RestFallback` for the markets seen on the stream.
+    #[must_use]
+    pub fn new(session: impl Into<SharedSession>) -> Self {
+        Self {
+            session: session.into(),
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            market_data_filter: MarketDataFilter::default(),
+            market_ids: Vec::new(),
+        }
+    }
+
+    /// Sets how often the markets are polled while the stream is down.
+    ///
+    /// # Parameters
+    ///
+    /// * `poll_interval` - The duration between two polls.
+    ///
+    /// # Returns
+    ///
+    /// The updated `RestFallback`.
+    #[must_use]
+    pub const fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Sets which prices are requested, usually the market data filter of the stream's market
+    /// subscription, so that the polled caches contain the same ladders as the streamed ones.
+    ///
+    /// # Parameters
+    ///
+    /// * `market_data_filter` - The market data filter of the subscription.
+    ///
+    /// # Returns
+    ///
+    /// The updated `RestFallback`.
+    #[must_use]
+    pub fn with_market_data_filter(mut self, market_data_filter: MarketDataFilter) -> Self {
+        self.market_data_filter = market_data_filter;
+        self
+    }
+
+    /// Polls the given markets as well, e.g. markets subscribed to while the stream was already
+    /// down.
+    ///
+    /// # Parameters
+    ///
+    /// * `market_ids` - The markets to poll.
+    ///
+    /// # Returns
+    ///
+    /// The updated `RestFallback`.
+    #[must_use]
+    pub fn with_market_ids(mut self, market_ids: impl IntoIterator<Item = MarketId>) -> Self {
+        self.market_ids.extend(market_ids);
+        self
+    }
+
+    /// Places the fallback between the stream task of `client` and its consumer.
+    ///
+    /// Polling starts when the stream reports [`LifecycleEvent::Disconnected`] or
+    /// [`LifecycleEvent::Reconnecting`] and stops on [`LifecycleEvent::Connected`]. Attach it
+    /// right after starting the stream, as earlier lifecycle events are not seen.
+    ///
+    /// # Type Parameters
+    ///
+    /// * `C` - The capacity of the channel between the fallback and the consumer.
+    ///
+    /// # Parameters
+    ///
+    /// * `client` - The handle of a running stream.
+    ///
+    /// # Returns
+    ///
+    /// * `BetfairStreamClient<T>` - The same handle, whose `sink` now receives the stream's
+    ///   output and the polled updates.
+    /// * `JoinHandle<()>` - The task forwarding and polling, which stops once the stream's
+    ///   output is closed or the consumer dropped the `sink`.
+    pub fn attach<const C: usize, T>(
+        self,
+        client: BetfairStreamClient<T>,
+    ) -> (BetfairStreamClient<T>, JoinHandle<()>)
+    where
+        T: MessageProcessor<Output = CachedMessage>,
+    {
+        let BetfairStreamClient {
+            send_to_stream,
+            sink,
+            lifecycle,
+            shutdown,
+            stats,
+            pending_acks,
+            processor,
+        } = client;
+        let (output, fallback_sink) = mpsc::channel(C);
+        let poller = Poller {
+            markets: self.market_ids.iter().cloned().collect(),
+            fallback: self,
+            polling: false,
+            catalogues: HashMap::new(),
+            books: HashMap::new(),
+            orders: HashMap::new(),
+        };
+        let task = tokio::spawn(poller.run(sink, lifecycle.resubscribe(), output));
+
+        (
+            BetfairStreamClient {
+                send_to_stream,
+                sink: fallback_sink,
+                lifecycle,
+                shutdown,
+                stats,
+                pending_acks,
+                processor,
+            },
+            task,
+        )
+    }
+}
+
+/// The state of an attached [`RestFallback`].
+#[derive(Debug)]
+struct Poller {
+    fallback: RestFallback,
+    /// Whether the stream is down.
+    polling: bool,
+    /// The markets to poll.
+    markets: HashSet<MarketId>,
+    catalogues: HashMap<MarketId, MarketCatalogue>,
+    /// The responses of the previous poll, to emit only what changed.
+    books: HashMap<MarketId, MarketBook>,
+    orders: HashMap<MarketId, Vec<CurrentOrderSummary>>,
+}
+
+impl Poller {
+    async fn run(
+        mut self,
+        mut sink: Receiver<CachedMessage>,
+        mut lifecycle: broadcast::Receiver<LifecycleEvent>,
+        output: Sender<CachedMessage>,
+    ) {
+        let mut lifecycle_open = true;
+        let mut interval = tokio::time::interval(self.fallback.poll_interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                // a reconnect has to stop polling before another poll starts
+                biased;
+                event = lifecycle.recv(), if lifecycle_open => match event {
+                    Ok(event) => self.on_lifecycle(&event, &mut interval),
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => {
+                        self.polling = false;
+                        lifecycle_open = false;
+                    }
+                },
+                message = sink.recv() => {
+                    let Some(message) = message else {
+                        return;
+                    };
+                    self.track(&message);
+                    if output.send(message).await.is_err() {
+                        return;
+                    }
+                }
+                _ = interval.tick(), if self.polling => {
+                    for message in self.poll().await {
+                        if output.send(message).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Start polling once the stream is down and stop once it is up again.
+    fn on_lifecycle(&mut self, event: &LifecycleEvent, interval: &mut Interval) {
+        match *event {
+            LifecycleEvent::Disconnected { .. } | LifecycleEvent::Reconnecting { .. } => {
+                if !self.polling {
+                    tracing::info!("stream down, polling markets over REST");
+                    self.polling = true;
+                    // the first poll emits every market
+                    self.books.clear();
+                    self.orders.clear();
+                    interval.reset_immediately();
+                }
+            }
+            LifecycleEvent::Connected { .. }
+            | LifecycleEvent::ShutDown
+            | LifecycleEvent::Fatal { .. } => {
+                if self.polling {
+                    tracing::info!("stream up, stopped polling markets over REST");
+                }
+                self.polling = false;
+            }
+            LifecycleEvent::Connecting | LifecycleEvent::Resubscribed => {}
+        }
+    }
+
+    /// Remember the markets carried by a message of the stream.
+    fn track(&mut self, message: &CachedMessage) {
+        let CachedMessage::MarketChange(ref markets) = *message else {
+            return;
+        };
+        for market in markets {
+            let closed = market.market_definition().is_some_and(|definition| {
+                definition.status == StreamMarketDefinitionStatus::Closed
+            });
+            if closed {
+                self.forget(market.market_id());
+            } else {
+                self.markets.insert(market.market_id().clone());
+            }
+        }
+    }
+
+    fn forget(&mut self, market_id: &MarketId) {
+        self.markets.remove(market_id);
+        self.catalogues.remove(market_id);
+        self.books.remove(market_id);
+        self.orders.remove(market_id);
+    }
+
+    /// Request the books and orders of every market, returning the markets that changed since
+    /// the previous poll.
+    ///
+    /// A failed request only skips the markets it was made for; they are requested again with
+    /// the next poll.
+    async fn poll(&mut self) -> Vec<CachedMessage> {
+        let client = self.fallback.session.client();
+        let publish_time = Utc::now();
+        let mut market_ids = self.markets.iter().cloned().collect::<Vec<_>>();
+        market_ids.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+        let unknown = market_ids
+            .iter()
+            .filter(|market_id| !self.catalogues.contains_key(*market_id))
+            .cloned()
+            .collect::<Vec<_>>();
+        for chunk in unknown.chunks(MARKETS_PER_REQUEST) {
+            let catalogues = match client
+                .send_request(
+                    list_market_catalogue::Parameters::builder()
+                        .filter(MarketFilter::builder().market_ids(chunk.to_vec()).build())
+                        .market_projection(vec![
+                            MarketProjection::Event,
+                            MarketProjection::EventType,
+                            MarketProjection::MarketDescription,
+                            MarketProjection::MarketStartTime,
+                            MarketProjection::RunnerDescription,
+                        ])
+                        .max_results(i32::try_from(chunk.len()).unwrap_or(i32::MAX))
+                        .build(),
+                )
+                .await
+            {
+                Ok(catalogues) => catalogues,
+                Err(err) => {
+                    tracing::warn!(?err, ?chunk, "could not request market catalogues");
+                    continue;
+                }
+            };
+            for catalogue in catalogues {
+                self.catalogues
+                    .insert(catalogue.market_id.clone(), catalogue);
+            }
+        }
+
+        let projection = price_projection(&self.fallback.market_data_filter);
+        let markets_per_request = (MAX_REQUEST_WEIGHT / request_weight(&projection)).max(1);
+        let mut markets = Vec::new();
+        let mut closed = Vec::new();
+        for chunk in market_ids.chunks(markets_per_request) {
+            let books = match client
+                .send_request(
+                    list_market_book::Parameters::builder()
+                        .market_ids(chunk.to_vec())
+                        .price_projection(projection.clone())
+                        .build(),
+                )
+                .await
+            {
+                Ok(books) => books,
+                Err(err) => {
+                    tracing::warn!(?err, ?chunk, "could not poll market books");
+                    continue;
+                }
+            };
+            for book in books {
+                if book.status == Some(MarketStatus::Closed) {
+                    closed.push(book.market_id.clone());
+                }
+                if self.books.get(&book.market_id) == Some(&book) {
+                    continue;
+                }
+                let Some(catalogue) = self.catalogues.get(&book.market_id) else {
+                    tracing::debug!(market_id = ?book.market_id, "no catalogue for polled market");
+                    continue;
+                };
+                markets.push(MarketBookCache::from_market_book(
+                    &book,
+                    catalogue,
+                    &self.fallback.market_data_filter,
+                    publish_time,
+                ));
+                self.books.insert(book.market_id.clone(), book);
+            }
+        }
+
+        let mut orders = Vec::new();
+        'chunks: for chunk in market_ids.chunks(MARKETS_PER_REQUEST) {
+            // the orders are paged, an incomplete chunk would look like cancelled orders
+            let mut current_orders = Vec::new();
+            loop {
+                let report = match client
+                    .send_request(
+                        list_current_orders::Parameters::builder()
+                            .market_ids(chunk.to_vec())
+                            .from_record(i32::try_from(current_orders.len()).unwrap_or(i32::MAX))
+                            .build(),
+                    )
+                    .await
+                {
+                    Ok(report) => report,
+                    Err(err) => {
+                        tracing::warn!(?err, ?chunk, "could not poll current orders");
+                        continue 'chunks;
+                    }
+                };
+                if !report.more_available {
+                    current_orders.extend(report.current_orders);
+                    break;
+                }
+                if report.current_orders.is_empty() {
+                    tracing::warn!(?chunk, "current orders changed while paging through them");
+                    continue 'chunks;
+                }
+                current_orders.extend(report.current_orders);
+            }
+            let mut by_market = HashMap::<MarketId, Vec<CurrentOrderSummary>>::new();
+            for order in current_orders {
+                by_market
+                    .entry(order.market_id.clone())
+                    .or_default()
+                    .push(order);
+            }
+            for market_id in chunk {
+                let current = by_market.remove(market_id).unwrap_or_default();
+                let previous = self.orders.get(market_id);
+                // markets without orders are only emitted once their last order disappeared
+                if previous.map_or(current.is_empty(), |previous| *previous == current) {
+                    continue;
+                }
+                orders.push(OrderBookCache::from_current_orders(
+                    market_id.clone(),
+                    &current,
+                    publish_time,
+                ));
+                self.orders.insert(market_id.clone(), current);
+            }
+        }
+
+        for market_id in &closed {
+            self.forget(market_id);
+        }
+
+        let mut messages = Vec::new();
+        if !markets.is_empty() {
+            messages.push(CachedMessage::MarketChange(markets));
+        }
+        if !orders.is_empty() {
+            messages.push(CachedMessage::OrderChange(orders));
+        }
+        messages
+    }
+}
+
+/// The weight of a single market in a `listMarketBook` request with `projection`, following
+/// Betfair's market data request limits.
+fn request_weight(projection: &PriceProjection) -> usize {
+    let requested = |data| {
+        projection
+            .price_data
+            .as_ref()
+            .is_some_and(|price_data| price_data.contains(&data))
+    };
+    // best offers deeper than 3 prices weigh proportionately more
+    let depth = projection
+        .ex_best_offers_overrides
+        .as_ref()
+        .and_then(|overrides| overrides.best_prices_depth)
+        .and_then(|depth| usize::try_from(depth).ok())
+        .unwrap_or(3)
+        .max(3);
+    let best_offers = (5 * depth).div_ceil(3);
+
+    let exchange = match (
+        requested(PriceData::ExAllOffers),
+        requested(PriceData::ExBestOffers),
+        requested(PriceData::ExTraded),
+    ) {
+        (true, _, true) => 32,
+        (true, _, false) => 17,
+        (false, true, true) if depth == 3 => 20,
+        (false, true, true) => best_offers + 17,
+        (false, true, false) => best_offers,
+        (false, false, true) => 17,
+        (false, false, false) => 0,
+    };
+    let sp = if requested(PriceData::SpAvailable) {
+        3
+    } else {
+        0
+    } + if requested(PriceData::SpTraded) { 7 } else { 0 };
+
+    match exchange + sp {
+        // a market without prices still weighs something
+        0 => 2,
+        weight => weight,
+    }
+}
+
+/// The `listMarketBook` price projection requesting what `filter` requests on the stream.
+fn price_projection(filter: &MarketDataFilter) -> PriceProjection {
+    let requested = |field| {
+        filter
+            .fields
+            .as_ref()
+            .is_none_or(|fields| fields.contains(&field))
+    };
+    let mut price_data = Vec::new();
+    if requested(Fields::ExAllOffers) {
+        price_data.push(PriceData::ExAllOffers);
+    } else if requested(Fields::ExBestOffers) || requested(Fields::ExBestOffersDisp) {
+        price_data.push(PriceData::ExBestOffers);
+    }
+    if requested(Fields::ExTraded) {
+        price_data.push(PriceData::ExTraded);
+    }
+    if requested(Fields::SpProjected) || requested(Fields::SpTraded) {
+        price_data.extend([PriceData::SpAvailable, PriceData::SpTraded]);
+    }
+
+    PriceProjection {
+        price_data: Some(price_data),
+        ex_best_offers_overrides: filter.ladder_levels.as_ref().map(|levels| {
+            ExBestOffersOverrides::builder()
+                .best_prices_depth(i32::from(levels.get()))
+                .build()
+        }),
+        // the virtual prices are the ones displayed on the website
+        virtualise: Some(
+            !requested(Fields::ExAllOffers)
+                && !requested(Fields::ExBestOffers)
+                && requested(Fields::ExBestOffersDisp),
+        ),
+        rollover_stakes: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use betfair_stream_types::request::market_subscription_message::LadderLevel;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn price_projection_follows_the_market_data_filter() {
+        let projection = price_projection(&MarketDataFilter {
+            ladder_levels: Some(LadderLevel::new(3).unwrap()),
+            fields: Some(vec![Fields::ExBestOffersDisp, Fields::ExTraded]),
+        });
+
+        assert_eq!(
+            projection.price_data,
+            Some(vec![PriceData::ExBestOffers, PriceData::ExTraded])
+        );
+        assert_eq!(
+            projection
+                .ex_best_offers_overrides
+                .and_then(|overrides| overrides.best_prices_depth),
+            Some(3)
+        );
+        assert_eq!(projection.virtualise, Some(true));
+    }
+
+    #[test]
+    fn default_filter_stays_within_the_request_weight_limit() {
+        let projection = price_projection(&MarketDataFilter::default());
+        let weight = request_weight(&projection);
+
+        assert_eq!(weight, 42);
+        let markets_per_request = MAX_REQUEST_WEIGHT / weight;
+        assert_eq!(markets_per_request, 4);
+        assert!(markets_per_request * weight <= MAX_REQUEST_WEIGHT);
+    }
+
+    #[test]
+    fn deep_best_offers_weigh_more() {
+        let projection = price_projection(&MarketDataFilter {
+            ladder_levels: Some(LadderLevel::new(10).unwrap()),
+            fields: Some(vec![Fields::ExBestOffers]),
+        });
+
+        assert_eq!(request_weight(&projection), 17);
+    }
+}
@@ -0,0 +1,121 @@
+//! A single login shared by REST requests and any number of streams.
+
+use std::sync::Arc;
+
+use backon::BackoffBuilder;
+use betfair_adapter::{ApiError, Authenticated, BetfairRpcClient, Unauthenticated};
+use tokio::sync::{Mutex, watch};
+use tokio::task::JoinHandle;
+
+/// Handle to an authenticated client that is replaced in one place when the session expires.
+///
+/// Streams started with [`BetfairStreamBuilder::new_authenticated`](crate::BetfairStreamBuilder::new_authenticated)
+/// reuse the session instead of logging in themselves. When Betfair rejects it (e.g. with
+/// `INVALID_SESSION_INFORMATION`) the first stream to notice logs in again and every other
+/// holder of the handle picks up the new client, so a single login serves all of them. Use
+/// [`Self::client`] or [`Self::watch`] to make REST requests with the current session.
+#[derive(Debug, Clone)]
+pub struct SharedSession {
+    current: Arc<watch::Sender<Arc<BetfairRpcClient<Authenticated>>>>,
+    /// Held while logging in so that concurrent re-authentications result in a single login.
+    /// Keeps the keep-alive of the last login made through this handle running.
+    login: Arc<Mutex<Option<KeepAlive>>>,
+}
+
+impl SharedSession {
+    /// Share an already authenticated client.
+    #[must_use]
+    pub fn new(client: Arc<BetfairRpcClient<Authenticated>>) -> Self {
+        Self {
+            current: Arc::new(watch::Sender::new(client)),
+            login: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Log in and share the resulting session.
+    ///
+    /// Unlike [`Self::new`] the session is kept alive until it is replaced, logged out or every
+    /// handle is dropped.
+    ///
+    /// # Errors
+    /// If logging in fails after all retries of `backoff`.
+    pub async fn login(
+        client: BetfairRpcClient<Unauthenticated>,
+        backoff: impl BackoffBuilder,
+    ) -> Result<Self, ApiError> {
+        let (client, keep_alive) = client.authenticate_with_backoff(backoff).await?;
+        Ok(Self {
+            current: Arc::new(watch::Sender::new(client)),
+            login: Arc::new(Mutex::new(Some(KeepAlive(keep_alive)))),
+        })
+    }
+
+    /// The client holding the current session.
+    #[must_use]
+    pub fn client(&self) -> Arc<BetfairRpcClient<Authenticated>> {
+        Arc::clone(&self.current.borrow())
+    }
+
+    /// Receive the client holding the current session, updated whenever it is replaced.
+    #[must_use]
+    pub fn watch(&self) -> watch::Receiver<Arc<BetfairRpcClient<Authenticated>>> {
+        self.current.subscribe()
+    }
+
+    /// Log in again because the session of `rejected` is no longer valid.
+    ///
+    /// If the session has already been replaced since `rejected` was obtained, the current
+    /// client is returned without logging in again.
+    ///
+    /// # Errors
+    /// If logging in fails after all retries of `backoff`.
+    pub async fn reauthenticate(
+        &self,
+        rejected: &Arc<BetfairRpcClient<Authenticated>>,
+        backoff: impl BackoffBuilder,
+    ) -> Result<Arc<BetfairRpcClient<Authenticated>>, ApiError> {
+        let mut login = self.login.lock().await;
+        let current = self.client();
+        if !Arc::ptr_eq(&current, rejected) {
+            return Ok(current);
+        }
+
+        tracing::info!("session rejected, logging in again");
+        let (client, keep_alive) = current
+            .unauthenticated()
+            .authenticate_with_backoff(backoff)
+            .await?;
+        *login = Some(KeepAlive(keep_alive));
+        self.current.send_replace(Arc::clone(&client));
+        Ok(client)
+    }
+
+    /// Log out of the current session and stop keeping it alive.
+    ///
+    /// Every holder of the handle loses the session; streams still using it log in again the
+    /// next time they connect, so shut them down first.
+    ///
+    /// # Errors
+    /// If the logout request fails.
+    pub async fn logout(&self) -> Result<(), ApiError> {
+        let mut login = self.login.lock().await;
+        login.take();
+        self.client().logout()?.execute().await?.ok()
+    }
+}
+
+impl From<Arc<BetfairRpcClient<Authenticated>>> for SharedSession {
+    fn from(client: Arc<BetfairRpcClient<Authenticated>>) -> Self {
+        Self::new(client)
+    }
+}
+
+/// The keep-alive task of a login, stopped once dropped.
+#[derive(Debug)]
+pub(crate) struct KeepAlive(pub(crate) JoinHandle<Result<(), ApiError>>);
+
+impl Drop for KeepAlive {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
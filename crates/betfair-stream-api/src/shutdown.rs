@@ -0,0 +1,137 @@
+//! Graceful shutdown of the stream task.
+
+use tokio::sync::watch;
+
+/// Stops a running stream task and waits until it has torn everything down.
+///
+/// Received through [`BetfairStreamClient::shutdown`](crate::BetfairStreamClient::shutdown).
+/// On shutdown the task stops reading from the stream, sends the requests that were already
+/// queued, closes the connection (including the TLS session) and stops the keep-alive of the
+/// session it logged in with. It can optionally log out, so that no session is left behind when
+/// a deployment is replaced.
+///
+/// Aborting the task or dropping its sender stay possible, but leave the socket and the session
+/// open.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    request: watch::Sender<Option<ShutdownRequest>>,
+    /// Never sent to; closed once the stream task has finished.
+    finished: watch::Receiver<()>,
+}
+
+/// How the stream task was asked to shut down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ShutdownRequest {
+    /// Log out of the session after closing the connection.
+    pub(crate) logout: bool,
+}
+
+/// The stream task's side of a [`ShutdownHandle`].
+#[derive(Debug)]
+pub(crate) struct ShutdownSignal {
+    request: watch::Receiver<Option<ShutdownRequest>>,
+    _finished: watch::Sender<()>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new() -> (Self, ShutdownSignal) {
+        let (request_tx, request_rx) = watch::channel(None);
+        let (finished_tx, finished_rx) = watch::channel(());
+        (
+            Self {
+                request: request_tx,
+                finished: finished_rx,
+            },
+            ShutdownSignal {
+                request: request_rx,
+                _finished: finished_tx,
+            },
+        )
+    }
+
+    /// Ask the stream task to shut down without waiting for it.
+    pub fn signal(&self) {
+        self.request(false);
+    }
+
+    /// Ask the stream task to shut down and log out of its session, without waiting for it.
+    ///
+    /// When the session is shared (see [`SharedSession`](crate::SharedSession)) every other
+    /// holder loses it as well.
+    pub fn signal_with_logout(&self) {
+        self.request(true);
+    }
+
+    /// Shut the stream task down and wait until it has finished.
+    pub async fn shutdown(&self) {
+        self.signal();
+        self.finished().await;
+    }
+
+    /// Shut the stream task down, log out of its session and wait until it has finished.
+    ///
+    /// See [`Self::signal_with_logout`].
+    pub async fn shutdown_and_logout(&self) {
+        self.signal_with_logout();
+        self.finished().await;
+    }
+
+    /// Wait until the stream task has finished, for whatever reason.
+    pub async fn finished(&self) {
+        let mut finished = self.finished.clone();
+        while finished.changed().await.is_ok() {}
+    }
+
+    /// Whether the stream task has finished.
+    #[must_use]
+    pub fn is_finished(&self) -> bool {
+        self.finished.has_changed().is_err()
+    }
+
+    fn request(&self, logout: bool) {
+        self.request.send_modify(|request| {
+            // a logout asked for once is not taken back
+            let logout = logout || request.is_some_and(|request| request.logout);
+            *request = Some(ShutdownRequest { logout });
+        });
+    }
+}
+
+impl ShutdownSignal {
+    /// Resolves once a shutdown was requested; never resolves if every handle is dropped.
+    pub(crate) async fn requested(&mut self) -> ShutdownRequest {
+        let request = self
+            .request
+            .wait_for(Option::is_some)
+            .await
+            .map(|request| request.unwrap_or(ShutdownRequest { logout: false }));
+        match request {
+            Ok(request) => request,
+            Err(_) => core::future::pending().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn logout_request_is_sticky() {
+        let (handle, mut signal) = ShutdownHandle::new();
+        handle.signal_with_logout();
+        handle.signal();
+
+        assert_eq!(signal.requested().await, ShutdownRequest { logout: true });
+    }
+
+    #[tokio::test]
+    async fn finishes_when_the_signal_is_dropped() {
+        let (handle, signal) = ShutdownHandle::new();
+        assert!(!handle.is_finished());
+
+        drop(signal);
+        handle.finished().await;
+        assert!(handle.is_finished());
+    }
+}
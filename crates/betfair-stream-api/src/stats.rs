@@ -0,0 +1,453 @@
+//! Health and latency statistics of the stream connection.
+
+use core::time::Duration;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use betfair_stream_types::request::RequestMessage;
+use betfair_stream_types::response::ResponseMessage;
+use tokio::time::Instant;
+
+/// How many latency samples the percentiles are computed from.
+const LATENCY_SAMPLES: usize = 1024;
+
+/// The window message and byte rates are averaged over.
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Heartbeats still waiting for their answer; older ones are assumed lost.
+const MAX_PENDING_HEARTBEATS: usize = 16;
+
+/// Thresholds above which the stream task raises a [`StreamAlarm`].
+///
+/// Alarms are delivered to the output through [`MessageProcessor::on_alarm`](crate::MessageProcessor::on_alarm).
+/// Each alarm is raised once when its threshold is crossed and again only after the stream
+/// has recovered in between, so a persistently slow feed does not flood the output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AlarmThresholds {
+    /// Raise [`StreamAlarm::HighLatency`] when a change is received later than this after
+    /// Betfair published it.
+    pub max_latency: Option<Duration>,
+    /// Raise [`StreamAlarm::Stale`] when nothing was received on an established connection for
+    /// this long. Betfair sends a heartbeat every 5 seconds on an idle subscription.
+    pub max_silence: Option<Duration>,
+}
+
+/// A health problem detected on the stream connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamAlarm {
+    /// A change arrived later than [`AlarmThresholds::max_latency`] after it was published.
+    HighLatency {
+        /// Time between the publish time of the change and receiving it.
+        latency: Duration,
+        /// The configured threshold.
+        threshold: Duration,
+    },
+    /// Nothing was received for longer than [`AlarmThresholds::max_silence`].
+    Stale {
+        /// Time since the last message was received.
+        silent_for: Duration,
+        /// The configured threshold.
+        threshold: Duration,
+    },
+}
+
+/// Live statistics of a stream connection, updated by the stream task.
+///
+/// Received through [`BetfairStreamClient::stats`](crate::BetfairStreamClient::stats); clones
+/// share the same counters.
+#[derive(Debug, Clone)]
+pub struct StreamStats {
+    inner: Arc<Mutex<Counters>>,
+}
+
+/// A point-in-time copy of [`StreamStats`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatsSnapshot {
+    /// The id Betfair assigned to the current connection.
+    pub connection_id: Option<String>,
+    /// How often the connection was re-established after the first connect.
+    pub reconnects: u64,
+    /// Messages received since the stream task started.
+    pub messages_received: u64,
+    /// Bytes received since the stream task started, excluding the message delimiters.
+    pub bytes_received: u64,
+    /// Messages received per second over the last 10 seconds.
+    pub messages_per_second: f64,
+    /// Bytes received per second over the last 10 seconds.
+    pub bytes_per_second: f64,
+    /// Publish-time-to-receive latency of the most recent changes, if any carried a publish time.
+    pub latency: Option<LatencyPercentiles>,
+    /// Round-trip time of the most recently answered heartbeat.
+    pub heartbeat_rtt: Option<Duration>,
+    /// Market changes Betfair flagged as conflated, i.e. combining several updates.
+    pub conflated_market_changes: u64,
+    /// Time since the last message was received.
+    pub since_last_message: Option<Duration>,
+}
+
+/// Percentiles of the publish-time-to-receive latency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyPercentiles {
+    /// The median latency.
+    pub p50: Duration,
+    /// The 90th percentile latency.
+    pub p90: Duration,
+    /// The 99th percentile latency.
+    pub p99: Duration,
+    /// The highest latency.
+    pub max: Duration,
+}
+
+#[derive(Debug)]
+struct Counters {
+    thresholds: AlarmThresholds,
+    connection_id: Option<String>,
+    connections: u64,
+    messages_received: u64,
+    bytes_received: u64,
+    conflated_market_changes: u64,
+    /// Messages and bytes received per second, oldest first.
+    rate: VecDeque<RateBucket>,
+    latencies: VecDeque<Duration>,
+    /// Ids and send times of heartbeats that were not answered yet, oldest first.
+    pending_heartbeats: VecDeque<(Option<i32>, Instant)>,
+    heartbeat_rtt: Option<Duration>,
+    last_message: Option<Instant>,
+    latency_alarm_raised: bool,
+    stale_alarm_raised: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RateBucket {
+    start: Instant,
+    messages: u64,
+    bytes: u64,
+}
+
+impl StreamStats {
+    pub(crate) fn new(thresholds: AlarmThresholds) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Counters {
+                thresholds,
+                connection_id: None,
+                connections: 0,
+                messages_received: 0,
+                bytes_received: 0,
+                conflated_market_changes: 0,
+                rate: VecDeque::new(),
+                latencies: VecDeque::with_capacity(LATENCY_SAMPLES),
+                pending_heartbeats: VecDeque::new(),
+                heartbeat_rtt: None,
+                last_message: None,
+                latency_alarm_raised: false,
+                stale_alarm_raised: false,
+            })),
+        }
+    }
+
+    /// Take a copy of the current statistics.
+    #[must_use]
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let now = Instant::now();
+        let mut counters = self.counters();
+        counters.expire_rate(now);
+        let (messages, bytes) = counters
+            .rate
+            .iter()
+            .fold((0, 0), |(messages, bytes), bucket| {
+                (messages + bucket.messages, bytes + bucket.bytes)
+            });
+        let window = counters
+            .rate
+            .front()
+            .map_or(RATE_WINDOW, |bucket| now.duration_since(bucket.start))
+            .clamp(Duration::from_secs(1), RATE_WINDOW)
+            .as_secs_f64();
+
+        StatsSnapshot {
+            connection_id: counters.connection_id.clone(),
+            reconnects: counters.connections.saturating_sub(1),
+            messages_received: counters.messages_received,
+            bytes_received: counters.bytes_received,
+            messages_per_second: messages as f64 / window,
+            bytes_per_second: bytes as f64 / window,
+            latency: percentiles(&counters.latencies),
+            heartbeat_rtt: counters.heartbeat_rtt,
+            conflated_market_changes: counters.conflated_market_changes,
+            since_last_message: counters
+                .last_message
+                .map(|last_message| now.duration_since(last_message)),
+        }
+    }
+
+    /// A connection was established; silence is measured from now on.
+    pub(crate) fn on_connected(&self, connection_id: Option<String>) {
+        let mut counters = self.counters();
+        counters.connection_id = connection_id;
+        counters.connections += 1;
+        counters.pending_heartbeats.clear();
+        counters.last_message = Some(Instant::now());
+        counters.stale_alarm_raised = false;
+    }
+
+    /// A request was sent to Betfair.
+    pub(crate) fn on_request(&self, request: &RequestMessage) {
+        let RequestMessage::Heartbeat(ref heartbeat) = *request else {
+            return;
+        };
+        let mut counters = self.counters();
+        if counters.pending_heartbeats.len() >= MAX_PENDING_HEARTBEATS {
+            counters.pending_heartbeats.pop_front();
+        }
+        counters
+            .pending_heartbeats
+            .push_back((heartbeat.id, Instant::now()));
+    }
+
+    /// A message of `bytes` bytes was received, returning an alarm if it arrived too late.
+    pub(crate) fn on_message(
+        &self,
+        bytes: usize,
+        message: &ResponseMessage,
+    ) -> Option<StreamAlarm> {
+        let now = Instant::now();
+        let bytes = u64::try_from(bytes).unwrap_or(u64::MAX);
+        let mut counters = self.counters();
+        counters.messages_received += 1;
+        counters.bytes_received += bytes;
+        counters.last_message = Some(now);
+        counters.stale_alarm_raised = false;
+        counters.count_rate(now, bytes);
+
+        let publish_time = match *message {
+            ResponseMessage::MarketChange(ref change) => {
+                let conflated = change
+                    .0
+                    .data
+                    .iter()
+                    .flatten()
+                    .filter(|market| market.conflated == Some(true))
+                    .count();
+                counters.conflated_market_changes += u64::try_from(conflated).unwrap_or(0);
+                change.0.publish_time
+            }
+            ResponseMessage::OrderChange(ref change) => change.0.publish_time,
+            ResponseMessage::Status(ref status) => {
+                counters.on_status(status.id(), now);
+                None
+            }
+            ResponseMessage::Connection(_) => None,
+        }?;
+
+        // clocks of Betfair and the local machine may disagree slightly
+        let latency = chrono::Utc::now()
+            .signed_duration_since(publish_time)
+            .to_std()
+            .unwrap_or_default();
+        counters.on_latency(latency)
+    }
+
+    /// When the connection counts as stale if nothing is received until then, unless that was
+    /// already reported.
+    pub(crate) fn stale_deadline(&self) -> Option<Instant> {
+        let counters = self.counters();
+        if counters.stale_alarm_raised {
+            return None;
+        }
+        Some(counters.last_message? + counters.thresholds.max_silence?)
+    }
+
+    /// Raise the staleness alarm after [`Self::stale_deadline`] passed.
+    pub(crate) fn raise_stale(&self) -> Option<StreamAlarm> {
+        let mut counters = self.counters();
+        let threshold = counters.thresholds.max_silence?;
+        let silent_for = counters.last_message?.elapsed();
+        if counters.stale_alarm_raised || silent_for < threshold {
+            return None;
+        }
+        counters.stale_alarm_raised = true;
+        Some(StreamAlarm::Stale {
+            silent_for,
+            threshold,
+        })
+    }
+
+    fn counters(&self) -> MutexGuard<'_, Counters> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Counters {
+    fn count_rate(&mut self, now: Instant, bytes: u64) {
+        match self.rate.back_mut() {
+            Some(bucket) if now.duration_since(bucket.start) < Duration::from_secs(1) => {
+                bucket.messages += 1;
+                bucket.bytes += bytes;
+            }
+            _ => self.rate.push_back(RateBucket {
+                start: now,
+                messages: 1,
+                bytes,
+            }),
+        }
+        self.expire_rate(now);
+    }
+
+    fn expire_rate(&mut self, now: Instant) {
+        while self
+            .rate
+            .front()
+            .is_some_and(|bucket| now.duration_since(bucket.start) > RATE_WINDOW)
+        {
+            self.rate.pop_front();
+        }
+    }
+
+    fn on_status(&mut self, id: Option<i32>, now: Instant) {
+        // Betfair answers in order, so the oldest heartbeat with the id is the one answered
+        let Some(index) = self
+            .pending_heartbeats
+            .iter()
+            .position(|&(heartbeat_id, _)| heartbeat_id == id)
+        else {
+            return;
+        };
+        if let Some((_, sent_at)) = self.pending_heartbeats.remove(index) {
+            self.heartbeat_rtt = Some(now.duration_since(sent_at));
+        }
+        // heartbeats sent before the answered one will not be answered anymore
+        self.pending_heartbeats.drain(..index);
+    }
+
+    fn on_latency(&mut self, latency: Duration) -> Option<StreamAlarm> {
+        if self.latencies.len() >= LATENCY_SAMPLES {
+            self.latencies.pop_front();
+        }
+        self.latencies.push_back(latency);
+
+        let threshold = self.thresholds.max_latency?;
+        if latency <= threshold {
+            self.latency_alarm_raised = false;
+            return None;
+        }
+        if self.latency_alarm_raised {
+            return None;
+        }
+        self.latency_alarm_raised = true;
+        Some(StreamAlarm::HighLatency { latency, threshold })
+    }
+}
+
+fn percentiles(latencies: &VecDeque<Duration>) -> Option<LatencyPercentiles> {
+    let mut sorted = latencies.iter().copied().collect::<Vec<_>>();
+    sorted.sort_unstable();
+    let max = *sorted.last()?;
+    let at = |percentile: usize| sorted[(sorted.len() - 1) * percentile / 100];
+    Some(LatencyPercentiles {
+        p50: at(50),
+        p90: at(90),
+        p99: at(99),
+        max,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use betfair_stream_types::request::heartbeat_message::HeartbeatMessage;
+    use betfair_stream_types::response::status_message::{StatusMessage, StatusSuccess};
+
+    use super::*;
+
+    fn status(id: i32) -> ResponseMessage {
+        ResponseMessage::Status(StatusMessage::Success(StatusSuccess {
+            id: Some(id),
+            connections_available: None,
+            connection_id: None,
+            connection_closed: None,
+        }))
+    }
+
+    #[test]
+    fn latency_alarm_is_raised_once_until_recovered() {
+        let stats = StreamStats::new(AlarmThresholds {
+            max_latency: Some(Duration::from_millis(100)),
+            max_silence: None,
+        });
+        let mut counters = stats.counters();
+
+        assert_eq!(counters.on_latency(Duration::from_millis(50)), None);
+        assert_eq!(
+            counters.on_latency(Duration::from_millis(150)),
+            Some(StreamAlarm::HighLatency {
+                latency: Duration::from_millis(150),
+                threshold: Duration::from_millis(100),
+            })
+        );
+        assert_eq!(counters.on_latency(Duration::from_millis(200)), None);
+        assert_eq!(counters.on_latency(Duration::from_millis(10)), None);
+        assert!(counters.on_latency(Duration::from_millis(300)).is_some());
+    }
+
+    #[test]
+    fn percentiles_of_latency_samples() {
+        let latencies = (1..=100)
+            .map(Duration::from_millis)
+            .collect::<VecDeque<_>>();
+
+        assert_eq!(
+            percentiles(&latencies),
+            Some(LatencyPercentiles {
+                p50: Duration::from_millis(50),
+                p90: Duration::from_millis(90),
+                p99: Duration::from_millis(99),
+                max: Duration::from_millis(100),
+            })
+        );
+        assert_eq!(percentiles(&VecDeque::new()), None);
+    }
+
+    #[tokio::test]
+    async fn heartbeat_round_trip_and_rates() {
+        let stats = StreamStats::new(AlarmThresholds::default());
+        stats.on_connected(Some("conn".to_owned()));
+        stats.on_request(&RequestMessage::Heartbeat(HeartbeatMessage {
+            id: Some(-2),
+        }));
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        stats.on_message(100, &status(-2));
+        stats.on_message(50, &status(1));
+
+        let snapshot = stats.snapshot();
+        assert!(
+            snapshot
+                .heartbeat_rtt
+                .is_some_and(|rtt| rtt >= Duration::from_millis(40))
+        );
+        assert_eq!(snapshot.messages_received, 2);
+        assert_eq!(snapshot.bytes_received, 150);
+        assert!((snapshot.bytes_per_second - 150.0).abs() < f64::EPSILON);
+        assert_eq!(snapshot.reconnects, 0);
+        assert_eq!(snapshot.connection_id.as_deref(), Some("conn"));
+    }
+
+    #[tokio::test]
+    async fn unanswered_heartbeat_is_dropped_once_a_later_one_is_answered() {
+        let stats = StreamStats::new(AlarmThresholds::default());
+        stats.on_request(&RequestMessage::Heartbeat(HeartbeatMessage {
+            id: Some(-2),
+        }));
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        stats.on_request(&RequestMessage::Heartbeat(HeartbeatMessage {
+            id: Some(-3),
+        }));
+        stats.on_message(100, &status(-3));
+
+        let counters = stats.counters();
+        assert!(
+            counters
+                .heartbeat_rtt
+                .is_some_and(|rtt| rtt < Duration::from_millis(40))
+        );
+        assert!(counters.pending_heartbeats.is_empty());
+    }
+}
@@ -0,0 +1,223 @@
+//! Replays market and order subscriptions after the stream connection has been re-established.
+//!
+//! Betfair forgets every subscription once the TCP connection drops. If the original
+//! subscription is resent with the last `clk` and `initialClk` values that were received for it,
+//! Betfair responds with a `RESUB_DELTA` containing only the changes since that point instead of
+//! a fresh full image.
+
+use betfair_stream_types::request::RequestMessage;
+use betfair_stream_types::request::market_subscription_message::MarketSubscriptionMessage;
+use betfair_stream_types::request::order_subscription_message::OrderSubscriptionMessage;
+use betfair_stream_types::response::{
+    Clock, DataChange, DatasetChangeMessage, InitialClock, ResponseMessage,
+};
+use serde::de::DeserializeOwned;
+
+/// The clock tokens received for a single subscription.
+///
+/// Mirrors the `update_clk`/`initial_clock` pair kept by
+/// [`StreamState`](crate::cache::tracker::StreamState), but is tracked separately for the market
+/// and the order subscription because both can share a single connection.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct SubscriptionClocks {
+    pub(crate) update_clk: Option<Clock>,
+    pub(crate) initial_clock: Option<InitialClock>,
+}
+
+impl SubscriptionClocks {
+    fn update<T: DeserializeOwned + DataChange<T>>(&mut self, msg: &DatasetChangeMessage<T>) {
+        if let Some(ref initial_clock) = msg.initial_clock {
+            self.initial_clock = Some(initial_clock.clone());
+        }
+        if let Some(ref update_clk) = msg.clock {
+            self.update_clk = Some(update_clk.clone());
+        }
+    }
+}
+
+/// Remembers the last market and order subscription sent to Betfair together with their clocks.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SubscriptionReplay {
+    market: Option<MarketSubscriptionMessage>,
+    market_clocks: SubscriptionClocks,
+    order: Option<OrderSubscriptionMessage>,
+    order_clocks: SubscriptionClocks,
+}
+
+impl SubscriptionReplay {
+    /// Record a request that is about to be sent to the stream.
+    ///
+    /// A new subscription replaces the previous one on Betfair's side, so the clocks received for
+    /// the old subscription are discarded.
+    pub(crate) fn on_request(&mut self, request: &RequestMessage) {
+        match request {
+            RequestMessage::MarketSubscription(msg) => {
+                self.market = Some(msg.clone());
+                self.market_clocks = SubscriptionClocks {
+                    update_clk: msg.clk.clone().map(Clock),
+                    initial_clock: msg.initial_clk.clone().map(InitialClock),
+                };
+            }
+            RequestMessage::OrderSubscription(msg) => {
+                self.order = Some(msg.clone());
+                self.order_clocks = SubscriptionClocks {
+                    update_clk: msg.clk.clone().map(Clock),
+                    initial_clock: msg.initial_clk.clone().map(InitialClock),
+                };
+            }
+            RequestMessage::Authentication(_) | RequestMessage::Heartbeat(_) => {}
+        }
+    }
+
+    /// Record the clocks carried by a message received from the stream.
+    pub(crate) fn on_response(&mut self, message: &ResponseMessage) {
+        match message {
+            ResponseMessage::MarketChange(msg) => self.market_clocks.update(msg),
+            ResponseMessage::OrderChange(msg) => self.order_clocks.update(msg),
+            ResponseMessage::Connection(_) | ResponseMessage::Status(_) => {}
+        }
+    }
+
+    /// The subscriptions that need to be resent after a reconnect.
+    ///
+    /// When both clocks are known they are attached to the request so that Betfair resumes the
+    /// subscription with a `RESUB_DELTA`; otherwise the subscription is resent as-is and a new
+    /// image is received.
+    pub(crate) fn requests(&self) -> Vec<RequestMessage> {
+        let mut requests = Vec::with_capacity(2);
+        if let Some(ref market) = self.market {
+            let mut market = market.clone();
+            (market.clk, market.initial_clk) = resume_clocks(&self.market_clocks);
+            requests.push(RequestMessage::MarketSubscription(market));
+        }
+        if let Some(ref order) = self.order {
+            let mut order = order.clone();
+            (order.clk, order.initial_clk) = resume_clocks(&self.order_clocks);
+            requests.push(RequestMessage::OrderSubscription(order));
+        }
+        requests
+    }
+
+    /// The active market subscription without clocks, which makes Betfair send a new image of
+    /// every subscribed market.
+    pub(crate) fn image_request(&self) -> Option<RequestMessage> {
+        let mut market = self.market.clone()?;
+        market.clk = None;
+        market.initial_clk = None;
+        Some(RequestMessage::MarketSubscription(market))
+    }
+
+    #[cfg(test)]
+    pub(crate) const fn market_clocks(&self) -> &SubscriptionClocks {
+        &self.market_clocks
+    }
+}
+
+fn resume_clocks(clocks: &SubscriptionClocks) -> (Option<String>, Option<String>) {
+    match (&clocks.update_clk, &clocks.initial_clock) {
+        (Some(clk), Some(initial_clk)) => (Some(clk.0.clone()), Some(initial_clk.0.clone())),
+        _ => (None, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use betfair_stream_types::request::order_subscription_message::OrderFilter;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn market_subscription() -> RequestMessage {
+        RequestMessage::MarketSubscription(MarketSubscriptionMessage {
+            id: Some(1),
+            segmentation_enabled: Some(true),
+            heartbeat_ms: Some(5000),
+            ..Default::default()
+        })
+    }
+
+    fn response(json: &str) -> ResponseMessage {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn nothing_to_replay_without_subscriptions() {
+        let replay = SubscriptionReplay::default();
+        assert!(replay.requests().is_empty());
+    }
+
+    #[test]
+    fn replays_subscription_without_clocks_before_any_data() {
+        let mut replay = SubscriptionReplay::default();
+        replay.on_request(&market_subscription());
+
+        assert_eq!(replay.requests(), vec![market_subscription()]);
+    }
+
+    #[test]
+    fn replays_market_subscription_with_latest_clocks() {
+        let mut replay = SubscriptionReplay::default();
+        replay.on_request(&market_subscription());
+        replay.on_response(&response(
+            r#"{"op":"mcm","id":1,"initialClk":"G1wxPQ==","clk":"AAAAAAAA","pt":1,"ct":"SUB_IMAGE","mc":[]}"#,
+        ));
+        replay.on_response(&response(
+            r#"{"op":"mcm","id":1,"clk":"AAAAAAAB","pt":2,"ct":"HEARTBEAT"}"#,
+        ));
+
+        let RequestMessage::MarketSubscription(expected) = market_subscription() else {
+            unreachable!()
+        };
+        let expected = MarketSubscriptionMessage {
+            clk: Some("AAAAAAAB".to_owned()),
+            initial_clk: Some("G1wxPQ==".to_owned()),
+            ..expected
+        };
+        assert_eq!(
+            replay.requests(),
+            vec![RequestMessage::MarketSubscription(expected)]
+        );
+    }
+
+    #[test]
+    fn new_subscription_discards_previous_clocks() {
+        let mut replay = SubscriptionReplay::default();
+        replay.on_request(&market_subscription());
+        replay.on_response(&response(
+            r#"{"op":"mcm","id":1,"initialClk":"G1wxPQ==","clk":"AAAAAAAA","pt":1,"ct":"SUB_IMAGE","mc":[]}"#,
+        ));
+        replay.on_request(&market_subscription());
+
+        assert_eq!(replay.market_clocks(), &SubscriptionClocks::default());
+        assert_eq!(replay.requests(), vec![market_subscription()]);
+    }
+
+    #[test]
+    fn market_and_order_clocks_are_tracked_separately() {
+        let mut replay = SubscriptionReplay::default();
+        replay.on_request(&market_subscription());
+        replay.on_request(&RequestMessage::OrderSubscription(
+            OrderSubscriptionMessage {
+                id: Some(2),
+                order_filter: Some(Box::new(OrderFilter::default())),
+                ..Default::default()
+            },
+        ));
+        replay.on_response(&response(
+            r#"{"op":"ocm","id":2,"initialClk":"b3JkZXI=","clk":"AAAAAAAC","pt":1,"ct":"SUB_IMAGE","oc":[]}"#,
+        ));
+
+        let requests = replay.requests();
+        assert_eq!(requests.len(), 2);
+        let RequestMessage::MarketSubscription(ref market) = requests[0] else {
+            panic!("expected market subscription");
+        };
+        assert_eq!(market.clk, None);
+        assert_eq!(market.initial_clk, None);
+        let RequestMessage::OrderSubscription(ref order) = requests[1] else {
+            panic!("expected order subscription");
+        };
+        assert_eq!(order.clk.as_deref(), Some("AAAAAAAC"));
+        assert_eq!(order.initial_clk.as_deref(), Some("b3JkZXI="));
+    }
+}
@@ -0,0 +1,160 @@
+//! How the TCP connection to the stream endpoint is secured.
+
+use alloc::sync::Arc;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use eyre::Context as _;
+use rustls::pki_types::{CertificateDer, ServerName};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+
+/// Transport used for the stream connection.
+///
+/// Betfair only accepts TLS connections, [`StreamTransport::Plaintext`] exists for pointing the
+/// stream at a local stand-in server or a test harness.
+#[derive(Debug, Clone)]
+pub enum StreamTransport {
+    /// Encrypt the connection with TLS.
+    Tls(TlsSettings),
+    /// Plain TCP without any encryption.
+    Plaintext,
+}
+
+impl Default for StreamTransport {
+    fn default() -> Self {
+        Self::Tls(TlsSettings::default())
+    }
+}
+
+/// TLS settings of the stream connection.
+///
+/// By default the server certificate is verified against the native root certificates and the
+/// server name is taken from the stream url.
+#[derive(Debug, Clone, Default)]
+pub struct TlsSettings {
+    /// Use this client configuration instead of building one from the native root certificates.
+    /// [`Self::extra_root_certificates`] are ignored when this is set.
+    pub client_config: Option<Arc<rustls::ClientConfig>>,
+    /// Root certificates trusted in addition to the native ones, e.g. the private CA of a
+    /// TLS-inspecting proxy.
+    pub extra_root_certificates: Vec<CertificateDer<'static>>,
+    /// Server name sent in the SNI extension and used to verify the server certificate.
+    /// Defaults to the host of the stream url.
+    pub server_name: Option<String>,
+}
+
+impl TlsSettings {
+    fn connector(&self) -> eyre::Result<tokio_rustls::TlsConnector> {
+        if let Some(ref config) = self.client_config {
+            return Ok(tokio_rustls::TlsConnector::from(Arc::clone(config)));
+        }
+
+        let mut roots = rustls::RootCertStore::empty();
+        let native_certs = rustls_native_certs::load_native_certs();
+        for cert in native_certs.certs {
+            roots.add(cert)?;
+        }
+        for cert in &self.extra_root_certificates {
+            roots
+                .add(cert.clone())
+                .wrap_err("invalid extra root certificate")?;
+        }
+
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(tokio_rustls::TlsConnector::from(Arc::new(config)))
+    }
+}
+
+/// Establishes connections according to a [`StreamTransport`].
+///
+/// Built once per connection streak so that the native root certificates are not loaded on every
+/// attempt.
+pub(crate) enum Connector {
+    Tls {
+        connector: tokio_rustls::TlsConnector,
+        server_name: ServerName<'static>,
+    },
+    Plaintext,
+}
+
+impl Connector {
+    /// `host` is the host of the stream url, used as the server name unless overridden.
+    pub(crate) fn new(transport: &StreamTransport, host: &str) -> eyre::Result<Self> {
+        match *transport {
+            StreamTransport::Tls(ref settings) => {
+                let server_name = settings.server_name.as_deref().unwrap_or(host);
+                let server_name = ServerName::try_from(server_name.to_owned())
+                    .wrap_err("failed to parse server name")?;
+                Ok(Self::Tls {
+                    connector: settings.connector()?,
+                    server_name,
+                })
+            }
+            StreamTransport::Plaintext => Ok(Self::Plaintext),
+        }
+    }
+
+    pub(crate) async fn connect(&self, stream: TcpStream) -> std::io::Result<MaybeTlsStream> {
+        match *self {
+            Self::Tls {
+                ref connector,
+                ref server_name,
+            } => {
+                let stream = connector.connect(server_name.clone(), stream).await?;
+                Ok(MaybeTlsStream::Tls(Box::new(stream)))
+            }
+            Self::Plaintext => Ok(MaybeTlsStream::Plain(stream)),
+        }
+    }
+}
+
+/// A connection to the stream endpoint, with or without TLS.
+#[derive(Debug)]
+pub(crate) enum MaybeTlsStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for MaybeTlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! Fixtures shared by the module tests.
+
+use std::sync::Arc;
+
+use betfair_adapter::betfair_types::types::sports_aping::MarketId;
+use betfair_rpc_server_mock::{Server, StreamServer};
+use betfair_stream_api::cache::market_subscriber::MarketSubscriber;
+use betfair_stream_api::types::request::RequestMessage;
+use betfair_stream_api::types::request::market_subscription_message::{Fields, MarketFilter};
+use betfair_stream_api::{BetfairStreamBuilder, BetfairStreamClient, Cache};
+
+/// Start a plaintext stream against `stream_server` and wait until it is authenticated.
+pub(crate) async fn connect(stream_server: &StreamServer) -> (Server, BetfairStreamClient<Cache>) {
+    let server = Server::new_with_stream_url(stream_server.url()).await;
+    let client = server.client().await;
+    let (mut client, _task) = BetfairStreamBuilder::<Cache>::new(client)
+        .with_plaintext()
+        .start::<10>();
+    // connection + authentication status
+    client.sink.recv().await.unwrap();
+    client.sink.recv().await.unwrap();
+    (server, client)
+}
+
+pub(crate) fn market_id(id: &str) -> MarketId {
+    MarketId(Arc::new(id.to_owned()))
+}
+
+pub(crate) fn market_subscriber(client: &BetfairStreamClient<Cache>) -> MarketSubscriber {
+    MarketSubscriber::new(
+        client,
+        MarketFilter::default(),
+        vec![Fields::ExBestOffersDisp],
+        None,
+    )
+}
+
+/// The market ids of the next market subscription received by the server.
+pub(crate) async fn next_subscription(stream_server: &StreamServer) -> Vec<String> {
+    loop {
+        let request = stream_server.next_request().await.unwrap();
+        let RequestMessage::MarketSubscription(subscription) = request else {
+            continue;
+        };
+        return subscription
+            .market_filter
+            .unwrap()
+            .market_ids
+            .unwrap()
+            .iter()
+            .map(|id| id.0.to_string())
+            .collect();
+    }
+}
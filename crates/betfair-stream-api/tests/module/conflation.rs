@@ -0,0 +1,62 @@
+use std::time::Duration;
+
+use betfair_adapter::betfair_types::size::Size;
+use betfair_rpc_server_mock::{Server, StreamServer};
+use betfair_stream_api::{BetfairStreamBuilder, Cache, CachedMessage, ConflationStats};
+use pretty_assertions::assert_eq;
+
+fn market_change(market_id: &str, total_matched: u32) -> String {
+    format!(
+        r#"{{"op":"mcm","id":1,"clk":"AAAAAAAA","pt":1478717720756,"mc":[{{"id":"{market_id}","tv":{total_matched}}}]}}"#
+    )
+}
+
+#[test_log::test(tokio::test)]
+async fn slow_consumer_receives_the_latest_state_per_market() {
+    let stream_server = StreamServer::new().await;
+    let server = Server::new_with_stream_url(stream_server.url()).await;
+    let client = server.client().await;
+    let (mut client, _task) = BetfairStreamBuilder::<Cache>::new(client)
+        .with_plaintext()
+        .start_conflated::<10>();
+    // connection + authentication status
+    client.sink.recv().await.unwrap();
+    client.sink.recv().await.unwrap();
+
+    stream_server.push_raw(market_change("1.1", 1));
+    stream_server.push_raw(market_change("1.2", 1));
+    stream_server.push_raw(market_change("1.1", 2));
+    stream_server.push_raw(market_change("1.1", 3));
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while client.sink.stats().market_updates_conflated < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    let Some(CachedMessage::MarketChange(markets)) = client.sink.recv().await else {
+        panic!("expected a market change");
+    };
+    let markets = markets
+        .iter()
+        .map(|market| (market.market_id().0.to_string(), market.total_matched()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        markets,
+        vec![
+            ("1.1".to_owned(), Size::new(3.0)),
+            ("1.2".to_owned(), Size::new(1.0))
+        ]
+    );
+    assert_eq!(client.sink.try_recv(), None);
+    assert_eq!(
+        client.sink.stats(),
+        ConflationStats {
+            market_updates_conflated: 2,
+            order_updates_conflated: 0,
+            events_dropped: 0,
+        }
+    );
+}
@@ -1 +1,14 @@
 mod build_cache_from_prod;
+mod common;
+mod conflation;
+mod market_events;
+mod pool;
+mod replay;
+mod rest_fallback;
+mod session;
+mod shutdown;
+mod stats;
+mod subscription_ack;
+mod subscription_manager;
+mod transport;
+mod validator;
@@ -0,0 +1,72 @@
+use std::time::Duration;
+
+use betfair_adapter::betfair_types::price::Price;
+use betfair_adapter::betfair_types::types::sports_aping::SelectionId;
+use betfair_rpc_server_mock::{Server, StreamServer};
+use betfair_stream_api::types::response::order_change_message::Side;
+use betfair_stream_api::{BetfairStreamBuilder, Cache, CachedMessage, MarketEvent};
+use pretty_assertions::assert_eq;
+
+use crate::common::market_id;
+
+fn market_definition(status: &str, version: u32) -> String {
+    format!(
+        r#"{{"bspMarket":false,"turnInPlayEnabled":true,"persistenceEnabled":true,"marketBaseRate":5,"eventId":"28009395","eventTypeId":"2","numberOfWinners":1,"bettingType":"ODDS","marketType":"MATCH_ODDS","marketTime":"2016-11-09T18:15:00.000Z","bspReconciled":false,"complete":true,"inPlay":false,"crossMatching":true,"runnersVoidable":false,"numberOfActiveRunners":1,"betDelay":0,"status":"{status}","runners":[{{"status":"ACTIVE","sortPriority":1,"id":1}}],"regulators":["MR_INT"],"discountAllowed":true,"timezone":"UTC","openDate":"2016-11-09T18:15:00.000Z","version":{version}}}"#
+    )
+}
+
+#[test_log::test(tokio::test)]
+async fn market_events_follow_the_market_change_they_were_derived_from() {
+    let stream_server = StreamServer::new().await;
+    let server = Server::new_with_stream_url(stream_server.url()).await;
+    let (mut client, _task) = BetfairStreamBuilder::<Cache>::new(server.client().await)
+        .with_plaintext()
+        .with_processor(Cache::new().with_market_events())
+        .start::<10>();
+    // connection + authentication status
+    client.sink.recv().await.unwrap();
+    client.sink.recv().await.unwrap();
+
+    stream_server.push_raw(format!(
+        r#"{{"op":"mcm","id":1,"clk":"AAAAAAAA","pt":1478717720756,"ct":"SUB_IMAGE","mc":[{{"id":"1.1","img":true,"marketDefinition":{},"rc":[{{"id":1,"atb":[[2.0,10]]}}]}}]}}"#,
+        market_definition("OPEN", 1)
+    ));
+    stream_server.push_raw(format!(
+        r#"{{"op":"mcm","id":1,"clk":"AAAAAAAB","pt":1478717720757,"mc":[{{"id":"1.1","marketDefinition":{},"rc":[{{"id":1,"atb":[[2.0,0],[1.9,5]]}}]}}]}}"#,
+        market_definition("SUSPENDED", 2)
+    ));
+
+    let mut received = Vec::new();
+    while received.len() < 3 {
+        let message = tokio::time::timeout(Duration::from_secs(5), client.sink.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        received.push(message);
+    }
+
+    let market_id = market_id("1.1");
+    assert!(matches!(received[0], CachedMessage::MarketChange(_)));
+    assert!(matches!(received[1], CachedMessage::MarketChange(_)));
+    assert_eq!(
+        received[2],
+        CachedMessage::MarketEvents(vec![
+            MarketEvent::MarketSuspended {
+                market_id: market_id.clone(),
+            },
+            MarketEvent::MarketDefinitionVersionChanged {
+                market_id: market_id.clone(),
+                old: 1,
+                new: 2,
+            },
+            MarketEvent::BestPriceChanged {
+                market_id,
+                selection_id: SelectionId(1),
+                handicap: None,
+                side: Side::Back,
+                old: Some(Price::new(2.0).unwrap()),
+                new: Some(Price::new(1.9).unwrap()),
+            },
+        ])
+    );
+}
@@ -0,0 +1,140 @@
+use std::time::Duration;
+
+use betfair_rpc_server_mock::{Server, StreamServer};
+use betfair_stream_api::types::request::market_subscription_message::Fields;
+use betfair_stream_api::types::response::status_message::ErrorCode;
+use betfair_stream_api::{BetfairStreamBuilder, BetfairStreamPool, Cache, PoolError};
+use pretty_assertions::assert_eq;
+
+use crate::common::{market_id, next_subscription};
+
+async fn pool(
+    stream_server: &StreamServer,
+    max_markets: usize,
+) -> (Server, BetfairStreamPool<Cache>) {
+    let server = Server::new_with_stream_url(stream_server.url()).await;
+    let client = server.client().await;
+    let builder = BetfairStreamBuilder::<Cache>::new(client).with_plaintext();
+    let pool = BetfairStreamPool::new(builder, vec![Fields::ExBestOffersDisp])
+        .with_max_markets_per_connection(max_markets)
+        .with_max_connections(2);
+    (server, pool)
+}
+
+fn closed_market(market_id: &str) -> String {
+    format!(
+        r#"{{"op":"mcm","id":1,"clk":"AAAAAAAA","pt":1478717720756,"mc":[{{"id":"{market_id}","marketDefinition":{{"bspMarket":false,"turnInPlayEnabled":true,"persistenceEnabled":true,"marketBaseRate":5,"eventId":"1","eventTypeId":"7","numberOfWinners":1,"bettingType":"ODDS","marketType":"WIN","marketTime":"2016-11-09T18:15:00.000Z","bspReconciled":false,"complete":true,"inPlay":false,"crossMatching":true,"runnersVoidable":false,"numberOfActiveRunners":0,"betDelay":0,"status":"CLOSED","runners":[],"regulators":["MR_INT"],"discountAllowed":true,"timezone":"UTC","version":1}}}}]}}"#
+    )
+}
+
+#[test_log::test(tokio::test)]
+async fn spreads_markets_and_closes_unneeded_connections() {
+    let stream_server = StreamServer::new().await;
+    let (_server, pool) = pool(&stream_server, 2).await;
+    let mut client = pool.start::<32>().await.unwrap();
+
+    for id in ["1.1", "1.2", "1.3"] {
+        client.subscribe_to_market(market_id(id)).await.unwrap();
+    }
+    assert_eq!(client.markets_per_connection(), vec![2, 1]);
+    assert_eq!(stream_server.open_connections(), 2);
+    client.subscribe_to_market(market_id("1.4")).await.unwrap();
+    assert_eq!(
+        client.subscribe_to_market(market_id("1.5")).await,
+        Err(PoolError::Full)
+    );
+
+    // closing a market frees a slot, the remaining markets fit on one connection
+    client
+        .unsubscribe_from_market(&market_id("1.4"))
+        .await
+        .unwrap();
+    stream_server.push_raw(closed_market("1.1"));
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while client.markets_per_connection() != vec![2] {
+            client.sink.recv().await.unwrap();
+        }
+    })
+    .await
+    .unwrap();
+    // the emptied connection was shut down after its market moved
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while stream_server.open_connections() != 1 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+#[test_log::test(tokio::test)]
+async fn gives_up_on_unreachable_endpoints() {
+    let stream_server = StreamServer::new().await;
+    let (_server, pool) = pool(&stream_server, 2).await;
+    // nothing listens on the stream url anymore
+    drop(stream_server);
+    let client = pool
+        .with_connect_timeout(Duration::from_millis(200))
+        .start::<32>()
+        .await
+        .unwrap();
+
+    assert_eq!(
+        client.subscribe_to_market(market_id("1.1")).await,
+        Err(PoolError::ConnectTimeout)
+    );
+    tokio::time::timeout(Duration::from_secs(5), client.shutdown())
+        .await
+        .unwrap();
+}
+
+#[test_log::test(tokio::test)]
+async fn opens_another_connection_when_betfair_rejects_the_subscription() {
+    let stream_server = StreamServer::new().await;
+    let (_server, pool) = pool(&stream_server, 10).await;
+    let client = pool.start::<32>().await.unwrap();
+
+    client.subscribe_to_market(market_id("1.1")).await.unwrap();
+    stream_server.fail_next_subscription(ErrorCode::SubscriptionLimitExceeded);
+    client.subscribe_to_market(market_id("1.2")).await.unwrap();
+
+    assert_eq!(client.markets_per_connection(), vec![1, 1]);
+}
+
+#[test_log::test(tokio::test)]
+async fn stops_opening_connections_when_betfair_limits_them() {
+    let stream_server = StreamServer::new().await;
+    let (_server, pool) = pool(&stream_server, 1).await;
+    let client = pool.start::<32>().await.unwrap();
+
+    client.subscribe_to_market(market_id("1.1")).await.unwrap();
+    stream_server.fail_next_authentication(ErrorCode::MaxConnectionLimitExceeded);
+    assert_eq!(
+        client.subscribe_to_market(market_id("1.2")).await,
+        Err(PoolError::Full)
+    );
+    // the next authentication would succeed, but the pool no longer tries
+    assert_eq!(
+        client.subscribe_to_market(market_id("1.3")).await,
+        Err(PoolError::Full)
+    );
+    assert_eq!(client.markets_per_connection(), vec![1]);
+}
+
+#[test_log::test(tokio::test)]
+async fn unsubscribing_narrows_the_connection_subscription() {
+    let stream_server = StreamServer::new().await;
+    let (_server, pool) = pool(&stream_server, 10).await;
+    let client = pool.start::<32>().await.unwrap();
+
+    client.subscribe_to_market(market_id("1.1")).await.unwrap();
+    client.subscribe_to_market(market_id("1.2")).await.unwrap();
+    assert_eq!(next_subscription(&stream_server).await, vec!["1.1"]);
+    assert_eq!(next_subscription(&stream_server).await, vec!["1.1", "1.2"]);
+
+    client
+        .unsubscribe_from_market(&market_id("1.1"))
+        .await
+        .unwrap();
+    assert_eq!(next_subscription(&stream_server).await, vec!["1.2"]);
+}
@@ -0,0 +1,149 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use async_compression::tokio::bufread::{BzEncoder, GzipEncoder};
+use betfair_stream_api::types::response::ResponseMessage;
+use betfair_stream_api::{Cache, CachedMessage, Forwarder, Pacing, StreamReplayBuilder};
+use pretty_assertions::assert_eq;
+use tokio::io::AsyncReadExt as _;
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("fixtures")
+        .join(name)
+}
+
+fn mcm(market_id: &str, pt: i64) -> String {
+    format!(r#"{{"op":"mcm","clk":"{pt}","pt":{pt},"mc":[{{"id":"{market_id}"}}]}}"#)
+}
+
+async fn collect<T: Send + 'static>(mut rx: tokio::sync::mpsc::Receiver<T>) -> Vec<T> {
+    let mut messages = Vec::new();
+    while let Some(message) = rx.recv().await {
+        messages.push(message);
+    }
+    messages
+}
+
+fn market_ids(messages: &[ResponseMessage]) -> Vec<(String, i64)> {
+    messages
+        .iter()
+        .map(|message| {
+            let ResponseMessage::MarketChange(msg) = message else {
+                panic!("expected a market change message");
+            };
+            let market = &msg.data.as_ref().unwrap()[0];
+            (
+                market.market_id.as_ref().unwrap().0.to_string(),
+                msg.publish_time.unwrap().timestamp_millis(),
+            )
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn replays_fixture_through_cache() {
+    let (rx, task) = StreamReplayBuilder::<Cache>::new([fixture("29788105")]).start::<100>();
+
+    let messages = collect(rx).await;
+    task.await.unwrap().unwrap();
+
+    assert!(!messages.is_empty());
+    assert!(messages.iter().all(
+        |message| matches!(message, CachedMessage::MarketChange(markets) if !markets.is_empty())
+    ));
+}
+
+#[tokio::test]
+async fn replays_compressed_files() {
+    let directory = tempfile::tempdir().unwrap();
+    let plain = tokio::fs::read(fixture("29788106")).await.unwrap();
+
+    let mut gzip = Vec::new();
+    GzipEncoder::new(plain.as_slice())
+        .read_to_end(&mut gzip)
+        .await
+        .unwrap();
+    let gzip_path = directory.path().join("29788106.gz");
+    tokio::fs::write(&gzip_path, gzip).await.unwrap();
+
+    let mut bzip2 = Vec::new();
+    BzEncoder::new(plain.as_slice())
+        .read_to_end(&mut bzip2)
+        .await
+        .unwrap();
+    let bzip2_path = directory.path().join("29788106.bz2");
+    tokio::fs::write(&bzip2_path, bzip2).await.unwrap();
+
+    for path in [fixture("29788106"), gzip_path, bzip2_path] {
+        let (rx, task) = StreamReplayBuilder::<Forwarder>::new_without_cache([path]).start::<100>();
+        let messages = collect(rx).await;
+        task.await.unwrap().unwrap();
+        assert_eq!(messages.len(), 6826);
+    }
+}
+
+#[tokio::test]
+async fn merges_files_in_publish_time_order() {
+    let directory = tempfile::tempdir().unwrap();
+    let first = directory.path().join("first");
+    let second = directory.path().join("second");
+    let lines = |lines: &[String]| lines.join("\n");
+    tokio::fs::write(
+        &first,
+        lines(&[mcm("1.1", 1), mcm("1.1", 4), mcm("1.1", 5)]),
+    )
+    .await
+    .unwrap();
+    tokio::fs::write(
+        &second,
+        lines(&[mcm("1.2", 2), mcm("1.2", 3), mcm("1.2", 6)]),
+    )
+    .await
+    .unwrap();
+
+    let (rx, task) =
+        StreamReplayBuilder::<Forwarder>::new_without_cache([first, second]).start::<10>();
+    let messages = collect(rx).await;
+    task.await.unwrap().unwrap();
+
+    assert_eq!(
+        market_ids(&messages),
+        vec![
+            ("1.1".to_owned(), 1),
+            ("1.2".to_owned(), 2),
+            ("1.2".to_owned(), 3),
+            ("1.1".to_owned(), 4),
+            ("1.1".to_owned(), 5),
+            ("1.2".to_owned(), 6),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn real_time_pacing_follows_publish_times() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("market");
+    tokio::fs::write(&path, [mcm("1.1", 1_000), mcm("1.1", 1_200)].join("\n"))
+        .await
+        .unwrap();
+
+    let started = tokio::time::Instant::now();
+    let (rx, task) = StreamReplayBuilder::<Forwarder>::new_without_cache([path])
+        .with_pacing(Pacing::RealTime)
+        .start::<10>();
+    let messages = collect(rx).await;
+    task.await.unwrap().unwrap();
+
+    assert_eq!(messages.len(), 2);
+    assert!(started.elapsed() >= Duration::from_millis(200));
+}
+
+#[tokio::test]
+async fn missing_file_fails_the_replay() {
+    let (rx, task) =
+        StreamReplayBuilder::<Forwarder>::new_without_cache(["does-not-exist"]).start::<10>();
+
+    assert!(collect(rx).await.is_empty());
+    assert!(task.await.unwrap().is_err());
+}
@@ -0,0 +1,187 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use betfair_adapter::betfair_types::numeric::F64Ord;
+use betfair_adapter::betfair_types::types::sports_aping::{
+    BetId, MarketId, SelectionId, list_current_orders, list_market_book, list_market_catalogue,
+};
+use betfair_rpc_server_mock::wiremock::ResponseTemplate;
+use betfair_rpc_server_mock::wiremock::matchers::{body_partial_json, path};
+use betfair_rpc_server_mock::{Server, StreamServer, rpc_path};
+use betfair_stream_api::cache::primitives::{MarketBookCache, OrderBookCache};
+use betfair_stream_api::types::request::market_subscription_message::MarketDataFilter;
+use betfair_stream_api::{
+    BetfairStreamBuilder, Cache, CachedMessage, LifecycleEvent, ReconnectPolicy, RestFallback,
+    SharedSession,
+};
+use pretty_assertions::assert_eq;
+use serde_json::json;
+
+/// Mock the catalogue and book of market `1.1`.
+async fn mock_market(server: &Server) {
+    server
+        .mock_authenticated_rpc_from_json::<list_market_catalogue::Parameters>(json!([{
+            "marketId": "1.1",
+            "marketName": "Match Odds",
+            "description": {
+                "bspMarket": false,
+                "marketTime": "2016-11-09T18:15:00.000Z",
+                "suspendTime": "2016-11-09T18:15:00.000Z",
+                "bettingType": "ODDS",
+                "turnInPlayEnabled": true,
+                "marketType": "MATCH_ODDS"
+            },
+            "runners": [{"selectionId": 1, "runnerName": "One", "handicap": 0.0, "sortPriority": 1}]
+        }]))
+        .mount(&server.bf_api_mock_server)
+        .await;
+    server
+        .mock_authenticated_rpc_from_json::<list_market_book::Parameters>(json!([{
+            "marketId": "1.1",
+            "isMarketDataDelayed": false,
+            "status": "OPEN",
+            "inplay": false,
+            "version": 7,
+            "runners": [{
+                "selectionId": 1,
+                "handicap": 0.0,
+                "status": "ACTIVE",
+                "lastPriceTraded": 2.0,
+                "ex": {
+                    "availableToBack": [{"price": 2.0, "size": 10.0}],
+                    "availableToLay": [{"price": 2.02, "size": 5.0}],
+                    "tradedVolume": []
+                }
+            }]
+        }]))
+        .mount(&server.bf_api_mock_server)
+        .await;
+}
+
+/// An executable back order on market `1.1`.
+fn order(bet_id: &str) -> serde_json::Value {
+    json!({
+        "betId": bet_id,
+        "marketId": "1.1",
+        "selectionId": 1,
+        "handicap": 0.0,
+        "priceSize": {"price": 2.0, "size": 4.0},
+        "bspLiability": 0.0,
+        "side": "BACK",
+        "status": "EXECUTABLE",
+        "persistenceType": "LAPSE",
+        "orderType": "LIMIT",
+        "placedDate": "2016-11-09T18:00:00.000Z",
+        "sizeMatched": 0.0,
+        "sizeRemaining": 4.0
+    })
+}
+
+/// Start a stream with a REST fallback for market `1.1`, drop its connection and return the
+/// first market and order changes polled over REST.
+async fn poll_while_disconnected(
+    stream_server: &StreamServer,
+    server: &Server,
+) -> (Vec<MarketBookCache>, Vec<OrderBookCache>) {
+    let (client, _keep_alive) = server.client().await.authenticate().await.unwrap();
+    let session = SharedSession::new(Arc::clone(&client));
+
+    let (client, _task) = BetfairStreamBuilder::<Cache>::new_authenticated(session.clone())
+        .with_plaintext()
+        .with_reconnect_policy(
+            ReconnectPolicy::default()
+                .with_initial_delay(Duration::from_secs(30))
+                .with_jitter(false),
+        )
+        .start::<10>();
+    let (mut client, _fallback) = RestFallback::new(session)
+        .with_poll_interval(Duration::from_millis(100))
+        .with_market_ids([MarketId::new("1.1")])
+        .attach::<10, _>(client);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !matches!(
+            client.lifecycle.recv().await.unwrap(),
+            LifecycleEvent::Connected { .. }
+        ) {}
+    })
+    .await
+    .unwrap();
+
+    stream_server.drop_connections();
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        let mut markets = None;
+        let mut orders = None;
+        while markets.is_none() || orders.is_none() {
+            match client.sink.recv().await.unwrap() {
+                CachedMessage::MarketChange(market) => markets = Some(market),
+                CachedMessage::OrderChange(order) => orders = Some(order),
+                _ => {}
+            }
+        }
+        (markets.unwrap(), orders.unwrap())
+    })
+    .await
+    .unwrap()
+}
+
+#[test_log::test(tokio::test)]
+async fn polls_markets_over_rest_while_the_stream_is_down() {
+    let stream_server = StreamServer::new().await;
+    let server = Server::new_with_stream_url(stream_server.url()).await;
+    mock_market(&server).await;
+    server
+        .mock_authenticated_rpc_from_json::<list_current_orders::Parameters>(json!({
+            "currentOrders": [order("42")],
+            "moreAvailable": false
+        }))
+        .mount(&server.bf_api_mock_server)
+        .await;
+
+    let (markets, orders) = poll_while_disconnected(&stream_server, &server).await;
+
+    assert_eq!(markets.len(), 1);
+    let book = markets[0].to_market_book(&MarketDataFilter::default());
+    assert_eq!(book.market_id, MarketId::new("1.1"));
+    assert_eq!(book.version, Some(7));
+    let runner = &book.runners.unwrap()[0];
+    assert_eq!(runner.selection_id, SelectionId(1));
+    assert_eq!(runner.last_price_traded, Some(F64Ord::new(2.0)));
+    assert_eq!(
+        markets[0].market_definition().unwrap().market_type,
+        "MATCH_ODDS"
+    );
+    assert_eq!(orders.len(), 1);
+    let summaries = orders[0].current_orders();
+    assert_eq!(summaries.len(), 1);
+    assert_eq!(summaries[0].bet_id, BetId::new("42"));
+}
+
+#[test_log::test(tokio::test)]
+async fn follows_every_page_of_current_orders() {
+    let stream_server = StreamServer::new().await;
+    let server = Server::new_with_stream_url(stream_server.url()).await;
+    mock_market(&server).await;
+    for (from_record, bet_id, more_available) in [(0, "42", true), (1, "43", false)] {
+        let orders_path = path(rpc_path::<list_current_orders::Parameters>());
+        server
+            .mock_builder("POST", orders_path, true)
+            .and(body_partial_json(json!({ "fromRecord": from_record })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "currentOrders": [order(bet_id)],
+                "moreAvailable": more_available
+            })))
+            .mount(&server.bf_api_mock_server)
+            .await;
+    }
+
+    let (_markets, orders) = poll_while_disconnected(&stream_server, &server).await;
+
+    assert_eq!(orders.len(), 1);
+    let bet_ids = orders[0]
+        .current_orders()
+        .iter()
+        .map(|order| order.bet_id.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(bet_ids, vec![BetId::new("42"), BetId::new("43")]);
+}
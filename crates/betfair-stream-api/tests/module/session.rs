@@ -0,0 +1,48 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use betfair_rpc_server_mock::{Server, StreamServer};
+use betfair_stream_api::types::response::status_message::ErrorCode;
+use betfair_stream_api::{BetfairStreamBuilder, Cache, LifecycleEvent, SharedSession};
+
+#[test_log::test(tokio::test)]
+async fn concurrent_reauthentication_logs_in_once() {
+    let server = Server::new().await;
+    let (client, _keep_alive) = server.client().await.authenticate().await.unwrap();
+    let session = SharedSession::new(Arc::clone(&client));
+
+    let (first, second) = tokio::join!(
+        session.reauthenticate(&client, backon::ExponentialBuilder::new()),
+        session.reauthenticate(&client, backon::ExponentialBuilder::new()),
+    );
+    let (first, second) = (first.unwrap(), second.unwrap());
+
+    assert!(!Arc::ptr_eq(&first, &client));
+    assert!(Arc::ptr_eq(&first, &second));
+    assert!(Arc::ptr_eq(&session.client(), &first));
+}
+
+#[test_log::test(tokio::test)]
+async fn rejected_session_is_replaced_for_every_holder() {
+    let stream_server = StreamServer::new().await;
+    let server = Server::new_with_stream_url(stream_server.url()).await;
+    let (client, _keep_alive) = server.client().await.authenticate().await.unwrap();
+    let session = SharedSession::new(Arc::clone(&client));
+    let mut watch = session.watch();
+
+    stream_server.fail_next_authentication(ErrorCode::InvalidSessionInformation);
+    let (mut stream, _task) = BetfairStreamBuilder::<Cache>::new_authenticated(session.clone())
+        .with_plaintext()
+        .start::<10>();
+
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !matches!(
+            stream.lifecycle.recv().await.unwrap(),
+            LifecycleEvent::Connected { .. }
+        ) {}
+    })
+    .await
+    .unwrap();
+    assert!(watch.has_changed().unwrap());
+    assert!(!Arc::ptr_eq(&watch.borrow_and_update(), &client));
+}
@@ -0,0 +1,125 @@
+use std::time::Duration;
+
+use betfair_rpc_server_mock::wiremock::matchers::path;
+use betfair_rpc_server_mock::{BOT_LOGIN_URL, LOGOUT, Server, StreamServer};
+use betfair_stream_api::types::request::RequestMessage;
+use betfair_stream_api::types::request::heartbeat_message::HeartbeatMessage;
+use betfair_stream_api::{BetfairStreamBuilder, Cache, LifecycleEvent};
+use serde_json::json;
+
+async fn wait_for_event(
+    lifecycle: &mut tokio::sync::broadcast::Receiver<LifecycleEvent>,
+    matches: impl Fn(&LifecycleEvent) -> bool,
+) {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !matches(&lifecycle.recv().await.unwrap()) {}
+    })
+    .await
+    .unwrap();
+}
+
+async fn mock_logout(server: &Server, expected_calls: u64) {
+    let response = json!({
+        "token": "",
+        "product": "AppKey",
+        "status": "SUCCESS",
+        "error": ""
+    });
+    server
+        .mock_success("GET", path(LOGOUT), "Logout", true, response)
+        .expect(expected_calls)
+        .mount(&server.bf_api_mock_server)
+        .await;
+}
+
+#[test_log::test(tokio::test)]
+async fn shutdown_sends_queued_requests_closes_the_connection_and_logs_out() {
+    let stream_server = StreamServer::new().await;
+    let server = Server::new_with_stream_url(stream_server.url()).await;
+    mock_logout(&server, 1).await;
+    let (mut stream, task) = BetfairStreamBuilder::<Cache>::new(server.client().await)
+        .with_plaintext()
+        .start::<10>();
+    wait_for_event(&mut stream.lifecycle, |event| {
+        matches!(event, LifecycleEvent::Connected { .. })
+    })
+    .await;
+    stream_server.next_request().await.unwrap(); // authentication
+
+    // queued before the shutdown, so it still reaches betfair
+    stream
+        .send_to_stream
+        .send(RequestMessage::Heartbeat(HeartbeatMessage { id: Some(7) }))
+        .await
+        .unwrap();
+    stream.shutdown.shutdown_and_logout().await;
+
+    assert!(stream.shutdown.is_finished());
+    assert_eq!(
+        stream_server.next_request().await,
+        Some(RequestMessage::Heartbeat(HeartbeatMessage { id: Some(7) }))
+    );
+    task.await.unwrap().unwrap();
+    wait_for_event(&mut stream.lifecycle, |event| {
+        *event == LifecycleEvent::ShutDown
+    })
+    .await;
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while stream_server.open_connections() > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    server.bf_api_mock_server.verify().await;
+}
+
+#[test_log::test(tokio::test)]
+async fn shutdown_without_logout_keeps_the_session() {
+    let stream_server = StreamServer::new().await;
+    let server = Server::new_with_stream_url(stream_server.url()).await;
+    mock_logout(&server, 0).await;
+    let (mut stream, task) = BetfairStreamBuilder::<Cache>::new(server.client().await)
+        .with_plaintext()
+        .start::<10>();
+    wait_for_event(&mut stream.lifecycle, |event| {
+        matches!(event, LifecycleEvent::Connected { .. })
+    })
+    .await;
+
+    stream.shutdown.shutdown().await;
+
+    task.await.unwrap().unwrap();
+    server.bf_api_mock_server.verify().await;
+}
+
+#[test_log::test(tokio::test)]
+async fn shutdown_while_login_keeps_failing() {
+    let stream_server = StreamServer::new().await;
+    let server = Server::new_with_stream_url(stream_server.url()).await;
+    let response = json!({ "loginStatus": "INVALID_USERNAME_OR_PASSWORD" });
+    server
+        .mock_success("POST", path(BOT_LOGIN_URL), "Failed login", false, response)
+        .with_priority(1)
+        .expect(1..)
+        .mount(&server.bf_api_mock_server)
+        .await;
+    mock_logout(&server, 0).await;
+    let (mut stream, task) = BetfairStreamBuilder::<Cache>::new(server.client().await)
+        .with_plaintext()
+        .start::<10>();
+
+    // let the first login attempt fail, then shut down while it backs off
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    tokio::time::timeout(Duration::from_secs(5), stream.shutdown.shutdown())
+        .await
+        .unwrap();
+
+    task.await.unwrap().unwrap();
+    wait_for_event(&mut stream.lifecycle, |event| {
+        *event == LifecycleEvent::ShutDown
+    })
+    .await;
+    assert_eq!(stream_server.open_connections(), 0);
+    server.bf_api_mock_server.verify().await;
+}
@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+use betfair_rpc_server_mock::{Server, StreamServer};
+use betfair_stream_api::{
+    AlarmThresholds, BetfairStreamBuilder, Cache, CachedMessage, LifecycleEvent, StreamAlarm,
+};
+
+#[test_log::test(tokio::test)]
+async fn silent_connection_raises_a_stale_alarm() {
+    let stream_server = StreamServer::new().await;
+    let server = Server::new_with_stream_url(stream_server.url()).await;
+    let (mut stream, _task) = BetfairStreamBuilder::<Cache>::new(server.client().await)
+        .with_plaintext()
+        .with_alarms(AlarmThresholds {
+            max_latency: None,
+            max_silence: Some(Duration::from_millis(100)),
+        })
+        .start::<10>();
+
+    let alarm = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(CachedMessage::Alarm(alarm)) = stream.sink.recv().await {
+                return alarm;
+            }
+        }
+    })
+    .await
+    .unwrap();
+
+    let StreamAlarm::Stale {
+        silent_for,
+        threshold,
+    } = alarm
+    else {
+        panic!("expected a stale alarm, got {alarm:?}");
+    };
+    assert_eq!(threshold, Duration::from_millis(100));
+    assert!(silent_for >= threshold);
+    // raised once until something is received again
+    assert!(
+        tokio::time::timeout(Duration::from_millis(300), stream.sink.recv())
+            .await
+            .is_err()
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn heartbeats_measure_the_round_trip_time() {
+    let stream_server = StreamServer::new().await;
+    let server = Server::new_with_stream_url(stream_server.url()).await;
+    stream_server.set_heartbeat_delay(Some(Duration::from_millis(20)));
+    let (mut stream, _task) = BetfairStreamBuilder::<Cache>::new(server.client().await)
+        .with_plaintext()
+        .with_heartbeat(Duration::from_millis(50))
+        .start::<10>();
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !matches!(
+            stream.lifecycle.recv().await.unwrap(),
+            LifecycleEvent::Connected { .. }
+        ) {}
+        while stream.stats.snapshot().heartbeat_rtt.is_none() {
+            stream.sink.recv().await.unwrap();
+        }
+    })
+    .await
+    .unwrap();
+
+    let stats = stream.stats.snapshot();
+    assert!(stats.heartbeat_rtt.unwrap() >= Duration::from_millis(20));
+    assert!(stats.messages_received >= 1);
+    assert!(stats.bytes_received > 0);
+    assert_eq!(stats.reconnects, 0);
+    assert!(stats.connection_id.is_some());
+}
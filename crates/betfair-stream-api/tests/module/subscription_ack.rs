@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+use betfair_rpc_server_mock::StreamServer;
+use betfair_stream_api::AckError;
+use betfair_stream_api::types::request::RequestMessage;
+use betfair_stream_api::types::request::heartbeat_message::HeartbeatMessage;
+use betfair_stream_api::types::response::status_message::{ErrorCode, StatusMessage};
+use pretty_assertions::assert_eq;
+
+use crate::common::{connect, market_id, market_subscriber};
+
+#[test_log::test(tokio::test)]
+async fn subscription_resolves_to_its_status() {
+    let stream_server = StreamServer::new().await;
+    let (_server, client) = connect(&stream_server).await;
+    let mut subscriber = market_subscriber(&client);
+
+    let ack = subscriber
+        .subscribe_to_market(market_id("1.1"))
+        .await
+        .unwrap();
+    let id = ack.id();
+
+    let StatusMessage::Success(status) = ack.await.unwrap() else {
+        panic!("expected the subscription to succeed");
+    };
+    assert_eq!(status.id, Some(id));
+}
+
+#[test_log::test(tokio::test)]
+async fn rejected_subscription_resolves_to_its_error() {
+    let stream_server = StreamServer::new().await;
+    let (_server, client) = connect(&stream_server).await;
+    let mut subscriber = market_subscriber(&client);
+
+    stream_server.fail_next_subscription(ErrorCode::SubscriptionLimitExceeded);
+    let rejected = subscriber
+        .subscribe_to_market(market_id("1.1"))
+        .await
+        .unwrap();
+    let accepted = subscriber
+        .subscribe_to_market(market_id("1.2"))
+        .await
+        .unwrap();
+
+    let StatusMessage::Failure(error) = rejected.await.unwrap() else {
+        panic!("expected the first subscription to fail");
+    };
+    assert_eq!(error.error_code, ErrorCode::SubscriptionLimitExceeded);
+    assert!(matches!(accepted.await, Ok(StatusMessage::Success(_))));
+}
+
+#[test_log::test(tokio::test)]
+async fn unanswered_request_times_out() {
+    let stream_server = StreamServer::new().await;
+    let (_server, client) = connect(&stream_server).await;
+
+    stream_server.set_heartbeat_delay(Some(Duration::from_secs(5)));
+    let ack = client
+        .send_with_ack(RequestMessage::Heartbeat(HeartbeatMessage { id: None }))
+        .await
+        .unwrap()
+        .with_timeout(Duration::from_millis(50));
+
+    assert_eq!(ack.await, Err(AckError::Timeout));
+}
@@ -0,0 +1,68 @@
+use std::sync::Arc;
+
+use betfair_adapter::betfair_types::types::sports_aping::EventTypeId;
+use betfair_rpc_server_mock::StreamServer;
+use betfair_stream_api::cache::subscription_manager::{SubscriptionError, SubscriptionManager};
+use betfair_stream_api::types::request::market_subscription_message::MarketFilter;
+use pretty_assertions::assert_eq;
+
+use crate::common::{connect, market_id, next_subscription};
+
+#[test_log::test(tokio::test)]
+async fn markets_stay_subscribed_until_the_last_owner_releases_them() {
+    let stream_server = StreamServer::new().await;
+    let (_server, client) = connect(&stream_server).await;
+    let manager = SubscriptionManager::new(&client);
+    let first = manager.owner().await;
+    let second = manager.owner().await;
+
+    first.subscribe_to_market(market_id("1.1")).await.unwrap();
+    assert_eq!(next_subscription(&stream_server).await, vec!["1.1"]);
+
+    let ack = second.subscribe_to_market(market_id("1.2")).await.unwrap();
+    assert_eq!(next_subscription(&stream_server).await, vec!["1.1", "1.2"]);
+    ack.unwrap().await.unwrap();
+
+    // already covered by the first owner, nothing is sent
+    let ack = second.subscribe_to_market(market_id("1.1")).await.unwrap();
+    assert!(ack.is_none());
+
+    // the second owner still holds both markets
+    assert!(first.release().await.unwrap().is_none());
+    let ack = second
+        .unsubscribe_from_market(&market_id("1.2"))
+        .await
+        .unwrap();
+    assert!(ack.is_some());
+    assert_eq!(next_subscription(&stream_server).await, vec!["1.1"]);
+
+    // nobody is interested in any market anymore
+    drop(second);
+    assert_eq!(
+        next_subscription(&stream_server).await,
+        Vec::<String>::new()
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn filters_restricting_other_dimensions_are_rejected() {
+    let stream_server = StreamServer::new().await;
+    let (_server, client) = connect(&stream_server).await;
+    let manager = SubscriptionManager::new(&client);
+    let first = manager.owner().await;
+    let second = manager.owner().await;
+
+    first.subscribe_to_market(market_id("1.1")).await.unwrap();
+    assert_eq!(next_subscription(&stream_server).await, vec!["1.1"]);
+
+    let filter = MarketFilter {
+        event_type_ids: Some(vec![EventTypeId(Arc::new("7".to_owned()))]),
+        ..MarketFilter::default()
+    };
+    let err = second.set_filter(Some(filter)).await.unwrap_err();
+    assert!(matches!(err, SubscriptionError::IncompatibleFilters));
+
+    // the rejected filter was not kept, so the market ids of both owners still merge
+    second.subscribe_to_market(market_id("1.2")).await.unwrap();
+    assert_eq!(next_subscription(&stream_server).await, vec!["1.1", "1.2"]);
+}
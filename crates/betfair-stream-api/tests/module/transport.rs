@@ -0,0 +1,114 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use betfair_rpc_server_mock::{Server, StreamServer};
+use betfair_stream_api::types::request::RequestMessage;
+use betfair_stream_api::types::request::market_subscription_message::MarketSubscriptionMessage;
+use betfair_stream_api::types::response::status_message::StatusMessage;
+use betfair_stream_api::{
+    BetfairStreamBuilder, BetfairStreamClient, Cache, CachedMessage, LifecycleEvent,
+    ReconnectPolicy,
+};
+
+async fn builder(stream_server: &StreamServer) -> (Server, BetfairStreamBuilder<Cache>) {
+    let server = Server::new_with_stream_url(stream_server.url()).await;
+    let client = server.client().await;
+    let builder = BetfairStreamBuilder::<Cache>::new(client);
+    (server, builder)
+}
+
+/// Wait for the connection and authentication status messages of a successful handshake.
+async fn assert_handshake(client: &mut BetfairStreamClient<Cache>) {
+    let Some(CachedMessage::Connection(connection)) = client.sink.recv().await else {
+        panic!("expected a connection message");
+    };
+    assert_eq!(connection.connection_id.as_deref(), Some("mock-1"));
+    let Some(CachedMessage::Status(StatusMessage::Success(_))) = client.sink.recv().await else {
+        panic!("expected authentication to succeed");
+    };
+}
+
+#[test_log::test(tokio::test)]
+async fn plaintext_transport_connects_and_subscribes() {
+    let stream_server = StreamServer::new().await;
+    let (_server, builder) = builder(&stream_server).await;
+    let (mut client, _task) = builder.with_plaintext().start::<10>();
+
+    assert_handshake(&mut client).await;
+    let mut connected = false;
+    while let Ok(event) = client.lifecycle.try_recv() {
+        connected |= matches!(event, LifecycleEvent::Connected { .. });
+    }
+    assert!(connected);
+
+    client
+        .send_to_stream
+        .send(RequestMessage::MarketSubscription(
+            MarketSubscriptionMessage {
+                id: Some(1),
+                ..Default::default()
+            },
+        ))
+        .await
+        .unwrap();
+    let Some(CachedMessage::Status(StatusMessage::Success(status))) = client.sink.recv().await
+    else {
+        panic!("expected subscription to succeed");
+    };
+    assert_eq!(status.id, Some(1));
+
+    assert!(matches!(
+        stream_server.next_request().await,
+        Some(RequestMessage::Authentication(_))
+    ));
+    assert!(matches!(
+        stream_server.next_request().await,
+        Some(RequestMessage::MarketSubscription(_))
+    ));
+}
+
+#[test_log::test(tokio::test)]
+async fn tls_transport_trusts_extra_root_certificate() {
+    let stream_server = StreamServer::new_with_tls().await;
+    let (_server, builder) = builder(&stream_server).await;
+    let certificate = stream_server.certificate().unwrap().clone();
+    let (mut client, _task) = builder.with_root_certificate(certificate).start::<10>();
+
+    assert_handshake(&mut client).await;
+}
+
+#[test_log::test(tokio::test)]
+async fn tls_transport_uses_custom_config_and_server_name() {
+    let stream_server = StreamServer::new_with_tls().await;
+    let (_server, builder) = builder(&stream_server).await;
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots
+        .add(stream_server.certificate().unwrap().clone())
+        .unwrap();
+    let config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    // the url points at 127.0.0.1, verify the certificate against its dns name instead
+    let (mut client, _task) = builder
+        .with_tls_config(Arc::new(config))
+        .with_server_name("localhost")
+        .start::<10>();
+
+    assert_handshake(&mut client).await;
+}
+
+#[test_log::test(tokio::test)]
+async fn tls_transport_rejects_untrusted_certificate() {
+    let stream_server = StreamServer::new_with_tls().await;
+    let (_server, builder) = builder(&stream_server).await;
+    let policy = ReconnectPolicy::default()
+        .with_jitter(false)
+        .with_initial_delay(Duration::from_millis(10))
+        .with_max_attempts(1);
+    let (mut client, task) = builder.with_reconnect_policy(policy).start::<10>();
+
+    assert!(task.await.unwrap().is_err());
+    assert!(client.sink.recv().await.is_none());
+    assert_eq!(stream_server.open_connections(), 0);
+}
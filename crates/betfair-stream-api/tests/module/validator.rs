@@ -0,0 +1,112 @@
+use std::time::Duration;
+
+use betfair_rpc_server_mock::{Server, StreamServer};
+use betfair_stream_api::cache::market_subscriber::MarketSubscriber;
+use betfair_stream_api::types::request::RequestMessage;
+use betfair_stream_api::types::request::market_subscription_message::{Fields, MarketFilter};
+use betfair_stream_api::{BetfairStreamBuilder, Cache, DataQualityIssue, Validator};
+use pretty_assertions::assert_eq;
+
+use crate::common::market_id;
+
+#[test_log::test(tokio::test)]
+async fn inconsistent_market_triggers_a_resubscribe_for_fresh_images() {
+    let stream_server = StreamServer::new().await;
+    let server = Server::new_with_stream_url(stream_server.url()).await;
+    let validator = Validator::new().with_resubscribe(true);
+    let mut issues = validator.subscribe();
+    let (client, _task) = BetfairStreamBuilder::<Cache>::new(server.client().await)
+        .with_plaintext()
+        .with_processor(Cache::new().with_validator(validator))
+        .start::<10>();
+    let mut subscriber = MarketSubscriber::new(
+        &client,
+        MarketFilter::default(),
+        vec![Fields::ExBestOffersDisp],
+        None,
+    );
+    let market_id = market_id("1.1");
+    subscriber
+        .subscribe_to_market(market_id.clone())
+        .await
+        .unwrap()
+        .await
+        .unwrap();
+    stream_server.next_request().await.unwrap(); // authentication
+    stream_server.next_request().await.unwrap(); // subscription
+
+    // a delta for a market whose image was never received
+    stream_server.push_raw(
+        r#"{"op":"mcm","id":1,"clk":"AAAAAAAB","pt":1478717720756,"mc":[{"id":"1.1","rc":[{"id":1,"atb":[[2.0,10]]}]}]}"#,
+    );
+
+    let issue = tokio::time::timeout(Duration::from_secs(5), issues.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(issue, DataQualityIssue::DeltaWithoutImage { market_id });
+    let Some(RequestMessage::MarketSubscription(resubscription)) =
+        tokio::time::timeout(Duration::from_secs(5), stream_server.next_request())
+            .await
+            .unwrap()
+    else {
+        panic!("expected a market subscription");
+    };
+    assert_eq!(resubscription.clk, None);
+    assert_eq!(resubscription.initial_clk, None);
+    assert_eq!(
+        resubscription
+            .market_filter
+            .and_then(|filter| filter.market_ids)
+            .map(|ids| ids.len()),
+        Some(1)
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn resubscribe_without_a_subscription_does_not_block_later_ones() {
+    let stream_server = StreamServer::new().await;
+    let server = Server::new_with_stream_url(stream_server.url()).await;
+    let validator = Validator::new().with_resubscribe(true);
+    let mut issues = validator.subscribe();
+    let (client, _task) = BetfairStreamBuilder::<Cache>::new(server.client().await)
+        .with_plaintext()
+        .with_processor(Cache::new().with_validator(validator))
+        .start::<10>();
+    stream_server.next_request().await.unwrap(); // authentication
+
+    // nothing is subscribed yet, so there is nothing to resubscribe
+    stream_server.push_raw(
+        r#"{"op":"mcm","id":1,"clk":"AAAAAAAB","pt":1478717720756,"mc":[{"id":"1.1","rc":[{"id":1,"atb":[[2.0,10]]}]}]}"#,
+    );
+    tokio::time::timeout(Duration::from_secs(5), issues.recv())
+        .await
+        .unwrap()
+        .unwrap();
+
+    let mut subscriber = MarketSubscriber::new(
+        &client,
+        MarketFilter::default(),
+        vec![Fields::ExBestOffersDisp],
+        None,
+    );
+    subscriber
+        .subscribe_to_market(market_id("1.2"))
+        .await
+        .unwrap()
+        .await
+        .unwrap();
+    stream_server.next_request().await.unwrap(); // subscription
+    stream_server.push_raw(
+        r#"{"op":"mcm","id":1,"clk":"AAAAAAAC","pt":1478717720757,"mc":[{"id":"1.3","rc":[{"id":1,"atb":[[2.0,10]]}]}]}"#,
+    );
+
+    let Some(RequestMessage::MarketSubscription(resubscription)) =
+        tokio::time::timeout(Duration::from_secs(5), stream_server.next_request())
+            .await
+            .unwrap()
+    else {
+        panic!("expected a market subscription");
+    };
+    assert_eq!(resubscription.clk, None);
+}
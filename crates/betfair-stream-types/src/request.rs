@@ -44,4 +44,15 @@ impl RequestMessage {
             Self::OrderSubscription(msg) => msg.id = Some(id),
         }
     }
+
+    /// Returns the ID of the request message, if set.
+    #[must_use]
+    pub const fn id(&self) -> Option<i32> {
+        match self {
+            Self::Authentication(msg) => msg.id,
+            Self::Heartbeat(msg) => msg.id,
+            Self::MarketSubscription(msg) => msg.id,
+            Self::OrderSubscription(msg) => msg.id,
+        }
+    }
 }
@@ -79,6 +79,12 @@ impl LadderLevel {
 
         Ok(Self(level))
     }
+
+    /// The number of levels.
+    #[must_use]
+    pub const fn get(&self) -> u8 {
+        self.0
+    }
 }
 
 #[derive(
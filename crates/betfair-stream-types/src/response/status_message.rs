@@ -27,6 +27,17 @@ pub enum StatusMessage {
     Failure(StatusError),
 }
 
+impl StatusMessage {
+    /// Returns the id of the request this status answers, if any.
+    #[must_use]
+    pub const fn id(&self) -> Option<i32> {
+        match self {
+            Self::Success(success) => success.id,
+            Self::Failure(failure) => failure.id,
+        }
+    }
+}
+
 /// Represents a successful status response in the Betfair streaming API.
 ///
 /// Contains optional metadata such as request identifier, connection limits, and status flags.
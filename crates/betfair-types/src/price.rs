@@ -84,6 +84,20 @@ impl From<Price> for f64 {
     }
 }
 
+/// The bands of the Betfair odds ladder as `(lower bound, upper bound, increment)`.
+const TICK_BANDS: [(f64, f64, f64); 10] = [
+    (1.01, 2.0, 0.01),
+    (2.0, 3.0, 0.02),
+    (3.0, 4.0, 0.05),
+    (4.0, 6.0, 0.1),
+    (6.0, 10.0, 0.2),
+    (10.0, 20.0, 0.5),
+    (20.0, 30.0, 1.0),
+    (30.0, 50.0, 2.0),
+    (50.0, 100.0, 5.0),
+    (100.0, 1000.0, 10.0),
+];
+
 impl Price {
     pub fn new(price: f64) -> Result<Self, PriceParseError> {
         let price = Self(Self::adjust_price_to_betfair_boundaries(price)?);
@@ -103,6 +117,24 @@ impl Price {
         self.0
     }
 
+    /// The position of the price on the Betfair odds ladder.
+    ///
+    /// # Returns
+    ///
+    /// `0` for the lowest price (1.01) up to `349` for the highest one (1000), so the difference
+    /// between two indices is the number of ticks between the prices.
+    #[must_use]
+    pub fn tick_index(&self) -> u32 {
+        let mut index = 0;
+        for (lower, upper, increment) in TICK_BANDS {
+            if self.0 <= upper {
+                return index + ((self.0 - lower) / increment).round() as u32;
+            }
+            index += ((upper - lower) / increment).round() as u32;
+        }
+        index
+    }
+
     /// Betfair docs: <https://docs.developer.betfair.com/pages/viewpage.action?pageId=6095894>
     /// Below is a list of price increments per price 'group'.  Placing a bet outside of these
     /// increments will result in an `INVALID_ODDS` error
@@ -299,6 +331,18 @@ mod price_serialization_tests {
         }
     }
 
+    #[test]
+    fn tick_index_counts_the_ticks_of_the_ladder() {
+        // stepping through the bands may repeat a price at the boundary of two bands
+        let mut prices = get_all_prices();
+        prices.dedup();
+
+        assert_eq!(prices.len(), 350);
+        for (index, price) in prices.into_iter().enumerate() {
+            assert_eq!(Price::new(price).unwrap().tick_index(), index as u32);
+        }
+    }
+
     fn check_decimal_places(value_str: &str, max_decimal_places: usize) {
         let parts: Vec<&str> = value_str.split('.').collect();
         assert!(parts.len() <= 2);